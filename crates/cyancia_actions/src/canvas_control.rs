@@ -28,6 +28,23 @@ canvas_tool_action!(PanToolAction, "pan_tool", "pan_tool");
 canvas_tool_action!(RotateToolAction, "rotate_tool", "rotate_tool");
 canvas_tool_action!(ZoomToolAction, "zoom_tool", "zoom_tool");
 canvas_tool_action!(BrushToolAction, "brush_tool", "brush_tool");
+canvas_tool_action!(EraserToolAction, "eraser_tool", "eraser_tool");
+canvas_tool_action!(RectSelectToolAction, "rect_select_tool", "rect_select_tool");
+canvas_tool_action!(
+    EllipseSelectToolAction,
+    "ellipse_select_tool",
+    "ellipse_select_tool"
+);
+canvas_tool_action!(LassoToolAction, "lasso_tool", "lasso_tool");
+canvas_tool_action!(
+    PolygonLassoToolAction,
+    "polygon_lasso_tool",
+    "polygon_lasso_tool"
+);
+canvas_tool_action!(MagicWandToolAction, "magic_wand_tool", "magic_wand_tool");
+canvas_tool_action!(FillToolAction, "fill_tool", "fill_tool");
+canvas_tool_action!(TransformToolAction, "transform_tool", "transform_tool");
+canvas_tool_action!(EyedropperToolAction, "eyedropper_tool", "eyedropper_tool");
 
 pub struct CanvasToolSwitch<T: CanvasToolAction> {
     activated: Instant,
@@ -53,3 +70,48 @@ impl<T: CanvasToolAction> ActionFunction for CanvasToolSwitch<T> {
         shell.tool_proxy().switch_tool(T::tool(), &canvas);
     }
 }
+
+#[derive(Default)]
+pub struct ToggleEraseAction {}
+
+impl ActionFunction for ToggleEraseAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("toggle_erase_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        match shell.tool_proxy().toggle_erase() {
+            Some(true) => log::info!("Erase mode on."),
+            Some(false) => log::info!("Erase mode off."),
+            None => {}
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ConfirmToolAction {}
+
+impl ActionFunction for ConfirmToolAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("confirm_tool_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        let canvas = shell.canvas();
+        shell.tool_proxy().confirm(&canvas);
+    }
+}
+
+#[derive(Default)]
+pub struct CancelToolAction {}
+
+impl ActionFunction for CancelToolAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("cancel_tool_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        let canvas = shell.canvas();
+        shell.tool_proxy().cancel(&canvas);
+    }
+}
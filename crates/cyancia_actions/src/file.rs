@@ -1,12 +1,19 @@
-use std::sync::Arc;
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use cyancia_canvas::CCanvas;
 use cyancia_id::Id;
-use cyancia_image::{CImage, layer::Layer, tile::GPU_TILE_STORAGE};
+use cyancia_image::{
+    CImage,
+    export::{self, ExportFormat, ExportOptions},
+    format::{DocumentFormat, cyan},
+    layer::Layer,
+    tile::GPU_TILE_STORAGE,
+};
 use cyancia_input::{action::Action, key::KeySequence};
 use glam::UVec2;
 use iced_runtime::Task;
-use rfd::{AsyncFileDialog, FileDialog};
+use parking_lot::RwLock;
+use rfd::{AsyncFileDialog, AsyncMessageDialog, FileDialog, MessageLevel};
 
 use crate::{ActionFunction, shell::ActionShell, task::ActionTask};
 
@@ -39,7 +46,41 @@ async fn load_image() -> Option<OpenFileTask> {
         return None;
     };
 
-    let img = match image::load_from_memory(&file.read().await) {
+    let path = file.path().to_path_buf();
+    let bytes = file.read().await;
+
+    if let Some(format) = DocumentFormat::from_path(&path) {
+        let (image, report) = match format.load(&bytes, &GPU_TILE_STORAGE) {
+            Ok(r) => r,
+            Err(e) => {
+                log::error!("Unable to open document from file {:?}: {}", file, e);
+                return None;
+            }
+        };
+        log::info!("Opened document from file {:?}.", file);
+        if !report.is_empty() {
+            AsyncMessageDialog::new()
+                .set_level(MessageLevel::Warning)
+                .set_title("Some parts of the document couldn't be imported")
+                .set_description(report.to_string())
+                .show()
+                .await;
+        }
+
+        return Some(OpenFileTask {
+            canvas: CCanvas {
+                image: RwLock::new(image),
+                transform: Default::default(),
+                // Imported documents are saved in our own format instead.
+                path: RwLock::new(format.can_save().then_some(path)),
+                history: Default::default(),
+                overlay: Default::default(),
+                previews: Default::default(),
+            },
+        });
+    }
+
+    let img = match image::load_from_memory(&bytes) {
         Ok(i) => i,
         Err(e) => {
             log::error!("Unable to open image from file {:?}: {}", file, e);
@@ -50,11 +91,180 @@ async fn load_image() -> Option<OpenFileTask> {
 
     let width = img.width();
     let height = img.height();
-    let layer = Layer::from_image(img, &GPU_TILE_STORAGE);
+    let name = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "Layer 1".to_string());
+    let layer = Layer::from_image(name, img, &GPU_TILE_STORAGE);
     let canvas = CCanvas {
-        image: Arc::new(CImage::from_layer(UVec2::new(width, height), layer)),
+        image: RwLock::new(CImage::from_layer(UVec2::new(width, height), layer)),
         transform: Default::default(),
+        path: Default::default(),
+        history: Default::default(),
+        overlay: Default::default(),
+        previews: Default::default(),
     };
 
     Some(OpenFileTask { canvas })
 }
+
+/// Saves the document to where it was opened from or last saved to, asking for a
+/// path if there is none.
+#[derive(Default)]
+pub struct SaveFileAction {}
+
+impl ActionFunction for SaveFileAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("save_file_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        let canvas = shell.canvas();
+        let path = canvas.path.read().clone();
+        shell.queue_task(Task::future(save_document(canvas, path)));
+    }
+}
+
+#[derive(Default)]
+pub struct SaveAsAction {}
+
+impl ActionFunction for SaveAsAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("save_as_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        shell.queue_task(Task::future(save_document(shell.canvas(), None)));
+    }
+}
+
+pub struct SaveFileTask {
+    canvas: Arc<CCanvas>,
+    path: PathBuf,
+}
+
+impl ActionTask for SaveFileTask {
+    fn apply(self: Box<Self>, _shell: &mut ActionShell) {
+        *self.canvas.path.write() = Some(self.path);
+    }
+}
+
+async fn save_document(canvas: Arc<CCanvas>, path: Option<PathBuf>) -> Option<SaveFileTask> {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            let dialog = DocumentFormat::ALL
+                .into_iter()
+                .filter(|format| format.can_save())
+                .fold(AsyncFileDialog::new(), |dialog, format| {
+                    dialog.add_filter(format.name(), &[format.extension()])
+                });
+            let Some(file) = dialog.save_file().await else {
+                log::error!("Unable to get selected file path.");
+                return None;
+            };
+            let path = file.path().to_path_buf();
+            if DocumentFormat::from_path(&path).is_some_and(|f| f.can_save()) {
+                path
+            } else {
+                path.with_extension(cyan::EXTENSION)
+            }
+        }
+    };
+    let format = DocumentFormat::from_path(&path)
+        .filter(|f| f.can_save())
+        .unwrap_or(DocumentFormat::Cyan);
+
+    let encoding = {
+        let mut image = canvas.image.write();
+        image.metadata_mut().touch();
+        format.save(&image, &GPU_TILE_STORAGE)
+    };
+    let bytes = match encoding.await {
+        Ok(b) => b,
+        Err(e) => {
+            log::error!("Unable to encode document: {}", e);
+            return None;
+        }
+    };
+
+    if let Err(e) = std::fs::write(&path, bytes) {
+        log::error!("Unable to save document to {:?}: {}", path, e);
+        return None;
+    }
+    log::info!("Saved document to {:?}.", path);
+
+    Some(SaveFileTask { canvas, path })
+}
+
+/// Flattens the document and writes it in the raster format picked by extension,
+/// with the options last set for that format.
+pub struct ExportAction {
+    options: RwLock<HashMap<ExportFormat, ExportOptions>>,
+}
+
+impl Default for ExportAction {
+    fn default() -> Self {
+        Self {
+            options: RwLock::new(
+                ExportFormat::ALL
+                    .into_iter()
+                    .map(|f| (f, ExportOptions::new(f)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl ExportAction {
+    pub fn options(&self, format: ExportFormat) -> ExportOptions {
+        self.options.read()[&format]
+    }
+
+    pub fn set_options(&self, options: ExportOptions) {
+        self.options.write().insert(options.format, options);
+    }
+}
+
+impl ActionFunction for ExportAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("export_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        let options = self.options.read().clone();
+        shell.queue_task(Task::future(export_image(shell.canvas(), options)));
+    }
+}
+
+async fn export_image(canvas: Arc<CCanvas>, options: HashMap<ExportFormat, ExportOptions>) {
+    let dialog = ExportFormat::ALL
+        .into_iter()
+        .fold(AsyncFileDialog::new(), |dialog, format| {
+            dialog.add_filter(format.name(), format.extensions())
+        });
+    let Some(file) = dialog.save_file().await else {
+        log::error!("Unable to get selected file path.");
+        return;
+    };
+    let mut path = file.path().to_path_buf();
+    let format = ExportFormat::from_path(&path).unwrap_or_else(|| {
+        path.set_extension(ExportFormat::Png.extension());
+        ExportFormat::Png
+    });
+
+    let encoding = export::export(&canvas.image.read(), &GPU_TILE_STORAGE, options[&format]);
+    let bytes = match encoding.await {
+        Ok(b) => b,
+        Err(e) => {
+            log::error!("Unable to export image: {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = std::fs::write(&path, bytes) {
+        log::error!("Unable to export image to {:?}: {}", path, e);
+        return;
+    }
+    log::info!("Exported image to {:?}.", path);
+}
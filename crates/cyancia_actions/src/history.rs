@@ -0,0 +1,45 @@
+use cyancia_id::Id;
+use cyancia_image::tile::GPU_TILE_STORAGE;
+use cyancia_input::action::Action;
+
+use crate::{ActionFunction, shell::ActionShell};
+
+#[derive(Default)]
+pub struct UndoAction {}
+
+impl ActionFunction for UndoAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("undo_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        let canvas = shell.canvas();
+        let mut history = canvas.history.lock();
+        let name = history.undo_name().map(str::to_string);
+        match history.undo(&mut canvas.image.write(), &GPU_TILE_STORAGE) {
+            Ok(true) => log::info!("Undid {}.", name.unwrap_or_default()),
+            Ok(false) => {}
+            Err(e) => log::error!("Unable to undo: {}", e),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RedoAction {}
+
+impl ActionFunction for RedoAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("redo_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        let canvas = shell.canvas();
+        let mut history = canvas.history.lock();
+        let name = history.redo_name().map(str::to_string);
+        match history.redo(&mut canvas.image.write(), &GPU_TILE_STORAGE) {
+            Ok(true) => log::info!("Redid {}.", name.unwrap_or_default()),
+            Ok(false) => {}
+            Err(e) => log::error!("Unable to redo: {}", e),
+        }
+    }
+}
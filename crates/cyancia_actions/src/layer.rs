@@ -0,0 +1,156 @@
+//! Edits of the layer tree, all of them acting on the active layer. They go
+//! through the history so each of them can be undone.
+
+use cyancia_id::Id;
+use cyancia_image::{CImage, history::LayerCommand, layer::Layer, tile::GPU_TILE_STORAGE};
+use cyancia_input::action::Action;
+
+use crate::{ActionFunction, shell::ActionShell};
+
+/// Records the command built from the image, if any, and makes the layer it acted
+/// on the active one.
+fn execute(shell: &mut ActionShell, command: impl FnOnce(&CImage) -> Option<LayerCommand>) {
+    let canvas = shell.canvas();
+    let mut history = canvas.history.lock();
+    let mut image = canvas.image.write();
+    let Some(command) = command(&image) else {
+        return;
+    };
+
+    match history.execute(&mut image, &GPU_TILE_STORAGE, command) {
+        Ok(id) => {
+            if image.layer(id).is_ok_and(|l| !l.is_group()) {
+                let _ = image.set_active_layer(id);
+            }
+        }
+        Err(e) => log::error!("Unable to edit layers: {}", e),
+    }
+}
+
+/// The parent of the layer and its index inside of it.
+fn position(image: &CImage, id: Id<Layer>) -> Option<(Id<Layer>, usize)> {
+    let parent = image.layer(id).ok()?.parent()?;
+    Some((parent, image.z_order(id).ok()?))
+}
+
+#[derive(Default)]
+pub struct NewLayerAction {}
+
+impl ActionFunction for NewLayerAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("new_layer_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        execute(shell, |image| {
+            let layer = Layer::paint(format!("Layer {}", image.layer_count() + 1));
+            let (parent, index) = match image.active_layer() {
+                Some(active) => {
+                    let (parent, index) = position(image, active)?;
+                    (parent, index + 1)
+                }
+                None => (image.root_id(), image.root().children().len()),
+            };
+            Some(LayerCommand::Insert {
+                layer,
+                parent,
+                index,
+            })
+        });
+    }
+}
+
+#[derive(Default)]
+pub struct DuplicateLayerAction {}
+
+impl ActionFunction for DuplicateLayerAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("duplicate_layer_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        execute(shell, |image| {
+            Some(LayerCommand::Duplicate {
+                id: image.active_layer()?,
+            })
+        });
+    }
+}
+
+#[derive(Default)]
+pub struct RemoveLayerAction {}
+
+impl ActionFunction for RemoveLayerAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("remove_layer_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        execute(shell, |image| {
+            Some(LayerCommand::Remove {
+                id: image.active_layer()?,
+            })
+        });
+    }
+}
+
+#[derive(Default)]
+pub struct RaiseLayerAction {}
+
+impl ActionFunction for RaiseLayerAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("raise_layer_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        execute(shell, |image| {
+            let id = image.active_layer()?;
+            let (parent, index) = position(image, id)?;
+            let siblings = image.layer(parent).ok()?.children().len();
+            (index + 1 < siblings).then_some(LayerCommand::Move {
+                id,
+                parent,
+                index: index + 1,
+            })
+        });
+    }
+}
+
+#[derive(Default)]
+pub struct LowerLayerAction {}
+
+impl ActionFunction for LowerLayerAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("lower_layer_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        execute(shell, |image| {
+            let id = image.active_layer()?;
+            let (parent, index) = position(image, id)?;
+            Some(LayerCommand::Move {
+                id,
+                parent,
+                index: index.checked_sub(1)?,
+            })
+        });
+    }
+}
+
+#[derive(Default)]
+pub struct ToggleLayerVisibilityAction {}
+
+impl ActionFunction for ToggleLayerVisibilityAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("toggle_layer_visibility_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        execute(shell, |image| {
+            let id = image.active_layer()?;
+            let mut properties = image.layer(id).ok()?.properties.clone();
+            properties.visible = !properties.visible;
+            Some(LayerCommand::SetProperties { id, properties })
+        });
+    }
+}
@@ -13,6 +13,9 @@ use crate::shell::ActionShell;
 
 pub mod canvas_control;
 pub mod file;
+pub mod history;
+pub mod layer;
+pub mod selection;
 pub mod shell;
 pub mod task;
 
@@ -44,6 +47,10 @@ impl ActionFunctionCollection {
             return;
         };
 
+        self.trigger_id(id, shell);
+    }
+
+    pub fn trigger_id(&self, id: Id<Action>, shell: &mut ActionShell) {
         if let Some(action) = self.functions.get(&id) {
             action.trigger(shell);
         }
@@ -0,0 +1,163 @@
+use std::sync::Arc;
+
+use cyancia_canvas::CCanvas;
+use cyancia_id::Id;
+use cyancia_image::{
+    region::{self, ColorMatch, SampleSource},
+    selection::{GPU_MASK_STORAGE, SelectionMask, SelectionMode},
+    tile::GPU_TILE_STORAGE,
+};
+use cyancia_input::action::Action;
+use glam::{UVec2, Vec4};
+use iced_runtime::Task;
+use parking_lot::RwLock;
+
+use crate::{ActionFunction, shell::ActionShell, task::ActionTask};
+
+#[derive(Default)]
+pub struct SelectAllAction {}
+
+impl ActionFunction for SelectAllAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("select_all_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        let canvas = shell.canvas();
+        let mut image = canvas.image.write();
+        let size = image.size();
+        image.selection_mut().select_all(size, &GPU_MASK_STORAGE);
+    }
+}
+
+#[derive(Default)]
+pub struct DeselectAction {}
+
+impl ActionFunction for DeselectAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("deselect_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        let canvas = shell.canvas();
+        canvas
+            .image
+            .write()
+            .selection_mut()
+            .clear(&GPU_MASK_STORAGE);
+    }
+}
+
+#[derive(Default)]
+pub struct InvertSelectionAction {}
+
+impl ActionFunction for InvertSelectionAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("invert_selection_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        let canvas = shell.canvas();
+        canvas
+            .image
+            .write()
+            .selection_mut()
+            .invert(&GPU_MASK_STORAGE);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorRangeOptions {
+    pub mode: SelectionMode,
+    /// Straight alpha.
+    pub color: Vec4,
+    /// Largest difference of any channel still selected fully, from 0 to 1.
+    pub tolerance: f32,
+    /// How far beyond the tolerance the selection fades out.
+    pub fuzziness: f32,
+    pub source: SampleSource,
+}
+
+impl Default for ColorRangeOptions {
+    fn default() -> Self {
+        Self {
+            mode: SelectionMode::Replace,
+            color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+            tolerance: 0.1,
+            fuzziness: 0.1,
+            source: SampleSource::Merged,
+        }
+    }
+}
+
+/// Selects every pixel of the image close to a color, connected or not.
+#[derive(Default)]
+pub struct ColorRangeAction {
+    options: RwLock<ColorRangeOptions>,
+}
+
+impl ColorRangeAction {
+    pub fn options(&self) -> ColorRangeOptions {
+        *self.options.read()
+    }
+
+    pub fn set_options(&self, options: ColorRangeOptions) {
+        *self.options.write() = options;
+    }
+}
+
+impl ActionFunction for ColorRangeAction {
+    fn id(&self) -> Id<Action> {
+        Id::from_str("color_range_action")
+    }
+
+    fn trigger(&self, shell: &mut ActionShell) {
+        let options = *self.options.read();
+        shell.queue_task(Task::future(select_color_range(shell.canvas(), options)));
+    }
+}
+
+pub struct ColorRangeTask {
+    canvas: Arc<CCanvas>,
+    mask: SelectionMask,
+    mode: SelectionMode,
+}
+
+impl ActionTask for ColorRangeTask {
+    fn apply(self: Box<Self>, _shell: &mut ActionShell) {
+        let mut image = self.canvas.image.write();
+        if self.mask.size() != image.size() {
+            return;
+        }
+        image
+            .selection_mut()
+            .apply_mask(&self.mask, self.mode, &GPU_MASK_STORAGE);
+    }
+}
+
+async fn select_color_range(
+    canvas: Arc<CCanvas>,
+    options: ColorRangeOptions,
+) -> Option<ColorRangeTask> {
+    let readback = options.source.read(&canvas.image.read(), &GPU_TILE_STORAGE);
+    let pixels = match readback.await {
+        Ok(p) => p,
+        Err(e) => {
+            log::error!("Unable to read pixels for the color range: {}", e);
+            return None;
+        }
+    };
+
+    let color = ColorMatch {
+        reference: options.color,
+        tolerance: options.tolerance,
+        fuzziness: options.fuzziness,
+    };
+    let size = UVec2::new(pixels.width(), pixels.height());
+    let mask = SelectionMask::from_coverage(size, &region::color_range(&pixels, &color));
+    Some(ColorRangeTask {
+        canvas,
+        mask,
+        mode: options.mode,
+    })
+}
@@ -1,13 +1,15 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use cyancia_actions::{
     ActionFunctionCollection,
     shell::{ActionShell, DestructedShell},
 };
 use cyancia_canvas::CCanvas;
+use cyancia_id::Id;
 use cyancia_input::{
+    action::Action,
     key::KeyboardState,
-    mouse::{HoverMouseState, PressedMouseState},
+    mouse::{InputQueue, PointerButton, PointerEvent, PointerSources, PointerState},
 };
 use cyancia_tools::ToolProxy;
 use iced::{
@@ -19,10 +21,17 @@ use iced::{
 pub struct InputManager {
     pub actions: ActionFunctionCollection,
     pub tools: Arc<ToolProxy>,
+    /// Pointer input from devices the window doesn't report, like tablets.
+    pub pointer_sources: PointerSources,
+    /// Buttons triggering an action instead of starting a stroke.
+    pub button_actions: HashMap<PointerButton, Id<Action>>,
 
     keyboard_state: KeyboardState,
+    /// Pointer events waiting for the next frame.
+    queue: InputQueue,
 
-    is_pressed: bool,
+    /// The button holding the current stroke.
+    pressed: Option<PointerButton>,
     cursor_position: Point,
 }
 
@@ -32,16 +41,15 @@ impl InputManager {
             actions,
             tools: Arc::new(tools),
             keyboard_state: KeyboardState::default(),
-            is_pressed: false,
+            queue: InputQueue::default(),
+            pressed: None,
             cursor_position: Point::default(),
+            pointer_sources: PointerSources::default(),
+            button_actions: HashMap::new(),
         }
     }
 
-    pub fn on_keyboard_event(
-        &mut self,
-        event: keyboard::Event,
-        shell: &mut ActionShell,
-    ) {
+    pub fn on_keyboard_event(&mut self, event: keyboard::Event, shell: &mut ActionShell) {
         match event {
             keyboard::Event::KeyPressed {
                 physical_key,
@@ -77,56 +85,45 @@ impl InputManager {
         }
     }
 
-    pub fn on_mouse_event(&mut self, event: mouse::Event, canvas: &CCanvas) {
+    pub fn on_mouse_event(&mut self, event: mouse::Event, shell: &mut ActionShell) {
         match event {
             mouse::Event::ButtonPressed(button) => {
-                if button != mouse::Button::Left {
+                let Some(button) = pointer_button(button) else {
+                    return;
+                };
+                if let Some(action) = self.button_actions.get(&button) {
+                    self.actions.trigger_id(*action, shell);
+                    return;
+                }
+                // Other buttons can't take over a stroke that already began.
+                if self.pressed.is_some() {
                     return;
                 }
 
-                self.is_pressed = true;
-                self.tools.mouse_pressed(
-                    &self.keyboard_state,
-                    &PressedMouseState {
-                        position: self.cursor_position,
-                    },
-                    canvas,
-                );
+                self.on_pointer_event(PointerEvent::Pressed(
+                    PointerState::mouse(self.cursor_position, true).with_button(button),
+                ));
             }
             mouse::Event::ButtonReleased(button) => {
-                if button != mouse::Button::Left {
+                let Some(button) = pointer_button(button) else {
+                    return;
+                };
+                if self.pressed != Some(button) {
                     return;
                 }
 
-                self.is_pressed = false;
-                self.tools.mouse_released(
-                    &self.keyboard_state,
-                    &PressedMouseState {
-                        position: self.cursor_position,
-                    },
-                    canvas,
-                );
+                self.on_pointer_event(PointerEvent::Released(
+                    PointerState::mouse(self.cursor_position, true).with_button(button),
+                ));
             }
             mouse::Event::CursorMoved { position } => {
-                self.cursor_position = position;
-
-                if self.is_pressed {
-                    self.tools.mouse_moved_pressing(
-                        &self.keyboard_state,
-                        &PressedMouseState {
-                            position: self.cursor_position,
-                        },
-                        canvas,
-                    );
-                } else {
-                    self.tools.mouse_moved_hovering(
-                        &self.keyboard_state,
-                        &HoverMouseState {
-                            position: self.cursor_position,
-                        },
-                        canvas,
-                    );
-                }
+                let event = match self.pressed {
+                    Some(button) => PointerEvent::MovedPressing(
+                        PointerState::mouse(position, true).with_button(button),
+                    ),
+                    None => PointerEvent::MovedHovering(PointerState::mouse(position, false)),
+                };
+                self.on_pointer_event(event);
             }
             mouse::Event::CursorLeft => {
                 // FIXME
@@ -137,4 +134,38 @@ impl InputManager {
             _ => {}
         }
     }
+
+    /// Queues pointer input from any device for the next [`InputManager::flush`].
+    pub fn on_pointer_event(&mut self, event: PointerEvent) {
+        match &event {
+            PointerEvent::Pressed(state) => self.pressed = Some(state.button),
+            PointerEvent::Released(_) => self.pressed = None,
+            _ => {}
+        }
+        self.cursor_position = event.state().position;
+        self.queue.push(event);
+    }
+
+    /// Dispatches everything received since the last flush to the tools, the
+    /// extra pointer sources included.
+    pub fn flush(&mut self, canvas: &CCanvas) {
+        for event in self.pointer_sources.drain() {
+            self.on_pointer_event(event);
+        }
+        let events = self.queue.drain();
+        if !events.is_empty() {
+            self.tools
+                .pointer_events(&self.keyboard_state, &events, canvas);
+        }
+        self.tools.frame(canvas);
+    }
+}
+
+fn pointer_button(button: mouse::Button) -> Option<PointerButton> {
+    match button {
+        mouse::Button::Left => Some(PointerButton::Primary),
+        mouse::Button::Right => Some(PointerButton::Secondary),
+        mouse::Button::Middle => Some(PointerButton::Middle),
+        _ => None,
+    }
 }
@@ -3,17 +3,33 @@ use std::{fmt::Debug, sync::Arc};
 use cyancia_actions::{
     ActionFunctionCollection,
     canvas_control::{
-        BrushToolAction, CanvasToolSwitch, PanToolAction, RotateToolAction, ZoomToolAction,
+        BrushToolAction, CancelToolAction, CanvasToolSwitch, ConfirmToolAction,
+        EllipseSelectToolAction, EraserToolAction, EyedropperToolAction, FillToolAction,
+        LassoToolAction, MagicWandToolAction, PanToolAction, PolygonLassoToolAction,
+        RectSelectToolAction, RotateToolAction, ToggleEraseAction, TransformToolAction,
+        ZoomToolAction,
     },
-    file::OpenFileAction,
+    file::{ExportAction, OpenFileAction, SaveAsAction, SaveFileAction},
+    history::{RedoAction, UndoAction},
+    layer::{
+        DuplicateLayerAction, LowerLayerAction, NewLayerAction, RaiseLayerAction,
+        RemoveLayerAction, ToggleLayerVisibilityAction,
+    },
+    selection::{ColorRangeAction, DeselectAction, InvertSelectionAction, SelectAllAction},
     shell::{ActionShell, DestructedShell},
     task::ActionTask,
 };
 use cyancia_assets::store::{AssetLoaderRegistry, AssetRegistry};
+use cyancia_brush::{
+    bitmap::{BRUSH_IMAGES, BrushImage},
+    preset::{BRUSH_PRESETS, BrushPresetCollection, BrushPresetManifest},
+    stamp::{BrushTextures, GPU_BRUSH_TEXTURES},
+};
 use cyancia_canvas::{CCanvas, widget::CanvasWidget};
 use cyancia_id::Id;
 use cyancia_image::{
     CImage,
+    selection::{GPU_MASK_STORAGE, MASK_FORMAT},
     tile::{GPU_TILE_STORAGE, GpuTileStorage},
 };
 use cyancia_input::{
@@ -26,7 +42,16 @@ use cyancia_render::{
     resources::{FULLSCREEN_VERTEX, FullscreenVertex, GLOBAL_SAMPLERS, GlobalSamplers},
 };
 use cyancia_tools::{
-    CanvasToolFunctionCollection, ToolProxy, brush::BrushTool, pan::PanTool, rotate::RotateTool,
+    CanvasToolFunctionCollection, ToolProxy,
+    brush::BrushTool,
+    eraser::EraserTool,
+    eyedropper::EyedropperTool,
+    fill::FillTool,
+    pan::PanTool,
+    rotate::RotateTool,
+    select::{EllipseSelectTool, LassoTool, PolygonLassoTool, RectSelectTool},
+    transform::TransformTool,
+    wand::MagicWandTool,
     zoom::ZoomTool,
 };
 use glam::UVec2;
@@ -35,6 +60,7 @@ use iced::{
     keyboard::{self, key},
     mouse, window,
 };
+use parking_lot::RwLock;
 
 use crate::input_manager::InputManager;
 
@@ -51,6 +77,7 @@ pub enum MainViewMessage {
     WindowOpened(window::Id),
     KeyboardEvent(keyboard::Event),
     MouseEvent(mouse::Event),
+    Frame,
     ActionTaskCompleted(Box<dyn ActionTask>),
 }
 
@@ -65,6 +92,7 @@ impl Debug for MainViewMessage {
             Self::WindowOpened(arg0) => f.debug_tuple("WindowOpened").field(arg0).finish(),
             Self::KeyboardEvent(arg0) => f.debug_tuple("KeyboardEvent").field(arg0).finish(),
             Self::MouseEvent(arg0) => f.debug_tuple("MouseEvent").field(arg0).finish(),
+            Self::Frame => write!(f, "Frame"),
             Self::ActionTaskCompleted(arg0) => f.debug_tuple("ActionTaskCompleted").finish(),
         }
     }
@@ -74,25 +102,68 @@ impl MainView {
     pub fn new() -> Self {
         let mut loaders = AssetLoaderRegistry::new();
         cyancia_input::register_loaders(&mut loaders);
-        let assets = AssetRegistry::new("assets", &loaders);
+        cyancia_brush::register_loaders(&mut loaders);
+        let mut assets = AssetRegistry::new("assets", &loaders);
+        assets.init_store::<BrushPresetManifest>();
+        assets.init_store::<BrushImage>();
+        BRUSH_PRESETS.init(BrushPresetCollection::new(
+            assets.store::<BrushPresetManifest>().clone(),
+        ));
+        BRUSH_IMAGES.init(assets.store::<BrushImage>().clone());
 
         let actions = {
             let mut collection = ActionFunctionCollection::new(ActionCollection::new(
                 assets.store::<ActionManifest>().clone(),
             ));
             collection.register::<OpenFileAction>();
+            collection.register::<SaveFileAction>();
+            collection.register::<SaveAsAction>();
+            collection.register::<ExportAction>();
+            collection.register::<UndoAction>();
+            collection.register::<RedoAction>();
+            collection.register::<NewLayerAction>();
+            collection.register::<DuplicateLayerAction>();
+            collection.register::<RemoveLayerAction>();
+            collection.register::<RaiseLayerAction>();
+            collection.register::<LowerLayerAction>();
+            collection.register::<ToggleLayerVisibilityAction>();
             collection.register::<CanvasToolSwitch<PanToolAction>>();
             collection.register::<CanvasToolSwitch<RotateToolAction>>();
             collection.register::<CanvasToolSwitch<ZoomToolAction>>();
             collection.register::<CanvasToolSwitch<BrushToolAction>>();
+            collection.register::<CanvasToolSwitch<EraserToolAction>>();
+            collection.register::<ToggleEraseAction>();
+            collection.register::<CanvasToolSwitch<RectSelectToolAction>>();
+            collection.register::<CanvasToolSwitch<EllipseSelectToolAction>>();
+            collection.register::<CanvasToolSwitch<LassoToolAction>>();
+            collection.register::<CanvasToolSwitch<PolygonLassoToolAction>>();
+            collection.register::<SelectAllAction>();
+            collection.register::<DeselectAction>();
+            collection.register::<InvertSelectionAction>();
+            collection.register::<ColorRangeAction>();
+            collection.register::<CanvasToolSwitch<MagicWandToolAction>>();
+            collection.register::<CanvasToolSwitch<FillToolAction>>();
+            collection.register::<CanvasToolSwitch<TransformToolAction>>();
+            collection.register::<CanvasToolSwitch<EyedropperToolAction>>();
+            collection.register::<ConfirmToolAction>();
+            collection.register::<CancelToolAction>();
             collection
         };
         let tool_functions = {
             let mut c = CanvasToolFunctionCollection::new();
             c.register::<BrushTool>();
+            c.register::<EraserTool>();
             c.register::<PanTool>();
             c.register::<RotateTool>();
             c.register::<ZoomTool>();
+            c.register::<RectSelectTool>();
+            c.register::<EllipseSelectTool>();
+            c.register::<LassoTool>();
+            c.register::<PolygonLassoTool>();
+            c.register::<MagicWandTool>();
+            c.register::<FillTool>();
+            c.register::<TransformTool>();
+            c.register::<EyedropperTool>();
             c
         };
         let tools = { ToolProxy::new(Id::from_str("brush_tool"), tool_functions) };
@@ -100,8 +171,12 @@ impl MainView {
         Self {
             assets,
             canvas: Arc::new(CCanvas {
-                image: Arc::new(CImage::new(UVec2 { x: 1024, y: 768 })),
+                image: RwLock::new(CImage::new(UVec2 { x: 1024, y: 768 })),
                 transform: Default::default(),
+                path: Default::default(),
+                history: Default::default(),
+                overlay: Default::default(),
+                previews: Default::default(),
             }),
             input_manager: InputManager::new(actions, tools),
 
@@ -126,6 +201,7 @@ impl MainView {
         CanvasWidget {
             canvas: self.canvas.clone(),
             gpu_tile_storage: GPU_TILE_STORAGE.clone_arc(),
+            mask_storage: GPU_MASK_STORAGE.clone_arc(),
         }
         .into()
     }
@@ -142,14 +218,25 @@ impl MainView {
                     GLOBAL_SAMPLERS.init(GlobalSamplers::new(&device));
                     FULLSCREEN_VERTEX.init(FullscreenVertex::new(&device));
                     GPU_TILE_STORAGE.init(GpuTileStorage::new(device.clone(), queue.clone()));
+                    GPU_BRUSH_TEXTURES.init(BrushTextures::new(&device));
+                    GPU_MASK_STORAGE.init(GpuTileStorage::with_format(
+                        device.clone(),
+                        queue.clone(),
+                        MASK_FORMAT,
+                    ));
                     RENDER_CONTEXT.init(RenderContext { device, queue });
                 }
             }
             MainViewMessage::KeyboardEvent(event) => {
+                // Actions have to see the strokes that came before the key.
+                self.input_manager.flush(&self.canvas);
                 self.input_manager.on_keyboard_event(event, &mut shell);
             }
             MainViewMessage::MouseEvent(event) => {
-                self.input_manager.on_mouse_event(event, &self.canvas);
+                self.input_manager.on_mouse_event(event, &mut shell);
+            }
+            MainViewMessage::Frame => {
+                self.input_manager.flush(&self.canvas);
             }
             MainViewMessage::ActionTaskCompleted(action_task) => {
                 action_task.apply(&mut shell);
@@ -160,14 +247,24 @@ impl MainView {
     }
 
     pub fn subscription(&self) -> Subscription<MainViewMessage> {
-        event::listen().filter_map(|event| match event {
-            iced::Event::Keyboard(event) => Some(MainViewMessage::KeyboardEvent(event)),
-            iced::Event::Mouse(event) => Some(MainViewMessage::MouseEvent(event)),
-            _ => None,
-        })
+        Subscription::batch([
+            event::listen().filter_map(|event| match event {
+                iced::Event::Keyboard(event) => Some(MainViewMessage::KeyboardEvent(event)),
+                iced::Event::Mouse(event) => Some(MainViewMessage::MouseEvent(event)),
+                _ => None,
+            }),
+            window::frames().map(|_| MainViewMessage::Frame),
+        ])
     }
 
     fn apply_shell(&mut self, shell: DestructedShell) -> Task<MainViewMessage> {
+        if !Arc::ptr_eq(&self.canvas, &shell.current_canvas) {
+            let image = self.canvas.image.read();
+            image.release_tiles(&GPU_TILE_STORAGE);
+            image.selection().release(&GPU_MASK_STORAGE);
+            drop(image);
+            self.canvas.history.lock().clear(&GPU_TILE_STORAGE);
+        }
         self.canvas = shell.current_canvas;
         Task::batch(shell.tasks).map(|t| MainViewMessage::ActionTaskCompleted(t))
     }
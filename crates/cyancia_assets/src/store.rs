@@ -1,7 +1,7 @@
 use std::{
     any::{Any, TypeId},
     collections::{HashMap, hash_map::Entry},
-    path::Path,
+    path::{Component, Path},
     sync::Arc,
 };
 
@@ -35,6 +35,22 @@ impl AssetLoaderRegistry {
     }
 }
 
+/// Id of the asset loaded from `path`, relative to the asset root. Lets assets
+/// refer to each other by path.
+pub fn path_id<T: Asset>(path: impl AsRef<Path>) -> Id<T> {
+    Id::from_str(&normalize_path(path.as_ref()))
+}
+
+fn normalize_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => s.to_str(),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
 pub struct AssetRegistry {
     stores: HashMap<TypeId, Box<dyn Any + Send + Sync + 'static>>,
 }
@@ -99,7 +115,7 @@ mod asset_loading {
         root: &Path,
     ) {
         let mut counter = 0;
-        match load_folder(assets, loaders, root, &mut counter) {
+        match load_folder(assets, loaders, root, root, &mut counter) {
             Ok(_) => {
                 log::info!(
                     "Successfully loaded {} assets from {}",
@@ -116,6 +132,7 @@ mod asset_loading {
     fn load_folder(
         assets: &mut AssetRegistry,
         loaders: &AssetLoaderRegistry,
+        root: &Path,
         path: &Path,
         counter: &mut u32,
     ) -> Result<(), std::io::Error> {
@@ -130,12 +147,12 @@ mod asset_loading {
 
             let path = entry.path();
             if path.is_dir() {
-                match load_folder(assets, loaders, &path, counter) {
+                match load_folder(assets, loaders, root, &path, counter) {
                     Ok(_) => {}
                     Err(e) => log::error!("Error loading directory {}: {}", path.display(), e),
                 };
             } else if path.is_file() {
-                match load_file(assets, loaders, &path) {
+                match load_file(assets, loaders, root, &path) {
                     Ok(_) => {
                         log::info!("Loaded file: {}", path.display());
                         *counter += 1;
@@ -163,6 +180,7 @@ mod asset_loading {
     fn load_file(
         assets: &mut AssetRegistry,
         loaders: &AssetLoaderRegistry,
+        root: &Path,
         path: &Path,
     ) -> Result<(), LoadFileError> {
         let ext = path
@@ -176,7 +194,12 @@ mod asset_loading {
         let asset = loader
             .read(&mut file)
             .map_err(|e| LoadFileError::Loader(path.to_path_buf(), e))?;
-        loader.insert_asset(UntypedId::random((*asset).type_id()), asset, assets);
+        let relative = normalize_path(path.strip_prefix(root).unwrap_or(path));
+        loader.insert_asset(
+            UntypedId::from_str(&relative, (*asset).type_id()),
+            asset,
+            assets,
+        );
 
         Ok(())
     }
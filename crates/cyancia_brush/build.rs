@@ -0,0 +1,7 @@
+fn main() {
+    wesl::Wesl::new("src/shaders")
+        .build_artifact(&"package::brush_dab".parse().unwrap(), "brush_dab");
+
+    wesl::Wesl::new("src/shaders")
+        .build_artifact(&"package::brush_smudge".parse().unwrap(), "brush_smudge");
+}
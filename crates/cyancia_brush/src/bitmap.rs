@@ -0,0 +1,97 @@
+//! Grayscale images used as brush tips and paper textures. Light pixels paint
+//! and transparent pixels count as black, so both white-on-black and
+//! white-on-transparent images work.
+
+use std::path::Path;
+
+use cyancia_assets::{
+    asset::Asset,
+    loader::AssetLoader,
+    store::{AssetStore, path_id},
+};
+use cyancia_id::Id;
+use cyancia_utils::global_instance::GlobalInstance;
+use glam::Vec2;
+use image::GrayImage;
+
+pub static BRUSH_IMAGES: GlobalInstance<AssetStore<BrushImage>> = GlobalInstance::new();
+
+#[derive(Debug, Clone)]
+pub struct BrushImage {
+    pub image: GrayImage,
+}
+
+impl Asset for BrushImage {}
+
+impl BrushImage {
+    /// Id of the image at `path` inside the assets folder.
+    pub fn id(path: impl AsRef<Path>) -> Id<BrushImage> {
+        path_id(path)
+    }
+
+    pub fn from_image(image: &image::DynamicImage) -> Self {
+        let luma_alpha = image.to_luma_alpha8();
+        let image = GrayImage::from_fn(luma_alpha.width(), luma_alpha.height(), |x, y| {
+            let [l, a] = luma_alpha.get_pixel(x, y).0;
+            image::Luma([((l as u16 * a as u16 + 127) / 255) as u8])
+        });
+        Self { image }
+    }
+
+    pub fn size(&self) -> Vec2 {
+        Vec2::new(self.image.width() as f32, self.image.height() as f32)
+    }
+
+    pub fn texel(&self, x: i32, y: i32) -> f32 {
+        if x < 0 || y < 0 || x >= self.image.width() as i32 || y >= self.image.height() as i32 {
+            return 0.0;
+        }
+        self.image.get_pixel(x as u32, y as u32).0[0] as f32 / 255.0
+    }
+
+    /// Bilinear sample at `position` in texels, zero outside the image.
+    pub fn sample(&self, position: Vec2) -> f32 {
+        let p = position - 0.5;
+        let base = p.floor();
+        let f = p - base;
+        let (x, y) = (base.x as i32, base.y as i32);
+        let top = self.texel(x, y) * (1.0 - f.x) + self.texel(x + 1, y) * f.x;
+        let bottom = self.texel(x, y + 1) * (1.0 - f.x) + self.texel(x + 1, y + 1) * f.x;
+        top * (1.0 - f.y) + bottom * f.y
+    }
+
+    /// Bilinear sample at `position` in texels, the image repeating forever.
+    pub fn sample_wrapped(&self, position: Vec2) -> f32 {
+        let (w, h) = (self.image.width() as i32, self.image.height() as i32);
+        if w == 0 || h == 0 {
+            return 0.0;
+        }
+        let p = position - 0.5;
+        let base = p.floor();
+        let f = p - base;
+        let texel = |x: i32, y: i32| self.texel(x.rem_euclid(w), y.rem_euclid(h));
+        let (x, y) = (base.x as i32, base.y as i32);
+        let top = texel(x, y) * (1.0 - f.x) + texel(x + 1, y) * f.x;
+        let bottom = texel(x, y + 1) * (1.0 - f.x) + texel(x + 1, y + 1) * f.x;
+        top * (1.0 - f.y) + bottom * f.y
+    }
+}
+
+#[derive(Default)]
+pub struct BrushImageLoader;
+
+impl AssetLoader for BrushImageLoader {
+    type Asset = BrushImage;
+
+    type Error = image::ImageError;
+
+    fn file_extensions() -> &'static [&'static str] {
+        &["png", "jpg", "jpeg", "webp"]
+    }
+
+    fn read(&self, reader: &mut dyn std::io::Read) -> Result<Self::Asset, Self::Error> {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        Ok(BrushImage::from_image(&image::load_from_memory(&buf)?))
+    }
+}
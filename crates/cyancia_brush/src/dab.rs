@@ -0,0 +1,172 @@
+//! The dab model, shared by the CPU rasterizer and `brush_dab.wesl`. Colors are
+//! straight alpha and pixels are sampled at their centers.
+
+use glam::{IVec2, Vec2, Vec3, Vec4, Vec4Swizzles};
+
+use crate::{BrushTip, PaperTexture, TextureBlend, bitmap::BrushImage};
+
+/// A single stamp of the brush tip.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dab {
+    pub center: Vec2,
+    pub radius: f32,
+    pub hardness: f32,
+    /// Opacity of the dab before the tip falloff.
+    pub flow: f32,
+    /// How opaque the stroke may get under this dab. Dabs never lower what
+    /// earlier dabs painted.
+    pub opacity: f32,
+    /// Rotation of the tip in radians.
+    pub angle: f32,
+    /// Stretch of the tip along its axes, negative when flipped.
+    pub scale: Vec2,
+    pub color: Vec3,
+}
+
+/// What a dab stamps.
+#[derive(Debug, Clone, Copy)]
+pub enum DabTip<'a> {
+    Procedural(BrushTip),
+    Image(&'a BrushImage),
+}
+
+/// The tip of a stroke along with its paper texture.
+#[derive(Debug, Clone, Copy)]
+pub struct DabShape<'a> {
+    pub tip: DabTip<'a>,
+    pub texture: Option<(&'a BrushImage, &'a PaperTexture)>,
+}
+
+impl DabShape<'_> {
+    /// Coverage of the dab at `pixel` with the texture applied, before flow.
+    pub fn coverage(&self, dab: &Dab, pixel: IVec2) -> f32 {
+        let coverage = dab.coverage(self.tip, pixel);
+        match self.texture {
+            Some((image, texture)) => apply_grain(
+                coverage,
+                grain(image, pixel, texture.scale, texture.contrast),
+                texture.blend,
+            ),
+            None => coverage,
+        }
+    }
+}
+
+impl Dab {
+    /// Pixels the dab may cover, min inclusive and max exclusive.
+    pub fn bounds(&self) -> (IVec2, IVec2) {
+        let extent = Vec2::splat(self.radius * self.scale.abs().max_element() + 1.0);
+        (
+            (self.center - extent).floor().as_ivec2(),
+            (self.center + extent).ceil().as_ivec2(),
+        )
+    }
+
+    /// Position of `point` relative to the center, in pixels of the unrotated
+    /// and unstretched tip.
+    pub fn local(&self, point: Vec2) -> Vec2 {
+        Vec2::from_angle(-self.angle).rotate(point - self.center) / self.scale
+    }
+
+    pub fn coverage(&self, tip: DabTip, pixel: IVec2) -> f32 {
+        let local = self.local(pixel.as_vec2() + 0.5);
+        match tip {
+            DabTip::Procedural(tip) => {
+                tip_coverage(tip, local.length(), self.radius, self.hardness)
+            }
+            DabTip::Image(image) => image_coverage(image, local, self.radius),
+        }
+    }
+}
+
+/// Opacity of the tip at `distance` pixels from its center.
+pub fn tip_coverage(tip: BrushTip, distance: f32, radius: f32, hardness: f32) -> f32 {
+    if radius <= 0.0 {
+        return 0.0;
+    }
+
+    let edge = (radius - distance + 0.5).clamp(0.0, 1.0);
+    let d = distance / radius;
+    let hardness = hardness.clamp(0.0, 1.0);
+    match tip {
+        BrushTip::Hard => edge,
+        BrushTip::Round => {
+            let t = ((d - hardness) / (1.0 - hardness).max(1e-4)).clamp(0.0, 1.0);
+            let falloff = 1.0 - t * t * (3.0 - 2.0 * t);
+            falloff.min(edge)
+        }
+        BrushTip::Soft => (1.0 - d * d).max(0.0).powf(1.0 + 3.0 * (1.0 - hardness)),
+    }
+}
+
+/// Coverage of an image tip whose longer side spans the diameter, `local` being
+/// relative to its center.
+pub fn image_coverage(image: &BrushImage, local: Vec2, radius: f32) -> f32 {
+    if radius <= 0.0 {
+        return 0.0;
+    }
+    let size = image.size();
+    image.sample(local / (radius * 2.0) * size.max_element() + size * 0.5)
+}
+
+/// Grain of a paper texture at a pixel of the canvas.
+pub fn grain(texture: &BrushImage, pixel: IVec2, scale: f32, contrast: f32) -> f32 {
+    let value = texture.sample_wrapped((pixel.as_vec2() + 0.5) / scale.max(1e-3));
+    ((value - 0.5) * contrast + 0.5).clamp(0.0, 1.0)
+}
+
+pub fn apply_grain(coverage: f32, grain: f32, blend: TextureBlend) -> f32 {
+    match blend {
+        TextureBlend::Multiply => coverage * grain,
+        TextureBlend::Subtract => (coverage - (1.0 - grain)).max(0.0),
+        TextureBlend::Min => coverage.min(grain),
+    }
+}
+
+/// Straight alpha `source over backdrop`.
+pub fn over(source: Vec4, backdrop: Vec4) -> Vec4 {
+    let alpha = source.w + backdrop.w * (1.0 - source.w);
+    if alpha <= 0.0 {
+        return Vec4::ZERO;
+    }
+    let color = (source.xyz() * source.w + backdrop.xyz() * backdrop.w * (1.0 - source.w)) / alpha;
+    color.extend(alpha)
+}
+
+/// Paints a dab of `alpha` over the stroke buffer, the result staying under the
+/// dab's opacity unless the buffer already was more opaque.
+pub fn accumulate(buffer: Vec4, color: Vec3, alpha: f32, opacity: f32) -> Vec4 {
+    let result = over(color.extend(alpha), buffer);
+    result
+        .xyz()
+        .extend(result.w.min(buffer.w.max(opacity.clamp(0.0, 1.0))))
+}
+
+/// How the stroke buffer ends up on the layer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StrokeParams {
+    pub opacity: f32,
+    /// Keeps the alpha of the layer, from [`LayerLocks::ALPHA`](cyancia_image::layer::LayerLocks::ALPHA).
+    pub preserve_alpha: bool,
+    /// Removes the stroke's coverage from the layer instead of painting color.
+    pub erase: bool,
+}
+
+/// Applies the stroke buffer onto what the layer held before the stroke began.
+/// Dabs accumulate in the buffer, so the stroke never gets more opaque than
+/// `opacity` however many dabs overlap.
+pub fn composite(base: Vec4, stroke: Vec4, params: &StrokeParams) -> Vec4 {
+    let alpha = stroke.w * params.opacity.clamp(0.0, 1.0);
+    if params.erase {
+        // Erasing only ever lowers alpha, which a locked alpha forbids.
+        if params.preserve_alpha {
+            base
+        } else {
+            base.xyz().extend(base.w * (1.0 - alpha))
+        }
+    } else if params.preserve_alpha {
+        base.xyz().lerp(stroke.xyz(), alpha).extend(base.w)
+    } else {
+        over(stroke.xyz().extend(alpha), base)
+    }
+}
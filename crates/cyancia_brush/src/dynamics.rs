@@ -0,0 +1,480 @@
+//! Drives brush parameters from pointer sensors. Everything here is pure, so
+//! dynamics can be evaluated without a window or a GPU.
+
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+
+/// A control point of a [`ResponseCurve`], both coordinates from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(from = "[f32; 2]", into = "[f32; 2]")]
+pub struct CurvePoint {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl CurvePoint {
+    pub fn new(x: f32, y: f32) -> Self {
+        Self {
+            x: x.clamp(0.0, 1.0),
+            y: y.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl From<[f32; 2]> for CurvePoint {
+    fn from([x, y]: [f32; 2]) -> Self {
+        Self::new(x, y)
+    }
+}
+
+impl From<CurvePoint> for [f32; 2] {
+    fn from(point: CurvePoint) -> Self {
+        [point.x, point.y]
+    }
+}
+
+/// Maps a sensor value to a parameter multiplier, linearly between control
+/// points sorted by `x`. There are always at least two points.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Vec<CurvePoint>", into = "Vec<CurvePoint>")]
+pub struct ResponseCurve {
+    points: Vec<CurvePoint>,
+}
+
+impl ResponseCurve {
+    pub fn new(points: impl IntoIterator<Item = CurvePoint>) -> Self {
+        let mut points = points.into_iter().collect::<Vec<_>>();
+        points.sort_by(|a, b| a.x.total_cmp(&b.x));
+        match points.len() {
+            0 => Self::default(),
+            1 => Self {
+                points: vec![
+                    CurvePoint::new(0.0, points[0].y),
+                    CurvePoint::new(1.0, points[0].y),
+                ],
+            },
+            _ => Self { points },
+        }
+    }
+
+    pub fn linear() -> Self {
+        Self::new([CurvePoint::new(0.0, 0.0), CurvePoint::new(1.0, 1.0)])
+    }
+
+    pub fn constant(y: f32) -> Self {
+        Self::new([CurvePoint::new(0.0, y), CurvePoint::new(1.0, y)])
+    }
+
+    pub fn points(&self) -> &[CurvePoint] {
+        &self.points
+    }
+
+    /// Adds a point, returning its index.
+    pub fn insert_point(&mut self, point: CurvePoint) -> usize {
+        let index = self.points.partition_point(|p| p.x <= point.x);
+        self.points.insert(index, point);
+        index
+    }
+
+    /// Moves a point, keeping it between its neighbours. Returns `false` if the
+    /// index is out of range.
+    pub fn move_point(&mut self, index: usize, point: CurvePoint) -> bool {
+        if index >= self.points.len() {
+            return false;
+        }
+        let min = index.checked_sub(1).map_or(0.0, |i| self.points[i].x);
+        let max = self.points.get(index + 1).map_or(1.0, |p| p.x);
+        self.points[index] = CurvePoint::new(point.x.clamp(min, max), point.y);
+        true
+    }
+
+    /// Removes a point. The last two points are kept.
+    pub fn remove_point(&mut self, index: usize) -> Option<CurvePoint> {
+        (self.points.len() > 2 && index < self.points.len()).then(|| self.points.remove(index))
+    }
+
+    pub fn evaluate(&self, x: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        let next = self.points.partition_point(|p| p.x < x);
+        if next == 0 {
+            return self.points[0].y;
+        }
+        let Some(b) = self.points.get(next) else {
+            return self.points[self.points.len() - 1].y;
+        };
+        let a = self.points[next - 1];
+        if b.x - a.x <= f32::EPSILON {
+            return b.y;
+        }
+        a.y + (b.y - a.y) * (x - a.x) / (b.x - a.x)
+    }
+}
+
+impl From<Vec<CurvePoint>> for ResponseCurve {
+    fn from(points: Vec<CurvePoint>) -> Self {
+        Self::new(points)
+    }
+}
+
+impl From<ResponseCurve> for Vec<CurvePoint> {
+    fn from(curve: ResponseCurve) -> Self {
+        curve.points
+    }
+}
+
+impl Default for ResponseCurve {
+    fn default() -> Self {
+        Self::linear()
+    }
+}
+
+/// Input a brush parameter can follow. Every sensor reads from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "sensor", rename_all = "snake_case")]
+pub enum Sensor {
+    Pressure,
+    /// Pointer speed, reaching 1 at `max` pixels per second.
+    Velocity {
+        max: f32,
+    },
+    /// How far the pen leans, 0 upright and 1 lying flat.
+    Tilt,
+    /// The direction the pen leans towards, a full turn counter-clockwise from +x.
+    TiltDirection,
+    /// Distance along the stroke, going from 0 to 1 every `length` pixels.
+    Distance {
+        length: f32,
+    },
+    /// A new random value for every dab.
+    Random,
+    /// Goes from 0 to 1 over the first `dabs` dabs of the stroke.
+    Fade {
+        dabs: u32,
+    },
+}
+
+impl Sensor {
+    pub fn read(&self, sample: &SensorSample, rng: &mut StrokeRng) -> f32 {
+        let value = match *self {
+            Sensor::Pressure => sample.pressure,
+            Sensor::Velocity { max } => sample.speed / max.max(f32::EPSILON),
+            Sensor::Tilt => sample.tilt.length() / 90.0,
+            Sensor::TiltDirection => {
+                if sample.tilt == Vec2::ZERO {
+                    0.0
+                } else {
+                    sample
+                        .tilt
+                        .y
+                        .atan2(sample.tilt.x)
+                        .rem_euclid(std::f32::consts::TAU)
+                        / std::f32::consts::TAU
+                }
+            }
+            Sensor::Distance { length } => (sample.distance / length.max(f32::EPSILON)).fract(),
+            Sensor::Random => rng.next_f32(),
+            Sensor::Fade { dabs } => sample.dab_index as f32 / dabs.max(1) as f32,
+        };
+        value.clamp(0.0, 1.0)
+    }
+}
+
+/// What the sensors are read from, for a single dab.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SensorSample {
+    pub pressure: f32,
+    /// Pixels per second.
+    pub speed: f32,
+    /// Pen tilt towards x and y in degrees.
+    pub tilt: Vec2,
+    /// Pixels traveled since the stroke began.
+    pub distance: f32,
+    pub dab_index: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SensorInput {
+    #[serde(flatten)]
+    pub sensor: Sensor,
+    #[serde(default)]
+    pub curve: ResponseCurve,
+}
+
+impl SensorInput {
+    pub fn new(sensor: Sensor) -> Self {
+        Self {
+            sensor,
+            curve: ResponseCurve::linear(),
+        }
+    }
+
+    pub fn with_curve(mut self, curve: ResponseCurve) -> Self {
+        self.curve = curve;
+        self
+    }
+}
+
+/// Sensors driving a single parameter. Their curves are multiplied together.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Dynamic {
+    pub inputs: Vec<SensorInput>,
+}
+
+impl Dynamic {
+    pub fn new(inputs: impl IntoIterator<Item = SensorInput>) -> Self {
+        Self {
+            inputs: inputs.into_iter().collect(),
+        }
+    }
+
+    pub fn is_constant(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// The multiplier of the parameter, 1 when no sensor is attached.
+    pub fn evaluate(&self, sample: &SensorSample, rng: &mut StrokeRng) -> f32 {
+        self.inputs
+            .iter()
+            .map(|input| input.curve.evaluate(input.sensor.read(sample, rng)))
+            .product()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BrushDynamics {
+    pub size: Dynamic,
+    /// Caps how opaque the stroke gets under each dab.
+    pub opacity: Dynamic,
+    pub flow: Dynamic,
+    pub hardness: Dynamic,
+    /// Rotates the tip by up to a full turn on top of the base angle.
+    pub angle: Dynamic,
+    pub scatter: Dynamic,
+    pub color_jitter: Dynamic,
+}
+
+impl Default for BrushDynamics {
+    fn default() -> Self {
+        Self {
+            size: Dynamic::new([SensorInput::new(Sensor::Pressure)]),
+            opacity: Dynamic::default(),
+            flow: Dynamic::default(),
+            hardness: Dynamic::default(),
+            angle: Dynamic::default(),
+            scatter: Dynamic::default(),
+            color_jitter: Dynamic::default(),
+        }
+    }
+}
+
+/// Small deterministic generator, so a stroke replays the same with the same seed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StrokeRng(u64);
+
+impl StrokeRng {
+    pub fn new(seed: u64) -> Self {
+        // Xorshift gets stuck on zero.
+        Self((seed ^ 0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{a} != {b}");
+    }
+
+    fn read(sensor: Sensor, sample: SensorSample) -> f32 {
+        sensor.read(&sample, &mut StrokeRng::new(0))
+    }
+
+    #[test]
+    fn linear_curve() {
+        let curve = ResponseCurve::linear();
+        assert_close(curve.evaluate(0.0), 0.0);
+        assert_close(curve.evaluate(0.25), 0.25);
+        assert_close(curve.evaluate(1.0), 1.0);
+        // Sensors never read outside of 0 to 1, but the curve clamps anyway.
+        assert_close(curve.evaluate(-1.0), 0.0);
+        assert_close(curve.evaluate(2.0), 1.0);
+    }
+
+    #[test]
+    fn curve_interpolates_between_points() {
+        let curve = ResponseCurve::new([
+            CurvePoint::new(1.0, 0.0),
+            CurvePoint::new(0.0, 0.2),
+            CurvePoint::new(0.5, 1.0),
+        ]);
+        assert_eq!(curve.points()[1], CurvePoint::new(0.5, 1.0));
+        assert_close(curve.evaluate(0.0), 0.2);
+        assert_close(curve.evaluate(0.25), 0.6);
+        assert_close(curve.evaluate(0.5), 1.0);
+        assert_close(curve.evaluate(0.75), 0.5);
+        assert_close(curve.evaluate(1.0), 0.0);
+    }
+
+    #[test]
+    fn curve_outside_of_its_points() {
+        let curve = ResponseCurve::new([CurvePoint::new(0.2, 0.3), CurvePoint::new(0.8, 0.9)]);
+        assert_close(curve.evaluate(0.0), 0.3);
+        assert_close(curve.evaluate(0.5), 0.6);
+        assert_close(curve.evaluate(1.0), 0.9);
+    }
+
+    #[test]
+    fn degenerate_curves() {
+        assert_eq!(ResponseCurve::new([]), ResponseCurve::linear());
+        let single = ResponseCurve::new([CurvePoint::new(0.4, 0.7)]);
+        assert_eq!(single, ResponseCurve::constant(0.7));
+        assert_close(single.evaluate(0.1), 0.7);
+
+        // A vertical step takes the upper point.
+        let step = ResponseCurve::new([
+            CurvePoint::new(0.0, 0.0),
+            CurvePoint::new(0.5, 0.0),
+            CurvePoint::new(0.5, 1.0),
+            CurvePoint::new(1.0, 1.0),
+        ]);
+        assert_close(step.evaluate(0.5), 0.0);
+        assert_close(step.evaluate(0.5001), 1.0);
+    }
+
+    #[test]
+    fn editing_curve_points() {
+        let mut curve = ResponseCurve::linear();
+        assert_eq!(curve.insert_point(CurvePoint::new(0.5, 0.9)), 1);
+        assert_close(curve.evaluate(0.5), 0.9);
+
+        // Points can't pass their neighbours.
+        assert!(curve.move_point(1, CurvePoint::new(1.5, 0.1)));
+        assert_eq!(curve.points()[1], CurvePoint::new(1.0, 0.1));
+        assert!(!curve.move_point(3, CurvePoint::new(0.5, 0.5)));
+
+        assert!(curve.remove_point(1).is_some());
+        assert!(curve.remove_point(0).is_none());
+        assert_eq!(curve.points().len(), 2);
+    }
+
+    #[test]
+    fn pressure() {
+        let sample = |pressure| SensorSample {
+            pressure,
+            ..Default::default()
+        };
+        assert_close(read(Sensor::Pressure, sample(0.0)), 0.0);
+        assert_close(read(Sensor::Pressure, sample(0.6)), 0.6);
+        assert_close(read(Sensor::Pressure, sample(1.5)), 1.0);
+    }
+
+    #[test]
+    fn velocity() {
+        let sample = |speed| SensorSample {
+            speed,
+            ..Default::default()
+        };
+        let sensor = Sensor::Velocity { max: 1000.0 };
+        assert_close(read(sensor, sample(0.0)), 0.0);
+        assert_close(read(sensor, sample(250.0)), 0.25);
+        assert_close(read(sensor, sample(4000.0)), 1.0);
+        assert_close(read(Sensor::Velocity { max: 0.0 }, sample(10.0)), 1.0);
+    }
+
+    #[test]
+    fn tilt() {
+        let sample = |x, y| SensorSample {
+            tilt: Vec2::new(x, y),
+            ..Default::default()
+        };
+        assert_close(read(Sensor::Tilt, sample(0.0, 0.0)), 0.0);
+        assert_close(read(Sensor::Tilt, sample(27.0, 36.0)), 0.5);
+        assert_close(read(Sensor::Tilt, sample(90.0, 90.0)), 1.0);
+
+        assert_close(read(Sensor::TiltDirection, sample(0.0, 0.0)), 0.0);
+        assert_close(read(Sensor::TiltDirection, sample(30.0, 0.0)), 0.0);
+        assert_close(read(Sensor::TiltDirection, sample(0.0, 30.0)), 0.25);
+        assert_close(read(Sensor::TiltDirection, sample(-30.0, 0.0)), 0.5);
+        assert_close(read(Sensor::TiltDirection, sample(0.0, -30.0)), 0.75);
+    }
+
+    #[test]
+    fn distance() {
+        let sample = |distance| SensorSample {
+            distance,
+            ..Default::default()
+        };
+        let sensor = Sensor::Distance { length: 100.0 };
+        assert_close(read(sensor, sample(0.0)), 0.0);
+        assert_close(read(sensor, sample(40.0)), 0.4);
+        // Starts over every length.
+        assert_close(read(sensor, sample(250.0)), 0.5);
+    }
+
+    #[test]
+    fn random_with_a_fixed_seed() {
+        let values = |seed| {
+            let mut rng = StrokeRng::new(seed);
+            (0..64)
+                .map(|_| Sensor::Random.read(&SensorSample::default(), &mut rng))
+                .collect::<Vec<_>>()
+        };
+        let first = values(7);
+        assert_eq!(first, values(7));
+        assert_ne!(first, values(8));
+        assert!(first.iter().all(|v| (0.0..1.0).contains(v)));
+        // Not stuck on a single value.
+        assert!(first.windows(2).any(|w| w[0] != w[1]));
+        // Nor is a seed cancelling out the mixing constant.
+        assert_ne!(StrokeRng::new(0x9E37_79B9_7F4A_7C15).next_u64(), 0);
+    }
+
+    #[test]
+    fn fade() {
+        let sample = |dab_index| SensorSample {
+            dab_index,
+            ..Default::default()
+        };
+        let sensor = Sensor::Fade { dabs: 4 };
+        assert_close(read(sensor, sample(0)), 0.0);
+        assert_close(read(sensor, sample(2)), 0.5);
+        assert_close(read(sensor, sample(4)), 1.0);
+        assert_close(read(sensor, sample(100)), 1.0);
+        assert_close(read(Sensor::Fade { dabs: 0 }, sample(1)), 1.0);
+    }
+
+    #[test]
+    fn dynamic_multiplies_its_inputs() {
+        let sample = SensorSample {
+            pressure: 0.5,
+            dab_index: 1,
+            ..Default::default()
+        };
+        let mut rng = StrokeRng::new(0);
+        assert_close(Dynamic::default().evaluate(&sample, &mut rng), 1.0);
+
+        let dynamic = Dynamic::new([
+            SensorInput::new(Sensor::Pressure),
+            SensorInput::new(Sensor::Fade { dabs: 2 }).with_curve(ResponseCurve::new([
+                CurvePoint::new(0.0, 1.0),
+                CurvePoint::new(1.0, 0.0),
+            ])),
+        ]);
+        assert_close(dynamic.evaluate(&sample, &mut rng), 0.25);
+    }
+}
@@ -0,0 +1,214 @@
+use std::path::PathBuf;
+
+use cyancia_assets::store::AssetLoaderRegistry;
+use serde::{Deserialize, Serialize};
+
+use crate::{bitmap::BrushImageLoader, dynamics::BrushDynamics, preset::BrushPresetLoader};
+
+pub mod bitmap;
+pub mod dab;
+pub mod dynamics;
+pub mod preset;
+pub mod raster;
+pub mod smudge;
+pub mod stamp;
+pub mod stroke;
+
+pub fn register_loaders(loaders: &mut AssetLoaderRegistry) {
+    loaders.register::<BrushPresetLoader>();
+    loaders.register::<BrushImageLoader>();
+}
+
+/// Shape of the falloff from the center of a dab to its edge.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BrushTip {
+    /// Fully opaque up to `hardness` of the radius, then a smooth falloff.
+    #[default]
+    Round,
+    /// A disc with a single antialiased pixel at its edge, ignores hardness.
+    Hard,
+    /// Falls off all the way from the center, lower hardness spreads it wider.
+    Soft,
+}
+
+impl BrushTip {
+    /// Identifier of the tip in the dab shader.
+    pub(crate) fn shader_id(self) -> u32 {
+        match self {
+            BrushTip::Round => 0,
+            BrushTip::Hard => 1,
+            BrushTip::Soft => 2,
+        }
+    }
+}
+
+/// What dabs do to the layer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BrushMode {
+    /// Deposits the brush color.
+    #[default]
+    Paint,
+    /// Drags the pixels under the previous dab along the stroke.
+    Smudge,
+    /// Picks up the color under each dab and paints it mixed with the brush
+    /// color, like wet paint.
+    Mix,
+    /// Softens the pixels under the dab.
+    Blur,
+}
+
+impl BrushMode {
+    /// Identifier of the mode in the smudge shader.
+    pub(crate) fn shader_id(self) -> u32 {
+        match self {
+            BrushMode::Paint => 0,
+            BrushMode::Smudge => 1,
+            BrushMode::Mix => 2,
+            BrushMode::Blur => 3,
+        }
+    }
+}
+
+/// What the tip turns with, on top of the angle of the brush.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TipRotation {
+    #[default]
+    Fixed,
+    /// Follows the direction of the stroke.
+    Direction,
+    /// Follows the barrel rotation of the pen.
+    Pen,
+}
+
+/// How a paper texture lets paint through, grain being 1 on light texels.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextureBlend {
+    /// Scales the coverage by the grain.
+    #[default]
+    Multiply,
+    /// Removes the darkness of the grain from the coverage, light pressure
+    /// only catches the peaks of the paper.
+    Subtract,
+    /// Caps the coverage at the grain.
+    Min,
+}
+
+impl TextureBlend {
+    /// Identifier of the blend in the dab shader.
+    pub(crate) fn shader_id(self) -> u32 {
+        match self {
+            TextureBlend::Multiply => 0,
+            TextureBlend::Subtract => 1,
+            TextureBlend::Min => 2,
+        }
+    }
+}
+
+/// A grayscale image modulating the coverage of dabs in canvas space.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaperTexture {
+    /// Path of the image inside the assets folder.
+    pub image: PathBuf,
+    /// Canvas pixels per texel.
+    #[serde(default = "PaperTexture::default_scale")]
+    pub scale: f32,
+    /// Pushes the grain away from mid gray, 1 leaving it as is.
+    #[serde(default = "PaperTexture::default_contrast")]
+    pub contrast: f32,
+    #[serde(default)]
+    pub blend: TextureBlend,
+}
+
+impl PaperTexture {
+    fn default_scale() -> f32 {
+        1.0
+    }
+
+    fn default_contrast() -> f32 {
+        1.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BrushSettings {
+    pub mode: BrushMode,
+    pub tip: BrushTip,
+    /// Grayscale image stamped instead of the procedural tip, a path inside
+    /// the assets folder. The longer side of the image spans the size.
+    pub tip_image: Option<PathBuf>,
+    /// Diameter of a dab in pixels.
+    pub size: f32,
+    /// Distance between two dabs as a fraction of the size.
+    pub spacing: f32,
+    /// Opacity of a single dab, dabs build up until they reach `opacity`.
+    pub flow: f32,
+    /// Maximum opacity of the whole stroke.
+    pub opacity: f32,
+    pub hardness: f32,
+    /// Rotation of the tip in radians.
+    pub angle: f32,
+    pub rotation: TipRotation,
+    /// Stretch of the tip along its own axes, before rotation.
+    pub scale_x: f32,
+    pub scale_y: f32,
+    /// Mirrors every other dab or so along the axes of the tip.
+    pub random_flip_x: bool,
+    pub random_flip_y: bool,
+    pub texture: Option<PaperTexture>,
+    /// How far dabs stray from the path, as a fraction of the size.
+    pub scatter: f32,
+    /// How far the hue of dabs may drift, 1 allowing any hue.
+    pub color_jitter: f32,
+    /// How much of the picked up color survives each dab when smudging or
+    /// mixing, longer smears and trails for higher values.
+    pub length: f32,
+    /// How much picked up color is in the paint when mixing, 0 painting the
+    /// brush color only and 1 only what was picked up.
+    pub wetness: f32,
+    /// Radius of the blur in pixels.
+    pub blur_radius: f32,
+    pub dynamics: BrushDynamics,
+}
+
+impl BrushSettings {
+    /// The smallest distance between dabs, keeps tiny brushes from emitting
+    /// thousands of dabs per pixel.
+    pub const MIN_SPACING: f32 = 0.5;
+
+    pub fn spacing_px(&self) -> f32 {
+        (self.size * self.spacing).max(Self::MIN_SPACING)
+    }
+}
+
+impl Default for BrushSettings {
+    fn default() -> Self {
+        Self {
+            mode: BrushMode::Paint,
+            tip: BrushTip::Round,
+            tip_image: None,
+            size: 20.0,
+            spacing: 0.1,
+            flow: 1.0,
+            opacity: 1.0,
+            hardness: 0.8,
+            angle: 0.0,
+            rotation: TipRotation::Fixed,
+            scale_x: 1.0,
+            scale_y: 1.0,
+            random_flip_x: false,
+            random_flip_y: false,
+            texture: None,
+            scatter: 0.0,
+            color_jitter: 0.0,
+            length: 0.8,
+            wetness: 0.5,
+            blur_radius: 4.0,
+            dynamics: BrushDynamics::default(),
+        }
+    }
+}
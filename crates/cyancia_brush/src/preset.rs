@@ -0,0 +1,105 @@
+//! Brush presets, loaded from `.brush` files anywhere under the assets folder.
+//! A file is a TOML table of named presets, so a whole brush pack can ship as
+//! a single file.
+
+use std::{collections::HashMap, sync::Arc};
+
+use cyancia_assets::{asset::Asset, loader::AssetLoader, store::AssetStore};
+use cyancia_id::Id;
+use cyancia_utils::global_instance::GlobalInstance;
+
+use crate::BrushSettings;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrushPreset {
+    pub name: Arc<str>,
+    pub settings: BrushSettings,
+}
+
+impl Asset for BrushPreset {}
+
+impl BrushPreset {
+    pub fn id(&self) -> Id<BrushPreset> {
+        Id::from_str(&self.name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BrushPresetManifest {
+    pub presets: Vec<BrushPreset>,
+}
+
+impl Asset for BrushPresetManifest {}
+
+#[derive(Default)]
+pub struct BrushPresetLoader;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BrushPresetLoaderError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+}
+
+impl AssetLoader for BrushPresetLoader {
+    type Asset = BrushPresetManifest;
+
+    type Error = BrushPresetLoaderError;
+
+    fn file_extensions() -> &'static [&'static str] {
+        &["brush"]
+    }
+
+    fn read(&self, reader: &mut dyn std::io::Read) -> Result<Self::Asset, Self::Error> {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        let presets = toml::from_slice::<HashMap<String, BrushSettings>>(&buf)?
+            .into_iter()
+            .map(|(name, settings)| BrushPreset {
+                name: Arc::from(name),
+                settings,
+            })
+            .collect();
+        Ok(BrushPresetManifest { presets })
+    }
+}
+
+pub static BRUSH_PRESETS: GlobalInstance<BrushPresetCollection> = GlobalInstance::new();
+
+/// Every preset of every loaded pack, by name. Later packs win on name clashes.
+#[derive(Debug, Default)]
+pub struct BrushPresetCollection {
+    presets: HashMap<Id<BrushPreset>, Arc<BrushPreset>>,
+}
+
+impl BrushPresetCollection {
+    pub fn new(manifests: AssetStore<BrushPresetManifest>) -> Self {
+        let presets = manifests
+            .into_map()
+            .into_values()
+            .flat_map(|manifest| manifest.presets.clone())
+            .map(|preset| (preset.id(), Arc::new(preset)))
+            .collect();
+        Self { presets }
+    }
+
+    pub fn get(&self, id: Id<BrushPreset>) -> Option<Arc<BrushPreset>> {
+        self.presets.get(&id).cloned()
+    }
+
+    /// All presets sorted by name.
+    pub fn presets(&self) -> Vec<Arc<BrushPreset>> {
+        let mut presets = self.presets.values().cloned().collect::<Vec<_>>();
+        presets.sort_by(|a, b| a.name.cmp(&b.name));
+        presets
+    }
+
+    pub fn len(&self) -> usize {
+        self.presets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.presets.is_empty()
+    }
+}
@@ -0,0 +1,373 @@
+//! Paints strokes on the CPU with the same dab model as [`DabStamper`](crate::stamp::DabStamper)
+//! and [`SmudgeStamper`](crate::smudge::SmudgeStamper), which makes strokes
+//! reproducible without a GPU.
+
+use std::sync::Arc;
+
+use glam::{IVec2, Vec2, Vec4};
+use image::{Rgba, Rgba32FImage};
+
+use crate::{
+    BrushMode, BrushTip, PaperTexture,
+    bitmap::BrushImage,
+    dab::{self, Dab, DabShape, DabTip, StrokeParams},
+    smudge::{self, SmudgeParams},
+};
+
+#[derive(Debug, Clone)]
+pub struct StrokeRaster {
+    /// The layer as it was before the stroke.
+    base: Rgba32FImage,
+    /// Dabs accumulated so far, straight alpha.
+    buffer: Rgba32FImage,
+    tip: BrushTip,
+    tip_image: Option<Arc<BrushImage>>,
+    texture: Option<(Arc<BrushImage>, PaperTexture)>,
+    params: StrokeParams,
+}
+
+impl StrokeRaster {
+    pub fn new(base: Rgba32FImage, tip: BrushTip, params: StrokeParams) -> Self {
+        let buffer = Rgba32FImage::new(base.width(), base.height());
+        Self {
+            base,
+            buffer,
+            tip,
+            tip_image: None,
+            texture: None,
+            params,
+        }
+    }
+
+    pub fn with_tip_image(mut self, image: Arc<BrushImage>) -> Self {
+        self.tip_image = Some(image);
+        self
+    }
+
+    pub fn with_texture(mut self, image: Arc<BrushImage>, texture: PaperTexture) -> Self {
+        self.texture = Some((image, texture));
+        self
+    }
+
+    pub fn stamp(&mut self, dab: &Dab) {
+        let size = IVec2::new(self.buffer.width() as i32, self.buffer.height() as i32);
+        let (min, max) = dab.bounds();
+        let (min, max) = (min.max(IVec2::ZERO), max.min(size));
+        let shape = DabShape {
+            tip: match &self.tip_image {
+                Some(image) => DabTip::Image(image),
+                None => DabTip::Procedural(self.tip),
+            },
+            texture: self.texture.as_ref().map(|(image, t)| (image.as_ref(), t)),
+        };
+
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                let coverage = shape.coverage(dab, IVec2::new(x, y));
+                if coverage <= 0.0 {
+                    continue;
+                }
+                let px = self.buffer.get_pixel_mut(x as u32, y as u32);
+                let buffer = Vec4::from_array(px.0);
+                let alpha = dab.flow * coverage;
+                *px = Rgba(dab::accumulate(buffer, dab.color, alpha, dab.opacity).to_array());
+            }
+        }
+    }
+
+    pub fn stamp_all<'a>(&mut self, dabs: impl IntoIterator<Item = &'a Dab>) {
+        for dab in dabs {
+            self.stamp(dab);
+        }
+    }
+
+    pub fn buffer(&self) -> &Rgba32FImage {
+        &self.buffer
+    }
+
+    /// The layer with the stroke applied.
+    pub fn result(&self) -> Rgba32FImage {
+        let mut result = self.base.clone();
+        for (px, stroke) in result.pixels_mut().zip(self.buffer.pixels()) {
+            let base = Vec4::from_array(px.0);
+            let stroke = Vec4::from_array(stroke.0);
+            *px = Rgba(dab::composite(base, stroke, &self.params).to_array());
+        }
+        result
+    }
+}
+
+/// Smudges, mixes or blurs a layer in place.
+#[derive(Debug, Clone)]
+pub struct SmudgeRaster {
+    layer: Rgba32FImage,
+    tip: BrushTip,
+    tip_image: Option<Arc<BrushImage>>,
+    texture: Option<(Arc<BrushImage>, PaperTexture)>,
+    params: SmudgeParams,
+    /// Center of the previous dab.
+    last: Option<Vec2>,
+    /// Color picked up so far, premultiplied.
+    carried: Option<Vec4>,
+}
+
+impl SmudgeRaster {
+    pub fn new(layer: Rgba32FImage, tip: BrushTip, params: SmudgeParams) -> Self {
+        Self {
+            layer,
+            tip,
+            tip_image: None,
+            texture: None,
+            params,
+            last: None,
+            carried: None,
+        }
+    }
+
+    pub fn with_tip_image(mut self, image: Arc<BrushImage>) -> Self {
+        self.tip_image = Some(image);
+        self
+    }
+
+    pub fn with_texture(mut self, image: Arc<BrushImage>, texture: PaperTexture) -> Self {
+        self.texture = Some((image, texture));
+        self
+    }
+
+    pub fn stamp(&mut self, dab: &Dab) {
+        let offset = dab.center - self.last.replace(dab.center).unwrap_or(dab.center);
+        let size = IVec2::new(self.layer.width() as i32, self.layer.height() as i32);
+        let (min, max) = dab.bounds();
+        let (min, max) = (min.max(IVec2::ZERO), max.min(size));
+        if min.cmpge(max).any() {
+            return;
+        }
+
+        let shape = DabShape {
+            tip: match &self.tip_image {
+                Some(image) => DabTip::Image(image),
+                None => DabTip::Procedural(self.tip),
+            },
+            texture: self.texture.as_ref().map(|(image, t)| (image.as_ref(), t)),
+        };
+        let coverage = |pixel| shape.coverage(dab, pixel) * dab.flow;
+        // Every pixel is computed from the layer as it was before the dab.
+        let before = self.layer.clone();
+        let layer = |pixel: IVec2| {
+            let p = pixel.clamp(IVec2::ZERO, size - 1);
+            smudge::premultiply(Vec4::from_array(before.get_pixel(p.x as u32, p.y as u32).0))
+        };
+
+        if self.params.mode == BrushMode::Mix {
+            let (mut color, mut weight) = (Vec4::ZERO, 0.0);
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    let pixel = IVec2::new(x, y);
+                    let k = coverage(pixel);
+                    color += layer(pixel) * k;
+                    weight += k;
+                }
+            }
+            if weight > 0.0 {
+                self.carried = Some(smudge::pick_up(
+                    self.carried,
+                    color / weight,
+                    self.params.length,
+                ));
+            }
+        }
+
+        let kernel = smudge::blur_kernel(self.params.blur_radius).collect::<Vec<_>>();
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                let pixel = IVec2::new(x, y);
+                let k = coverage(pixel);
+                if k <= 0.0 {
+                    continue;
+                }
+
+                let current = layer(pixel);
+                let result = match self.params.mode {
+                    BrushMode::Paint => current,
+                    BrushMode::Smudge => {
+                        let source = layer(smudge::smudge_source(pixel, offset));
+                        current.lerp(source, k * self.params.length)
+                    }
+                    BrushMode::Mix => {
+                        let paint = smudge::wet_paint(dab.color, self.carried, self.params.wetness);
+                        current.lerp(paint, k)
+                    }
+                    BrushMode::Blur => {
+                        let (sum, weights) = kernel.iter().fold(
+                            (Vec4::ZERO, 0.0),
+                            |(sum, weights), (offset, weight)| {
+                                (sum + layer(pixel + offset) * weight, weights + weight)
+                            },
+                        );
+                        current.lerp(sum / weights, k)
+                    }
+                };
+                let pixel = smudge::finish_pixel(result, current, self.params.preserve_alpha);
+                *self.layer.get_pixel_mut(x as u32, y as u32) = Rgba(pixel.to_array());
+            }
+        }
+    }
+
+    pub fn stamp_all<'a>(&mut self, dabs: impl IntoIterator<Item = &'a Dab>) {
+        for dab in dabs {
+            self.stamp(dab);
+        }
+    }
+
+    pub fn layer(&self) -> &Rgba32FImage {
+        &self.layer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use glam::Vec3;
+
+    use crate::{
+        BrushSettings,
+        stroke::{Stroke, StrokePoint},
+    };
+
+    use super::*;
+
+    const PARAMS: StrokeParams = StrokeParams {
+        opacity: 1.0,
+        preserve_alpha: false,
+        erase: false,
+    };
+
+    fn raster(tip: BrushTip) -> StrokeRaster {
+        StrokeRaster::new(Rgba32FImage::new(64, 64), tip, PARAMS)
+    }
+
+    fn dab(hardness: f32, flow: f32, opacity: f32) -> Dab {
+        Dab {
+            center: Vec2::splat(32.5),
+            radius: 10.0,
+            hardness,
+            flow,
+            opacity,
+            angle: 0.0,
+            scale: Vec2::ONE,
+            color: Vec3::ONE,
+        }
+    }
+
+    /// Dabs of a straight stroke from x 10 to 50.
+    fn stroke(settings: BrushSettings) -> Vec<Dab> {
+        let mut stroke = Stroke::new(settings, Vec3::ONE, 0);
+        let start = Instant::now();
+        let mut dabs = stroke.move_to(StrokePoint::new(Vec2::new(10.0, 32.0), start));
+        dabs.extend(stroke.move_to(StrokePoint::new(
+            Vec2::new(50.0, 32.0),
+            start + Duration::from_millis(100),
+        )));
+        dabs
+    }
+
+    fn alpha(raster: &StrokeRaster, x: u32, y: u32) -> f32 {
+        raster.buffer().get_pixel(x, y).0[3]
+    }
+
+    #[test]
+    fn dabs_are_spaced_by_a_fraction_of_the_size() {
+        for (spacing, count) in [(0.25, 9), (0.5, 5), (1.0, 3)] {
+            let dabs = stroke(BrushSettings {
+                size: 20.0,
+                spacing,
+                ..Default::default()
+            });
+            assert_eq!(dabs.len(), count, "spacing {spacing}");
+            for pair in dabs.windows(2) {
+                let distance = pair[0].center.distance(pair[1].center);
+                assert!((distance - 20.0 * spacing).abs() < 1e-4, "{distance}");
+            }
+        }
+
+        let mut raster = raster(BrushTip::Hard);
+        raster.stamp_all(&stroke(BrushSettings {
+            size: 20.0,
+            spacing: 0.25,
+            ..Default::default()
+        }));
+        assert!(alpha(&raster, 30, 32) > 0.99);
+        assert_eq!(alpha(&raster, 30, 45), 0.0);
+        assert_eq!(alpha(&raster, 62, 32), 0.0);
+    }
+
+    #[test]
+    fn hard_tips_fall_off_sharper_than_soft_ones() {
+        let profile = |tip: BrushTip, hardness: f32| {
+            let mut raster = raster(tip);
+            raster.stamp(&dab(hardness, 1.0, 1.0));
+            // Pixel centers 0 to 11 pixels right of the dab center.
+            (32..44).map(|x| alpha(&raster, x, 32)).collect::<Vec<_>>()
+        };
+        let hard = profile(BrushTip::Hard, 0.5);
+        let soft = profile(BrushTip::Soft, 0.5);
+
+        assert!(hard[..10].iter().all(|a| *a == 1.0));
+        assert_eq!(hard[11], 0.0);
+        assert_eq!(soft[0], 1.0);
+        assert!(soft[5] > 0.0 && soft[5] < 1.0);
+        assert!(soft.windows(2).all(|w| w[0] >= w[1]));
+        assert!(hard.iter().zip(&soft).all(|(h, s)| h >= s));
+
+        // Round tips are fully opaque up to the hardness, then fade.
+        let round = profile(BrushTip::Round, 0.5);
+        assert!(round[..5].iter().all(|a| *a == 1.0));
+        assert!(round[7] > 0.0 && round[7] < 1.0);
+        assert!(round.windows(2).all(|w| w[0] >= w[1]));
+        let sharper = profile(BrushTip::Round, 0.9);
+        assert!(sharper.iter().zip(&round).all(|(h, s)| h >= s));
+    }
+
+    #[test]
+    fn flow_builds_up_to_the_opacity() {
+        let mut raster = raster(BrushTip::Hard);
+        let mut expected = 0.0;
+        for _ in 0..3 {
+            raster.stamp(&dab(1.0, 0.25, 0.6));
+            expected += (1.0 - expected) * 0.25;
+            assert!((alpha(&raster, 32, 32) - expected).abs() < 1e-5);
+        }
+        for _ in 0..10 {
+            raster.stamp(&dab(1.0, 0.25, 0.6));
+        }
+        assert!((alpha(&raster, 32, 32) - 0.6).abs() < 1e-5);
+
+        // Lower opacity dabs don't take anything away.
+        raster.stamp(&dab(1.0, 1.0, 1.0));
+        raster.stamp(&dab(1.0, 0.25, 0.3));
+        assert_eq!(alpha(&raster, 32, 32), 1.0);
+    }
+
+    #[test]
+    fn stroke_opacity_scales_the_buffer_onto_the_layer() {
+        let mut base = Rgba32FImage::new(64, 64);
+        base.put_pixel(32, 32, Rgba([0.0, 0.0, 0.0, 1.0]));
+        let mut raster = StrokeRaster::new(
+            base,
+            BrushTip::Hard,
+            StrokeParams {
+                opacity: 0.5,
+                ..PARAMS
+            },
+        );
+        raster.stamp(&dab(1.0, 1.0, 1.0));
+
+        let result = raster.result();
+        let over_black = result.get_pixel(32, 32).0;
+        assert_eq!(over_black, [0.5, 0.5, 0.5, 1.0]);
+        let over_nothing = result.get_pixel(33, 32).0;
+        assert_eq!(over_nothing, [1.0, 1.0, 1.0, 0.5]);
+        assert_eq!(result.get_pixel(0, 0).0, [0.0; 4]);
+    }
+}
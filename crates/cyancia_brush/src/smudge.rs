@@ -0,0 +1,511 @@
+//! Smudge, mix and blur dabs, which paint with the pixels they land on. Unlike
+//! painted strokes they change the layer directly, every dab sampling what the
+//! previous ones left. Colors are mixed premultiplied so transparent pixels
+//! don't bleed black.
+
+use std::sync::Arc;
+
+use cyancia_id::Id;
+use cyancia_image::{layer::Layer, tile::GpuTileStorage};
+use cyancia_render::buffer::DynamicBuffer;
+use cyancia_utils::include_shader;
+use encase::ShaderType;
+use glam::{IVec2, UVec2, Vec2, Vec3, Vec4, Vec4Swizzles};
+use wgpu::{
+    BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+    BindGroupLayoutEntry, BindingResource, BindingType, Buffer, BufferBindingType,
+    BufferDescriptor, BufferUsages, CommandEncoderDescriptor, ComputePassDescriptor,
+    ComputePipeline, ComputePipelineDescriptor, Device, Extent3d, PipelineLayoutDescriptor,
+    ShaderModuleDescriptor, ShaderSource, ShaderStages, Texture, TextureDescriptor,
+    TextureDimension, TextureUsages,
+};
+
+use crate::{
+    BrushMode, BrushSettings,
+    dab::Dab,
+    stamp::{self, BrushTextures, GpuShape},
+};
+
+/// Taps of the blur kernel on each side of its center.
+pub const BLUR_TAPS: i32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SmudgeParams {
+    pub mode: BrushMode,
+    pub length: f32,
+    pub wetness: f32,
+    pub blur_radius: f32,
+    /// Keeps the alpha of the layer, from [`LayerLocks::ALPHA`](cyancia_image::layer::LayerLocks::ALPHA).
+    pub preserve_alpha: bool,
+}
+
+impl SmudgeParams {
+    pub fn new(settings: &BrushSettings, preserve_alpha: bool) -> Self {
+        Self {
+            mode: settings.mode,
+            length: settings.length.clamp(0.0, 1.0),
+            wetness: settings.wetness.clamp(0.0, 1.0),
+            blur_radius: settings.blur_radius.max(0.0),
+            preserve_alpha,
+        }
+    }
+
+    /// How far outside a dab the pixels it reads may lie, `offset` being the
+    /// move since the previous dab.
+    pub fn padding(&self, offset: Vec2) -> u32 {
+        match self.mode {
+            BrushMode::Smudge => offset.abs().max_element().ceil() as u32 + 1,
+            BrushMode::Blur => (blur_step(self.blur_radius) * BLUR_TAPS as f32).ceil() as u32 + 1,
+            BrushMode::Paint | BrushMode::Mix => 0,
+        }
+    }
+}
+
+pub fn premultiply(color: Vec4) -> Vec4 {
+    (color.xyz() * color.w).extend(color.w)
+}
+
+pub fn unpremultiply(color: Vec4) -> Vec4 {
+    if color.w <= 0.0 {
+        return Vec4::ZERO;
+    }
+    (color.xyz() / color.w).extend(color.w)
+}
+
+/// Turns a mixed premultiplied color back into a layer pixel.
+pub fn finish_pixel(result: Vec4, current: Vec4, preserve_alpha: bool) -> Vec4 {
+    let result = unpremultiply(result);
+    if preserve_alpha {
+        result.xyz().extend(current.w)
+    } else {
+        result
+    }
+}
+
+/// The pixel a smudge dab drags into `pixel`.
+pub fn smudge_source(pixel: IVec2, offset: Vec2) -> IVec2 {
+    (pixel.as_vec2() - offset + 0.5).floor().as_ivec2()
+}
+
+/// The color carried after a dab picked up `picked`, premultiplied.
+pub fn pick_up(carried: Option<Vec4>, picked: Vec4, length: f32) -> Vec4 {
+    match carried {
+        Some(carried) => picked.lerp(carried, length),
+        None => picked,
+    }
+}
+
+/// The brush color diluted with the carried color, premultiplied.
+pub fn wet_paint(color: Vec3, carried: Option<Vec4>, wetness: f32) -> Vec4 {
+    match carried {
+        Some(carried) => color.extend(1.0).lerp(carried, wetness),
+        None => color.extend(1.0),
+    }
+}
+
+fn blur_step(radius: f32) -> f32 {
+    (radius / BLUR_TAPS as f32).max(1.0)
+}
+
+/// Offsets and weights of a gaussian blur, the weights not being normalized.
+pub fn blur_kernel(radius: f32) -> impl Iterator<Item = (IVec2, f32)> {
+    let step = blur_step(radius);
+    let sigma = (radius * 0.5).max(0.5);
+    (-BLUR_TAPS..=BLUR_TAPS).flat_map(move |y| {
+        (-BLUR_TAPS..=BLUR_TAPS).map(move |x| {
+            let offset = (Vec2::new(x as f32, y as f32) * step + 0.5)
+                .floor()
+                .as_ivec2();
+            let weight = (-(offset.length_squared() as f32) / (2.0 * sigma * sigma)).exp();
+            (offset, weight)
+        })
+    })
+}
+
+#[derive(Debug, Clone, Copy, ShaderType)]
+struct SmudgeUniform {
+    center: Vec2,
+    offset: Vec2,
+    scale: Vec2,
+    radius: f32,
+    hardness: f32,
+    angle: f32,
+    flow: f32,
+    color: Vec3,
+    rect_min: IVec2,
+    rect_size: UVec2,
+    tile_origin: IVec2,
+    patch_origin: IVec2,
+    image_size: IVec2,
+    mode: u32,
+    length: f32,
+    wetness: f32,
+    blur_radius: f32,
+    preserve_alpha: u32,
+    tip: u32,
+    tip_image: u32,
+    texture: u32,
+    texture_scale: f32,
+    texture_contrast: f32,
+    texture_blend: u32,
+    masked: u32,
+}
+
+/// A smudge, mix or blur stroke being painted on the GPU.
+#[derive(Debug)]
+pub struct GpuSmudge {
+    target: Id<Layer>,
+    image_size: UVec2,
+    shape: GpuShape,
+    params: SmudgeParams,
+    /// Selection mask limiting where the stroke lands.
+    selection: Option<(Arc<GpuTileStorage>, Id<Layer>)>,
+    /// Center of the previous dab.
+    last: Option<Vec2>,
+    /// Color picked up so far, stays on the GPU for the whole stroke.
+    carried: Option<Buffer>,
+}
+
+impl GpuSmudge {
+    pub fn new(
+        target: Id<Layer>,
+        image_size: UVec2,
+        shape: GpuShape,
+        params: SmudgeParams,
+    ) -> Self {
+        Self {
+            target,
+            image_size,
+            shape,
+            params,
+            selection: None,
+            last: None,
+            carried: None,
+        }
+    }
+
+    /// Only smudges where the mask `id` in `masks` is selected.
+    pub fn with_selection(mut self, masks: Arc<GpuTileStorage>, id: Id<Layer>) -> Self {
+        self.selection = Some((masks, id));
+        self
+    }
+
+    pub fn target(&self) -> Id<Layer> {
+        self.target
+    }
+
+    /// Tiles of the image covered by the dabs, sorted by row.
+    pub fn tiles_of(&self, dabs: &[Dab]) -> Vec<UVec2> {
+        stamp::covered_tiles(self.image_size, dabs)
+    }
+}
+
+/// What a dab dispatches, recorded first so the uniforms can be written at once.
+enum Pass {
+    /// Copies the layer around the dab into the patch.
+    Sample {
+        min: UVec2,
+        max: UVec2,
+    },
+    Pickup {
+        offset: u32,
+        tile: UVec2,
+    },
+    Apply {
+        offset: u32,
+        tile: UVec2,
+        size: UVec2,
+    },
+}
+
+#[derive(Debug)]
+pub struct SmudgeStamper {
+    pickup: ComputePipeline,
+    apply: ComputePipeline,
+    layout: BindGroupLayout,
+    uniforms: DynamicBuffer<SmudgeUniform>,
+    /// The layer around the current dab as it was before the dab, which lets
+    /// dabs read pixels of tiles they are writing.
+    patch: Option<Texture>,
+}
+
+impl SmudgeStamper {
+    pub fn new(device: &Device) -> Self {
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("brush smudge layout"),
+            entries: &[
+                // patch
+                stamp::texture_entry(0),
+                // dab uniform
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: Some(<SmudgeUniform as ShaderType>::min_size()),
+                    },
+                    count: None,
+                },
+                // carried color
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // layer
+                stamp::storage_texture_entry(3),
+                // tip image
+                stamp::texture_entry(4),
+                // paper texture
+                stamp::texture_entry(5),
+                // selection mask
+                stamp::texture_entry(6),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("brush smudge pipeline layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("brush smudge shader"),
+            source: ShaderSource::Wgsl(include_shader!("brush_smudge.wgsl").into()),
+        });
+
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some("brush smudge pipeline"),
+                layout: Some(&pipeline_layout),
+                entry_point: Some(entry_point),
+                module: &shader_module,
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+
+        Self {
+            pickup: pipeline("pickup"),
+            apply: pipeline("apply"),
+            layout,
+            uniforms: DynamicBuffer::new(
+                Some("brush smudge uniform buffer"),
+                BufferUsages::UNIFORM,
+            ),
+            patch: None,
+        }
+    }
+
+    /// The patch, grown to hold at least `size` pixels.
+    fn reserve_patch(&mut self, device: &Device, size: UVec2) -> Texture {
+        let current = self
+            .patch
+            .as_ref()
+            .map_or(UVec2::ZERO, |p| UVec2::new(p.width(), p.height()));
+        if let Some(patch) = &self.patch
+            && current.cmpge(size).all()
+        {
+            return patch.clone();
+        }
+
+        let size = size.max(current).max(UVec2::splat(64));
+        self.patch
+            .insert(device.create_texture(&TextureDescriptor {
+                label: Some("brush smudge patch"),
+                size: Extent3d {
+                    width: size.x,
+                    height: size.y,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: GpuTileStorage::TILE_FORMAT,
+                usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                view_formats: &[],
+            }))
+            .clone()
+    }
+
+    /// Paints the dabs one after the other, each seeing the result of the
+    /// previous ones.
+    pub fn stamp(
+        &mut self,
+        tiles: &GpuTileStorage,
+        textures: &BrushTextures,
+        stroke: &mut GpuSmudge,
+        dabs: &[Dab],
+    ) {
+        let device = tiles.device();
+        let image_size = stroke.image_size;
+        let params = stroke.params;
+        let texture = stroke.shape.texture();
+
+        let mut passes = Vec::new();
+        let mut patch_size = UVec2::ZERO;
+        self.uniforms.clear();
+        for dab in dabs {
+            let offset = dab.center - stroke.last.replace(dab.center).unwrap_or(dab.center);
+            let Some((min, max)) = stamp::clip(dab, image_size) else {
+                continue;
+            };
+
+            let padding = UVec2::splat(params.padding(offset));
+            let patch_min = min.saturating_sub(padding);
+            let patch_max = (max + padding).min(image_size);
+            patch_size = patch_size.max(patch_max - patch_min);
+            passes.push(Pass::Sample {
+                min: patch_min,
+                max: patch_max,
+            });
+
+            let mut uniform = SmudgeUniform {
+                center: dab.center,
+                offset,
+                scale: dab.scale,
+                radius: dab.radius,
+                hardness: dab.hardness,
+                angle: dab.angle,
+                flow: dab.flow,
+                color: dab.color,
+                rect_min: min.as_ivec2(),
+                rect_size: max - min,
+                tile_origin: IVec2::ZERO,
+                patch_origin: patch_min.as_ivec2(),
+                image_size: image_size.as_ivec2(),
+                mode: params.mode.shader_id(),
+                length: params.length,
+                wetness: params.wetness,
+                blur_radius: params.blur_radius,
+                preserve_alpha: params.preserve_alpha as u32,
+                tip: stroke.shape.tip.shader_id(),
+                tip_image: stroke.shape.tip_image.is_some() as u32,
+                texture: texture.is_some() as u32,
+                texture_scale: texture.map_or(1.0, |t| t.scale),
+                texture_contrast: texture.map_or(1.0, |t| t.contrast),
+                texture_blend: texture.map_or(0, |t| t.blend.shader_id()),
+                masked: stroke.selection.is_some() as u32,
+            };
+
+            let first_tile = min / GpuTileStorage::TILE_SIZE;
+            let last_tile = (max - 1) / GpuTileStorage::TILE_SIZE;
+            if params.mode == BrushMode::Mix {
+                let offset = self.uniforms.push(&uniform);
+                passes.push(Pass::Pickup {
+                    offset: offset.expect("Writing into a vector never fails.") as u32,
+                    tile: first_tile,
+                });
+            }
+
+            for y in first_tile.y..=last_tile.y {
+                for x in first_tile.x..=last_tile.x {
+                    let tile = UVec2::new(x, y);
+                    let tile_min = tile * GpuTileStorage::TILE_SIZE;
+                    let rect_min = min.max(tile_min);
+                    let rect_max = max.min(tile_min + GpuTileStorage::TILE_SIZE);
+                    uniform.rect_min = rect_min.as_ivec2();
+                    uniform.rect_size = rect_max - rect_min;
+                    uniform.tile_origin = tile_min.as_ivec2();
+                    let offset = self.uniforms.push(&uniform);
+                    passes.push(Pass::Apply {
+                        offset: offset.expect("Writing into a vector never fails.") as u32,
+                        tile,
+                        size: rect_max - rect_min,
+                    });
+                }
+            }
+        }
+        if passes.is_empty() {
+            return;
+        }
+
+        stroke.shape.upload(tiles, textures);
+        let carried = stroke.carried.get_or_insert_with(|| {
+            device.create_buffer(&BufferDescriptor {
+                label: Some("brush smudge carried color"),
+                size: 32,
+                usage: BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        });
+
+        self.uniforms.write_buffer(device);
+        let patch = self.reserve_patch(device, patch_size);
+        let patch_view = patch.create_view(&Default::default());
+        let Some(uniform_binding) = self.uniforms.binding() else {
+            return;
+        };
+        let (tip_view, texture_view) = stroke.shape.views(textures);
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("brush smudge encoder"),
+        });
+        for pass in passes {
+            let (offset, tile, pipeline, workgroups) = match pass {
+                Pass::Sample { min, max } => {
+                    tiles.copy_region_to_texture(&mut encoder, stroke.target, min, max, &patch);
+                    continue;
+                }
+                Pass::Pickup { offset, tile } => (offset, tile, &self.pickup, UVec2::ONE),
+                Pass::Apply { offset, tile, size } => (offset, tile, &self.apply, (size + 15) / 16),
+            };
+
+            let target = tiles.get_tile_mut(stroke.target, tile);
+            let mask = stroke
+                .selection
+                .as_ref()
+                .map(|(masks, id)| masks.get_tile(*id, tile));
+            let mask_view = mask.as_ref().map_or(&patch_view, |m| &*m.view);
+            let bind_group = device.create_bind_group(&BindGroupDescriptor {
+                label: Some("brush smudge bind group"),
+                layout: &self.layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::TextureView(&patch_view),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: uniform_binding.clone(),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: carried.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: BindingResource::TextureView(&target.view),
+                    },
+                    BindGroupEntry {
+                        binding: 4,
+                        resource: BindingResource::TextureView(&tip_view),
+                    },
+                    BindGroupEntry {
+                        binding: 5,
+                        resource: BindingResource::TextureView(&texture_view),
+                    },
+                    BindGroupEntry {
+                        binding: 6,
+                        resource: BindingResource::TextureView(mask_view),
+                    },
+                ],
+            });
+
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("brush smudge pass"),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[offset]);
+            pass.dispatch_workgroups(workgroups.x, workgroups.y, 1);
+        }
+        tiles.queue().submit([encoder.finish()]);
+    }
+}
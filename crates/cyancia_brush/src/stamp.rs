@@ -0,0 +1,569 @@
+//! Stamps dabs into the tiles of a layer on the GPU.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::Arc,
+};
+
+use cyancia_id::Id;
+use cyancia_image::{
+    layer::Layer,
+    tile::{GpuTileStorage, Tile},
+};
+use cyancia_render::buffer::DynamicBuffer;
+use cyancia_utils::{global_instance::GlobalInstance, include_shader};
+use encase::{ShaderType, StorageBuffer};
+use glam::{IVec2, UVec2, Vec2, Vec3};
+use parking_lot::RwLock;
+use wgpu::{
+    BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+    BindGroupLayoutEntry, BindingResource, BindingType, BufferBindingType, BufferUsages,
+    CommandEncoder, CommandEncoderDescriptor, ComputePassDescriptor, ComputePipeline,
+    ComputePipelineDescriptor, Device, Extent3d, Origin3d, PipelineLayoutDescriptor, Queue,
+    ShaderModuleDescriptor, ShaderSource, ShaderStages, StorageTextureAccess,
+    TexelCopyBufferLayout, TexelCopyTextureInfo, Texture, TextureAspect, TextureDescriptor,
+    TextureDimension, TextureFormat, TextureSampleType, TextureUsages, TextureView,
+    TextureViewDimension,
+    util::{BufferInitDescriptor, DeviceExt},
+};
+
+use crate::{
+    BrushTip, PaperTexture,
+    bitmap::BrushImage,
+    dab::{Dab, StrokeParams},
+};
+
+#[derive(Debug, Clone, Copy, ShaderType)]
+struct GpuDab {
+    center: Vec2,
+    radius: f32,
+    hardness: f32,
+    color: Vec3,
+    flow: f32,
+    opacity: f32,
+    angle: f32,
+    scale: Vec2,
+}
+
+#[derive(Debug, Clone, Copy, ShaderType)]
+struct TileUniform {
+    origin: IVec2,
+    first_dab: u32,
+    dab_count: u32,
+    tip: u32,
+    opacity: f32,
+    preserve_alpha: u32,
+    tip_image: u32,
+    texture: u32,
+    texture_scale: f32,
+    texture_contrast: f32,
+    texture_blend: u32,
+    erase: u32,
+    masked: u32,
+}
+
+/// A stroke being painted on the GPU. The stroke buffer and the copy of the
+/// layer before the stroke live in the tile storage under layers of their own,
+/// call [`GpuStroke::finish`] to release them.
+#[derive(Debug)]
+pub struct GpuStroke {
+    target: Id<Layer>,
+    base: Id<Layer>,
+    buffer: Id<Layer>,
+    image_size: UVec2,
+    shape: GpuShape,
+    params: StrokeParams,
+    /// Selection mask limiting where the stroke lands.
+    selection: Option<(Arc<GpuTileStorage>, Id<Layer>)>,
+    /// Tiles whose original content was copied into `base`.
+    touched: HashSet<UVec2>,
+}
+
+impl GpuStroke {
+    pub fn new(
+        target: Id<Layer>,
+        image_size: UVec2,
+        shape: GpuShape,
+        params: StrokeParams,
+    ) -> Self {
+        Self {
+            target,
+            base: Id::random(),
+            buffer: Id::random(),
+            image_size,
+            shape,
+            params,
+            selection: None,
+            touched: HashSet::new(),
+        }
+    }
+
+    /// Only paints where the mask `id` in `masks` is selected.
+    pub fn with_selection(mut self, masks: Arc<GpuTileStorage>, id: Id<Layer>) -> Self {
+        self.selection = Some((masks, id));
+        self
+    }
+
+    pub fn target(&self) -> Id<Layer> {
+        self.target
+    }
+
+    pub(crate) fn selection_tile(&self, index: UVec2) -> Option<Tile> {
+        self.selection
+            .as_ref()
+            .map(|(masks, id)| masks.get_tile(*id, index))
+    }
+
+    /// Tiles of the image covered by the dabs, sorted by row.
+    pub fn tiles_of(&self, dabs: &[Dab]) -> Vec<UVec2> {
+        covered_tiles(self.image_size, dabs)
+    }
+
+    /// Releases the stroke buffer and the copy of the layer.
+    pub fn finish(self, tiles: &GpuTileStorage) {
+        tiles.free_layer(self.base);
+        tiles.free_layer(self.buffer);
+    }
+}
+
+/// The tip of a stroke on the GPU along with the images it samples.
+#[derive(Debug, Clone, Default)]
+pub struct GpuShape {
+    pub tip: BrushTip,
+    pub tip_image: Option<(Id<BrushImage>, Arc<BrushImage>)>,
+    pub texture: Option<(Id<BrushImage>, Arc<BrushImage>, PaperTexture)>,
+}
+
+impl GpuShape {
+    pub(crate) fn upload(&self, tiles: &GpuTileStorage, textures: &BrushTextures) {
+        if let Some((id, image)) = &self.tip_image {
+            textures.upload(tiles.device(), tiles.queue(), *id, image);
+        }
+        if let Some((id, image, _)) = &self.texture {
+            textures.upload(tiles.device(), tiles.queue(), *id, image);
+        }
+    }
+
+    /// Views of the tip image and the paper texture.
+    pub(crate) fn views(&self, textures: &BrushTextures) -> (Arc<TextureView>, Arc<TextureView>) {
+        (
+            textures.view(self.tip_image.as_ref().map(|(id, _)| *id)),
+            textures.view(self.texture.as_ref().map(|(id, _, _)| *id)),
+        )
+    }
+
+    pub(crate) fn texture(&self) -> Option<&PaperTexture> {
+        self.texture.as_ref().map(|(_, _, texture)| texture)
+    }
+}
+
+/// Tiles of the image covered by the dabs, sorted by row.
+pub(crate) fn covered_tiles(image_size: UVec2, dabs: &[Dab]) -> Vec<UVec2> {
+    let mut indices = BTreeSet::new();
+    for dab in dabs {
+        let Some((min, max)) = clip(dab, image_size) else {
+            continue;
+        };
+        let (min, max) = (
+            min / GpuTileStorage::TILE_SIZE,
+            (max - 1) / GpuTileStorage::TILE_SIZE,
+        );
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                indices.insert((y, x));
+            }
+        }
+    }
+    indices.into_iter().map(|(y, x)| UVec2::new(x, y)).collect()
+}
+
+/// Pixels of the image the dab may cover, or `None` if it lies outside.
+pub(crate) fn clip(dab: &Dab, image_size: UVec2) -> Option<(UVec2, UVec2)> {
+    let (min, max) = dab.bounds();
+    let min = min.max(IVec2::ZERO);
+    let max = max.min(image_size.as_ivec2());
+    min.cmplt(max)
+        .all()
+        .then(|| (min.as_uvec2(), max.as_uvec2()))
+}
+
+/// Tip images and paper textures of every brush tool, uploaded once on first
+/// use.
+pub static GPU_BRUSH_TEXTURES: GlobalInstance<BrushTextures> = GlobalInstance::new();
+
+/// Tip images and paper textures on the GPU, uploaded on first use and shared
+/// by the stampers.
+#[derive(Debug)]
+pub struct BrushTextures {
+    images: RwLock<HashMap<Id<BrushImage>, Arc<TextureView>>>,
+    /// Bound in place of a missing tip image or texture.
+    blank: Arc<TextureView>,
+}
+
+impl BrushTextures {
+    pub fn new(device: &Device) -> Self {
+        let blank = device
+            .create_texture(&Self::descriptor(UVec2::ONE))
+            .create_view(&Default::default());
+        Self {
+            images: RwLock::new(HashMap::new()),
+            blank: Arc::new(blank),
+        }
+    }
+
+    fn descriptor(size: UVec2) -> TextureDescriptor<'static> {
+        TextureDescriptor {
+            label: Some("brush image"),
+            size: Extent3d {
+                width: size.x.max(1),
+                height: size.y.max(1),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R8Unorm,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            view_formats: &[],
+        }
+    }
+
+    pub fn upload(&self, device: &Device, queue: &Queue, id: Id<BrushImage>, image: &BrushImage) {
+        if self.images.read().contains_key(&id) {
+            return;
+        }
+
+        let mut images = self.images.write();
+        // Another stamper may have uploaded it in the meantime.
+        if images.contains_key(&id) {
+            return;
+        }
+        let size = UVec2::new(image.image.width(), image.image.height());
+        let texture = device.create_texture(&Self::descriptor(size));
+        queue.write_texture(
+            texture.as_image_copy(),
+            image.image.as_raw(),
+            TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(size.x),
+                rows_per_image: Some(size.y),
+            },
+            Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+        );
+        images.insert(id, Arc::new(texture.create_view(&Default::default())));
+    }
+
+    /// The uploaded image, or a blank texture.
+    pub fn view(&self, id: Option<Id<BrushImage>>) -> Arc<TextureView> {
+        id.and_then(|id| self.images.read().get(&id).cloned())
+            .unwrap_or_else(|| self.blank.clone())
+    }
+}
+
+pub(crate) fn texture_entry(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Texture {
+            sample_type: TextureSampleType::Float { filterable: false },
+            view_dimension: TextureViewDimension::D2,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+pub(crate) fn storage_texture_entry(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::StorageTexture {
+            access: StorageTextureAccess::WriteOnly,
+            format: GpuTileStorage::TILE_FORMAT,
+            view_dimension: TextureViewDimension::D2,
+        },
+        count: None,
+    }
+}
+
+#[derive(Debug)]
+pub struct DabStamper {
+    pipeline: ComputePipeline,
+    layout: BindGroupLayout,
+    uniforms: DynamicBuffer<TileUniform>,
+    /// Holds the stroke buffer of a tile while the shader writes its new content.
+    scratch: Texture,
+}
+
+impl DabStamper {
+    pub fn new(device: &Device) -> Self {
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("brush dab layout"),
+            entries: &[
+                // layer before the stroke
+                texture_entry(0),
+                // stroke buffer
+                texture_entry(1),
+                // dabs
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(<GpuDab as ShaderType>::min_size()),
+                    },
+                    count: None,
+                },
+                // tile uniform
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: Some(<TileUniform as ShaderType>::min_size()),
+                    },
+                    count: None,
+                },
+                // new stroke buffer
+                storage_texture_entry(4),
+                // layer
+                storage_texture_entry(5),
+                // tip image
+                texture_entry(6),
+                // paper texture
+                texture_entry(7),
+                // selection mask
+                texture_entry(8),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("brush dab pipeline layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("brush dab shader"),
+            source: ShaderSource::Wgsl(include_shader!("brush_dab.wgsl").into()),
+        });
+
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("brush dab pipeline"),
+            layout: Some(&pipeline_layout),
+            entry_point: Some("main"),
+            module: &shader_module,
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        let scratch = device.create_texture(&TextureDescriptor {
+            label: Some("brush scratch tile"),
+            size: Extent3d {
+                width: GpuTileStorage::TILE_SIZE,
+                height: GpuTileStorage::TILE_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: GpuTileStorage::TILE_FORMAT,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+
+        Self {
+            pipeline,
+            layout,
+            uniforms: DynamicBuffer::new(Some("brush tile uniform buffer"), BufferUsages::UNIFORM),
+            scratch,
+        }
+    }
+
+    /// Paints the dabs, in order, into the target layer of the stroke. Tiles are
+    /// allocated as the dabs reach them.
+    pub fn stamp(
+        &mut self,
+        tiles: &GpuTileStorage,
+        textures: &BrushTextures,
+        stroke: &mut GpuStroke,
+        dabs: &[Dab],
+    ) {
+        let indices = stroke.tiles_of(dabs);
+        if indices.is_empty() {
+            return;
+        }
+
+        let fresh = indices
+            .iter()
+            .copied()
+            .filter(|index| stroke.touched.insert(*index))
+            .collect::<Vec<_>>();
+        tiles.copy_tiles(stroke.target, stroke.base, &fresh);
+
+        let texture = stroke.shape.texture();
+        let mut gpu_dabs = Vec::new();
+        let mut offsets = Vec::with_capacity(indices.len());
+        self.uniforms.clear();
+        for index in &indices {
+            let tile_min = *index * GpuTileStorage::TILE_SIZE;
+            let tile_max = tile_min + GpuTileStorage::TILE_SIZE;
+            let first_dab = gpu_dabs.len() as u32;
+            gpu_dabs.extend(
+                dabs.iter()
+                    .filter(|dab| {
+                        clip(dab, stroke.image_size).is_some_and(|(min, max)| {
+                            min.cmplt(tile_max).all() && max.cmpgt(tile_min).all()
+                        })
+                    })
+                    .map(|dab| GpuDab {
+                        center: dab.center,
+                        radius: dab.radius,
+                        hardness: dab.hardness,
+                        color: dab.color,
+                        flow: dab.flow,
+                        opacity: dab.opacity,
+                        angle: dab.angle,
+                        scale: dab.scale,
+                    }),
+            );
+
+            let offset = self.uniforms.push(&TileUniform {
+                origin: tile_min.as_ivec2(),
+                first_dab,
+                dab_count: gpu_dabs.len() as u32 - first_dab,
+                tip: stroke.shape.tip.shader_id(),
+                opacity: stroke.params.opacity,
+                preserve_alpha: stroke.params.preserve_alpha as u32,
+                tip_image: stroke.shape.tip_image.is_some() as u32,
+                texture: texture.is_some() as u32,
+                texture_scale: texture.map_or(1.0, |t| t.scale),
+                texture_contrast: texture.map_or(1.0, |t| t.contrast),
+                texture_blend: texture.map_or(0, |t| t.blend.shader_id()),
+                erase: stroke.params.erase as u32,
+                masked: stroke.selection.is_some() as u32,
+            });
+            offsets.push(offset.expect("Writing into a vector never fails.") as u32);
+        }
+
+        let device = tiles.device();
+        stroke.shape.upload(tiles, textures);
+        let (tip_view, texture_view) = stroke.shape.views(textures);
+
+        self.uniforms.write_buffer(device);
+        let Some(uniform_binding) = self.uniforms.binding() else {
+            return;
+        };
+
+        let mut dab_data = StorageBuffer::new(Vec::new());
+        dab_data
+            .write(&gpu_dabs)
+            .expect("Writing into a vector never fails.");
+        let dab_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("brush dab buffer"),
+            contents: dab_data.as_ref(),
+            usage: BufferUsages::STORAGE,
+        });
+        let scratch_view = self.scratch.create_view(&Default::default());
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("brush dab encoder"),
+        });
+        for (index, offset) in indices.into_iter().zip(offsets) {
+            let base = tiles.get_tile(stroke.base, index);
+            let buffer = tiles.get_tile_mut(stroke.buffer, index);
+            let target = tiles.get_tile_mut(stroke.target, index);
+            // Unmasked strokes never read the mask, any float texture does.
+            let mask = stroke.selection_tile(index).unwrap_or_else(|| base.clone());
+            self.copy_to_scratch(&mut encoder, &buffer);
+
+            let bind_group = device.create_bind_group(&BindGroupDescriptor {
+                label: Some("brush dab bind group"),
+                layout: &self.layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::TextureView(&base.view),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::TextureView(&scratch_view),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: dab_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: uniform_binding.clone(),
+                    },
+                    BindGroupEntry {
+                        binding: 4,
+                        resource: BindingResource::TextureView(&buffer.view),
+                    },
+                    BindGroupEntry {
+                        binding: 5,
+                        resource: BindingResource::TextureView(&target.view),
+                    },
+                    BindGroupEntry {
+                        binding: 6,
+                        resource: BindingResource::TextureView(&tip_view),
+                    },
+                    BindGroupEntry {
+                        binding: 7,
+                        resource: BindingResource::TextureView(&texture_view),
+                    },
+                    BindGroupEntry {
+                        binding: 8,
+                        resource: BindingResource::TextureView(&mask.view),
+                    },
+                ],
+            });
+
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("brush dab pass"),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[offset]);
+            pass.dispatch_workgroups(
+                GpuTileStorage::TILE_SIZE.div_ceil(16),
+                GpuTileStorage::TILE_SIZE.div_ceil(16),
+                1,
+            );
+        }
+        tiles.queue().submit([encoder.finish()]);
+    }
+
+    fn copy_to_scratch(&self, encoder: &mut CommandEncoder, tile: &Tile) {
+        encoder.copy_texture_to_texture(
+            TexelCopyTextureInfo {
+                texture: tile.view.texture(),
+                mip_level: 0,
+                origin: Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: tile.id.pile_layer,
+                },
+                aspect: TextureAspect::All,
+            },
+            TexelCopyTextureInfo {
+                texture: &self.scratch,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            Extent3d {
+                width: GpuTileStorage::TILE_SIZE,
+                height: GpuTileStorage::TILE_SIZE,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use std::{f32::consts::TAU, time::Instant};
+
+use glam::{Vec2, Vec3};
+
+use crate::{
+    BrushSettings, TipRotation,
+    dab::Dab,
+    dynamics::{SensorSample, StrokeRng},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathStep {
+    pub position: Vec2,
+    /// Where the step lies on the segment, 0 at its start and 1 at its end.
+    pub t: f32,
+}
+
+/// Places dabs along the pointer path at even distances, carrying the leftover
+/// distance over to the next segment so spacing doesn't depend on event rate.
+#[derive(Debug, Default, Clone)]
+pub struct PathInterpolator {
+    last: Option<Vec2>,
+    /// Distance traveled since the last dab.
+    traveled: f32,
+}
+
+impl PathInterpolator {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Moves the pointer to `to`. The first position of a path always gets a dab.
+    pub fn advance(&mut self, to: Vec2, spacing: f32) -> Vec<PathStep> {
+        let Some(from) = self.last.replace(to) else {
+            self.traveled = 0.0;
+            return vec![PathStep {
+                position: to,
+                t: 1.0,
+            }];
+        };
+
+        let length = from.distance(to);
+        if length <= 0.0 {
+            return Vec::new();
+        }
+
+        let spacing = spacing.max(1e-3);
+        let mut steps = Vec::new();
+        let mut distance = spacing - self.traveled;
+        while distance <= length {
+            let t = distance / length;
+            steps.push(PathStep {
+                position: from.lerp(to, t),
+                t,
+            });
+            distance += spacing;
+        }
+        self.traveled = length - (distance - spacing);
+        steps
+    }
+}
+
+/// A pointer sample in image pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StrokePoint {
+    pub position: Vec2,
+    pub pressure: f32,
+    /// Pen tilt towards x and y in degrees.
+    pub tilt: Vec2,
+    /// Barrel rotation of the pen in degrees.
+    pub rotation: f32,
+    pub timestamp: Instant,
+}
+
+impl StrokePoint {
+    /// A point without stylus data, pressing fully.
+    pub fn new(position: Vec2, timestamp: Instant) -> Self {
+        Self {
+            position,
+            pressure: 1.0,
+            tilt: Vec2::ZERO,
+            rotation: 0.0,
+            timestamp,
+        }
+    }
+}
+
+/// Turns pointer samples into dabs, evaluating the brush dynamics for each.
+#[derive(Debug, Clone)]
+pub struct Stroke {
+    settings: BrushSettings,
+    color: Vec3,
+    path: PathInterpolator,
+    rng: StrokeRng,
+    last: Option<StrokePoint>,
+    /// Smoothed pointer speed in pixels per second.
+    speed: f32,
+    /// Pixels traveled up to the last point.
+    distance: f32,
+    dab_count: u32,
+    /// Distance to the next dab, following the size of the last one.
+    spacing: f32,
+    /// Direction of the last move in radians.
+    direction: f32,
+}
+
+impl Stroke {
+    pub fn new(settings: BrushSettings, color: Vec3, seed: u64) -> Self {
+        let spacing = settings.spacing_px();
+        Self {
+            settings,
+            color,
+            path: PathInterpolator::default(),
+            rng: StrokeRng::new(seed),
+            last: None,
+            speed: 0.0,
+            distance: 0.0,
+            dab_count: 0,
+            spacing,
+            direction: 0.0,
+        }
+    }
+
+    pub fn settings(&self) -> &BrushSettings {
+        &self.settings
+    }
+
+    pub fn dab_count(&self) -> u32 {
+        self.dab_count
+    }
+
+    pub fn move_to(&mut self, point: StrokePoint) -> Vec<Dab> {
+        let previous = self.last.replace(point).unwrap_or(point);
+        let length = previous.position.distance(point.position);
+        let seconds = point
+            .timestamp
+            .saturating_duration_since(previous.timestamp)
+            .as_secs_f32();
+        if seconds > 0.0 {
+            // Events don't arrive at a steady rate, raw speed jumps around.
+            self.speed += (length / seconds - self.speed) * 0.5;
+        }
+
+        if length > 0.0 {
+            let delta = point.position - previous.position;
+            self.direction = delta.y.atan2(delta.x);
+        }
+
+        let start_distance = self.distance;
+        self.distance += length;
+
+        let mut dabs = Vec::new();
+        for step in self.path.advance(point.position, self.spacing) {
+            let sample = SensorSample {
+                pressure: previous.pressure + (point.pressure - previous.pressure) * step.t,
+                speed: self.speed,
+                tilt: previous.tilt.lerp(point.tilt, step.t),
+                distance: start_distance + length * step.t,
+                dab_index: self.dab_count,
+            };
+            let rotation = match self.settings.rotation {
+                TipRotation::Fixed => 0.0,
+                TipRotation::Direction => self.direction,
+                TipRotation::Pen => {
+                    // The shorter way around, 350° to 10° shouldn't spin backwards.
+                    let delta =
+                        (point.rotation - previous.rotation + 180.0).rem_euclid(360.0) - 180.0;
+                    (previous.rotation + delta * step.t).to_radians()
+                }
+            };
+            let dab = self.dab(step.position, rotation, &sample);
+            self.dab_count += 1;
+            self.spacing =
+                (dab.radius * 2.0 * dab.scale.abs().max_element() * self.settings.spacing)
+                    .max(BrushSettings::MIN_SPACING);
+            dabs.push(dab);
+        }
+        dabs
+    }
+
+    /// `rotation` turns the tip on top of the angle of the brush.
+    fn dab(&mut self, position: Vec2, rotation: f32, sample: &SensorSample) -> Dab {
+        let Self {
+            settings,
+            rng,
+            color,
+            ..
+        } = self;
+        let dynamics = &settings.dynamics;
+
+        let size = settings.size * dynamics.size.evaluate(sample, rng);
+        let flow = settings.flow * dynamics.flow.evaluate(sample, rng);
+        let opacity = dynamics.opacity.evaluate(sample, rng);
+        let hardness = settings.hardness * dynamics.hardness.evaluate(sample, rng);
+
+        let mut angle = settings.angle + rotation;
+        if !dynamics.angle.is_constant() {
+            angle += dynamics.angle.evaluate(sample, rng) * TAU;
+        }
+
+        let mut scale = Vec2::new(settings.scale_x, settings.scale_y).max(Vec2::splat(1e-3));
+        if settings.random_flip_x && rng.next_f32() < 0.5 {
+            scale.x = -scale.x;
+        }
+        if settings.random_flip_y && rng.next_f32() < 0.5 {
+            scale.y = -scale.y;
+        }
+
+        let mut center = position;
+        let scatter = settings.scatter * dynamics.scatter.evaluate(sample, rng);
+        if scatter > 0.0 {
+            let direction = Vec2::from_angle(rng.next_f32() * TAU);
+            center += direction * rng.next_f32() * scatter * size;
+        }
+
+        let mut color = *color;
+        let jitter = settings.color_jitter * dynamics.color_jitter.evaluate(sample, rng);
+        if jitter > 0.0 {
+            color = shift_hue(color, (rng.next_f32() * 2.0 - 1.0) * jitter * 0.5);
+        }
+
+        Dab {
+            center,
+            radius: size * 0.5,
+            hardness,
+            flow,
+            opacity,
+            angle,
+            scale,
+            color,
+        }
+    }
+}
+
+/// Rotates the hue of a color by a fraction of a turn.
+fn shift_hue(rgb: Vec3, turns: f32) -> Vec3 {
+    let max = rgb.max_element();
+    let min = rgb.min_element();
+    let chroma = max - min;
+    if chroma <= 0.0 {
+        return rgb;
+    }
+
+    let hue = if max == rgb.x {
+        ((rgb.y - rgb.z) / chroma).rem_euclid(6.0)
+    } else if max == rgb.y {
+        (rgb.z - rgb.x) / chroma + 2.0
+    } else {
+        (rgb.x - rgb.y) / chroma + 4.0
+    };
+    let hue = (hue + turns * 6.0).rem_euclid(6.0);
+
+    let x = chroma * (1.0 - (hue.rem_euclid(2.0) - 1.0).abs());
+    let rgb = match hue as u32 {
+        0 => Vec3::new(chroma, x, 0.0),
+        1 => Vec3::new(x, chroma, 0.0),
+        2 => Vec3::new(0.0, chroma, x),
+        3 => Vec3::new(0.0, x, chroma),
+        4 => Vec3::new(x, 0.0, chroma),
+        _ => Vec3::new(chroma, 0.0, x),
+    };
+    rgb + min
+}
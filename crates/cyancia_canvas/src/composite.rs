@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+
+use cyancia_id::Id;
+use cyancia_image::{
+    CImage,
+    blend::{BlendMode, GroupIsolation},
+    layer::Layer,
+};
+use cyancia_render::buffer::DynamicBuffer;
+use cyancia_utils::include_shader;
+use encase::ShaderType;
+use wgpu::{
+    BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+    BindGroupLayoutEntry, BindingType, BufferBindingType, BufferUsages, CommandEncoder,
+    ComputePassDescriptor, ComputePipeline, ComputePipelineDescriptor, Device,
+    PipelineLayoutDescriptor, ShaderModuleDescriptor, ShaderSource, ShaderStages,
+    StorageTextureAccess, TextureFormat, TextureSampleType, TextureView, TextureViewDimension,
+};
+
+#[derive(Debug, Clone, Copy, ShaderType)]
+pub struct BlendUniform {
+    pub mode: u32,
+    pub opacity: f32,
+}
+
+/// A single operation of the compositing process. Slots are indices into the
+/// intermediate buffers owned by the renderer.
+#[derive(Debug, Clone)]
+pub enum CompositeStep {
+    Clear {
+        target: usize,
+    },
+    DrawLayer {
+        layer: Id<Layer>,
+        target: usize,
+    },
+    Blend {
+        backdrop: usize,
+        source: usize,
+        target: usize,
+        uniform_offset: u32,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct CompositePlan {
+    pub steps: Vec<CompositeStep>,
+    pub slot_count: usize,
+    pub output: usize,
+}
+
+impl CompositePlan {
+    /// Plans the image, drawing the layers in `previews` in place of the ones
+    /// they are keyed by.
+    pub fn new(
+        image: &CImage,
+        previews: &HashMap<Id<Layer>, Id<Layer>>,
+        blend_uniforms: &mut DynamicBuffer<BlendUniform>,
+    ) -> Self {
+        let mut planner = CompositePlanner {
+            image,
+            previews,
+            steps: Vec::new(),
+            free_slots: Vec::new(),
+            slot_count: 0,
+            blend_uniforms,
+        };
+
+        let backdrop = planner.allocate();
+        planner
+            .steps
+            .push(CompositeStep::Clear { target: backdrop });
+        let output = planner.composite_children(image.root(), backdrop, 1.0);
+
+        Self {
+            steps: planner.steps,
+            slot_count: planner.slot_count,
+            output,
+        }
+    }
+}
+
+struct CompositePlanner<'a> {
+    image: &'a CImage,
+    previews: &'a HashMap<Id<Layer>, Id<Layer>>,
+    steps: Vec<CompositeStep>,
+    free_slots: Vec<usize>,
+    slot_count: usize,
+    blend_uniforms: &'a mut DynamicBuffer<BlendUniform>,
+}
+
+impl CompositePlanner<'_> {
+    fn allocate(&mut self) -> usize {
+        self.free_slots.pop().unwrap_or_else(|| {
+            self.slot_count += 1;
+            self.slot_count - 1
+        })
+    }
+
+    fn release(&mut self, slot: usize) {
+        self.free_slots.push(slot);
+    }
+
+    fn blend(&mut self, backdrop: usize, source: usize, mode: BlendMode, opacity: f32) -> usize {
+        let target = self.allocate();
+        let uniform_offset = self
+            .blend_uniforms
+            .push(&BlendUniform {
+                mode: mode as u32,
+                opacity,
+            })
+            .unwrap_or_default() as u32;
+        self.steps.push(CompositeStep::Blend {
+            backdrop,
+            source,
+            target,
+            uniform_offset,
+        });
+        self.release(backdrop);
+        self.release(source);
+        target
+    }
+
+    fn composite_children(&mut self, group: &Layer, mut backdrop: usize, opacity: f32) -> usize {
+        for child in group.children() {
+            let Ok(layer) = self.image.layer(*child) else {
+                continue;
+            };
+            if !layer.properties.visible {
+                continue;
+            }
+
+            let layer_opacity = layer.properties.opacity * opacity;
+            match layer.isolation() {
+                None => {
+                    let source = self.allocate();
+                    let drawn = self.previews.get(&layer.id()).copied();
+                    self.steps.push(CompositeStep::DrawLayer {
+                        layer: drawn.unwrap_or(layer.id()),
+                        target: source,
+                    });
+                    backdrop =
+                        self.blend(backdrop, source, layer.properties.blend_mode, layer_opacity);
+                }
+                Some(GroupIsolation::PassThrough) => {
+                    backdrop = self.composite_children(layer, backdrop, layer_opacity);
+                }
+                Some(GroupIsolation::Isolated) => {
+                    let isolated = self.allocate();
+                    self.steps.push(CompositeStep::Clear { target: isolated });
+                    let isolated = self.composite_children(layer, isolated, 1.0);
+                    backdrop = self.blend(
+                        backdrop,
+                        isolated,
+                        layer.properties.blend_mode,
+                        layer_opacity,
+                    );
+                }
+            }
+        }
+
+        backdrop
+    }
+}
+
+#[derive(Debug)]
+pub struct CanvasBlendPipeline {
+    pipeline: ComputePipeline,
+    layout: BindGroupLayout,
+    uniforms: DynamicBuffer<BlendUniform>,
+}
+
+impl CanvasBlendPipeline {
+    pub fn new(device: &Device, format: TextureFormat) -> Self {
+        let texture_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: false },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("canvas blend layout"),
+            entries: &[
+                // backdrop
+                texture_entry(0),
+                // source
+                texture_entry(1),
+                // blend uniform
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: Some(<BlendUniform as ShaderType>::min_size()),
+                    },
+                    count: None,
+                },
+                // output
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::StorageTexture {
+                        access: StorageTextureAccess::WriteOnly,
+                        format,
+                        view_dimension: TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("canvas blend pipeline layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("canvas blend shader"),
+            source: ShaderSource::Wgsl(include_shader!("canvas_blend.wgsl").into()),
+        });
+
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("canvas blend pipeline"),
+            layout: Some(&pipeline_layout),
+            entry_point: Some("main"),
+            module: &shader_module,
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        Self {
+            pipeline,
+            layout,
+            uniforms: DynamicBuffer::new(
+                Some("canvas blend uniform buffer"),
+                BufferUsages::UNIFORM,
+            ),
+        }
+    }
+
+    pub fn prepare(
+        &mut self,
+        device: &Device,
+        image: &CImage,
+        previews: &HashMap<Id<Layer>, Id<Layer>>,
+    ) -> CompositePlan {
+        self.uniforms.clear();
+        let plan = CompositePlan::new(image, previews, &mut self.uniforms);
+        self.uniforms.write_buffer(device);
+        plan
+    }
+
+    pub fn blend(
+        &self,
+        device: &Device,
+        encoder: &mut CommandEncoder,
+        uniform_offset: u32,
+        backdrop: &TextureView,
+        source: &TextureView,
+        target: &TextureView,
+    ) {
+        let Some(uniform_binding) = self.uniforms.binding() else {
+            return;
+        };
+        let target_size = target.texture().size();
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("canvas blend bind group"),
+            layout: &self.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(backdrop),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(source),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: uniform_binding,
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(target),
+                },
+            ],
+        });
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("canvas blend pass"),
+            timestamp_writes: None,
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[uniform_offset]);
+        pass.dispatch_workgroups(
+            target_size.width.div_ceil(16),
+            target_size.height.div_ceil(16),
+            1,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::UVec2;
+
+    use super::*;
+
+    /// Replays the plan on names instead of pixels, each blend written as
+    /// `(backdrop < source)` and a cleared slot as `_`.
+    fn evaluate(image: &CImage, plan: &CompositePlan) -> String {
+        let mut slots = vec![String::new(); plan.slot_count];
+        let mut offsets = Vec::new();
+        for step in &plan.steps {
+            match step {
+                CompositeStep::Clear { target } => slots[*target] = "_".to_string(),
+                CompositeStep::DrawLayer { layer, target } => {
+                    slots[*target] = image.layer(*layer).unwrap().properties.name.clone();
+                }
+                CompositeStep::Blend {
+                    backdrop,
+                    source,
+                    target,
+                    uniform_offset,
+                } => {
+                    assert!(!offsets.contains(uniform_offset));
+                    offsets.push(*uniform_offset);
+                    slots[*target] = format!("({} < {})", slots[*backdrop], slots[*source]);
+                }
+            }
+        }
+        slots[plan.output].clone()
+    }
+
+    fn plan(image: &CImage) -> CompositePlan {
+        let mut uniforms = DynamicBuffer::new(None, BufferUsages::UNIFORM);
+        CompositePlan::new(image, &HashMap::new(), &mut uniforms)
+    }
+
+    /// Layer `a` at the bottom, then group `g` holding `b` and `c`, then `d`.
+    fn image(isolation: GroupIsolation) -> (CImage, HashMap<&'static str, Id<Layer>>) {
+        let mut image = CImage::empty(UVec2::splat(64));
+        let root = image.root_id();
+        let mut ids = HashMap::new();
+        ids.insert("a", image.insert_layer(Layer::paint("a"), root, 0).unwrap());
+        let mut group = Layer::group("g");
+        group.set_isolation(isolation);
+        let g = image.insert_layer(group, root, 1).unwrap();
+        ids.insert("g", g);
+        ids.insert("b", image.insert_layer(Layer::paint("b"), g, 0).unwrap());
+        ids.insert("c", image.insert_layer(Layer::paint("c"), g, 1).unwrap());
+        ids.insert("d", image.insert_layer(Layer::paint("d"), root, 2).unwrap());
+        (image, ids)
+    }
+
+    #[test]
+    fn pass_through_children_blend_onto_backdrop() {
+        let (image, _) = image(GroupIsolation::PassThrough);
+        let plan = plan(&image);
+        assert_eq!(evaluate(&image, &plan), "((((_ < a) < b) < c) < d)");
+        // A backdrop and a layer at a time, plus the blend result.
+        assert_eq!(plan.slot_count, 3);
+    }
+
+    #[test]
+    fn isolated_children_blend_onto_transparency() {
+        let (image, _) = image(GroupIsolation::Isolated);
+        let plan = plan(&image);
+        assert_eq!(evaluate(&image, &plan), "(((_ < a) < ((_ < b) < c)) < d)");
+    }
+
+    #[test]
+    fn hidden_layers_and_groups_are_skipped() {
+        let (mut image, ids) = image(GroupIsolation::Isolated);
+        image.layer_mut(ids["b"]).unwrap().properties.visible = false;
+        assert_eq!(evaluate(&image, &plan(&image)), "(((_ < a) < (_ < c)) < d)");
+
+        image.layer_mut(ids["g"]).unwrap().properties.visible = false;
+        assert_eq!(evaluate(&image, &plan(&image)), "((_ < a) < d)");
+    }
+
+    #[test]
+    fn empty_image_is_cleared_backdrop() {
+        let image = CImage::empty(UVec2::splat(64));
+        let plan = plan(&image);
+        assert_eq!(evaluate(&image, &plan), "_");
+        assert_eq!(plan.slot_count, 1);
+    }
+
+    #[test]
+    fn previews_are_drawn_in_place_of_their_layer() {
+        let (image, ids) = image(GroupIsolation::PassThrough);
+        let preview = Id::random();
+        let previews = HashMap::from([(ids["b"], preview)]);
+        let mut uniforms = DynamicBuffer::new(None, BufferUsages::UNIFORM);
+        let plan = CompositePlan::new(&image, &previews, &mut uniforms);
+
+        let drawn = plan
+            .steps
+            .iter()
+            .filter_map(|step| match step {
+                CompositeStep::DrawLayer { layer, .. } => Some(*layer),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(drawn, [ids["a"], preview, ids["c"], ids["d"]]);
+    }
+}
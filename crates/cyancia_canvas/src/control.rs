@@ -7,6 +7,11 @@ pub struct CanvasTransform {
 }
 
 impl CanvasTransform {
+    /// Maps a position on the widget back to image pixel coordinates.
+    pub fn widget_to_pixel(&self, position: Vec2) -> Vec2 {
+        self.pixel_to_widget.inverse().transform_point2(position)
+    }
+
     pub fn translate(&mut self, delta: Vec2) {
         let translation = Mat3::from_translation(delta);
         self.pixel_to_widget = translation * self.pixel_to_widget;
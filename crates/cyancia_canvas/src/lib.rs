@@ -1,17 +1,28 @@
-use std::sync::Arc;
+use std::{collections::HashMap, path::PathBuf};
 
-use cyancia_image::CImage;
-use parking_lot::RwLock;
+use cyancia_id::Id;
+use cyancia_image::{CImage, history::History, layer::Layer};
+use parking_lot::{Mutex, RwLock};
 
-use crate::control::CanvasTransform;
+use crate::{control::CanvasTransform, overlay::CanvasOverlay};
 
+pub mod composite;
 pub mod control;
+pub mod overlay;
 pub mod render;
 pub mod resource;
+pub mod selection;
 pub mod widget;
 
 #[derive(Debug)]
 pub struct CCanvas {
-    pub image: Arc<CImage>,
+    pub image: RwLock<CImage>,
     pub transform: RwLock<CanvasTransform>,
+    /// Where the document was last opened from or saved to, in the native format.
+    pub path: RwLock<Option<PathBuf>>,
+    pub history: Mutex<History>,
+    pub overlay: RwLock<CanvasOverlay>,
+    /// Layers drawn in place of others, keyed by the layer they stand in for.
+    /// Tools preview changes on them without touching the layer itself.
+    pub previews: RwLock<HashMap<Id<Layer>, Id<Layer>>>,
 }
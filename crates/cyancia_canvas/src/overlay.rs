@@ -0,0 +1,22 @@
+use glam::Vec2;
+
+/// Guides a tool draws over the image, like the frame and handles of a
+/// transform. Positions are in image pixels, the widget maps them to the view.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CanvasOverlay {
+    pub lines: Vec<(Vec2, Vec2)>,
+    pub handles: Vec<Vec2>,
+    pub pivot: Option<Vec2>,
+}
+
+impl CanvasOverlay {
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty() && self.handles.is_empty() && self.pivot.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.handles.clear();
+        self.pivot = None;
+    }
+}
@@ -6,7 +6,10 @@ use cyancia_image::{
     tile::{GpuTileStorage, TileId},
 };
 use cyancia_math::iced_rect::{RectangleConversion, RectangleTransform};
-use cyancia_render::{buffer::DynamicBuffer, resources::{FULLSCREEN_VERTEX, GLOBAL_SAMPLERS}};
+use cyancia_render::{
+    buffer::DynamicBuffer,
+    resources::{FULLSCREEN_VERTEX, GLOBAL_SAMPLERS},
+};
 use cyancia_utils::include_shader;
 use encase::ShaderType;
 use glam::{Mat3, UVec2};
@@ -25,13 +28,24 @@ use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
 };
 
-use crate::CCanvas;
+use crate::{
+    CCanvas,
+    composite::{CanvasBlendPipeline, CompositePlan, CompositeStep},
+    selection::CanvasSelectionPipeline,
+};
 
 #[derive(Debug)]
 pub struct CanvasRenderer {
-    buffer: Option<Arc<TextureView>>,
+    buffers: Vec<Arc<TextureView>>,
+    buffer_size: UVec2,
+    plan: Option<CompositePlan>,
+    /// The selection mask in widget space, for the marching ants.
+    selection_buffer: Option<Arc<TextureView>>,
+    selection: Option<Id<Layer>>,
     render_pipeline: CanvasRenderPipeline,
+    blend_pipeline: CanvasBlendPipeline,
     present_pipeline: CanvasPresentPipeline,
+    selection_pipeline: CanvasSelectionPipeline,
     device: Arc<Device>,
 }
 
@@ -43,24 +57,46 @@ impl shader::Pipeline for CanvasRenderer {
         Self: Sized,
     {
         Self {
-            buffer: None,
+            buffers: Vec::new(),
+            buffer_size: UVec2::ZERO,
+            plan: None,
+            selection_buffer: None,
+            selection: None,
             render_pipeline: CanvasRenderPipeline::new(&device, GpuTileStorage::TILE_FORMAT),
+            blend_pipeline: CanvasBlendPipeline::new(&device, GpuTileStorage::TILE_FORMAT),
             present_pipeline: CanvasPresentPipeline::new(&device, format),
+            selection_pipeline: CanvasSelectionPipeline::new(&device, format),
             device: device.clone().into(),
         }
     }
 }
 
 impl CanvasRenderer {
-    pub fn resize_buffer(&mut self, size: UVec2) {
-        if let Some(buffer) = &self.buffer {
-            if buffer.texture().width() == size.x && buffer.texture().height() == size.y {
-                return;
-            }
+    pub fn resize_buffers(&mut self, size: UVec2, count: usize) {
+        if self.buffer_size != size {
+            self.buffers.clear();
+            self.selection_buffer = None;
+            self.buffer_size = size;
         }
 
+        while self.buffers.len() < count {
+            let texture_view = self.create_buffer(size, "canvas render buffer");
+            self.buffers.push(texture_view);
+        }
+    }
+
+    pub fn selection_buffer(&mut self) -> Arc<TextureView> {
+        if let Some(buffer) = &self.selection_buffer {
+            return buffer.clone();
+        }
+        let buffer = self.create_buffer(self.buffer_size, "canvas selection buffer");
+        self.selection_buffer = Some(buffer.clone());
+        buffer
+    }
+
+    fn create_buffer(&self, size: UVec2, label: &'static str) -> Arc<TextureView> {
         let texture = self.device.create_texture(&TextureDescriptor {
-            label: Some("canvas render buffer"),
+            label: Some(label),
             size: Extent3d {
                 width: size.x,
                 height: size.y,
@@ -76,9 +112,7 @@ impl CanvasRenderer {
             view_formats: &[],
         });
 
-        let texture_view = texture.create_view(&TextureViewDescriptor::default());
-
-        self.buffer = Some(Arc::new(texture_view));
+        Arc::new(texture.create_view(&TextureViewDescriptor::default()))
     }
 }
 
@@ -86,6 +120,7 @@ impl CanvasRenderer {
 pub struct CanvasPrimitive {
     pub canvas: Arc<CCanvas>,
     pub tile_storage: Arc<GpuTileStorage>,
+    pub mask_storage: Arc<GpuTileStorage>,
 }
 
 impl shader::Primitive for CanvasPrimitive {
@@ -100,16 +135,38 @@ impl shader::Primitive for CanvasPrimitive {
         viewport: &shader::Viewport,
     ) {
         let size = UVec2::new(bounds.width as u32, bounds.height as u32);
-        renderer.resize_buffer(size);
         let transform = self.canvas.transform.read();
+        let image = self.canvas.image.read();
+        let image_size = image.size();
+
+        // Tiles drawn in the last frame are still marked as in use at this point.
+        self.tile_storage.maintain();
+        self.tile_storage.advance_frame();
+        self.tile_storage.request_empty_tile_sweep();
+        self.mask_storage.maintain();
+        self.mask_storage.advance_frame();
+
+        let previews = self.canvas.previews.read();
+        let plan = renderer
+            .blend_pipeline
+            .prepare(&renderer.device, &image, &previews);
+        renderer.resize_buffers(size, plan.slot_count);
+        renderer.plan = Some(plan);
+
+        let selection = image.selection();
+        renderer.selection = selection.is_active().then(|| selection.id());
+        if renderer.selection.is_some() {
+            renderer.selection_buffer();
+            renderer.selection_pipeline.prepare(&renderer.device);
+        }
 
         renderer.render_pipeline.prepare(
             &renderer.device,
             CanvasUniform {
                 transform: transform.pixel_to_widget,
                 inv_transform: transform.pixel_to_widget.inverse(),
-                size: self.canvas.image.size(),
-                total_tile_count: GpuTileStorage::calc_tile_count(self.canvas.image.size()),
+                size: image_size,
+                total_tile_count: GpuTileStorage::calc_tile_count(image_size),
                 tile_size: GpuTileStorage::TILE_SIZE,
             },
         );
@@ -122,21 +179,102 @@ impl shader::Primitive for CanvasPrimitive {
         target: &TextureView,
         clip_bounds: &Rectangle<u32>,
     ) {
-        let Some(buffer) = &renderer.buffer else {
+        let Some(plan) = &renderer.plan else {
             return;
         };
+        if renderer.buffers.len() < plan.slot_count {
+            return;
+        }
 
-        renderer.render_pipeline.draw(
+        for step in &plan.steps {
+            match step {
+                CompositeStep::Clear { target } => {
+                    encoder.begin_render_pass(&RenderPassDescriptor {
+                        label: Some("canvas clear pass"),
+                        color_attachments: &[Some(RenderPassColorAttachment {
+                            view: &renderer.buffers[*target],
+                            depth_slice: None,
+                            resolve_target: None,
+                            ops: Operations {
+                                load: LoadOp::Clear(Color::TRANSPARENT),
+                                store: StoreOp::Store,
+                            },
+                        })],
+                        ..Default::default()
+                    });
+                }
+                CompositeStep::DrawLayer { layer, target } => {
+                    renderer.render_pipeline.draw(
+                        &renderer.device,
+                        encoder,
+                        &self.tile_storage,
+                        clip_bounds,
+                        &renderer.buffers[*target],
+                        *layer,
+                    );
+                }
+                CompositeStep::Blend {
+                    backdrop,
+                    source,
+                    target,
+                    uniform_offset,
+                } => {
+                    renderer.blend_pipeline.blend(
+                        &renderer.device,
+                        encoder,
+                        *uniform_offset,
+                        &renderer.buffers[*backdrop],
+                        &renderer.buffers[*source],
+                        &renderer.buffers[*target],
+                    );
+                }
+            }
+        }
+
+        let Some(canvas_uniform) = renderer.render_pipeline.uniform_binding() else {
+            return;
+        };
+        renderer.present_pipeline.present(
             &renderer.device,
             encoder,
-            &self.tile_storage,
+            &renderer.buffers[plan.output],
+            canvas_uniform,
+            target,
             clip_bounds,
-            buffer,
-            self.canvas.image.root().id(),
         );
-        renderer
-            .present_pipeline
-            .present(&renderer.device, encoder, buffer, &target, clip_bounds);
+
+        if let Some(selection) = renderer.selection
+            && let Some(buffer) = &renderer.selection_buffer
+        {
+            encoder.begin_render_pass(&RenderPassDescriptor {
+                label: Some("canvas selection clear pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: buffer,
+                    depth_slice: None,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Color::TRANSPARENT),
+                        store: StoreOp::Store,
+                    },
+                })],
+                ..Default::default()
+            });
+            renderer.render_pipeline.draw(
+                &renderer.device,
+                encoder,
+                &self.mask_storage,
+                clip_bounds,
+                buffer,
+                selection,
+            );
+            renderer.selection_pipeline.draw(
+                &renderer.device,
+                encoder,
+                buffer,
+                target,
+                clip_bounds,
+            );
+        }
     }
 }
 
@@ -247,6 +385,10 @@ impl CanvasRenderPipeline {
         }
     }
 
+    pub fn uniform_binding(&self) -> Option<BindingResource<'_>> {
+        self.uniform_buffer.entire_binding()
+    }
+
     pub fn prepare(&mut self, device: &Device, uniform: CanvasUniform) {
         self.uniform_buffer.clear();
         self.uniform_buffer.push(&uniform);
@@ -261,7 +403,7 @@ impl CanvasRenderPipeline {
         tile_storage: &GpuTileStorage,
         clip_bounds: &Rectangle<u32>,
         target: &TextureView,
-        root_layer_id: Id<Layer>,
+        layer_id: Id<Layer>,
     ) {
         let Some(uniform) = &self.uniform else {
             return;
@@ -272,11 +414,8 @@ impl CanvasRenderPipeline {
         let target_size = target.texture().size();
 
         let rect_cs = clip_bounds.transform(&uniform.inv_transform);
-        let visible_tiles = tile_storage.get_tile_views(
-            rect_cs.as_urect(),
-            uniform.total_tile_count,
-            root_layer_id,
-        );
+        let visible_tiles =
+            tile_storage.get_tile_views(rect_cs.as_urect(), uniform.total_tile_count, layer_id);
         for group in visible_tiles {
             // dbg!(group.pile_texture.texture());
             let mut mapper_data =
@@ -373,6 +512,16 @@ impl CanvasPresentPipeline {
                     ty: BindingType::Sampler(SamplerBindingType::Filtering),
                     count: None,
                 },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(<CanvasUniform as ShaderType>::min_size()),
+                    },
+                    count: None,
+                },
             ],
         });
 
@@ -403,10 +552,7 @@ impl CanvasPresentPipeline {
             cache: None,
         });
 
-        Self {
-            pipeline,
-            layout,
-        }
+        Self { pipeline, layout }
     }
 
     pub fn present(
@@ -414,6 +560,7 @@ impl CanvasPresentPipeline {
         device: &Device,
         encoder: &mut CommandEncoder,
         src: &TextureView,
+        canvas_uniform: BindingResource,
         dst: &TextureView,
         clip_bounds: &Rectangle<u32>,
     ) {
@@ -429,6 +576,10 @@ impl CanvasPresentPipeline {
                     binding: 1,
                     resource: BindingResource::Sampler(&GLOBAL_SAMPLERS.linear_clamp()),
                 },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: canvas_uniform,
+                },
             ],
         });
 
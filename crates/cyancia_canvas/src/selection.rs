@@ -0,0 +1,171 @@
+use std::time::Instant;
+
+use cyancia_render::{buffer::DynamicBuffer, resources::FULLSCREEN_VERTEX};
+use cyancia_utils::include_shader;
+use encase::ShaderType;
+use iced_core::Rectangle;
+use wgpu::{
+    BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+    BindGroupLayoutEntry, BindingResource, BindingType, BlendState, BufferBindingType,
+    BufferUsages, ColorTargetState, ColorWrites, CommandEncoder, Device, FragmentState, LoadOp,
+    Operations, PipelineLayoutDescriptor, RenderPassColorAttachment, RenderPassDescriptor,
+    RenderPipeline, RenderPipelineDescriptor, ShaderModuleDescriptor, ShaderSource, ShaderStages,
+    StoreOp, TextureFormat, TextureSampleType, TextureView, TextureViewDimension,
+};
+
+#[derive(Debug, Clone, Copy, ShaderType)]
+pub struct SelectionStyle {
+    pub phase: u32,
+    pub dash: u32,
+}
+
+/// Outlines the selection with marching ants.
+#[derive(Debug)]
+pub struct CanvasSelectionPipeline {
+    pipeline: RenderPipeline,
+    layout: BindGroupLayout,
+    uniform_buffer: DynamicBuffer<SelectionStyle>,
+    epoch: Instant,
+}
+
+impl CanvasSelectionPipeline {
+    /// Length of a dash in widget pixels.
+    pub const DASH: u32 = 4;
+    /// Pixels the ants move per second.
+    pub const SPEED: f32 = 16.0;
+
+    pub fn new(device: &Device, format: TextureFormat) -> Self {
+        let shader = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("canvas selection shader"),
+            source: ShaderSource::Wgsl(include_shader!("canvas_selection.wgsl").into()),
+        });
+
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("canvas selection layout"),
+            entries: &[
+                // mask rendered to widget space
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                // style uniform
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(<SelectionStyle as ShaderType>::min_size()),
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("canvas selection pipeline layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("canvas selection pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: FULLSCREEN_VERTEX.fullscreen_vertex_state(),
+            fragment: Some(FragmentState {
+                module: &shader,
+                entry_point: Some("fragment"),
+                targets: &[Some(ColorTargetState {
+                    format,
+                    blend: Some(BlendState::REPLACE),
+                    write_mask: ColorWrites::ALL,
+                })],
+                compilation_options: Default::default(),
+            }),
+            primitive: Default::default(),
+            depth_stencil: None,
+            multisample: Default::default(),
+            multiview: None,
+            cache: None,
+        });
+
+        Self {
+            pipeline,
+            layout,
+            uniform_buffer: DynamicBuffer::new(
+                Some("canvas selection uniform buffer"),
+                BufferUsages::UNIFORM,
+            ),
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn prepare(&mut self, device: &Device) {
+        let period = Self::DASH * 2;
+        let phase = (self.epoch.elapsed().as_secs_f32() * Self::SPEED) as u32 % period;
+        self.uniform_buffer.clear();
+        self.uniform_buffer.push(&SelectionStyle {
+            phase: period - phase,
+            dash: Self::DASH,
+        });
+        self.uniform_buffer.write_buffer(device);
+    }
+
+    pub fn draw(
+        &self,
+        device: &Device,
+        encoder: &mut CommandEncoder,
+        mask: &TextureView,
+        dst: &TextureView,
+        clip_bounds: &Rectangle<u32>,
+    ) {
+        let Some(uniform_binding) = self.uniform_buffer.entire_binding() else {
+            return;
+        };
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("canvas selection bind group"),
+            layout: &self.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(mask),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: uniform_binding,
+                },
+            ],
+        });
+
+        let mut pass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some("canvas selection pass"),
+            color_attachments: &[Some(RenderPassColorAttachment {
+                view: dst,
+                depth_slice: None,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Load,
+                    store: StoreOp::Store,
+                },
+            })],
+            ..Default::default()
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.set_scissor_rect(
+            clip_bounds.x,
+            clip_bounds.y,
+            clip_bounds.width,
+            clip_bounds.height,
+        );
+        pass.draw(0..3, 0..1);
+    }
+}
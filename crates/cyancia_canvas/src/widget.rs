@@ -5,12 +5,14 @@ use cyancia_image::tile::GpuTileStorage;
 use cyancia_input::action::{ActionCollection, ActionManifest};
 use glam::{UVec2, Vec2};
 use iced_core::{
-    Clipboard, Element, Event, Layout, Length, Rectangle, Shell, Size, Widget,
+    Clipboard, Color, Element, Event, Layout, Length, Point, Rectangle, Shell, Size, Widget,
     keyboard::{self, key},
     layout::{self, Limits},
     mouse, renderer,
+    time::{Duration, Instant},
     widget::{Tree, tree},
 };
+use iced_graphics::geometry::{Frame, Path, Renderer as _, Stroke};
 use iced_wgpu::primitive::Renderer;
 use iced_widget::{renderer::wgpu::primitive, shader::Program};
 
@@ -19,6 +21,70 @@ use crate::{CCanvas, render::CanvasPrimitive};
 pub struct CanvasWidget {
     pub canvas: Arc<CCanvas>,
     pub gpu_tile_storage: Arc<GpuTileStorage>,
+    /// Tiles of the selection masks.
+    pub mask_storage: Arc<GpuTileStorage>,
+}
+
+impl CanvasWidget {
+    /// How often the marching ants move.
+    const ANTS_INTERVAL: Duration = Duration::from_millis(80);
+    /// Side of the square handles of the overlay, in logical pixels.
+    const HANDLE_SIZE: f32 = 8.0;
+
+    /// Draws the guides of the current tool on top of the image, a dark line
+    /// under a light one so they show on any content.
+    fn draw_overlay(&self, renderer: &mut iced_wgpu::Renderer, bounds: Rectangle) {
+        let overlay = self.canvas.overlay.read();
+        if overlay.is_empty() {
+            return;
+        }
+
+        let pixel_to_widget = self.canvas.transform.read().pixel_to_widget;
+        let to_widget = |p: Vec2| {
+            let p = pixel_to_widget.transform_point2(p);
+            Point::new(p.x, p.y)
+        };
+
+        let mut frame = Frame::with_bounds(renderer, bounds);
+        let guides = Path::new(|builder| {
+            for (a, b) in &overlay.lines {
+                builder.move_to(to_widget(*a));
+                builder.line_to(to_widget(*b));
+            }
+            if let Some(pivot) = overlay.pivot {
+                let center = to_widget(pivot);
+                builder.circle(center, Self::HANDLE_SIZE * 0.5);
+                builder.move_to(Point::new(center.x - Self::HANDLE_SIZE, center.y));
+                builder.line_to(Point::new(center.x + Self::HANDLE_SIZE, center.y));
+                builder.move_to(Point::new(center.x, center.y - Self::HANDLE_SIZE));
+                builder.line_to(Point::new(center.x, center.y + Self::HANDLE_SIZE));
+            }
+        });
+        frame.stroke(
+            &guides,
+            Stroke::default().with_width(3.0).with_color(Color::BLACK),
+        );
+        frame.stroke(
+            &guides,
+            Stroke::default().with_width(1.0).with_color(Color::WHITE),
+        );
+
+        for handle in &overlay.handles {
+            let center = to_widget(*handle);
+            let half = Self::HANDLE_SIZE * 0.5;
+            let square = Path::rectangle(
+                Point::new(center.x - half, center.y - half),
+                Size::new(Self::HANDLE_SIZE, Self::HANDLE_SIZE),
+            );
+            frame.fill(&square, Color::WHITE);
+            frame.stroke(
+                &square,
+                Stroke::default().with_width(1.0).with_color(Color::BLACK),
+            );
+        }
+
+        renderer.draw_geometry(frame.into_geometry());
+    }
 }
 
 impl<Message, Theme> Widget<Message, Theme, iced_wgpu::Renderer> for CanvasWidget {
@@ -48,6 +114,13 @@ impl<Message, Theme> Widget<Message, Theme, iced_wgpu::Renderer> for CanvasWidge
     ) {
         self.canvas.transform.write().widget_size =
             Vec2::new(layout.bounds().width, layout.bounds().height);
+
+        if self.canvas.image.read().selection().is_active() {
+            shell.request_redraw_at(Instant::now() + Self::ANTS_INTERVAL);
+        }
+        if !self.canvas.overlay.read().is_empty() {
+            shell.request_redraw();
+        }
     }
 
     fn draw(
@@ -65,8 +138,10 @@ impl<Message, Theme> Widget<Message, Theme, iced_wgpu::Renderer> for CanvasWidge
             CanvasPrimitive {
                 canvas: self.canvas.clone(),
                 tile_storage: self.gpu_tile_storage.clone(),
+                mask_storage: self.mask_storage.clone(),
             },
         );
+        self.draw_overlay(renderer, layout.bounds());
     }
 }
 
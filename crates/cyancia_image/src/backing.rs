@@ -0,0 +1,104 @@
+//! CPU side storage of tiles that were evicted from the GPU piles.
+
+use cyancia_id::Id;
+use dashmap::DashMap;
+use glam::UVec2;
+
+use crate::layer::Layer;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TileCompression {
+    None,
+    #[default]
+    Lz4,
+}
+
+#[derive(Debug, Clone)]
+pub struct CpuTile {
+    data: Vec<u8>,
+    compression: TileCompression,
+}
+
+impl CpuTile {
+    pub fn new(raw: &[u8], compression: TileCompression) -> Self {
+        let data = match compression {
+            TileCompression::None => raw.to_vec(),
+            TileCompression::Lz4 => lz4_flex::compress_prepend_size(raw),
+        };
+        Self { data, compression }
+    }
+
+    /// Rebuilds a tile from what [`CpuTile::stored_data`] returned.
+    pub fn from_stored(data: Vec<u8>, compression: TileCompression) -> Self {
+        Self { data, compression }
+    }
+
+    /// Bytes actually held in memory.
+    pub fn stored_size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The tile data as held in memory, compressed or not.
+    pub fn stored_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn compression(&self) -> TileCompression {
+        self.compression
+    }
+
+    /// Raw `Rgba16Float` texels of the tile, rows tightly packed.
+    pub fn decode(&self) -> Vec<u8> {
+        match self.compression {
+            TileCompression::None => self.data.clone(),
+            TileCompression::Lz4 => lz4_flex::decompress_size_prepended(&self.data)
+                .expect("Tile data compressed by ourselves should always be valid."),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CpuTileStore {
+    tiles: DashMap<(Id<Layer>, UVec2), CpuTile>,
+}
+
+impl CpuTileStore {
+    pub fn insert(&self, image_layer: Id<Layer>, index: UVec2, tile: CpuTile) {
+        self.tiles.insert((image_layer, index), tile);
+    }
+
+    pub fn get(&self, image_layer: Id<Layer>, index: UVec2) -> Option<CpuTile> {
+        self.tiles
+            .get(&(image_layer, index))
+            .map(|r| r.value().clone())
+    }
+
+    pub fn remove(&self, image_layer: Id<Layer>, index: UVec2) -> Option<CpuTile> {
+        self.tiles.remove(&(image_layer, index)).map(|(_, t)| t)
+    }
+
+    pub fn contains(&self, image_layer: Id<Layer>, index: UVec2) -> bool {
+        self.tiles.contains_key(&(image_layer, index))
+    }
+
+    /// Indices of all tiles of the layer held by this store.
+    pub fn layer_tiles(&self, image_layer: Id<Layer>) -> Vec<UVec2> {
+        self.tiles
+            .iter()
+            .filter(|r| r.key().0 == image_layer)
+            .map(|r| r.key().1)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    pub fn stored_bytes(&self) -> usize {
+        self.tiles.iter().map(|r| r.value().stored_size()).sum()
+    }
+}
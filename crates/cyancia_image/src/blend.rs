@@ -0,0 +1,337 @@
+//! CPU reference implementation of layer blending, matching `canvas_blend.wesl`.
+//!
+//! Colors are straight (non-premultiplied) alpha. Formulas follow the W3C
+//! Compositing and Blending Level 1 specification, with `Add` and `Subtract`
+//! as the usual linear dodge and subtract modes.
+
+use glam::{Vec3, Vec4};
+use serde::{Deserialize, Serialize};
+
+#[repr(u32)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    #[default]
+    Normal = 0,
+    Multiply = 1,
+    Screen = 2,
+    Overlay = 3,
+    Add = 4,
+    Subtract = 5,
+    Darken = 6,
+    Lighten = 7,
+    ColorDodge = 8,
+    ColorBurn = 9,
+    SoftLight = 10,
+    HardLight = 11,
+    Difference = 12,
+    Hue = 13,
+    Saturation = 14,
+    Color = 15,
+    Luminosity = 16,
+}
+
+impl BlendMode {
+    pub const ALL: [BlendMode; 17] = [
+        BlendMode::Normal,
+        BlendMode::Multiply,
+        BlendMode::Screen,
+        BlendMode::Overlay,
+        BlendMode::Add,
+        BlendMode::Subtract,
+        BlendMode::Darken,
+        BlendMode::Lighten,
+        BlendMode::ColorDodge,
+        BlendMode::ColorBurn,
+        BlendMode::SoftLight,
+        BlendMode::HardLight,
+        BlendMode::Difference,
+        BlendMode::Hue,
+        BlendMode::Saturation,
+        BlendMode::Color,
+        BlendMode::Luminosity,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BlendMode::Normal => "Normal",
+            BlendMode::Multiply => "Multiply",
+            BlendMode::Screen => "Screen",
+            BlendMode::Overlay => "Overlay",
+            BlendMode::Add => "Add",
+            BlendMode::Subtract => "Subtract",
+            BlendMode::Darken => "Darken",
+            BlendMode::Lighten => "Lighten",
+            BlendMode::ColorDodge => "Color Dodge",
+            BlendMode::ColorBurn => "Color Burn",
+            BlendMode::SoftLight => "Soft Light",
+            BlendMode::HardLight => "Hard Light",
+            BlendMode::Difference => "Difference",
+            BlendMode::Hue => "Hue",
+            BlendMode::Saturation => "Saturation",
+            BlendMode::Color => "Color",
+            BlendMode::Luminosity => "Luminosity",
+        }
+    }
+
+    pub fn is_separable(self) -> bool {
+        !matches!(
+            self,
+            BlendMode::Hue | BlendMode::Saturation | BlendMode::Color | BlendMode::Luminosity
+        )
+    }
+}
+
+/// How a group is composited into its parent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupIsolation {
+    /// Children blend directly with whatever is below the group. The blend mode
+    /// of the group itself is ignored.
+    #[default]
+    PassThrough,
+    /// Children are composited onto a transparent backdrop first, then the result
+    /// is blended into the parent using the blend mode of the group.
+    Isolated,
+}
+
+/// Blends two straight alpha colors, `source` over `backdrop`.
+pub fn composite(mode: BlendMode, backdrop: Vec4, source: Vec4, opacity: f32) -> Vec4 {
+    let ab = backdrop.w;
+    let a_s = source.w * opacity;
+    let ao = a_s + ab * (1.0 - a_s);
+    if ao <= 0.0 {
+        return Vec4::ZERO;
+    }
+
+    let cb = backdrop.truncate();
+    let cs = source.truncate();
+    let mixed = (1.0 - ab) * cs + ab * blend_color(mode, cb, cs);
+    let co = (a_s * mixed + ab * (1.0 - a_s) * cb) / ao;
+    co.extend(ao)
+}
+
+/// The blending function `B(Cb, Cs)`.
+pub fn blend_color(mode: BlendMode, cb: Vec3, cs: Vec3) -> Vec3 {
+    match mode {
+        BlendMode::Normal => cs,
+        BlendMode::Multiply => cb * cs,
+        BlendMode::Screen => screen(cb, cs),
+        BlendMode::Overlay => hard_light(cs, cb),
+        BlendMode::Add => (cb + cs).min(Vec3::ONE),
+        BlendMode::Subtract => (cb - cs).max(Vec3::ZERO),
+        BlendMode::Darken => cb.min(cs),
+        BlendMode::Lighten => cb.max(cs),
+        BlendMode::ColorDodge => separable(cb, cs, color_dodge),
+        BlendMode::ColorBurn => separable(cb, cs, color_burn),
+        BlendMode::SoftLight => separable(cb, cs, soft_light),
+        BlendMode::HardLight => hard_light(cb, cs),
+        BlendMode::Difference => (cb - cs).abs(),
+        BlendMode::Hue => set_lum(set_sat(cs, sat(cb)), lum(cb)),
+        BlendMode::Saturation => set_lum(set_sat(cb, sat(cs)), lum(cb)),
+        BlendMode::Color => set_lum(cs, lum(cb)),
+        BlendMode::Luminosity => set_lum(cb, lum(cs)),
+    }
+}
+
+fn separable(cb: Vec3, cs: Vec3, f: fn(f32, f32) -> f32) -> Vec3 {
+    Vec3::new(f(cb.x, cs.x), f(cb.y, cs.y), f(cb.z, cs.z))
+}
+
+fn screen(cb: Vec3, cs: Vec3) -> Vec3 {
+    cb + cs - cb * cs
+}
+
+fn hard_light(cb: Vec3, cs: Vec3) -> Vec3 {
+    separable(cb, cs, |b, s| {
+        if s <= 0.5 {
+            b * 2.0 * s
+        } else {
+            let s = 2.0 * s - 1.0;
+            b + s - b * s
+        }
+    })
+}
+
+fn color_dodge(b: f32, s: f32) -> f32 {
+    if b <= 0.0 {
+        0.0
+    } else if s >= 1.0 {
+        1.0
+    } else {
+        (b / (1.0 - s)).min(1.0)
+    }
+}
+
+fn color_burn(b: f32, s: f32) -> f32 {
+    if b >= 1.0 {
+        1.0
+    } else if s <= 0.0 {
+        0.0
+    } else {
+        1.0 - ((1.0 - b) / s).min(1.0)
+    }
+}
+
+fn soft_light(b: f32, s: f32) -> f32 {
+    if s <= 0.5 {
+        b - (1.0 - 2.0 * s) * b * (1.0 - b)
+    } else {
+        let d = if b <= 0.25 {
+            ((16.0 * b - 12.0) * b + 4.0) * b
+        } else {
+            b.sqrt()
+        };
+        b + (2.0 * s - 1.0) * (d - b)
+    }
+}
+
+fn lum(c: Vec3) -> f32 {
+    c.dot(Vec3::new(0.3, 0.59, 0.11))
+}
+
+fn clip_color(c: Vec3) -> Vec3 {
+    let l = lum(c);
+    let n = c.min_element();
+    let x = c.max_element();
+    let mut c = c;
+    if n < 0.0 {
+        c = l + (c - l) * l / (l - n);
+    }
+    if x > 1.0 {
+        c = l + (c - l) * (1.0 - l) / (x - l);
+    }
+    c
+}
+
+fn set_lum(c: Vec3, l: f32) -> Vec3 {
+    clip_color(c + (l - lum(c)))
+}
+
+fn sat(c: Vec3) -> f32 {
+    c.max_element() - c.min_element()
+}
+
+fn set_sat(c: Vec3, s: f32) -> Vec3 {
+    let max = c.max_element();
+    let min = c.min_element();
+    if max > min {
+        (c - min) * s / (max - min)
+    } else {
+        Vec3::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKDROP: Vec3 = Vec3::new(0.2, 0.5, 0.8);
+    const SOURCE: Vec3 = Vec3::new(0.7, 0.3, 0.6);
+
+    fn assert_close(mode: BlendMode, actual: Vec3, expected: Vec3) {
+        assert!(
+            actual.abs_diff_eq(expected, 1e-5),
+            "{}: expected {expected}, got {actual}",
+            mode.name()
+        );
+    }
+
+    /// `B(Cb, Cs)` of every mode, worked out by hand from the formulas of the
+    /// specification for the same pair of colors.
+    #[test]
+    fn blend_color_matches_specification() {
+        let expected = [
+            (BlendMode::Normal, Vec3::new(0.7, 0.3, 0.6)),
+            (BlendMode::Multiply, Vec3::new(0.14, 0.15, 0.48)),
+            (BlendMode::Screen, Vec3::new(0.76, 0.65, 0.92)),
+            (BlendMode::Overlay, Vec3::new(0.28, 0.3, 0.84)),
+            (BlendMode::Add, Vec3::new(0.9, 0.8, 1.0)),
+            (BlendMode::Subtract, Vec3::new(0.0, 0.2, 0.2)),
+            (BlendMode::Darken, Vec3::new(0.2, 0.3, 0.6)),
+            (BlendMode::Lighten, Vec3::new(0.7, 0.5, 0.8)),
+            (BlendMode::ColorDodge, Vec3::new(2.0 / 3.0, 5.0 / 7.0, 1.0)),
+            (BlendMode::ColorBurn, Vec3::new(0.0, 0.0, 2.0 / 3.0)),
+            (
+                BlendMode::SoftLight,
+                Vec3::new(0.2992, 0.4, 0.8 + 0.2 * (0.8f32.sqrt() - 0.8)),
+            ),
+            (BlendMode::HardLight, Vec3::new(0.52, 0.3, 0.84)),
+            (BlendMode::Difference, Vec3::new(0.5, 0.2, 0.2)),
+            (BlendMode::Hue, Vec3::new(0.8135, 0.2135, 0.6635)),
+            (BlendMode::Saturation, Vec3::new(0.281, 0.481, 0.681)),
+            (BlendMode::Color, Vec3::new(0.69, 0.29, 0.59)),
+            (BlendMode::Luminosity, Vec3::new(0.21, 0.51, 0.81)),
+        ];
+        assert_eq!(expected.len(), BlendMode::ALL.len());
+
+        for (mode, expected) in expected {
+            assert_close(mode, blend_color(mode, BACKDROP, SOURCE), expected);
+        }
+    }
+
+    #[test]
+    fn dodge_and_burn_edge_cases() {
+        assert_eq!(color_dodge(0.0, 1.0), 0.0);
+        assert_eq!(color_dodge(0.3, 1.0), 1.0);
+        assert_eq!(color_burn(1.0, 0.0), 1.0);
+        assert_eq!(color_burn(0.7, 0.0), 0.0);
+    }
+
+    #[test]
+    fn non_separable_modes_keep_luminosity_in_range() {
+        let saturated = Vec3::new(1.0, 0.0, 0.0);
+        for mode in [BlendMode::Hue, BlendMode::Color, BlendMode::Luminosity] {
+            let c = blend_color(mode, Vec3::splat(0.95), saturated);
+            let in_range = c.cmpge(Vec3::splat(-1e-6)) & c.cmple(Vec3::splat(1.0 + 1e-6));
+            assert!(in_range.all(), "{}: {c} is out of range", mode.name());
+        }
+    }
+
+    #[test]
+    fn opaque_source_over_opaque_backdrop_is_the_blend() {
+        for mode in BlendMode::ALL {
+            let result = composite(mode, BACKDROP.extend(1.0), SOURCE.extend(1.0), 1.0);
+            assert_close(mode, result.truncate(), blend_color(mode, BACKDROP, SOURCE));
+            assert_eq!(result.w, 1.0);
+        }
+    }
+
+    #[test]
+    fn opacity_mixes_with_backdrop() {
+        for mode in BlendMode::ALL {
+            let result = composite(mode, BACKDROP.extend(1.0), SOURCE.extend(1.0), 0.25);
+            let expected = BACKDROP.lerp(blend_color(mode, BACKDROP, SOURCE), 0.25);
+            assert_close(mode, result.truncate(), expected);
+            assert_eq!(result.w, 1.0);
+        }
+    }
+
+    #[test]
+    fn transparent_backdrop_shows_source_unblended() {
+        for mode in BlendMode::ALL {
+            let result = composite(mode, Vec4::ZERO, SOURCE.extend(0.5), 1.0);
+            assert_close(mode, result.truncate(), SOURCE);
+            assert_eq!(result.w, 0.5);
+        }
+        assert_eq!(
+            composite(BlendMode::Screen, Vec4::ZERO, Vec4::ZERO, 1.0),
+            Vec4::ZERO
+        );
+    }
+
+    #[test]
+    fn partial_alphas_follow_source_over() {
+        // αo = αs + αb(1 - αs) = 0.5 + 0.5 * 0.5
+        let result = composite(
+            BlendMode::Multiply,
+            BACKDROP.extend(0.5),
+            SOURCE.extend(0.5),
+            1.0,
+        );
+        assert!((result.w - 0.75).abs() < 1e-6);
+        let mixed = 0.5 * SOURCE + 0.5 * BACKDROP * SOURCE;
+        let expected = (0.5 * mixed + 0.25 * BACKDROP) / 0.75;
+        assert_close(BlendMode::Multiply, result.truncate(), expected);
+    }
+}
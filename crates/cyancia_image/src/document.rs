@@ -0,0 +1,59 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Color space the pixel values of the document are encoded in.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ColorProfile {
+    #[default]
+    Srgb,
+    LinearSrgb,
+    /// An embedded ICC profile. The data is stored next to the document rather
+    /// than inline.
+    Icc {
+        name: String,
+        #[serde(skip)]
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentMetadata {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub description: String,
+    /// Seconds since the unix epoch.
+    pub created: u64,
+    /// Seconds since the unix epoch.
+    pub modified: u64,
+}
+
+impl Default for DocumentMetadata {
+    fn default() -> Self {
+        let now = unix_now();
+        Self {
+            title: String::new(),
+            author: String::new(),
+            description: String::new(),
+            created: now,
+            modified: now,
+        }
+    }
+}
+
+impl DocumentMetadata {
+    pub fn touch(&mut self) {
+        self.modified = unix_now();
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,348 @@
+//! Encoding the flattened document into common raster formats.
+
+use std::{future::Future, io::Cursor, path::Path};
+
+use exr::prelude::{Image as ExrImage, SpecificChannels, Vec2, WritableImage};
+use half::f16;
+use image::{
+    DynamicImage, Rgba32FImage,
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{self, PngEncoder},
+        tiff::TiffEncoder,
+    },
+    imageops::{self, FilterType},
+};
+
+use crate::{CImage, document::ColorProfile, readback::ReadbackError, tile::GpuTileStorage};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    #[error("{format:?} can't be exported with {depth:?} channels")]
+    UnsupportedBitDepth {
+        format: ExportFormat,
+        depth: BitDepth,
+    },
+    #[error("{0:?} has no alpha channel, use a matte or discard the alpha")]
+    UnsupportedAlpha(ExportFormat),
+    #[error("Invalid scale factor {0}")]
+    InvalidScale(f32),
+    #[error("Invalid quality {0}, expected 1 to 100")]
+    InvalidQuality(u8),
+    #[error("Failed to encode WebP image: {0}")]
+    WebP(String),
+    #[error(transparent)]
+    Readback(#[from] ReadbackError),
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+    #[error(transparent)]
+    Exr(#[from] exr::error::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExportFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Tiff,
+    Exr,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 5] = [
+        ExportFormat::Png,
+        ExportFormat::Jpeg,
+        ExportFormat::WebP,
+        ExportFormat::Tiff,
+        ExportFormat::Exr,
+    ];
+
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|f| f.extensions().contains(&ext.as_str()))
+    }
+
+    /// The preferred extension.
+    pub fn extension(self) -> &'static str {
+        self.extensions()[0]
+    }
+
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            ExportFormat::Png => &["png"],
+            ExportFormat::Jpeg => &["jpg", "jpeg"],
+            ExportFormat::WebP => &["webp"],
+            ExportFormat::Tiff => &["tif", "tiff"],
+            ExportFormat::Exr => &["exr"],
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "PNG",
+            ExportFormat::Jpeg => "JPEG",
+            ExportFormat::WebP => "WebP",
+            ExportFormat::Tiff => "TIFF",
+            ExportFormat::Exr => "OpenEXR",
+        }
+    }
+
+    /// Supported bit depths, the first one being the default.
+    pub fn bit_depths(self) -> &'static [BitDepth] {
+        match self {
+            ExportFormat::Png => &[BitDepth::Eight, BitDepth::Sixteen],
+            ExportFormat::Jpeg | ExportFormat::WebP => &[BitDepth::Eight],
+            ExportFormat::Tiff => &[BitDepth::Eight, BitDepth::Sixteen, BitDepth::Float],
+            ExportFormat::Exr => &[BitDepth::Half, BitDepth::Float],
+        }
+    }
+
+    pub fn supports_alpha(self) -> bool {
+        !matches!(self, ExportFormat::Jpeg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+    /// 16 bit floats, the precision tiles are stored in.
+    Half,
+    Float,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PngCompression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlphaHandling {
+    Keep,
+    /// Drops the alpha channel, keeping the color of transparent pixels.
+    Discard,
+    /// Composites the image over a solid color.
+    Matte([f32; 3]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    pub bit_depth: BitDepth,
+    /// JPEG and WebP quality from 1 to 100. WebP is lossless at 100.
+    pub quality: u8,
+    pub png_compression: PngCompression,
+    pub alpha: AlphaHandling,
+    /// Resizes the exported image, the document is always flattened at full resolution.
+    pub scale: f32,
+}
+
+impl ExportOptions {
+    pub fn new(format: ExportFormat) -> Self {
+        Self {
+            format,
+            bit_depth: format.bit_depths()[0],
+            quality: 90,
+            png_compression: PngCompression::default(),
+            alpha: if format.supports_alpha() {
+                AlphaHandling::Keep
+            } else {
+                AlphaHandling::Matte([1.0; 3])
+            },
+            scale: 1.0,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ExportError> {
+        if !self.format.bit_depths().contains(&self.bit_depth) {
+            return Err(ExportError::UnsupportedBitDepth {
+                format: self.format,
+                depth: self.bit_depth,
+            });
+        }
+        if self.alpha == AlphaHandling::Keep && !self.format.supports_alpha() {
+            return Err(ExportError::UnsupportedAlpha(self.format));
+        }
+        if !(self.scale.is_finite() && self.scale > 0.0) {
+            return Err(ExportError::InvalidScale(self.scale));
+        }
+        if !(1..=100).contains(&self.quality) {
+            return Err(ExportError::InvalidQuality(self.quality));
+        }
+        Ok(())
+    }
+}
+
+/// Flattens the document and encodes it. OpenEXR files are written in linear
+/// light, converted from sRGB if that is the color profile of the document.
+pub fn export(
+    image: &CImage,
+    tiles: &GpuTileStorage,
+    options: ExportOptions,
+) -> impl Future<Output = Result<Vec<u8>, ExportError>> + Send + 'static {
+    let readback = image.read_flattened(tiles);
+    let linearize = *image.color_profile() == ColorProfile::Srgb;
+
+    async move {
+        options.validate()?;
+        let mut pixels = readback.await?;
+        if options.format == ExportFormat::Exr && linearize {
+            for px in pixels.pixels_mut() {
+                for c in &mut px.0[..3] {
+                    *c = srgb_to_linear(*c);
+                }
+            }
+        }
+        encode(pixels, &options)
+    }
+}
+
+pub fn encode(pixels: Rgba32FImage, options: &ExportOptions) -> Result<Vec<u8>, ExportError> {
+    options.validate()?;
+
+    let mut pixels = scale(pixels, options.scale);
+    if let AlphaHandling::Matte(color) = options.alpha {
+        for px in pixels.pixels_mut() {
+            let a = px.0[3];
+            for (c, matte) in px.0[..3].iter_mut().zip(color) {
+                *c = *c * a + matte * (1.0 - a);
+            }
+            px.0[3] = 1.0;
+        }
+    }
+    let alpha = options.alpha == AlphaHandling::Keep;
+
+    let mut out = Cursor::new(Vec::new());
+    let image = DynamicImage::ImageRgba32F(pixels);
+    let image = match (options.bit_depth, alpha) {
+        (BitDepth::Eight, true) => DynamicImage::ImageRgba8(image.to_rgba8()),
+        (BitDepth::Eight, false) => DynamicImage::ImageRgb8(image.to_rgb8()),
+        (BitDepth::Sixteen, true) => DynamicImage::ImageRgba16(image.to_rgba16()),
+        (BitDepth::Sixteen, false) => DynamicImage::ImageRgb16(image.to_rgb16()),
+        (BitDepth::Half | BitDepth::Float, true) => image,
+        (BitDepth::Half | BitDepth::Float, false) => DynamicImage::ImageRgb32F(image.to_rgb32f()),
+    };
+
+    match options.format {
+        ExportFormat::Png => {
+            let compression = match options.png_compression {
+                PngCompression::Fast => png::CompressionType::Fast,
+                PngCompression::Default => png::CompressionType::Default,
+                PngCompression::Best => png::CompressionType::Best,
+            };
+            image.write_with_encoder(PngEncoder::new_with_quality(
+                &mut out,
+                compression,
+                png::FilterType::Adaptive,
+            ))?;
+        }
+        ExportFormat::Jpeg => {
+            image.write_with_encoder(JpegEncoder::new_with_quality(&mut out, options.quality))?;
+        }
+        ExportFormat::WebP => {
+            let encoder = match &image {
+                DynamicImage::ImageRgba8(img) => {
+                    webp::Encoder::from_rgba(img.as_raw(), img.width(), img.height())
+                }
+                DynamicImage::ImageRgb8(img) => {
+                    webp::Encoder::from_rgb(img.as_raw(), img.width(), img.height())
+                }
+                _ => unreachable!("WebP is always exported with 8 bit channels."),
+            };
+            let memory = match options.quality {
+                100 => encoder.encode_simple(true, 100.0),
+                quality => encoder.encode_simple(false, quality as f32),
+            }
+            .map_err(|e| ExportError::WebP(format!("{e:?}")))?;
+            return Ok(memory.to_vec());
+        }
+        ExportFormat::Tiff => {
+            image.write_with_encoder(TiffEncoder::new(&mut out))?;
+        }
+        ExportFormat::Exr => {
+            let pixels = image.into_rgba32f();
+            match options.bit_depth {
+                BitDepth::Half => write_exr(&pixels, alpha, f16::from_f32, &mut out)?,
+                _ => write_exr(&pixels, alpha, |v| v, &mut out)?,
+            }
+        }
+    }
+
+    Ok(out.into_inner())
+}
+
+fn write_exr<T>(
+    pixels: &Rgba32FImage,
+    alpha: bool,
+    convert: fn(f32) -> T,
+    out: &mut Cursor<Vec<u8>>,
+) -> Result<(), ExportError>
+where
+    T: exr::prelude::IntoSample,
+{
+    let size = (pixels.width() as usize, pixels.height() as usize);
+    let texel = move |Vec2(x, y): Vec2<usize>| pixels.get_pixel(x as u32, y as u32).0.map(convert);
+
+    if alpha {
+        let channels = SpecificChannels::rgba(move |pos| {
+            let [r, g, b, a] = texel(pos);
+            (r, g, b, a)
+        });
+        ExrImage::from_channels(size, channels)
+            .write()
+            .to_buffered(out)?;
+    } else {
+        let channels = SpecificChannels::rgb(move |pos| {
+            let [r, g, b, _] = texel(pos);
+            (r, g, b)
+        });
+        ExrImage::from_channels(size, channels)
+            .write()
+            .to_buffered(out)?;
+    }
+    Ok(())
+}
+
+/// Resamples with premultiplied alpha, so transparent pixels don't bleed their color.
+fn scale(mut pixels: Rgba32FImage, factor: f32) -> Rgba32FImage {
+    if factor == 1.0 {
+        return pixels;
+    }
+
+    for px in pixels.pixels_mut() {
+        let a = px.0[3];
+        px.0[..3].iter_mut().for_each(|c| *c *= a);
+    }
+
+    let width = ((pixels.width() as f32 * factor).round() as u32).max(1);
+    let height = ((pixels.height() as f32 * factor).round() as u32).max(1);
+    let filter = if factor > 1.0 {
+        FilterType::CatmullRom
+    } else {
+        FilterType::Lanczos3
+    };
+    let mut scaled = imageops::resize(&pixels, width, height, filter);
+
+    for px in scaled.pixels_mut() {
+        // Filters with negative lobes can overshoot.
+        let a = px.0[3].clamp(0.0, 1.0);
+        px.0[3] = a;
+        if a > 0.0 {
+            px.0[..3].iter_mut().for_each(|c| *c /= a);
+        }
+    }
+    scaled
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
@@ -0,0 +1,415 @@
+//! The native `.cyan` document format.
+//!
+//! A zip archive holding:
+//! - `mimetype`, stored uncompressed as the first entry so the file can be
+//!   identified without unpacking.
+//! - `document.toml`, the manifest with the format version, canvas size, color
+//!   profile, metadata and the layer tree.
+//! - `profile.icc`, present if the document has an embedded ICC profile.
+//! - `tiles/{layer}/{x}_{y}`, raw `Rgba16Float` texels of each non-empty tile,
+//!   `layer` being the index of the layer in the manifest.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{Cursor, Read, Write},
+};
+
+use cyancia_id::Id;
+use glam::UVec2;
+use serde::{Deserialize, Serialize};
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    CImage,
+    blend::{BlendMode, GroupIsolation},
+    document::{ColorProfile, DocumentMetadata},
+    layer::{Layer, LayerError, LayerLocks, LayerProperties},
+    readback::ReadbackError,
+    tile::GpuTileStorage,
+};
+
+pub const EXTENSION: &str = "cyan";
+pub const MIMETYPE: &str = "application/x-cyancia";
+pub const FORMAT_NAME: &str = "cyancia";
+/// Bumped whenever the layout of the manifest or the tiles changes. Older files
+/// are migrated in [`migrate`].
+pub const FORMAT_VERSION: u32 = 1;
+
+const MIMETYPE_ENTRY: &str = "mimetype";
+const MANIFEST_ENTRY: &str = "document.toml";
+const PROFILE_ENTRY: &str = "profile.icc";
+
+#[derive(Debug, thiserror::Error)]
+pub enum CyanError {
+    #[error("Not a cyancia document")]
+    NotADocument,
+    #[error("Document version {0} is newer than the supported version {FORMAT_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("Malformed manifest: {0}")]
+    Manifest(String),
+    #[error("Layer {0} refers to missing parent {1}")]
+    MissingParent(usize, usize),
+    #[error("Tile {0} has {1} bytes, expected {2}")]
+    TileSize(String, usize, u64),
+    #[error("Tile {1} of layer {0} lies outside of the canvas")]
+    TileOutOfRange(usize, UVec2),
+    #[error(transparent)]
+    Layer(#[from] LayerError),
+    #[error(transparent)]
+    Readback(#[from] ReadbackError),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Raw texels of each non-empty tile of a layer.
+type LayerTiles = Vec<(UVec2, Vec<u8>)>;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    header: Header,
+    width: u32,
+    height: u32,
+    color_profile: ColorProfile,
+    metadata: DocumentMetadata,
+    #[serde(default)]
+    layers: Vec<LayerEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum LayerEntryKind {
+    Paint,
+    Group,
+}
+
+/// Layers are listed depth first, parents before their children and siblings from
+/// bottom to top.
+#[derive(Debug, Serialize, Deserialize)]
+struct LayerEntry {
+    name: String,
+    kind: LayerEntryKind,
+    /// Index of the parent in the layer list. Top level layers have none.
+    parent: Option<usize>,
+    visible: bool,
+    opacity: f32,
+    blend_mode: BlendMode,
+    locks: u8,
+    isolation: Option<GroupIsolation>,
+    #[serde(default)]
+    tiles: Vec<[u32; 2]>,
+}
+
+/// Encodes the document. Tiles are read back from the GPU first, so the image can
+/// be unlocked while the future runs.
+pub fn save(
+    image: &CImage,
+    tiles: &GpuTileStorage,
+) -> impl Future<Output = Result<Vec<u8>, CyanError>> + Send + 'static {
+    let ids = image.descendants(image.root_id()).unwrap_or_default();
+    let indices = ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (*id, i))
+        .collect::<HashMap<_, _>>();
+
+    let mut readbacks = Vec::new();
+    let mut entries = Vec::with_capacity(ids.len());
+    for (i, id) in ids.iter().enumerate() {
+        let layer = &image.layers[id];
+        let properties = &layer.properties;
+        entries.push(LayerEntry {
+            name: properties.name.clone(),
+            kind: if layer.is_group() {
+                LayerEntryKind::Group
+            } else {
+                LayerEntryKind::Paint
+            },
+            parent: layer.parent.and_then(|p| indices.get(&p).copied()),
+            visible: properties.visible,
+            opacity: properties.opacity,
+            blend_mode: properties.blend_mode,
+            locks: properties.locks.bits(),
+            isolation: layer.isolation(),
+            tiles: Vec::new(),
+        });
+        if !layer.is_group() {
+            readbacks.push((i, tiles.read_layer_tiles(*id)));
+        }
+    }
+
+    let manifest = Manifest {
+        header: Header {
+            format: FORMAT_NAME.to_string(),
+            version: FORMAT_VERSION,
+        },
+        width: image.size.x,
+        height: image.size.y,
+        color_profile: image.color_profile.clone(),
+        metadata: image.metadata.clone(),
+        layers: entries,
+    };
+
+    async move {
+        let mut manifest = manifest;
+        let mut layer_tiles = Vec::new();
+        for (i, readback) in readbacks {
+            let mut data = readback.await?;
+            data.retain(|(_, data)| !GpuTileStorage::is_transparent(data));
+            data.sort_unstable_by_key(|(index, _)| (index.y, index.x));
+            manifest.layers[i].tiles = data.iter().map(|(index, _)| index.to_array()).collect();
+            layer_tiles.push((i, data));
+        }
+
+        encode(&manifest, &layer_tiles)
+    }
+}
+
+fn encode(manifest: &Manifest, layer_tiles: &[(usize, LayerTiles)]) -> Result<Vec<u8>, CyanError> {
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file(MIMETYPE_ENTRY, stored)?;
+    zip.write_all(MIMETYPE.as_bytes())?;
+
+    let manifest_str = toml::to_string(manifest).map_err(|e| CyanError::Manifest(e.to_string()))?;
+    zip.start_file(MANIFEST_ENTRY, deflated)?;
+    zip.write_all(manifest_str.as_bytes())?;
+
+    if let ColorProfile::Icc { data, .. } = &manifest.color_profile {
+        zip.start_file(PROFILE_ENTRY, deflated)?;
+        zip.write_all(data)?;
+    }
+
+    for (layer, tiles) in layer_tiles {
+        for (index, data) in tiles {
+            zip.start_file(tile_entry(*layer, *index), deflated)?;
+            zip.write_all(data)?;
+        }
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Decodes a document and uploads its tiles.
+pub fn load(bytes: &[u8], tiles: &GpuTileStorage) -> Result<CImage, CyanError> {
+    let mut zip = ZipArchive::new(Cursor::new(bytes))?;
+
+    let mimetype = read_entry(&mut zip, MIMETYPE_ENTRY).map_err(|_| CyanError::NotADocument)?;
+    if mimetype != MIMETYPE.as_bytes() {
+        return Err(CyanError::NotADocument);
+    }
+
+    let manifest_str = String::from_utf8(read_entry(&mut zip, MANIFEST_ENTRY)?)
+        .map_err(|e| CyanError::Manifest(e.to_string()))?;
+    let table = manifest_str
+        .parse::<toml::Table>()
+        .map_err(|e| CyanError::Manifest(e.to_string()))?;
+    let mut manifest = migrate(table)?
+        .try_into::<Manifest>()
+        .map_err(|e| CyanError::Manifest(e.to_string()))?;
+
+    if let ColorProfile::Icc { data, .. } = &mut manifest.color_profile {
+        *data = read_entry(&mut zip, PROFILE_ENTRY)?;
+    }
+
+    let mut image = CImage::empty(UVec2::new(manifest.width, manifest.height));
+    image.color_profile = manifest.color_profile;
+    image.metadata = manifest.metadata;
+
+    // Tiles uploaded before the error would be left behind without a layer.
+    if let Err(e) = load_layers(&mut image, manifest.layers, &mut zip, tiles) {
+        image.release_tiles(tiles);
+        return Err(e);
+    }
+
+    Ok(image)
+}
+
+fn load_layers(
+    image: &mut CImage,
+    entries: Vec<LayerEntry>,
+    zip: &mut ZipArchive<Cursor<&[u8]>>,
+    tiles: &GpuTileStorage,
+) -> Result<(), CyanError> {
+    let tile_count = GpuTileStorage::calc_tile_count(image.size);
+    let mut ids = Vec::<Id<Layer>>::with_capacity(entries.len());
+    for (i, entry) in entries.into_iter().enumerate() {
+        let mut layer = match entry.kind {
+            LayerEntryKind::Paint => Layer::paint(""),
+            LayerEntryKind::Group => Layer::group(""),
+        };
+        layer.properties = LayerProperties {
+            name: entry.name,
+            visible: entry.visible,
+            opacity: entry.opacity,
+            blend_mode: entry.blend_mode,
+            locks: LayerLocks::from_bits_truncate(entry.locks),
+        };
+        if let Some(isolation) = entry.isolation {
+            layer.set_isolation(isolation);
+        }
+
+        let parent = match entry.parent {
+            Some(p) => *ids.get(p).ok_or(CyanError::MissingParent(i, p))?,
+            None => image.root,
+        };
+        let index = image.layer(parent)?.children().len();
+        let id = image.insert_layer(layer, parent, index)?;
+        ids.push(id);
+
+        for [x, y] in entry.tiles {
+            let index = UVec2::new(x, y);
+            if index.cmpge(tile_count).any() {
+                return Err(CyanError::TileOutOfRange(i, index));
+            }
+            let name = tile_entry(i, index);
+            let data = read_entry(zip, &name)?;
+            if data.len() as u64 != GpuTileStorage::TILE_BYTES {
+                return Err(CyanError::TileSize(
+                    name,
+                    data.len(),
+                    GpuTileStorage::TILE_BYTES,
+                ));
+            }
+            tiles.write_tile_data(id, index, &data);
+        }
+    }
+
+    Ok(())
+}
+
+/// Brings a manifest of any older version up to [`FORMAT_VERSION`].
+fn migrate(manifest: toml::Table) -> Result<toml::Table, CyanError> {
+    migrate_to(manifest, FORMAT_VERSION, upgrade)
+}
+
+/// Applies `upgrade` one version at a time until the manifest is at `target`.
+fn migrate_to(
+    mut manifest: toml::Table,
+    target: u32,
+    upgrade: impl Fn(u32, toml::Table) -> Result<toml::Table, CyanError>,
+) -> Result<toml::Table, CyanError> {
+    let header = manifest
+        .get("header")
+        .and_then(|h| h.as_table())
+        .ok_or_else(|| CyanError::Manifest("missing header".to_string()))?;
+    if header.get("format").and_then(|f| f.as_str()) != Some(FORMAT_NAME) {
+        return Err(CyanError::NotADocument);
+    }
+    let mut version = header
+        .get("version")
+        .and_then(|v| v.as_integer())
+        .ok_or_else(|| CyanError::Manifest("missing version".to_string()))?
+        as u32;
+    if version > target {
+        return Err(CyanError::UnsupportedVersion(version));
+    }
+
+    while version < target {
+        manifest = upgrade(version, manifest)?;
+        version += 1;
+        if let Some(header) = manifest.get_mut("header").and_then(|h| h.as_table_mut()) {
+            header.insert("version".to_string(), i64::from(version).into());
+        }
+    }
+    Ok(manifest)
+}
+
+/// Turns a manifest of `version` into one of the next version. Each format
+/// change matches on the version it replaces here, along with bumping
+/// [`FORMAT_VERSION`].
+fn upgrade(version: u32, _manifest: toml::Table) -> Result<toml::Table, CyanError> {
+    // Version 1 is the first one, there's nothing to upgrade from yet.
+    Err(CyanError::Manifest(format!("unknown version {version}")))
+}
+
+fn tile_entry(layer: usize, index: UVec2) -> String {
+    format!("tiles/{}/{}_{}", layer, index.x, index.y)
+}
+
+fn read_entry(zip: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Vec<u8>, CyanError> {
+    let mut file = zip.by_name(name)?;
+    let mut data = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(format: &str, version: u32) -> toml::Table {
+        format!("width = 64\nheight = 64\n[header]\nformat = \"{format}\"\nversion = {version}\n")
+            .parse()
+            .unwrap()
+    }
+
+    fn version(manifest: &toml::Table) -> i64 {
+        manifest["header"]["version"].as_integer().unwrap()
+    }
+
+    #[test]
+    fn current_version_is_unchanged() {
+        let current = manifest(FORMAT_NAME, FORMAT_VERSION);
+        assert_eq!(migrate(current.clone()).unwrap(), current);
+    }
+
+    #[test]
+    fn rejects_other_formats_and_versions() {
+        assert!(matches!(
+            migrate(manifest("krita", FORMAT_VERSION)),
+            Err(CyanError::NotADocument)
+        ));
+        assert!(matches!(
+            migrate(manifest(FORMAT_NAME, FORMAT_VERSION + 1)),
+            Err(CyanError::UnsupportedVersion(v)) if v == FORMAT_VERSION + 1
+        ));
+        assert!(matches!(
+            migrate(manifest(FORMAT_NAME, 0)),
+            Err(CyanError::Manifest(_))
+        ));
+    }
+
+    /// What a bump to version 3 would look like, say version 2 renamed
+    /// `width` and `height` to a `size` pair and version 3 added `dpi`.
+    #[test]
+    fn upgrades_one_version_at_a_time() {
+        let upgrade = |version: u32, mut manifest: toml::Table| match version {
+            1 => {
+                let width = manifest.remove("width").unwrap();
+                let height = manifest.remove("height").unwrap();
+                manifest.insert("size".to_string(), vec![width, height].into());
+                Ok(manifest)
+            }
+            2 => {
+                assert!(manifest.contains_key("size"));
+                manifest.insert("dpi".to_string(), 72i64.into());
+                Ok(manifest)
+            }
+            v => Err(CyanError::Manifest(format!("unknown version {v}"))),
+        };
+
+        let migrated = migrate_to(manifest(FORMAT_NAME, 1), 3, upgrade).unwrap();
+        assert_eq!(version(&migrated), 3);
+        assert_eq!(migrated["size"].as_array().unwrap().len(), 2);
+        assert_eq!(migrated["dpi"].as_integer(), Some(72));
+        assert!(!migrated.contains_key("width"));
+
+        // Files already at an intermediate version only take the later steps.
+        let mut partial = manifest(FORMAT_NAME, 2);
+        partial.insert("size".to_string(), vec![64i64, 64].into());
+        let migrated = migrate_to(partial, 3, upgrade).unwrap();
+        assert_eq!(version(&migrated), 3);
+        assert_eq!(migrated["dpi"].as_integer(), Some(72));
+    }
+}
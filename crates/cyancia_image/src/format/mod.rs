@@ -0,0 +1,127 @@
+//! Reading and writing documents in file formats that keep the layer structure.
+
+use std::{future::Future, path::Path, pin::Pin};
+
+use crate::{CImage, tile::GpuTileStorage};
+
+pub mod cyan;
+pub mod ora;
+pub mod psd;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FormatError {
+    #[error(transparent)]
+    Cyan(#[from] cyan::CyanError),
+    #[error(transparent)]
+    Ora(#[from] ora::OraError),
+    #[error(transparent)]
+    Psd(#[from] psd::PsdError),
+    #[error("Saving as {0} is not supported")]
+    ReadOnly(&'static str),
+}
+
+/// Parts of an imported document that have no equivalent and were imported
+/// differently or left out, to be shown to the user.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    pub warnings: Vec<String>,
+}
+
+impl ImportReport {
+    pub fn is_empty(&self) -> bool {
+        self.warnings.is_empty()
+    }
+
+    pub fn warn(&mut self, warning: String) {
+        log::warn!("{warning}");
+        self.warnings.push(warning);
+    }
+}
+
+impl std::fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for warning in &self.warnings {
+            writeln!(f, "{warning}")?;
+        }
+        Ok(())
+    }
+}
+
+pub type SaveFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>, FormatError>> + Send>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DocumentFormat {
+    Cyan,
+    Ora,
+    Psd,
+}
+
+impl DocumentFormat {
+    pub const ALL: [DocumentFormat; 3] = [
+        DocumentFormat::Cyan,
+        DocumentFormat::Ora,
+        DocumentFormat::Psd,
+    ];
+
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|f| f.extensions().contains(&ext.as_str()))
+    }
+
+    /// The preferred extension.
+    pub fn extension(self) -> &'static str {
+        self.extensions()[0]
+    }
+
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            DocumentFormat::Cyan => &[cyan::EXTENSION],
+            DocumentFormat::Ora => &[ora::EXTENSION],
+            DocumentFormat::Psd => &[psd::EXTENSION, psd::LARGE_EXTENSION],
+        }
+    }
+
+    /// Whether documents can be written in this format, not just read.
+    pub fn can_save(self) -> bool {
+        !matches!(self, DocumentFormat::Psd)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DocumentFormat::Cyan => "Cyancia Document",
+            DocumentFormat::Ora => "OpenRaster",
+            DocumentFormat::Psd => "Photoshop Document",
+        }
+    }
+
+    /// Decodes a document along with what couldn't be imported as is.
+    pub fn load(
+        self,
+        bytes: &[u8],
+        tiles: &GpuTileStorage,
+    ) -> Result<(CImage, ImportReport), FormatError> {
+        Ok(match self {
+            DocumentFormat::Cyan => (cyan::load(bytes, tiles)?, ImportReport::default()),
+            DocumentFormat::Ora => (ora::load(bytes, tiles)?, ImportReport::default()),
+            DocumentFormat::Psd => psd::load(bytes, tiles)?,
+        })
+    }
+
+    pub fn save(self, image: &CImage, tiles: &GpuTileStorage) -> SaveFuture {
+        match self {
+            DocumentFormat::Cyan => {
+                let future = cyan::save(image, tiles);
+                Box::pin(async move { Ok(future.await?) })
+            }
+            DocumentFormat::Ora => {
+                let future = ora::save(image, tiles);
+                Box::pin(async move { Ok(future.await?) })
+            }
+            DocumentFormat::Psd => {
+                Box::pin(std::future::ready(Err(FormatError::ReadOnly(self.name()))))
+            }
+        }
+    }
+}
@@ -0,0 +1,426 @@
+//! OpenRaster, the layered interchange format shared by most open source painting
+//! programs.
+//!
+//! Layers are stored as PNG files, listed in `stack.xml` from top to bottom. Pixels
+//! are written as 8 bit straight alpha, which is what other programs expect.
+
+use std::{
+    future::Future,
+    io::{Cursor, Read, Write},
+};
+
+use cyancia_id::Id;
+use glam::{IVec2, UVec2};
+use image::{DynamicImage, ImageFormat, Rgba32FImage};
+use quick_xml::{
+    Reader, Writer,
+    events::{BytesDecl, BytesEnd, BytesStart, Event},
+};
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    CImage,
+    blend::{BlendMode, GroupIsolation},
+    layer::{Layer, LayerError, LayerLocks},
+    readback::ReadbackError,
+    tile::GpuTileStorage,
+};
+
+pub const EXTENSION: &str = "ora";
+pub const MIMETYPE: &str = "image/openraster";
+pub const SPEC_VERSION: &str = "0.0.6";
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+const MIMETYPE_ENTRY: &str = "mimetype";
+const STACK_ENTRY: &str = "stack.xml";
+const MERGED_ENTRY: &str = "mergedimage.png";
+const THUMBNAIL_ENTRY: &str = "Thumbnails/thumbnail.png";
+
+#[derive(Debug, thiserror::Error)]
+pub enum OraError {
+    #[error("Not an OpenRaster file")]
+    NotOpenRaster,
+    #[error("Malformed stack.xml: {0}")]
+    Stack(String),
+    #[error("Invalid value {1:?} for attribute {0}")]
+    Attribute(&'static str, String),
+    #[error("Layer image {0} is missing")]
+    MissingLayerImage(String),
+    #[error(transparent)]
+    Xml(#[from] quick_xml::Error),
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+    #[error(transparent)]
+    Layer(#[from] LayerError),
+    #[error(transparent)]
+    Readback(#[from] ReadbackError),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl From<quick_xml::events::attributes::AttrError> for OraError {
+    fn from(value: quick_xml::events::attributes::AttrError) -> Self {
+        Self::Xml(value.into())
+    }
+}
+
+/// Name of the blend mode in the `composite-op` attribute. Modes outside of the
+/// spec use our own prefix, other programs fall back to `svg:src-over` for them.
+pub fn composite_op(mode: BlendMode) -> &'static str {
+    match mode {
+        BlendMode::Normal => "svg:src-over",
+        BlendMode::Multiply => "svg:multiply",
+        BlendMode::Screen => "svg:screen",
+        BlendMode::Overlay => "svg:overlay",
+        BlendMode::Add => "svg:plus",
+        BlendMode::Subtract => "cyancia:subtract",
+        BlendMode::Darken => "svg:darken",
+        BlendMode::Lighten => "svg:lighten",
+        BlendMode::ColorDodge => "svg:color-dodge",
+        BlendMode::ColorBurn => "svg:color-burn",
+        BlendMode::SoftLight => "svg:soft-light",
+        BlendMode::HardLight => "svg:hard-light",
+        BlendMode::Difference => "svg:difference",
+        BlendMode::Hue => "svg:hue",
+        BlendMode::Saturation => "svg:saturation",
+        BlendMode::Color => "svg:color",
+        BlendMode::Luminosity => "svg:luminosity",
+    }
+}
+
+pub fn blend_mode_from_composite_op(op: &str) -> Option<BlendMode> {
+    BlendMode::ALL
+        .into_iter()
+        .find(|mode| composite_op(*mode) == op)
+}
+
+pub fn load(bytes: &[u8], tiles: &GpuTileStorage) -> Result<CImage, OraError> {
+    let mut zip = ZipArchive::new(Cursor::new(bytes))?;
+
+    let mimetype = read_entry(&mut zip, MIMETYPE_ENTRY).map_err(|_| OraError::NotOpenRaster)?;
+    if mimetype.trim_ascii() != MIMETYPE.as_bytes() {
+        return Err(OraError::NotOpenRaster);
+    }
+
+    let stack = read_entry(&mut zip, STACK_ENTRY)?;
+    let mut reader = Reader::from_reader(stack.as_slice());
+    reader.config_mut().trim_text(true);
+
+    let mut image = None::<CImage>;
+    // Groups currently open, the outermost `<stack>` being the root.
+    let mut open_groups = Vec::<Id<Layer>>::new();
+    let mut buf = Vec::new();
+    loop {
+        let event = reader.read_event_into(&mut buf)?;
+        let (element, is_empty) = match &event {
+            Event::Start(e) => (e, false),
+            Event::Empty(e) => (e, true),
+            Event::End(e) => {
+                if e.name().as_ref() == b"stack" {
+                    open_groups.pop();
+                }
+                buf.clear();
+                continue;
+            }
+            Event::Eof => break,
+            _ => {
+                buf.clear();
+                continue;
+            }
+        };
+        let attrs = Attributes::parse(element)?;
+
+        match element.name().as_ref() {
+            b"image" => {
+                let w = attrs.parse_or("w", 0u32)?;
+                let h = attrs.parse_or("h", 0u32)?;
+                image = Some(CImage::empty(UVec2::new(w, h)));
+            }
+            b"stack" => {
+                let image = image
+                    .as_mut()
+                    .ok_or_else(|| OraError::Stack("stack outside of image".to_string()))?;
+                let Some(parent) = open_groups.last().copied() else {
+                    // The outermost stack is the root group.
+                    if !is_empty {
+                        open_groups.push(image.root_id());
+                    }
+                    buf.clear();
+                    continue;
+                };
+
+                let mut group = Layer::group(attrs.get("name").unwrap_or("Group"));
+                attrs.apply_properties(&mut group)?;
+                let isolation = match attrs.get("isolation") {
+                    Some("isolate") => GroupIsolation::Isolated,
+                    // Groups with a blend mode of their own need isolation to
+                    // render as intended.
+                    _ if group.properties.blend_mode != BlendMode::Normal => {
+                        GroupIsolation::Isolated
+                    }
+                    _ => GroupIsolation::PassThrough,
+                };
+                group.set_isolation(isolation);
+
+                // Elements are listed from top to bottom.
+                let id = image.insert_layer(group, parent, 0)?;
+                if !is_empty {
+                    open_groups.push(id);
+                }
+            }
+            b"layer" => {
+                let image = image
+                    .as_mut()
+                    .ok_or_else(|| OraError::Stack("layer outside of image".to_string()))?;
+                let parent = *open_groups
+                    .last()
+                    .ok_or_else(|| OraError::Stack("layer outside of stack".to_string()))?;
+
+                let mut layer = Layer::paint(attrs.get("name").unwrap_or("Layer"));
+                attrs.apply_properties(&mut layer)?;
+                let offset = IVec2::new(attrs.parse_or("x", 0)?, attrs.parse_or("y", 0)?);
+
+                if let Some(src) = attrs.get("src") {
+                    let png = read_entry(&mut zip, src)
+                        .map_err(|_| OraError::MissingLayerImage(src.to_string()))?;
+                    let pixels = image::load_from_memory(&png)?.into_rgba32f();
+                    tiles.upload_image_at(layer.id(), &pixels, offset, image.size());
+                }
+
+                image.insert_layer(layer, parent, 0)?;
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    image.ok_or_else(|| OraError::Stack("missing image element".to_string()))
+}
+
+/// Encodes the document along with the merged image and thumbnail. Tiles are read
+/// back from the GPU first, so the image can be unlocked while the future runs.
+pub fn save(
+    image: &CImage,
+    tiles: &GpuTileStorage,
+) -> impl Future<Output = Result<Vec<u8>, OraError>> + Send + 'static {
+    let snapshot = image.clone();
+    let readbacks = image
+        .descendants(image.root_id())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|id| !image.layers[id].is_group())
+        .map(|id| (id, tiles.read_layer_tiles(id)))
+        .collect::<Vec<_>>();
+    let merged = image.read_flattened(tiles);
+
+    async move {
+        let image = snapshot;
+        let mut layer_pngs = std::collections::HashMap::new();
+        for (i, (id, readback)) in readbacks.into_iter().enumerate() {
+            let tiles = readback.await?;
+            if let Some((pixels, offset)) = crop_layer(&tiles, image.size()) {
+                let name = format!("data/layer{i}.png");
+                layer_pngs.insert(id, (name, encode_png(pixels)?, offset));
+            }
+        }
+
+        let merged = DynamicImage::ImageRgba32F(merged.await?);
+        let thumbnail = merged.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+
+        let mut xml = Writer::new_with_indent(Cursor::new(Vec::new()), b' ', 2);
+        xml.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+        let size = image.size();
+        xml.write_event(Event::Start(BytesStart::new("image").with_attributes([
+            ("version", SPEC_VERSION),
+            ("w", &size.x.to_string()),
+            ("h", &size.y.to_string()),
+        ])))?;
+        xml.write_event(Event::Start(BytesStart::new("stack")))?;
+        write_children(&mut xml, &image, image.root(), &layer_pngs)?;
+        xml.write_event(Event::End(BytesEnd::new("stack")))?;
+        xml.write_event(Event::End(BytesEnd::new("image")))?;
+
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(MIMETYPE_ENTRY, stored)?;
+        zip.write_all(MIMETYPE.as_bytes())?;
+        zip.start_file(STACK_ENTRY, deflated)?;
+        zip.write_all(&xml.into_inner().into_inner())?;
+        // PNG data is already compressed.
+        for (name, png, _) in layer_pngs.values() {
+            zip.start_file(name.as_str(), stored)?;
+            zip.write_all(png)?;
+        }
+        zip.start_file(MERGED_ENTRY, stored)?;
+        zip.write_all(&encode_png(merged.into_rgba32f())?)?;
+        zip.start_file(THUMBNAIL_ENTRY, stored)?;
+        zip.write_all(&encode_png(thumbnail.into_rgba32f())?)?;
+
+        Ok(zip.finish()?.into_inner())
+    }
+}
+
+fn write_children(
+    xml: &mut Writer<Cursor<Vec<u8>>>,
+    image: &CImage,
+    group: &Layer,
+    layer_pngs: &std::collections::HashMap<Id<Layer>, (String, Vec<u8>, UVec2)>,
+) -> Result<(), OraError> {
+    for child in group.children().iter().rev() {
+        let layer = &image.layers[child];
+        let properties = &layer.properties;
+        let opacity = properties.opacity.to_string();
+        let mut attrs = vec![
+            ("name", properties.name.as_str()),
+            ("opacity", &opacity),
+            (
+                "visibility",
+                if properties.visible {
+                    "visible"
+                } else {
+                    "hidden"
+                },
+            ),
+            ("composite-op", composite_op(properties.blend_mode)),
+        ];
+        if properties.locks.contains(LayerLocks::PIXELS) {
+            attrs.push(("edit-locked", "true"));
+        }
+
+        match layer.isolation() {
+            Some(isolation) => {
+                attrs.push((
+                    "isolation",
+                    match isolation {
+                        GroupIsolation::PassThrough => "auto",
+                        GroupIsolation::Isolated => "isolate",
+                    },
+                ));
+                xml.write_event(Event::Start(
+                    BytesStart::new("stack").with_attributes(attrs),
+                ))?;
+                write_children(xml, image, layer, layer_pngs)?;
+                xml.write_event(Event::End(BytesEnd::new("stack")))?;
+            }
+            None => {
+                let (x, y);
+                if let Some((src, _, offset)) = layer_pngs.get(child) {
+                    x = offset.x.to_string();
+                    y = offset.y.to_string();
+                    attrs.extend([("src", src.as_str()), ("x", &x), ("y", &y)]);
+                }
+                xml.write_event(Event::Empty(
+                    BytesStart::new("layer").with_attributes(attrs),
+                ))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Assembles the tiles of a layer into an image covering only the non-empty tiles,
+/// clipped to the canvas. Returns the image along with its offset.
+fn crop_layer(tiles: &[(UVec2, Vec<u8>)], canvas: UVec2) -> Option<(Rgba32FImage, UVec2)> {
+    let tiles = tiles
+        .iter()
+        .filter(|(_, data)| !GpuTileStorage::is_transparent(data))
+        .collect::<Vec<_>>();
+    let min = tiles.iter().map(|(index, _)| *index).reduce(UVec2::min)? * GpuTileStorage::TILE_SIZE;
+    let max =
+        (tiles.iter().map(|(index, _)| *index).reduce(UVec2::max)? + 1) * GpuTileStorage::TILE_SIZE;
+    let max = max.min(canvas);
+    if min.cmpge(max).any() {
+        return None;
+    }
+
+    let size = max - min;
+    let mut pixels = Rgba32FImage::new(size.x, size.y);
+    let texel_bytes = GpuTileStorage::TILE_PIXEL_BYTES as usize;
+    for (index, data) in tiles {
+        let origin = index * GpuTileStorage::TILE_SIZE;
+        for (i, texel) in data.chunks_exact(texel_bytes).enumerate() {
+            let p = origin
+                + UVec2::new(
+                    i as u32 % GpuTileStorage::TILE_SIZE,
+                    i as u32 / GpuTileStorage::TILE_SIZE,
+                );
+            if p.cmpge(max).any() {
+                continue;
+            }
+            let px = pixels.get_pixel_mut(p.x - min.x, p.y - min.y);
+            for (c, channel) in texel.chunks_exact(2).enumerate() {
+                px.0[c] = half::f16::from_le_bytes([channel[0], channel[1]]).to_f32();
+            }
+        }
+    }
+
+    Some((pixels, min))
+}
+
+fn encode_png(pixels: Rgba32FImage) -> Result<Vec<u8>, OraError> {
+    let mut png = Cursor::new(Vec::new());
+    DynamicImage::ImageRgba32F(pixels)
+        .to_rgba8()
+        .write_to(&mut png, ImageFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+fn read_entry(zip: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Vec<u8>, OraError> {
+    let mut file = zip.by_name(name)?;
+    let mut data = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Unescaped attributes of a `stack.xml` element.
+struct Attributes(Vec<(String, String)>);
+
+impl Attributes {
+    fn parse(element: &BytesStart) -> Result<Self, OraError> {
+        let mut attrs = Vec::new();
+        for attr in element.attributes() {
+            let attr = attr?;
+            attrs.push((
+                String::from_utf8_lossy(attr.key.as_ref()).into_owned(),
+                attr.unescape_value()?.into_owned(),
+            ));
+        }
+        Ok(Self(attrs))
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn parse_or<T: std::str::FromStr>(&self, key: &'static str, default: T) -> Result<T, OraError> {
+        match self.get(key) {
+            Some(value) => value
+                .trim()
+                .parse()
+                .map_err(|_| OraError::Attribute(key, value.to_string())),
+            None => Ok(default),
+        }
+    }
+
+    fn apply_properties(&self, layer: &mut Layer) -> Result<(), OraError> {
+        let properties = &mut layer.properties;
+        properties.opacity = self.parse_or("opacity", 1.0f32)?.clamp(0.0, 1.0);
+        properties.visible = self.get("visibility") != Some("hidden");
+        if let Some(op) = self.get("composite-op") {
+            properties.blend_mode = blend_mode_from_composite_op(op).unwrap_or_else(|| {
+                log::warn!("Unsupported composite-op {op}, falling back to normal.");
+                BlendMode::Normal
+            });
+        }
+        if self.get("edit-locked") == Some("true") {
+            properties.locks |= LayerLocks::PIXELS;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,850 @@
+//! Photoshop documents (`.psd` and `.psb`), import only.
+//!
+//! Raster layers, group folders, opacity, visibility, locks and the blend modes we
+//! have an equivalent for are kept. Layer masks are baked into the alpha of their
+//! layer since layers don't carry masks. Features without an equivalent, like
+//! adjustment layers or masks on groups, are skipped and listed in the
+//! [`ImportReport`], while files that can't be represented at all are rejected
+//! with an error.
+
+use std::io::Read;
+
+use flate2::read::ZlibDecoder;
+use glam::{IVec2, UVec2};
+use image::Rgba32FImage;
+
+use crate::{
+    CImage,
+    blend::{BlendMode, GroupIsolation},
+    document::ColorProfile,
+    format::ImportReport,
+    layer::{Layer, LayerError, LayerLocks},
+    tile::GpuTileStorage,
+};
+
+pub const EXTENSION: &str = "psd";
+pub const LARGE_EXTENSION: &str = "psb";
+
+const SIGNATURE: &[u8; 4] = b"8BPS";
+const ICC_PROFILE_RESOURCE: u16 = 1039;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PsdError {
+    #[error("Not a Photoshop document")]
+    NotPsd,
+    #[error("Unsupported Photoshop document version {0}")]
+    UnsupportedVersion(u16),
+    #[error("Unsupported color mode {0}, only RGB and grayscale documents can be opened")]
+    UnsupportedColorMode(u16),
+    #[error("Unsupported bit depth {0}, only 8 and 16 bit channels are supported")]
+    UnsupportedDepth(u16),
+    #[error("Unsupported channel compression {0}")]
+    UnsupportedCompression(u16),
+    #[error("Unexpected end of file while reading {0}")]
+    UnexpectedEof(&'static str),
+    #[error("Malformed run length encoded channel data")]
+    InvalidRle,
+    #[error("Group folders are not balanced")]
+    UnbalancedGroups,
+    #[error(transparent)]
+    Layer(#[from] LayerError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorMode {
+    Grayscale,
+    Rgb,
+}
+
+impl ColorMode {
+    fn channels(self) -> usize {
+        match self {
+            ColorMode::Grayscale => 1,
+            ColorMode::Rgb => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    /// Large document format, with wider length fields.
+    psb: bool,
+    channels: u16,
+    size: UVec2,
+    depth: u16,
+    color_mode: ColorMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Layer,
+    /// Top of a group, closing it when reading bottom up.
+    Folder,
+    /// Hidden marker below the contents of a group.
+    Divider,
+}
+
+#[derive(Debug)]
+struct Mask {
+    top: i32,
+    left: i32,
+    bottom: i32,
+    right: i32,
+    default_color: u8,
+    disabled: bool,
+}
+
+#[derive(Debug)]
+struct LayerRecord {
+    top: i32,
+    left: i32,
+    bottom: i32,
+    right: i32,
+    /// Channel ids along with the length of their data.
+    channels: Vec<(i16, u64)>,
+    blend_key: [u8; 4],
+    opacity: u8,
+    clipping: bool,
+    hidden: bool,
+    name: String,
+    section: Section,
+    mask: Option<Mask>,
+    locks: LayerLocks,
+    /// Additional information keys we can't represent.
+    unsupported: Option<&'static str>,
+}
+
+impl LayerRecord {
+    fn size(&self) -> UVec2 {
+        UVec2::new(
+            (self.right - self.left).max(0) as u32,
+            (self.bottom - self.top).max(0) as u32,
+        )
+    }
+}
+
+/// A layer record with its decoded pixels.
+#[derive(Debug)]
+struct PsdLayer {
+    record: LayerRecord,
+    pixels: Option<Rgba32FImage>,
+}
+
+enum Node {
+    Layer(usize),
+    Group(usize, Vec<Node>),
+}
+
+pub fn load(bytes: &[u8], tiles: &GpuTileStorage) -> Result<(CImage, ImportReport), PsdError> {
+    let mut r = ByteReader::new(bytes);
+    let header = read_header(&mut r)?;
+
+    let color_mode_len = r.u32("color mode data")? as usize;
+    r.skip(color_mode_len, "color mode data")?;
+
+    let resources_len = r.u32("image resources")? as usize;
+    let resources = r.bytes(resources_len, "image resources")?;
+    let icc = find_icc_profile(resources);
+
+    let layer_section_len = r.length(header.psb, "layer and mask information")? as usize;
+    let layer_section = r.bytes(layer_section_len, "layer and mask information")?;
+    let layers = read_layers(layer_section, &header)?;
+
+    let mut image = CImage::empty(header.size);
+    if let Some(data) = icc {
+        image.set_color_profile(ColorProfile::Icc {
+            name: "Embedded".to_string(),
+            data,
+        });
+    }
+    let mut report = ImportReport::default();
+
+    if layers.is_empty() {
+        // Documents with a single background layer only carry the merged image.
+        let pixels = read_merged_image(&mut r, &header)?;
+        let root = image.root_id();
+        let id = image.insert_layer(Layer::paint("Background"), root, 0)?;
+        tiles.upload_image_at(id, &pixels, IVec2::ZERO, header.size);
+        return Ok((image, report));
+    }
+
+    let tree = build_tree(&layers)?;
+    let root = image.root_id();
+    let inserted = insert_nodes(
+        &mut image,
+        tiles,
+        &mut report,
+        root,
+        tree,
+        &mut layers.into_iter().map(Some).collect(),
+    );
+    // Layers inserted before the error already had their tiles uploaded.
+    if let Err(e) = inserted {
+        image.release_tiles(tiles);
+        return Err(e);
+    }
+    Ok((image, report))
+}
+
+fn read_header(r: &mut ByteReader) -> Result<Header, PsdError> {
+    if r.bytes(4, "header")? != SIGNATURE {
+        return Err(PsdError::NotPsd);
+    }
+    let psb = match r.u16("header")? {
+        1 => false,
+        2 => true,
+        v => return Err(PsdError::UnsupportedVersion(v)),
+    };
+    r.skip(6, "header")?;
+    let channels = r.u16("header")?;
+    let height = r.u32("header")?;
+    let width = r.u32("header")?;
+    let depth = r.u16("header")?;
+    if depth != 8 && depth != 16 {
+        return Err(PsdError::UnsupportedDepth(depth));
+    }
+    let color_mode = match r.u16("header")? {
+        1 => ColorMode::Grayscale,
+        3 => ColorMode::Rgb,
+        m => return Err(PsdError::UnsupportedColorMode(m)),
+    };
+
+    Ok(Header {
+        psb,
+        channels,
+        size: UVec2::new(width, height),
+        depth,
+        color_mode,
+    })
+}
+
+fn find_icc_profile(resources: &[u8]) -> Option<Vec<u8>> {
+    let mut r = ByteReader::new(resources);
+    while r.remaining() >= 12 {
+        if r.bytes(4, "image resource").ok()? != b"8BIM" {
+            return None;
+        }
+        let id = r.u16("image resource").ok()?;
+        let name_len = r.u8("image resource").ok()? as usize;
+        // Pascal string padded to an even length, including the length byte.
+        r.skip(name_len + (name_len + 1) % 2, "image resource")
+            .ok()?;
+        let len = r.u32("image resource").ok()? as usize;
+        let data = r.bytes(len, "image resource").ok()?;
+        r.skip(len % 2, "image resource").ok()?;
+        if id == ICC_PROFILE_RESOURCE {
+            return Some(data.to_vec());
+        }
+    }
+    None
+}
+
+fn read_layers(section: &[u8], header: &Header) -> Result<Vec<PsdLayer>, PsdError> {
+    if section.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut r = ByteReader::new(section);
+    let layer_info_len = r.length(header.psb, "layer info")? as usize;
+    let layer_info = r.bytes(layer_info_len, "layer info")?;
+    if !layer_info.is_empty() {
+        return read_layer_info(layer_info, header);
+    }
+
+    // 16 bit documents keep their layers in a tagged block after the global mask.
+    let global_mask_len = r.u32("global layer mask")? as usize;
+    r.skip(global_mask_len, "global layer mask")?;
+    for (key, data) in tagged_blocks(&mut r, header.psb)? {
+        if &key == b"Lr16" || &key == b"Lr32" {
+            return read_layer_info(data, header);
+        }
+    }
+    Ok(Vec::new())
+}
+
+fn read_layer_info(data: &[u8], header: &Header) -> Result<Vec<PsdLayer>, PsdError> {
+    let mut r = ByteReader::new(data);
+    let count = r.i16("layer count")?.unsigned_abs() as usize;
+
+    let mut records = Vec::with_capacity(count);
+    for _ in 0..count {
+        records.push(read_layer_record(&mut r, header)?);
+    }
+
+    let mut layers = Vec::with_capacity(count);
+    for record in records {
+        let pixels = read_layer_pixels(&mut r, &record, header)?;
+        layers.push(PsdLayer { record, pixels });
+    }
+    Ok(layers)
+}
+
+fn read_layer_record(r: &mut ByteReader, header: &Header) -> Result<LayerRecord, PsdError> {
+    let top = r.i32("layer record")?;
+    let left = r.i32("layer record")?;
+    let bottom = r.i32("layer record")?;
+    let right = r.i32("layer record")?;
+
+    let channel_count = r.u16("layer record")? as usize;
+    let mut channels = Vec::with_capacity(channel_count);
+    for _ in 0..channel_count {
+        let id = r.i16("layer record")?;
+        let len = r.length(header.psb, "layer record")?;
+        channels.push((id, len));
+    }
+
+    if r.bytes(4, "layer record")? != b"8BIM" {
+        return Err(PsdError::NotPsd);
+    }
+    let blend_key = r.array("layer record")?;
+    let opacity = r.u8("layer record")?;
+    let clipping = r.u8("layer record")? != 0;
+    let flags = r.u8("layer record")?;
+    r.skip(1, "layer record")?;
+
+    let extra_len = r.u32("layer record")? as usize;
+    let mut extra = ByteReader::new(r.bytes(extra_len, "layer record")?);
+
+    let mask_len = extra.u32("layer mask")? as usize;
+    let mask_data = extra.bytes(mask_len, "layer mask")?;
+    let mask = (mask_len >= 18)
+        .then(|| {
+            let mut m = ByteReader::new(mask_data);
+            Ok::<_, PsdError>(Mask {
+                top: m.i32("layer mask")?,
+                left: m.i32("layer mask")?,
+                bottom: m.i32("layer mask")?,
+                right: m.i32("layer mask")?,
+                default_color: m.u8("layer mask")?,
+                disabled: m.u8("layer mask")? & 0b10 != 0,
+            })
+        })
+        .transpose()?;
+
+    let blending_ranges_len = extra.u32("blending ranges")? as usize;
+    extra.skip(blending_ranges_len, "blending ranges")?;
+
+    let name_len = extra.u8("layer name")? as usize;
+    let mut name = String::from_utf8_lossy(extra.bytes(name_len, "layer name")?).into_owned();
+    // Pascal string padded to a multiple of 4, including the length byte.
+    extra.skip((4 - (name_len + 1) % 4) % 4, "layer name")?;
+
+    let mut record = LayerRecord {
+        top,
+        left,
+        bottom,
+        right,
+        channels,
+        blend_key,
+        opacity,
+        clipping,
+        hidden: flags & 0b10 != 0,
+        name: String::new(),
+        section: Section::Layer,
+        mask,
+        locks: LayerLocks::empty(),
+        unsupported: None,
+    };
+
+    for (key, data) in tagged_blocks(&mut extra, header.psb)? {
+        let mut d = ByteReader::new(data);
+        match &key {
+            b"luni" => {
+                let len = d.u32("unicode layer name")? as usize;
+                let units = (0..len)
+                    .map(|_| d.u16("unicode layer name"))
+                    .collect::<Result<Vec<_>, _>>()?;
+                name = String::from_utf16_lossy(&units)
+                    .trim_end_matches('\0')
+                    .to_string();
+            }
+            b"lsct" | b"lsdk" => {
+                record.section = match d.u32("section divider")? {
+                    1 | 2 => Section::Folder,
+                    3 => Section::Divider,
+                    _ => Section::Layer,
+                };
+                if d.remaining() >= 8 && d.bytes(4, "section divider")? == b"8BIM" {
+                    record.blend_key = d.array("section divider")?;
+                }
+            }
+            b"lspf" => {
+                let flags = d.u32("layer protection")?;
+                record.locks.set(LayerLocks::ALPHA, flags & 0b001 != 0);
+                record.locks.set(LayerLocks::PIXELS, flags & 0b010 != 0);
+                record.locks.set(LayerLocks::POSITION, flags & 0b100 != 0);
+            }
+            b"SoCo" | b"GdFl" | b"PtFl" => record.unsupported = Some("fill layer"),
+            b"levl" | b"curv" | b"brit" | b"hue2" | b"hue " | b"blnc" | b"vibA" | b"mixr"
+            | b"selc" | b"phfl" | b"expA" | b"grdm" | b"nvrt" | b"post" | b"thrs" | b"clrL"
+            | b"blwh" => record.unsupported = Some("adjustment layer"),
+            _ => {}
+        }
+    }
+
+    record.name = name;
+    Ok(record)
+}
+
+/// Key and data of an additional information block.
+type TaggedBlock<'a> = ([u8; 4], &'a [u8]);
+
+/// Collects the `8BIM` tagged blocks until the data runs out.
+fn tagged_blocks<'a>(r: &mut ByteReader<'a>, psb: bool) -> Result<Vec<TaggedBlock<'a>>, PsdError> {
+    const WIDE_KEYS: [&[u8; 4]; 13] = [
+        b"LMsk", b"Lr16", b"Lr32", b"Layr", b"Mt16", b"Mt32", b"Mtrn", b"Alph", b"FMsk", b"lnk2",
+        b"FEid", b"FXid", b"PxSD",
+    ];
+
+    let mut blocks = Vec::new();
+    while r.remaining() >= 12 {
+        // Blocks may be padded, skip to the next signature.
+        while r.remaining() >= 12 && !matches!(r.peek(4), Some(b"8BIM" | b"8B64")) {
+            r.skip(1, "tagged block")?;
+        }
+        if r.remaining() < 12 {
+            break;
+        }
+        r.skip(4, "tagged block")?;
+        let key = r.array("tagged block")?;
+        let len = if psb && WIDE_KEYS.contains(&&key) {
+            r.u64("tagged block")?
+        } else {
+            r.u32("tagged block")? as u64
+        } as usize;
+        blocks.push((key, r.bytes(len, "tagged block")?));
+    }
+    Ok(blocks)
+}
+
+fn read_layer_pixels(
+    r: &mut ByteReader,
+    record: &LayerRecord,
+    header: &Header,
+) -> Result<Option<Rgba32FImage>, PsdError> {
+    let size = record.size();
+    let mut pixels = (record.section == Section::Layer && size.x > 0 && size.y > 0)
+        .then(|| Rgba32FImage::from_pixel(size.x, size.y, image::Rgba([0.0, 0.0, 0.0, 1.0])));
+    let color_channels = header.color_mode.channels();
+
+    for (id, len) in &record.channels {
+        let data = r.bytes(*len as usize, "channel image data")?;
+        let Some(pixels) = &mut pixels else {
+            continue;
+        };
+
+        let (channel_size, target) = match *id {
+            -1 => (size, Some(3)),
+            0..=2 if (*id as usize) < color_channels => (size, Some(*id as usize)),
+            -2 => match &record.mask {
+                Some(mask) => (
+                    UVec2::new(
+                        (mask.right - mask.left).max(0) as u32,
+                        (mask.bottom - mask.top).max(0) as u32,
+                    ),
+                    None,
+                ),
+                None => continue,
+            },
+            _ => continue,
+        };
+        if channel_size.x == 0 || channel_size.y == 0 {
+            continue;
+        }
+
+        let values = decode_layer_channel(data, channel_size, header)?;
+        match target {
+            Some(c) => {
+                for (px, value) in pixels.pixels_mut().zip(&values) {
+                    px.0[c] = *value;
+                }
+            }
+            None => {
+                if let Some(mask) = &record.mask
+                    && !mask.disabled
+                {
+                    apply_mask(pixels, record, mask, &values);
+                }
+            }
+        }
+    }
+
+    if let Some(pixels) = &mut pixels
+        && header.color_mode == ColorMode::Grayscale
+    {
+        for px in pixels.pixels_mut() {
+            px.0[1] = px.0[0];
+            px.0[2] = px.0[0];
+        }
+    }
+
+    Ok(pixels)
+}
+
+/// Multiplies the alpha of the layer with the mask. Outside of the mask rectangle
+/// the default color of the mask applies.
+fn apply_mask(pixels: &mut Rgba32FImage, record: &LayerRecord, mask: &Mask, values: &[f32]) {
+    let mask_width = mask.right - mask.left;
+    let default = mask.default_color as f32 / 255.0;
+    for (x, y, px) in pixels.enumerate_pixels_mut() {
+        let mx = record.left + x as i32 - mask.left;
+        let my = record.top + y as i32 - mask.top;
+        let inside = mx >= 0 && my >= 0 && mx < mask_width && my < mask.bottom - mask.top;
+        let value = if inside {
+            values[(my * mask_width + mx) as usize]
+        } else {
+            default
+        };
+        px.0[3] *= value;
+    }
+}
+
+fn decode_layer_channel(data: &[u8], size: UVec2, header: &Header) -> Result<Vec<f32>, PsdError> {
+    let mut r = ByteReader::new(data);
+    let compression = r.u16("channel image data")?;
+    let bytes_per_sample = header.depth as usize / 8;
+    let row_bytes = size.x as usize * bytes_per_sample;
+    let raw_len = row_bytes * size.y as usize;
+
+    let raw = match compression {
+        0 => r.bytes(raw_len, "channel image data")?.to_vec(),
+        1 => {
+            let counts = (0..size.y)
+                .map(|_| r.row_count(header.psb, "channel image data"))
+                .collect::<Result<Vec<_>, _>>()?;
+            decode_rle_rows(&mut r, &counts, row_bytes)?
+        }
+        2 | 3 => {
+            let mut raw = Vec::with_capacity(raw_len);
+            ZlibDecoder::new(r.rest()).read_to_end(&mut raw)?;
+            if raw.len() < raw_len {
+                return Err(PsdError::UnexpectedEof("compressed channel image data"));
+            }
+            if compression == 3 {
+                undo_prediction(&mut raw, row_bytes, header.depth);
+            }
+            raw
+        }
+        c => return Err(PsdError::UnsupportedCompression(c)),
+    };
+
+    Ok(samples_to_f32(&raw[..raw_len], header.depth))
+}
+
+fn read_merged_image(r: &mut ByteReader, header: &Header) -> Result<Rgba32FImage, PsdError> {
+    let size = header.size;
+    let color_channels = header.color_mode.channels();
+    let channels = (header.channels as usize).min(color_channels + 1);
+    let bytes_per_sample = header.depth as usize / 8;
+    let row_bytes = size.x as usize * bytes_per_sample;
+
+    let compression = r.u16("merged image data")?;
+    let planes = match compression {
+        0 => (0..channels)
+            .map(|_| {
+                r.bytes(row_bytes * size.y as usize, "merged image data")
+                    .map(|b| b.to_vec())
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        1 => {
+            // Row lengths of every channel come first, followed by the rows.
+            let counts = (0..header.channels as usize * size.y as usize)
+                .map(|_| r.row_count(header.psb, "merged image data"))
+                .collect::<Result<Vec<_>, _>>()?;
+            counts
+                .chunks(size.y as usize)
+                .take(channels)
+                .map(|counts| decode_rle_rows(r, counts, row_bytes))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+        c => return Err(PsdError::UnsupportedCompression(c)),
+    };
+
+    let mut pixels = Rgba32FImage::from_pixel(size.x, size.y, image::Rgba([0.0, 0.0, 0.0, 1.0]));
+    for (c, plane) in planes.iter().enumerate() {
+        let target = if c < color_channels { c } else { 3 };
+        for (px, value) in pixels.pixels_mut().zip(samples_to_f32(plane, header.depth)) {
+            px.0[target] = value;
+        }
+    }
+    if header.color_mode == ColorMode::Grayscale {
+        for px in pixels.pixels_mut() {
+            px.0[1] = px.0[0];
+            px.0[2] = px.0[0];
+        }
+    }
+    Ok(pixels)
+}
+
+/// PackBits encoded rows.
+fn decode_rle_rows(
+    r: &mut ByteReader,
+    counts: &[u64],
+    row_bytes: usize,
+) -> Result<Vec<u8>, PsdError> {
+    let mut out = Vec::with_capacity(row_bytes * counts.len());
+    for count in counts {
+        let mut row = ByteReader::new(r.bytes(*count as usize, "run length encoded row")?);
+        let start = out.len();
+        while row.remaining() > 0 && out.len() - start < row_bytes {
+            let n = row.u8("run length encoded row")? as i8;
+            match n {
+                0.. => out.extend_from_slice(row.bytes(n as usize + 1, "run length encoded row")?),
+                -127..=-1 => {
+                    let value = row.u8("run length encoded row")?;
+                    out.extend(std::iter::repeat_n(value, (1 - n as isize) as usize));
+                }
+                -128 => {}
+            }
+        }
+        if out.len() - start != row_bytes {
+            return Err(PsdError::InvalidRle);
+        }
+    }
+    Ok(out)
+}
+
+/// Reverses the per row delta encoding of compression mode 3.
+fn undo_prediction(raw: &mut [u8], row_bytes: usize, depth: u16) {
+    for row in raw.chunks_exact_mut(row_bytes) {
+        match depth {
+            8 => {
+                for i in 1..row.len() {
+                    row[i] = row[i].wrapping_add(row[i - 1]);
+                }
+            }
+            _ => {
+                let mut prev = 0u16;
+                for sample in row.chunks_exact_mut(2) {
+                    prev = prev.wrapping_add(u16::from_be_bytes([sample[0], sample[1]]));
+                    sample.copy_from_slice(&prev.to_be_bytes());
+                }
+            }
+        }
+    }
+}
+
+fn samples_to_f32(raw: &[u8], depth: u16) -> Vec<f32> {
+    match depth {
+        8 => raw.iter().map(|v| *v as f32 / 255.0).collect(),
+        _ => raw
+            .chunks_exact(2)
+            .map(|v| u16::from_be_bytes([v[0], v[1]]) as f32 / 65535.0)
+            .collect(),
+    }
+}
+
+/// Rebuilds the group hierarchy. Records are listed from bottom to top, a group
+/// starting at its divider and ending at its folder record.
+fn build_tree(layers: &[PsdLayer]) -> Result<Vec<Node>, PsdError> {
+    let mut stack = vec![Vec::new()];
+    for (i, layer) in layers.iter().enumerate() {
+        match layer.record.section {
+            Section::Layer => stack.last_mut().unwrap().push(Node::Layer(i)),
+            Section::Divider => stack.push(Vec::new()),
+            Section::Folder => {
+                if stack.len() < 2 {
+                    return Err(PsdError::UnbalancedGroups);
+                }
+                let children = stack.pop().unwrap();
+                stack.last_mut().unwrap().push(Node::Group(i, children));
+            }
+        }
+    }
+    if stack.len() != 1 {
+        return Err(PsdError::UnbalancedGroups);
+    }
+    Ok(stack.pop().unwrap())
+}
+
+fn insert_nodes(
+    image: &mut CImage,
+    tiles: &GpuTileStorage,
+    report: &mut ImportReport,
+    parent: cyancia_id::Id<Layer>,
+    nodes: Vec<Node>,
+    layers: &mut Vec<Option<PsdLayer>>,
+) -> Result<(), PsdError> {
+    for node in nodes {
+        let (index, children) = match node {
+            Node::Layer(i) => (i, None),
+            Node::Group(i, children) => (i, Some(children)),
+        };
+        let Some(PsdLayer { record, pixels }) = layers[index].take() else {
+            continue;
+        };
+
+        if let Some(feature) = record.unsupported {
+            report.warn(format!(
+                "Layer {:?} is a {}, which is not supported. It is imported as an empty layer.",
+                record.name, feature
+            ));
+        }
+        if record.clipping {
+            report.warn(format!(
+                "Clipping of layer {:?} is not supported, it is imported unclipped.",
+                record.name
+            ));
+        }
+
+        let mut layer = match children {
+            Some(_) => Layer::group(record.name.clone()),
+            None => Layer::paint(record.name.clone()),
+        };
+        layer.properties.opacity = record.opacity as f32 / 255.0;
+        layer.properties.visible = !record.hidden;
+        layer.properties.locks = record.locks;
+
+        let blend_mode = blend_mode_from_key(&record.blend_key);
+        match blend_mode {
+            Some(mode) => layer.properties.blend_mode = mode,
+            None if &record.blend_key == b"pass" => {}
+            None => report.warn(format!(
+                "Blend mode {:?} of layer {:?} is not supported, falling back to normal.",
+                String::from_utf8_lossy(&record.blend_key),
+                record.name
+            )),
+        }
+
+        let index = image.layer(parent)?.children().len();
+        match children {
+            Some(children) => {
+                if record.mask.is_some() {
+                    report.warn(format!(
+                        "Mask of group {:?} is not supported, it is ignored.",
+                        record.name
+                    ));
+                }
+                layer.set_isolation(if &record.blend_key == b"pass" {
+                    GroupIsolation::PassThrough
+                } else {
+                    GroupIsolation::Isolated
+                });
+                let id = image.insert_layer(layer, parent, index)?;
+                insert_nodes(image, tiles, report, id, children, layers)?;
+            }
+            None => {
+                // Inserted first, so the tiles are never left without a layer.
+                let id = image.insert_layer(layer, parent, index)?;
+                if let Some(pixels) = pixels {
+                    tiles.upload_image_at(
+                        id,
+                        &pixels,
+                        IVec2::new(record.left, record.top),
+                        image.size(),
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn blend_mode_from_key(key: &[u8; 4]) -> Option<BlendMode> {
+    Some(match key {
+        b"norm" => BlendMode::Normal,
+        b"mul " => BlendMode::Multiply,
+        b"scrn" => BlendMode::Screen,
+        b"over" => BlendMode::Overlay,
+        b"lddg" => BlendMode::Add,
+        b"fsub" => BlendMode::Subtract,
+        b"dark" => BlendMode::Darken,
+        b"lite" => BlendMode::Lighten,
+        b"div " => BlendMode::ColorDodge,
+        b"idiv" => BlendMode::ColorBurn,
+        b"sLit" => BlendMode::SoftLight,
+        b"hLit" => BlendMode::HardLight,
+        b"diff" => BlendMode::Difference,
+        b"hue " => BlendMode::Hue,
+        b"sat " => BlendMode::Saturation,
+        b"colr" => BlendMode::Color,
+        b"lum " => BlendMode::Luminosity,
+        _ => return None,
+    })
+}
+
+/// Big endian reader over a byte slice.
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn peek(&self, len: usize) -> Option<&'a [u8]> {
+        self.pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos..];
+        self.pos = self.data.len();
+        rest
+    }
+
+    fn bytes(&mut self, len: usize, what: &'static str) -> Result<&'a [u8], PsdError> {
+        let bytes = self.peek(len).ok_or(PsdError::UnexpectedEof(what))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize, what: &'static str) -> Result<(), PsdError> {
+        self.bytes(len, what).map(|_| ())
+    }
+
+    fn array<const N: usize>(&mut self, what: &'static str) -> Result<[u8; N], PsdError> {
+        Ok(self.bytes(N, what)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self, what: &'static str) -> Result<u8, PsdError> {
+        Ok(self.array::<1>(what)?[0])
+    }
+
+    fn u16(&mut self, what: &'static str) -> Result<u16, PsdError> {
+        Ok(u16::from_be_bytes(self.array(what)?))
+    }
+
+    fn i16(&mut self, what: &'static str) -> Result<i16, PsdError> {
+        Ok(i16::from_be_bytes(self.array(what)?))
+    }
+
+    fn u32(&mut self, what: &'static str) -> Result<u32, PsdError> {
+        Ok(u32::from_be_bytes(self.array(what)?))
+    }
+
+    fn i32(&mut self, what: &'static str) -> Result<i32, PsdError> {
+        Ok(i32::from_be_bytes(self.array(what)?))
+    }
+
+    fn u64(&mut self, what: &'static str) -> Result<u64, PsdError> {
+        Ok(u64::from_be_bytes(self.array(what)?))
+    }
+
+    /// Section lengths are twice as wide in PSB files.
+    fn length(&mut self, psb: bool, what: &'static str) -> Result<u64, PsdError> {
+        if psb {
+            self.u64(what)
+        } else {
+            self.u32(what).map(u64::from)
+        }
+    }
+
+    /// Byte counts of run length encoded rows, twice as wide in PSB files.
+    fn row_count(&mut self, psb: bool, what: &'static str) -> Result<u64, PsdError> {
+        if psb {
+            self.u32(what).map(u64::from)
+        } else {
+            self.u16(what).map(u64::from)
+        }
+    }
+}
@@ -0,0 +1,845 @@
+//! Undo history of a document.
+//!
+//! Pixel edits are recorded per tile. Before a tile is modified for the first
+//! time in an edit, it is copied into a snapshot layer that only exists in the
+//! tile storage. Undoing swaps the tiles of the layer with its snapshot, which
+//! turns the entry into its own redo. Structural edits store their inverse in the
+//! same way. Once the snapshots exceed the memory cap, the oldest ones are
+//! spilled to disk.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use cyancia_id::Id;
+use glam::UVec2;
+use uuid::Uuid;
+
+use crate::{
+    CImage,
+    backing::{CpuTile, TileCompression},
+    layer::{Layer, LayerError, LayerProperties},
+    readback::ReadbackError,
+    tile::GpuTileStorage,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum HistoryError {
+    #[error("Spilled history file {0:?} is corrupted")]
+    CorruptedSpill(PathBuf),
+    #[error(transparent)]
+    Layer(#[from] LayerError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Readback(#[from] ReadbackError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryConfig {
+    /// Bytes of tile snapshots kept in memory before older entries are spilled.
+    pub memory_cap: u64,
+    /// Entries beyond this count are dropped, oldest first.
+    pub max_entries: usize,
+    /// Where spilled entries are written. Without one, entries over the memory
+    /// cap are dropped instead.
+    pub spill_dir: Option<PathBuf>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            memory_cap: 1024 * 1024 * 1024,
+            max_entries: 200,
+            spill_dir: Some(
+                std::env::temp_dir().join(format!("cyancia-history-{}", Uuid::new_v4().simple())),
+            ),
+        }
+    }
+}
+
+/// A structural edit of the layer tree.
+#[derive(Debug, Clone)]
+pub enum LayerCommand {
+    Insert {
+        layer: Layer,
+        parent: Id<Layer>,
+        index: usize,
+    },
+    /// Removes the layer along with its descendants.
+    Remove { id: Id<Layer> },
+    /// Deep copies the layer right above it, see [`CImage::duplicate_layer`].
+    Duplicate { id: Id<Layer> },
+    /// Moves the layer into `parent` at `index`, see [`CImage::move_layer`].
+    Move {
+        id: Id<Layer>,
+        parent: Id<Layer>,
+        index: usize,
+    },
+    SetProperties {
+        id: Id<Layer>,
+        properties: LayerProperties,
+    },
+}
+
+impl LayerCommand {
+    fn name(&self) -> &'static str {
+        match self {
+            LayerCommand::Insert { .. } => "Add Layer",
+            LayerCommand::Remove { .. } => "Remove Layer",
+            LayerCommand::Duplicate { .. } => "Duplicate Layer",
+            LayerCommand::Move { .. } => "Move Layer",
+            LayerCommand::SetProperties { .. } => "Layer Properties",
+        }
+    }
+}
+
+/// A change that can be applied to the document, turning into its inverse.
+#[derive(Debug)]
+enum Change {
+    Tiles(TileChange),
+    /// Puts layers that are not part of the document back in.
+    Attach {
+        root: Layer,
+        descendants: Vec<Layer>,
+        parent: Id<Layer>,
+        index: usize,
+    },
+    Detach {
+        id: Id<Layer>,
+    },
+    Move {
+        id: Id<Layer>,
+        parent: Id<Layer>,
+        index: usize,
+    },
+    Properties {
+        id: Id<Layer>,
+        properties: LayerProperties,
+    },
+}
+
+impl Change {
+    fn apply(&mut self, image: &mut CImage, tiles: &GpuTileStorage) -> Result<(), HistoryError> {
+        let inverse = match self {
+            Change::Tiles(change) => {
+                change.unspill(tiles)?;
+                tiles.swap_tiles(change.layer, change.snapshot, &change.indices);
+                return Ok(());
+            }
+            Change::Attach {
+                root,
+                descendants,
+                parent,
+                index,
+            } => {
+                let id =
+                    image.restore_layers(root.clone(), descendants.clone(), *parent, *index)?;
+                Change::Detach { id }
+            }
+            Change::Detach { id } => {
+                let parent = image.layer(*id)?.parent().ok_or(LayerError::RootLayer)?;
+                let index = image.z_order(*id)?;
+                let mut removed = image.remove_layer(*id)?.into_iter();
+                Change::Attach {
+                    root: removed
+                        .next()
+                        .expect("Removed layers start with the layer itself."),
+                    descendants: removed.collect(),
+                    parent,
+                    index,
+                }
+            }
+            Change::Move { id, parent, index } => {
+                let old_parent = image.layer(*id)?.parent().ok_or(LayerError::RootLayer)?;
+                let old_index = image.z_order(*id)?;
+                image.move_layer(*id, *parent, *index)?;
+                Change::Move {
+                    id: *id,
+                    parent: old_parent,
+                    index: old_index,
+                }
+            }
+            Change::Properties { id, properties } => {
+                std::mem::swap(&mut image.layer_mut(*id)?.properties, properties);
+                return Ok(());
+            }
+        };
+        *self = inverse;
+        Ok(())
+    }
+
+    /// Bytes of tile snapshots held in memory.
+    fn memory(&self, tiles: &GpuTileStorage) -> u64 {
+        match self {
+            Change::Tiles(change) if change.spill.is_none() => {
+                change
+                    .indices
+                    .iter()
+                    .filter(|index| tiles.has_tile(change.snapshot, **index))
+                    .count() as u64
+                    * GpuTileStorage::TILE_BYTES
+            }
+            _ => 0,
+        }
+    }
+
+    /// Frees whatever only this change keeps alive.
+    fn release(self, tiles: &GpuTileStorage) {
+        match self {
+            Change::Tiles(change) => {
+                tiles.free_layer(change.snapshot);
+                if let Some(path) = change.spill
+                    && let Err(e) = fs::remove_file(&path)
+                {
+                    log::warn!("Unable to remove spilled history file {:?}: {}", path, e);
+                }
+            }
+            Change::Attach {
+                root, descendants, ..
+            } => {
+                for layer in std::iter::once(root).chain(descendants) {
+                    if !layer.is_group() {
+                        tiles.free_layer(layer.id());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TileChange {
+    layer: Id<Layer>,
+    /// Holds the other side of the swap.
+    snapshot: Id<Layer>,
+    indices: Vec<UVec2>,
+    spill: Option<PathBuf>,
+}
+
+impl TileChange {
+    /// Moves the snapshot tiles into a file, see [`encode_spill`].
+    fn spill(&mut self, dir: &Path, tiles: &GpuTileStorage) -> Result<(), HistoryError> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.tiles", self.snapshot.simple()));
+        let taken = tiles.take_layer(self.snapshot)?;
+        let data = encode_spill(&taken);
+
+        if let Err(e) = fs::File::create(&path).and_then(|mut f| f.write_all(&data)) {
+            for (index, tile) in taken {
+                tiles.backing.insert(self.snapshot, index, tile);
+            }
+            return Err(e.into());
+        }
+        self.spill = Some(path);
+        Ok(())
+    }
+
+    fn unspill(&mut self, tiles: &GpuTileStorage) -> Result<(), HistoryError> {
+        let Some(path) = &self.spill else {
+            return Ok(());
+        };
+
+        let mut data = Vec::new();
+        fs::File::open(path)?.read_to_end(&mut data)?;
+        let restored =
+            decode_spill(&data).ok_or_else(|| HistoryError::CorruptedSpill(path.clone()))?;
+
+        for (index, tile) in restored {
+            tiles.backing.insert(self.snapshot, index, tile);
+        }
+        if let Err(e) = fs::remove_file(path) {
+            log::warn!("Unable to remove spilled history file {:?}: {}", path, e);
+        }
+        self.spill = None;
+        Ok(())
+    }
+}
+
+/// Layout of each tile in a spilled file, little endian: index x and y as u32,
+/// compression as u8, data length as u64, data.
+fn encode_spill(tiles: &[(UVec2, CpuTile)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (index, tile) in tiles {
+        data.extend(index.x.to_le_bytes());
+        data.extend(index.y.to_le_bytes());
+        data.push(match tile.compression() {
+            TileCompression::None => 0,
+            TileCompression::Lz4 => 1,
+        });
+        data.extend((tile.stored_data().len() as u64).to_le_bytes());
+        data.extend(tile.stored_data());
+    }
+    data
+}
+
+/// Tiles of a spilled file, `None` if it's cut short or malformed.
+fn decode_spill(data: &[u8]) -> Option<Vec<(UVec2, CpuTile)>> {
+    let mut tiles = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (header, tail) = rest.split_at_checked(17)?;
+        let index = UVec2::new(
+            u32::from_le_bytes(header[0..4].try_into().unwrap()),
+            u32::from_le_bytes(header[4..8].try_into().unwrap()),
+        );
+        let compression = match header[8] {
+            0 => TileCompression::None,
+            1 => TileCompression::Lz4,
+            _ => return None,
+        };
+        let len = u64::from_le_bytes(header[9..17].try_into().unwrap()) as usize;
+        let (stored, tail) = tail.split_at_checked(len)?;
+        tiles.push((index, CpuTile::from_stored(stored.to_vec(), compression)));
+        rest = tail;
+    }
+    Some(tiles)
+}
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    change: Change,
+    memory: u64,
+}
+
+/// A pixel edit that is still being recorded.
+#[derive(Debug)]
+struct PendingTiles {
+    name: String,
+    layer: Id<Layer>,
+    snapshot: Id<Layer>,
+    indices: HashSet<UVec2>,
+}
+
+#[derive(Debug, Default)]
+pub struct History {
+    config: HistoryConfig,
+    /// Oldest entries at the front.
+    undo: VecDeque<Entry>,
+    /// Next entry to redo at the back.
+    redo: Vec<Entry>,
+    pending: Option<PendingTiles>,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig) -> Self {
+        Self {
+            config,
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            pending: None,
+        }
+    }
+
+    pub fn config(&self) -> &HistoryConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: HistoryConfig, tiles: &GpuTileStorage) {
+        self.config = config;
+        self.enforce_limits(tiles);
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.pending.is_some() || !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn undo_name(&self) -> Option<&str> {
+        match &self.pending {
+            Some(pending) => Some(&pending.name),
+            None => self.undo.back().map(|e| e.name.as_str()),
+        }
+    }
+
+    pub fn redo_name(&self) -> Option<&str> {
+        self.redo.last().map(|e| e.name.as_str())
+    }
+
+    /// Bytes of tile snapshots held in memory.
+    pub fn memory(&self) -> u64 {
+        self.undo.iter().chain(&self.redo).map(|e| e.memory).sum()
+    }
+
+    /// Snapshots tiles of `layer` before they are modified. Tiles already recorded
+    /// in the open edit are skipped, so this can be called for every dab of a
+    /// stroke. Recording on another layer commits the open edit first.
+    pub fn record_tiles(
+        &mut self,
+        tiles: &GpuTileStorage,
+        name: &str,
+        layer: Id<Layer>,
+        indices: impl IntoIterator<Item = UVec2>,
+    ) {
+        if self.pending.as_ref().is_some_and(|p| p.layer != layer) {
+            self.commit(tiles);
+        }
+
+        let pending = self.pending.get_or_insert_with(|| PendingTiles {
+            name: name.to_string(),
+            layer,
+            snapshot: Id::random(),
+            indices: HashSet::new(),
+        });
+        let new = indices
+            .into_iter()
+            .filter(|index| pending.indices.insert(*index))
+            .collect::<Vec<_>>();
+        if !new.is_empty() {
+            tiles.copy_tiles(layer, pending.snapshot, &new);
+        }
+    }
+
+    /// Closes the open pixel edit, making it a single undo step.
+    pub fn commit(&mut self, tiles: &GpuTileStorage) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        if pending.indices.is_empty() {
+            return;
+        }
+
+        let change = Change::Tiles(TileChange {
+            layer: pending.layer,
+            snapshot: pending.snapshot,
+            indices: pending.indices.into_iter().collect(),
+            spill: None,
+        });
+        self.push(pending.name, change, tiles);
+    }
+
+    /// Puts the tiles of the open pixel edit back as they were and drops the
+    /// edit, as if it never happened.
+    pub fn discard(&mut self, tiles: &GpuTileStorage) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let indices = pending.indices.into_iter().collect::<Vec<_>>();
+        tiles.swap_tiles(pending.layer, pending.snapshot, &indices);
+        tiles.free_layer(pending.snapshot);
+    }
+
+    /// Applies the command to the image and records it. Returns the layer the
+    /// command acted on, which is the copy for [`LayerCommand::Duplicate`].
+    pub fn execute(
+        &mut self,
+        image: &mut CImage,
+        tiles: &GpuTileStorage,
+        command: LayerCommand,
+    ) -> Result<Id<Layer>, HistoryError> {
+        self.commit(tiles);
+
+        let name = command.name().to_string();
+        let (id, mut change) = match command {
+            LayerCommand::Insert {
+                layer,
+                parent,
+                index,
+            } => (
+                layer.id(),
+                Change::Attach {
+                    root: layer,
+                    descendants: Vec::new(),
+                    parent,
+                    index,
+                },
+            ),
+            LayerCommand::Remove { id } => (id, Change::Detach { id }),
+            LayerCommand::Duplicate { id } => {
+                // Applied right away, undoing it takes the copy out again.
+                let copy = image.duplicate_layer(id, tiles)?;
+                self.push(name, Change::Detach { id: copy }, tiles);
+                return Ok(copy);
+            }
+            LayerCommand::Move { id, parent, index } => (id, Change::Move { id, parent, index }),
+            LayerCommand::SetProperties { id, properties } => {
+                (id, Change::Properties { id, properties })
+            }
+        };
+        change.apply(image, tiles)?;
+        self.push(name, change, tiles);
+        Ok(id)
+    }
+
+    /// Reverts the last entry. Returns whether there was anything to undo.
+    pub fn undo(
+        &mut self,
+        image: &mut CImage,
+        tiles: &GpuTileStorage,
+    ) -> Result<bool, HistoryError> {
+        self.commit(tiles);
+        let Some(mut entry) = self.undo.pop_back() else {
+            return Ok(false);
+        };
+
+        if let Err(e) = entry.change.apply(image, tiles) {
+            self.undo.push_back(entry);
+            return Err(e);
+        }
+        entry.memory = entry.change.memory(tiles);
+        self.redo.push(entry);
+        self.enforce_limits(tiles);
+        Ok(true)
+    }
+
+    /// Applies the last undone entry again. Returns whether there was anything to
+    /// redo.
+    pub fn redo(
+        &mut self,
+        image: &mut CImage,
+        tiles: &GpuTileStorage,
+    ) -> Result<bool, HistoryError> {
+        self.commit(tiles);
+        let Some(mut entry) = self.redo.pop() else {
+            return Ok(false);
+        };
+
+        if let Err(e) = entry.change.apply(image, tiles) {
+            self.redo.push(entry);
+            return Err(e);
+        }
+        entry.memory = entry.change.memory(tiles);
+        self.undo.push_back(entry);
+        self.enforce_limits(tiles);
+        Ok(true)
+    }
+
+    /// Drops every entry. Used when the document is closed.
+    pub fn clear(&mut self, tiles: &GpuTileStorage) {
+        if let Some(pending) = self.pending.take() {
+            tiles.free_layer(pending.snapshot);
+        }
+        for entry in self.undo.drain(..).chain(self.redo.drain(..)) {
+            entry.change.release(tiles);
+        }
+    }
+
+    fn push(&mut self, name: String, change: Change, tiles: &GpuTileStorage) {
+        for entry in self.redo.drain(..) {
+            entry.change.release(tiles);
+        }
+
+        let memory = change.memory(tiles);
+        self.undo.push_back(Entry {
+            name,
+            change,
+            memory,
+        });
+        self.enforce_limits(tiles);
+    }
+
+    fn enforce_limits(&mut self, tiles: &GpuTileStorage) {
+        while self.undo.len() + self.redo.len() > self.config.max_entries {
+            let Some(entry) = self.undo.pop_front() else {
+                break;
+            };
+            entry.change.release(tiles);
+        }
+
+        let mut memory = self.memory();
+        if memory <= self.config.memory_cap {
+            return;
+        }
+
+        let Some(dir) = &self.config.spill_dir else {
+            while memory > self.config.memory_cap {
+                let Some(entry) = self.undo.pop_front() else {
+                    break;
+                };
+                memory -= entry.memory;
+                entry.change.release(tiles);
+            }
+            return;
+        };
+
+        // Oldest undo entries first, then the redo entries furthest away.
+        for entry in self.undo.iter_mut().chain(self.redo.iter_mut()) {
+            if memory <= self.config.memory_cap {
+                break;
+            }
+            let Change::Tiles(change) = &mut entry.change else {
+                continue;
+            };
+            if change.spill.is_some() {
+                continue;
+            }
+
+            if let Err(e) = change.spill(dir, tiles) {
+                log::error!(
+                    "Unable to spill history entry {:?} to disk: {}",
+                    entry.name,
+                    e
+                );
+                break;
+            }
+            memory -= entry.memory;
+            entry.memory = 0;
+        }
+    }
+}
+
+impl Drop for History {
+    fn drop(&mut self) {
+        for entry in self.undo.iter().chain(&self.redo) {
+            if let Change::Tiles(TileChange {
+                spill: Some(path), ..
+            }) = &entry.change
+            {
+                let _ = fs::remove_file(path);
+            }
+        }
+        if let Some(dir) = &self.config.spill_dir {
+            // Only succeeds once empty, other documents may share the directory.
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tile::tests::{filled, storage};
+
+    use super::*;
+
+    fn image() -> (CImage, Id<Layer>) {
+        let mut image = CImage::empty(UVec2::splat(256));
+        let root = image.root_id();
+        let layer = image.insert_layer(Layer::paint("a"), root, 0).unwrap();
+        (image, layer)
+    }
+
+    fn set_opacity(
+        history: &mut History,
+        image: &mut CImage,
+        tiles: &GpuTileStorage,
+        id: Id<Layer>,
+        opacity: f32,
+    ) {
+        let mut properties = image.layer(id).unwrap().properties.clone();
+        properties.opacity = opacity;
+        history
+            .execute(image, tiles, LayerCommand::SetProperties { id, properties })
+            .unwrap();
+    }
+
+    /// Fills the first tile of the layer as a single edit.
+    fn paint(history: &mut History, tiles: &GpuTileStorage, layer: Id<Layer>, value: f32) {
+        history.record_tiles(tiles, "Paint", layer, [UVec2::ZERO]);
+        tiles.write_tile_data(layer, UVec2::ZERO, &filled(value));
+        history.commit(tiles);
+    }
+
+    fn texels(tiles: &GpuTileStorage, layer: Id<Layer>) -> Vec<u8> {
+        let tile = tiles.get_tile(layer, UVec2::ZERO);
+        futures::executor::block_on(tiles.read_tiles(&[tile]))
+            .unwrap()
+            .remove(0)
+    }
+
+    fn spill_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("cyancia-history-test-{}", Uuid::new_v4().simple()))
+    }
+
+    #[test]
+    fn undo_and_redo_walk_the_entries_in_order() {
+        let Some(tiles) = storage() else {
+            return;
+        };
+        let (mut image, layer) = image();
+        let mut history = History::default();
+        set_opacity(&mut history, &mut image, &tiles, layer, 0.5);
+        set_opacity(&mut history, &mut image, &tiles, layer, 0.25);
+        let opacity = |image: &CImage| image.layer(layer).unwrap().properties.opacity;
+
+        assert_eq!(history.undo_name(), Some("Layer Properties"));
+        assert!(history.undo(&mut image, &tiles).unwrap());
+        assert_eq!(opacity(&image), 0.5);
+        assert!(history.undo(&mut image, &tiles).unwrap());
+        assert_eq!(opacity(&image), 1.0);
+        assert!(!history.undo(&mut image, &tiles).unwrap());
+
+        assert!(history.redo(&mut image, &tiles).unwrap());
+        assert_eq!(opacity(&image), 0.5);
+        assert!(history.redo(&mut image, &tiles).unwrap());
+        assert_eq!(opacity(&image), 0.25);
+        assert!(!history.redo(&mut image, &tiles).unwrap());
+    }
+
+    #[test]
+    fn new_edit_drops_the_redo_entries() {
+        let Some(tiles) = storage() else {
+            return;
+        };
+        let (mut image, layer) = image();
+        let mut history = History::default();
+        set_opacity(&mut history, &mut image, &tiles, layer, 0.5);
+        history.undo(&mut image, &tiles).unwrap();
+        assert!(history.can_redo());
+
+        paint(&mut history, &tiles, layer, 0.75);
+        assert!(!history.can_redo());
+        assert_eq!(history.undo_name(), Some("Paint"));
+        assert!(!history.redo(&mut image, &tiles).unwrap());
+    }
+
+    #[test]
+    fn pixel_edits_swap_the_recorded_tiles() {
+        let Some(tiles) = storage() else {
+            return;
+        };
+        let (mut image, layer) = image();
+        let mut history = History::default();
+        tiles.write_tile_data(layer, UVec2::ZERO, &filled(0.25));
+        paint(&mut history, &tiles, layer, 0.75);
+        assert_eq!(history.memory(), GpuTileStorage::TILE_BYTES);
+
+        history.undo(&mut image, &tiles).unwrap();
+        assert_eq!(texels(&tiles, layer), filled(0.25));
+        history.redo(&mut image, &tiles).unwrap();
+        assert_eq!(texels(&tiles, layer), filled(0.75));
+    }
+
+    #[test]
+    fn discarded_edit_puts_the_tiles_back() {
+        let Some(tiles) = storage() else {
+            return;
+        };
+        let (_, layer) = image();
+        let mut history = History::default();
+        tiles.write_tile_data(layer, UVec2::ZERO, &filled(0.25));
+        history.record_tiles(&tiles, "Paint", layer, [UVec2::ZERO]);
+        tiles.write_tile_data(layer, UVec2::ZERO, &filled(0.75));
+        assert!(history.can_undo());
+
+        history.discard(&tiles);
+        assert!(!history.can_undo());
+        assert_eq!(texels(&tiles, layer), filled(0.25));
+    }
+
+    #[test]
+    fn entries_over_the_memory_cap_are_spilled_and_restored() {
+        let Some(tiles) = storage() else {
+            return;
+        };
+        let (mut image, layer) = image();
+        let dir = spill_dir();
+        let mut history = History::new(HistoryConfig {
+            memory_cap: GpuTileStorage::TILE_BYTES,
+            max_entries: 10,
+            spill_dir: Some(dir.clone()),
+        });
+        tiles.write_tile_data(layer, UVec2::ZERO, &filled(0.25));
+        paint(&mut history, &tiles, layer, 0.5);
+        paint(&mut history, &tiles, layer, 0.75);
+
+        // The older edit went to disk.
+        assert_eq!(history.memory(), GpuTileStorage::TILE_BYTES);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        history.undo(&mut image, &tiles).unwrap();
+        assert_eq!(texels(&tiles, layer), filled(0.5));
+        history.undo(&mut image, &tiles).unwrap();
+        assert_eq!(texels(&tiles, layer), filled(0.25));
+
+        history.redo(&mut image, &tiles).unwrap();
+        history.redo(&mut image, &tiles).unwrap();
+        assert_eq!(texels(&tiles, layer), filled(0.75));
+
+        drop(history);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn entries_over_the_memory_cap_are_dropped_without_a_spill_dir() {
+        let Some(tiles) = storage() else {
+            return;
+        };
+        let (mut image, layer) = image();
+        let mut history = History::new(HistoryConfig {
+            memory_cap: GpuTileStorage::TILE_BYTES,
+            max_entries: 10,
+            spill_dir: None,
+        });
+        tiles.write_tile_data(layer, UVec2::ZERO, &filled(0.25));
+        paint(&mut history, &tiles, layer, 0.5);
+        paint(&mut history, &tiles, layer, 0.75);
+
+        assert_eq!(history.memory(), GpuTileStorage::TILE_BYTES);
+        assert!(history.undo(&mut image, &tiles).unwrap());
+        assert!(!history.undo(&mut image, &tiles).unwrap());
+    }
+
+    #[test]
+    fn oldest_entries_over_the_count_are_dropped() {
+        let Some(tiles) = storage() else {
+            return;
+        };
+        let (mut image, layer) = image();
+        let mut history = History::new(HistoryConfig {
+            max_entries: 2,
+            spill_dir: None,
+            ..Default::default()
+        });
+        for opacity in [0.75, 0.5, 0.25] {
+            set_opacity(&mut history, &mut image, &tiles, layer, opacity);
+        }
+
+        assert!(history.undo(&mut image, &tiles).unwrap());
+        assert!(history.undo(&mut image, &tiles).unwrap());
+        assert!(!history.undo(&mut image, &tiles).unwrap());
+        assert_eq!(image.layer(layer).unwrap().properties.opacity, 0.75);
+    }
+
+    #[test]
+    fn spill_format_round_trips() {
+        let tiles = vec![
+            (
+                UVec2::new(1, 2),
+                CpuTile::new(&filled(0.5), TileCompression::Lz4),
+            ),
+            (
+                UVec2::new(u32::MAX, 0),
+                CpuTile::new(&[1, 2, 3], TileCompression::None),
+            ),
+        ];
+        let data = encode_spill(&tiles);
+        // Both headers plus the data.
+        assert_eq!(
+            data.len(),
+            2 * 17 + tiles[0].1.stored_size() + tiles[1].1.stored_size()
+        );
+        assert_eq!(&data[17 + tiles[0].1.stored_size() + 8..][..1], [0]);
+
+        let decoded = decode_spill(&data).unwrap();
+        assert_eq!(decoded.len(), 2);
+        for ((index, tile), (decoded_index, decoded_tile)) in tiles.iter().zip(&decoded) {
+            assert_eq!(index, decoded_index);
+            assert_eq!(tile.compression(), decoded_tile.compression());
+            assert_eq!(tile.decode(), decoded_tile.decode());
+        }
+        assert!(decode_spill(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_spills_are_rejected() {
+        let data = encode_spill(&[(
+            UVec2::ZERO,
+            CpuTile::new(&[1, 2, 3, 4], TileCompression::None),
+        )]);
+        // Cut short in the header and in the data.
+        assert!(decode_spill(&data[..10]).is_none());
+        assert!(decode_spill(&data[..data.len() - 1]).is_none());
+
+        let mut unknown = data.clone();
+        unknown[8] = 7;
+        assert!(decode_spill(&unknown).is_none());
+    }
+}
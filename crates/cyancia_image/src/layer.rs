@@ -1,33 +1,155 @@
 use cyancia_id::Id;
-use glam::UVec2;
 use image::DynamicImage;
-use wgpu::TextureFormat;
 
-use crate::tile::GpuTileStorage;
+use crate::{
+    blend::{BlendMode, GroupIsolation},
+    tile::GpuTileStorage,
+};
 
-#[derive(Debug)]
+bitflags::bitflags! {
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct LayerLocks: u8 {
+        /// Pixels of the layer can't be modified.
+        const PIXELS = 1 << 0;
+        /// Transparent pixels stay transparent when painting.
+        const ALPHA = 1 << 1;
+        /// The layer can't be moved, reordered or re-parented.
+        const POSITION = 1 << 2;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerProperties {
+    pub name: String,
+    pub visible: bool,
+    pub opacity: f32,
+    pub blend_mode: BlendMode,
+    pub locks: LayerLocks,
+}
+
+impl LayerProperties {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            visible: true,
+            opacity: 1.0,
+            blend_mode: BlendMode::Normal,
+            locks: LayerLocks::empty(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayerKind {
+    Paint,
+    /// Children are ordered from bottom to top.
+    Group {
+        children: Vec<Id<Layer>>,
+        isolation: GroupIsolation,
+    },
+}
+
+#[derive(Debug, Clone)]
 pub struct Layer {
-    pub id: Id<Layer>,
-    pub size: UVec2,
+    pub(crate) id: Id<Layer>,
+    pub(crate) parent: Option<Id<Layer>>,
+    pub(crate) kind: LayerKind,
+    pub properties: LayerProperties,
 }
 
 impl Layer {
-    pub fn new() -> Self {
+    pub fn paint(name: impl Into<String>) -> Self {
         Self {
             id: Id::random(),
-            size: UVec2::ZERO,
+            parent: None,
+            kind: LayerKind::Paint,
+            properties: LayerProperties::new(name),
         }
     }
 
+    pub fn group(name: impl Into<String>) -> Self {
+        Self {
+            id: Id::random(),
+            parent: None,
+            kind: LayerKind::Group {
+                children: Vec::new(),
+                isolation: GroupIsolation::PassThrough,
+            },
+            properties: LayerProperties::new(name),
+        }
+    }
+
+    pub fn from_image(name: impl Into<String>, img: DynamicImage, tiles: &GpuTileStorage) -> Self {
+        let layer = Self::paint(name);
+        tiles.upload_image(layer.id, img);
+        layer
+    }
+
+    pub fn with_properties(mut self, properties: LayerProperties) -> Self {
+        self.properties = properties;
+        self
+    }
+
     pub fn id(&self) -> Id<Layer> {
         self.id
     }
 
-    pub fn from_image(img: DynamicImage, tiles: &GpuTileStorage) -> Self {
-        let id = Id::random();
-        let size = UVec2::new(img.width(), img.height());
-        tiles.upload_image(id, img);
+    pub fn parent(&self) -> Option<Id<Layer>> {
+        self.parent
+    }
+
+    pub fn kind(&self) -> &LayerKind {
+        &self.kind
+    }
+
+    pub fn is_group(&self) -> bool {
+        matches!(self.kind, LayerKind::Group { .. })
+    }
 
-        Self { id, size }
+    pub fn children(&self) -> &[Id<Layer>] {
+        match &self.kind {
+            LayerKind::Paint => &[],
+            LayerKind::Group { children, .. } => children,
+        }
     }
+
+    pub(crate) fn children_mut(&mut self) -> Option<&mut Vec<Id<Layer>>> {
+        match &mut self.kind {
+            LayerKind::Paint => None,
+            LayerKind::Group { children, .. } => Some(children),
+        }
+    }
+
+    pub fn isolation(&self) -> Option<GroupIsolation> {
+        match &self.kind {
+            LayerKind::Paint => None,
+            LayerKind::Group { isolation, .. } => Some(*isolation),
+        }
+    }
+
+    pub fn set_isolation(&mut self, new_isolation: GroupIsolation) {
+        if let LayerKind::Group { isolation, .. } = &mut self.kind {
+            *isolation = new_isolation;
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LayerError {
+    #[error("Layer not found with id {0:?}")]
+    LayerNotFound(Id<Layer>),
+    #[error("Layer {0:?} is not a group")]
+    NotAGroup(Id<Layer>),
+    #[error("Layer {0:?} is a group, not a paint layer")]
+    NotAPaintLayer(Id<Layer>),
+    #[error("Layer {0:?} already exists")]
+    DuplicatedId(Id<Layer>),
+    #[error("Index {1} is out of range for children of group {0:?}")]
+    IndexOutOfRange(Id<Layer>, usize),
+    #[error("Root layer can't be removed or moved")]
+    RootLayer,
+    #[error("Layer {0:?} can't be moved into its own descendant {1:?}")]
+    CyclicParent(Id<Layer>, Id<Layer>),
+    #[error("Position of layer {0:?} is locked")]
+    PositionLocked(Id<Layer>),
 }
@@ -1,29 +1,69 @@
-use std::path::Path;
+use std::{collections::HashMap, path::Path};
 
+use cyancia_id::Id;
 use glam::UVec2;
 use image::DynamicImage;
 
-use crate::layer::Layer;
+use crate::{
+    document::{ColorProfile, DocumentMetadata},
+    layer::{Layer, LayerError, LayerLocks},
+    selection::Selection,
+    tile::GpuTileStorage,
+};
 
+pub mod backing;
+pub mod blend;
+pub mod document;
+pub mod export;
+pub mod format;
+pub mod history;
 pub mod layer;
+pub mod readback;
+pub mod region;
+pub mod selection;
 pub mod tile;
+pub mod warp;
 
-#[derive(Debug)]
+/// Copied paint layers along with their originals, as `(original, copy)`.
+type LayerCopies = Vec<(Id<Layer>, Id<Layer>)>;
+
+#[derive(Debug, Clone)]
 pub struct CImage {
     size: UVec2,
-    root: Layer,
+    root: Id<Layer>,
+    layers: HashMap<Id<Layer>, Layer>,
+    /// The paint layer tools operate on.
+    active: Option<Id<Layer>>,
+    color_profile: ColorProfile,
+    metadata: DocumentMetadata,
+    selection: Selection,
 }
 
 impl CImage {
     pub fn new(size: UVec2) -> Self {
+        Self::from_layer(size, Layer::paint("Layer 1"))
+    }
+
+    pub fn empty(size: UVec2) -> Self {
+        let root = Layer::group("Root");
         Self {
             size,
-            root: Layer::new(),
+            root: root.id,
+            layers: HashMap::from_iter([(root.id, root)]),
+            active: None,
+            color_profile: ColorProfile::default(),
+            metadata: DocumentMetadata::default(),
+            selection: Selection::default(),
         }
     }
 
-    pub fn from_layer(size: UVec2, root: Layer) -> Self {
-        Self { size, root }
+    pub fn from_layer(size: UVec2, layer: Layer) -> Self {
+        let mut image = Self::empty(size);
+        let root = image.root;
+        image
+            .insert_layer(layer, root, 0)
+            .expect("Inserting into an empty root should never fail.");
+        image
     }
 
     pub fn from_file(path: impl AsRef<Path>) -> image::ImageResult<Self> {
@@ -32,17 +72,510 @@ impl CImage {
 
     pub fn from_dynamic(img: DynamicImage) -> Self {
         let size = UVec2::new(img.width(), img.height());
-        Self {
-            size,
-            root: Layer::new(),
-        }
+        Self::new(size)
     }
 
     pub fn size(&self) -> UVec2 {
         self.size
     }
 
+    pub fn color_profile(&self) -> &ColorProfile {
+        &self.color_profile
+    }
+
+    pub fn set_color_profile(&mut self, color_profile: ColorProfile) {
+        self.color_profile = color_profile;
+    }
+
+    pub fn metadata(&self) -> &DocumentMetadata {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut DocumentMetadata {
+        &mut self.metadata
+    }
+
+    pub fn selection(&self) -> &Selection {
+        &self.selection
+    }
+
+    pub fn selection_mut(&mut self) -> &mut Selection {
+        &mut self.selection
+    }
+
     pub fn root(&self) -> &Layer {
-        &self.root
+        &self.layers[&self.root]
+    }
+
+    pub fn root_id(&self) -> Id<Layer> {
+        self.root
+    }
+
+    pub fn contains(&self, id: Id<Layer>) -> bool {
+        self.layers.contains_key(&id)
+    }
+
+    pub fn layer(&self, id: Id<Layer>) -> Result<&Layer, LayerError> {
+        self.layers.get(&id).ok_or(LayerError::LayerNotFound(id))
+    }
+
+    pub fn layer_mut(&mut self, id: Id<Layer>) -> Result<&mut Layer, LayerError> {
+        self.layers
+            .get_mut(&id)
+            .ok_or(LayerError::LayerNotFound(id))
+    }
+
+    pub fn layers(&self) -> impl Iterator<Item = &Layer> {
+        self.layers.values()
+    }
+
+    /// The layer selected for painting. Falls back to the topmost paint layer if
+    /// none was selected or the selected one is gone.
+    pub fn active_layer(&self) -> Option<Id<Layer>> {
+        self.active
+            .filter(|id| self.layers.get(id).is_some_and(|l| !l.is_group()))
+            .or_else(|| {
+                self.descendants(self.root)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .rev()
+                    .find(|id| !self.layers[id].is_group())
+            })
+    }
+
+    pub fn set_active_layer(&mut self, id: Id<Layer>) -> Result<(), LayerError> {
+        if self.layer(id)?.is_group() {
+            return Err(LayerError::NotAPaintLayer(id));
+        }
+        self.active = Some(id);
+        Ok(())
+    }
+
+    pub fn layer_count(&self) -> usize {
+        // Root is not a real layer.
+        self.layers.len() - 1
+    }
+
+    /// Index of the layer inside its parent, 0 being the bottom-most.
+    pub fn z_order(&self, id: Id<Layer>) -> Result<usize, LayerError> {
+        let parent = self.layer(id)?.parent.ok_or(LayerError::RootLayer)?;
+        Ok(self.layers[&parent]
+            .children()
+            .iter()
+            .position(|c| *c == id)
+            .expect("Layer should exist in children of its parent."))
+    }
+
+    /// Whether the layer and all of its ancestors are visible.
+    pub fn is_visible(&self, id: Id<Layer>) -> bool {
+        let mut current = Some(id);
+        while let Some(id) = current {
+            let Some(layer) = self.layers.get(&id) else {
+                return false;
+            };
+            if !layer.properties.visible {
+                return false;
+            }
+            current = layer.parent;
+        }
+        true
+    }
+
+    /// All descendants of a layer, depth first and from bottom to top. Groups are
+    /// listed before their children.
+    pub fn descendants(&self, id: Id<Layer>) -> Result<Vec<Id<Layer>>, LayerError> {
+        let mut result = Vec::new();
+        self.collect_descendants(self.layer(id)?, &mut result);
+        Ok(result)
+    }
+
+    fn collect_descendants(&self, layer: &Layer, result: &mut Vec<Id<Layer>>) {
+        for child in layer.children() {
+            result.push(*child);
+            self.collect_descendants(&self.layers[child], result);
+        }
+    }
+
+    /// All paint layers that end up on the canvas, from bottom to top.
+    pub fn visible_paint_layers(&self) -> Vec<Id<Layer>> {
+        self.descendants(self.root)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|id| !self.layers[id].is_group() && self.is_visible(*id))
+            .collect()
+    }
+
+    pub fn insert_layer(
+        &mut self,
+        mut layer: Layer,
+        parent: Id<Layer>,
+        index: usize,
+    ) -> Result<Id<Layer>, LayerError> {
+        if self.layers.contains_key(&layer.id) {
+            return Err(LayerError::DuplicatedId(layer.id));
+        }
+
+        let children = self.group_children_mut(parent)?;
+        if index > children.len() {
+            return Err(LayerError::IndexOutOfRange(parent, index));
+        }
+        children.insert(index, layer.id);
+
+        let id = layer.id;
+        layer.parent = Some(parent);
+        self.layers.insert(id, layer);
+        Ok(id)
+    }
+
+    pub fn insert_layer_above(
+        &mut self,
+        layer: Layer,
+        anchor: Id<Layer>,
+    ) -> Result<Id<Layer>, LayerError> {
+        let parent = self.layer(anchor)?.parent.ok_or(LayerError::RootLayer)?;
+        let index = self.z_order(anchor)? + 1;
+        self.insert_layer(layer, parent, index)
+    }
+
+    /// Removes the layer along with all its descendants. The removed layers are
+    /// returned so their tiles can be released by the caller.
+    pub fn remove_layer(&mut self, id: Id<Layer>) -> Result<Vec<Layer>, LayerError> {
+        let parent = self.layer(id)?.parent.ok_or(LayerError::RootLayer)?;
+        self.check_position_unlocked(id)?;
+
+        self.group_children_mut(parent)?.retain(|c| *c != id);
+
+        let mut removed_ids = vec![id];
+        removed_ids.extend(self.descendants(id)?);
+        Ok(removed_ids
+            .into_iter()
+            .filter_map(|id| self.layers.remove(&id))
+            .collect())
+    }
+
+    /// Puts back a subtree taken out by [`CImage::remove_layer`], `root` being the
+    /// first of the removed layers.
+    pub fn restore_layers(
+        &mut self,
+        root: Layer,
+        descendants: Vec<Layer>,
+        parent: Id<Layer>,
+        index: usize,
+    ) -> Result<Id<Layer>, LayerError> {
+        if let Some(layer) = descendants.iter().find(|l| self.layers.contains_key(&l.id)) {
+            return Err(LayerError::DuplicatedId(layer.id));
+        }
+
+        let id = self.insert_layer(root, parent, index)?;
+        self.layers
+            .extend(descendants.into_iter().map(|layer| (layer.id, layer)));
+        Ok(id)
+    }
+
+    /// Moves the layer to `index` inside its current parent.
+    pub fn reorder_layer(&mut self, id: Id<Layer>, index: usize) -> Result<(), LayerError> {
+        let parent = self.layer(id)?.parent.ok_or(LayerError::RootLayer)?;
+        self.move_layer(id, parent, index)
+    }
+
+    /// Moves the layer into `new_parent` at `index`. The index is resolved after
+    /// the layer is taken out of its original parent.
+    pub fn move_layer(
+        &mut self,
+        id: Id<Layer>,
+        new_parent: Id<Layer>,
+        index: usize,
+    ) -> Result<(), LayerError> {
+        let old_parent = self.layer(id)?.parent.ok_or(LayerError::RootLayer)?;
+        self.check_position_unlocked(id)?;
+
+        if new_parent == id || self.descendants(id)?.contains(&new_parent) {
+            return Err(LayerError::CyclicParent(id, new_parent));
+        }
+
+        let new_len = self.group_children_mut(new_parent)?.len();
+        let max_index = if new_parent == old_parent {
+            new_len - 1
+        } else {
+            new_len
+        };
+        if index > max_index {
+            return Err(LayerError::IndexOutOfRange(new_parent, index));
+        }
+
+        self.group_children_mut(old_parent)?.retain(|c| *c != id);
+        self.group_children_mut(new_parent)?.insert(index, id);
+        self.layers.get_mut(&id).unwrap().parent = Some(new_parent);
+        Ok(())
+    }
+
+    /// Deep copies the layer and places the copy right above it.
+    pub fn duplicate_layer(
+        &mut self,
+        id: Id<Layer>,
+        tiles: &GpuTileStorage,
+    ) -> Result<Id<Layer>, LayerError> {
+        let (new_id, copies) = self.duplicate_tree(id)?;
+        for (from, to) in copies {
+            tiles.copy_layer(from, to);
+        }
+        Ok(new_id)
+    }
+
+    /// The layer tree half of [`CImage::duplicate_layer`]. Also returns every
+    /// copied paint layer along with its original, whose tiles are still to be
+    /// copied.
+    fn duplicate_tree(&mut self, id: Id<Layer>) -> Result<(Id<Layer>, LayerCopies), LayerError> {
+        let parent = self.layer(id)?.parent.ok_or(LayerError::RootLayer)?;
+        let index = self.z_order(id)? + 1;
+        let mut copies = Vec::new();
+        let new_id = self.duplicate_subtree(id, parent, &mut copies);
+        self.group_children_mut(parent)?.insert(index, new_id);
+        Ok((new_id, copies))
+    }
+
+    fn duplicate_subtree(
+        &mut self,
+        id: Id<Layer>,
+        parent: Id<Layer>,
+        copies: &mut LayerCopies,
+    ) -> Id<Layer> {
+        let mut layer = self.layers[&id].clone();
+        let new_id = Id::random();
+        layer.id = new_id;
+        layer.parent = Some(parent);
+
+        if let Some(children) = layer.children_mut() {
+            let old_children = std::mem::take(children);
+            let new_children = old_children
+                .into_iter()
+                .map(|child| self.duplicate_subtree(child, new_id, copies))
+                .collect();
+            *layer.children_mut().unwrap() = new_children;
+        } else {
+            copies.push((id, new_id));
+        }
+
+        self.layers.insert(new_id, layer);
+        new_id
+    }
+
+    /// Releases the tiles of every layer. Used when the image is closed.
+    pub fn release_tiles(&self, tiles: &GpuTileStorage) {
+        for layer in self.layers.values().filter(|l| !l.is_group()) {
+            tiles.free_layer(layer.id);
+        }
+    }
+
+    fn group_children_mut(&mut self, id: Id<Layer>) -> Result<&mut Vec<Id<Layer>>, LayerError> {
+        self.layers
+            .get_mut(&id)
+            .ok_or(LayerError::LayerNotFound(id))?
+            .children_mut()
+            .ok_or(LayerError::NotAGroup(id))
+    }
+
+    fn check_position_unlocked(&self, id: Id<Layer>) -> Result<(), LayerError> {
+        if self
+            .layer(id)?
+            .properties
+            .locks
+            .contains(LayerLocks::POSITION)
+        {
+            Err(LayerError::PositionLocked(id))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Root holding three paint layers, bottom to top, and a group above them
+    /// holding a paint layer and a nested group with one more paint layer.
+    struct Tree {
+        image: CImage,
+        paints: [Id<Layer>; 3],
+        group: Id<Layer>,
+        inner: Id<Layer>,
+        group_paint: Id<Layer>,
+        inner_paint: Id<Layer>,
+    }
+
+    fn tree() -> Tree {
+        let mut image = CImage::empty(UVec2::splat(64));
+        let root = image.root_id();
+        let paints = [0, 1, 2].map(|i| {
+            image
+                .insert_layer(Layer::paint(format!("Paint {i}")), root, i)
+                .unwrap()
+        });
+        let group = image.insert_layer(Layer::group("Group"), root, 3).unwrap();
+        let group_paint = image
+            .insert_layer(Layer::paint("Group Paint"), group, 0)
+            .unwrap();
+        let inner = image.insert_layer(Layer::group("Inner"), group, 1).unwrap();
+        let inner_paint = image
+            .insert_layer(Layer::paint("Inner Paint"), inner, 0)
+            .unwrap();
+        Tree {
+            image,
+            paints,
+            group,
+            inner,
+            group_paint,
+            inner_paint,
+        }
+    }
+
+    #[test]
+    fn move_within_parent_resolves_index_without_the_layer() {
+        let Tree {
+            mut image,
+            paints: [a, b, c],
+            group,
+            ..
+        } = tree();
+        let root = image.root_id();
+
+        image.move_layer(a, root, 2).unwrap();
+        assert_eq!(image.root().children(), &[b, c, a, group]);
+
+        image.reorder_layer(a, 0).unwrap();
+        assert_eq!(image.root().children(), &[a, b, c, group]);
+
+        // The top is the last child once the layer is taken out.
+        image.reorder_layer(a, 3).unwrap();
+        assert_eq!(image.root().children(), &[b, c, group, a]);
+        assert!(matches!(
+            image.reorder_layer(a, 4),
+            Err(LayerError::IndexOutOfRange(_, 4))
+        ));
+    }
+
+    #[test]
+    fn move_into_other_parent_allows_appending() {
+        let Tree {
+            mut image,
+            paints: [a, b, c],
+            group,
+            inner,
+            group_paint,
+            ..
+        } = tree();
+        let root = image.root_id();
+
+        assert!(matches!(
+            image.move_layer(a, group, 3),
+            Err(LayerError::IndexOutOfRange(_, 3))
+        ));
+        image.move_layer(a, group, 2).unwrap();
+        assert_eq!(
+            image.layer(group).unwrap().children(),
+            &[group_paint, inner, a]
+        );
+        assert_eq!(image.root().children(), &[b, c, group]);
+        assert_eq!(image.layer(a).unwrap().parent(), Some(group));
+
+        assert!(matches!(
+            image.move_layer(b, a, 0),
+            Err(LayerError::NotAGroup(_))
+        ));
+        assert!(matches!(
+            image.move_layer(root, group, 0),
+            Err(LayerError::RootLayer)
+        ));
+    }
+
+    #[test]
+    fn move_into_own_subtree_is_cyclic() {
+        let Tree {
+            mut image,
+            group,
+            inner,
+            ..
+        } = tree();
+
+        assert!(matches!(
+            image.move_layer(group, group, 0),
+            Err(LayerError::CyclicParent(_, _))
+        ));
+        assert!(matches!(
+            image.move_layer(group, inner, 0),
+            Err(LayerError::CyclicParent(_, _))
+        ));
+        assert_eq!(image.layer(inner).unwrap().parent(), Some(group));
+    }
+
+    #[test]
+    fn position_lock_prevents_moving_and_removing() {
+        let Tree {
+            mut image,
+            paints: [a, b, c],
+            group,
+            ..
+        } = tree();
+        let root = image.root_id();
+        image.layer_mut(b).unwrap().properties.locks = LayerLocks::POSITION;
+
+        assert!(matches!(
+            image.reorder_layer(b, 0),
+            Err(LayerError::PositionLocked(_))
+        ));
+        assert!(matches!(
+            image.move_layer(b, group, 0),
+            Err(LayerError::PositionLocked(_))
+        ));
+        assert!(matches!(
+            image.remove_layer(b),
+            Err(LayerError::PositionLocked(_))
+        ));
+        assert_eq!(image.root().children(), &[a, b, c, group]);
+
+        // Other layers still move around it.
+        image.move_layer(a, root, 1).unwrap();
+        assert_eq!(image.root().children(), &[b, a, c, group]);
+    }
+
+    #[test]
+    fn duplicate_gives_the_subtree_new_ids() {
+        let Tree {
+            mut image,
+            paints: [a, b, c],
+            group,
+            inner,
+            group_paint,
+            inner_paint,
+        } = tree();
+        let count = image.layer_count();
+
+        let (copy, copies) = image.duplicate_tree(group).unwrap();
+        assert_eq!(image.root().children(), &[a, b, c, group, copy]);
+        assert_eq!(image.layer_count(), count + 4);
+
+        let old = [group, group_paint, inner, inner_paint];
+        let new = image.descendants(copy).unwrap();
+        assert_eq!(new.len(), 3);
+        assert!(old.iter().all(|id| !new.contains(id) && *id != copy));
+
+        let copy_layer = image.layer(copy).unwrap();
+        assert_eq!(copy_layer.properties.name, "Group");
+        assert_eq!(copy_layer.children(), &[new[0], new[1]]);
+        let inner_copy = image.layer(new[1]).unwrap();
+        assert_eq!(inner_copy.parent(), Some(copy));
+        assert_eq!(inner_copy.children(), &[new[2]]);
+        assert_eq!(image.layer(new[2]).unwrap().parent(), Some(new[1]));
+
+        // Only paint layers carry tiles to copy.
+        assert_eq!(copies, vec![(group_paint, new[0]), (inner_paint, new[2])]);
+
+        // The originals are untouched.
+        assert_eq!(
+            image.layer(group).unwrap().children(),
+            &[group_paint, inner]
+        );
+        assert_eq!(image.layer(inner).unwrap().children(), &[inner_paint]);
     }
 }
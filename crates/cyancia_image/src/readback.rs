@@ -0,0 +1,570 @@
+//! Copying pixels from the GPU tiles back into CPU images.
+//!
+//! Readbacks are submitted right away and resolved asynchronously, the returned
+//! futures don't borrow the storage and can be moved to other tasks. Evicted tiles
+//! are decoded from the CPU store directly without touching the GPU.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, mpsc},
+};
+
+use cyancia_id::Id;
+use futures::channel::oneshot;
+use glam::UVec2;
+use iced_core::Rectangle;
+use image::{DynamicImage, Rgba32FImage};
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::{ParallelSlice, ParallelSliceMut},
+};
+use wgpu::{
+    BufferAsyncError, BufferDescriptor, BufferUsages, COPY_BYTES_PER_ROW_ALIGNMENT, Device,
+    Extent3d, MapMode, Origin3d, PollError, PollType, SubmissionIndex, TexelCopyBufferInfo,
+    TexelCopyBufferLayout, TexelCopyTextureInfo, TextureAspect,
+};
+
+use crate::{
+    CImage,
+    blend::{self, GroupIsolation},
+    layer::Layer,
+    tile::{GpuTileStorage, Tile},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadbackError {
+    #[error("Failed to map readback buffer: {0}")]
+    BufferMap(#[from] BufferAsyncError),
+    #[error("Failed to wait for readback: {0}")]
+    Poll(#[from] PollError),
+    #[error("Readback was cancelled before completion")]
+    Cancelled,
+}
+
+/// Polls the device on a thread of its own until submitted readbacks are done,
+/// so they resolve whether or not the renderer is active. The thread ends once
+/// the poller is dropped.
+#[derive(Debug)]
+pub(crate) struct ReadbackPoller {
+    submissions: mpsc::Sender<SubmissionIndex>,
+}
+
+impl ReadbackPoller {
+    pub(crate) fn new(device: Arc<Device>) -> Self {
+        let (submissions, receiver) = mpsc::channel::<SubmissionIndex>();
+        std::thread::Builder::new()
+            .name("tile readback poller".into())
+            .spawn(move || {
+                for submission in receiver {
+                    if let Err(e) = device.poll(PollType::Wait {
+                        submission_index: Some(submission),
+                        timeout: None,
+                    }) {
+                        log::error!("Failed to wait for tile readback: {e}");
+                    }
+                }
+            })
+            .expect("Failed to spawn the tile readback poller.");
+        Self { submissions }
+    }
+
+    fn wait(&self, submission: SubmissionIndex) {
+        // Only fails once the thread is gone, which it never is before us.
+        let _ = self.submissions.send(submission);
+    }
+}
+
+/// Part of a tile to be copied into the output image.
+#[derive(Debug, Clone)]
+struct Region {
+    tile: Tile,
+    /// Position inside the tile.
+    origin: UVec2,
+    size: UVec2,
+    /// Position inside the output image.
+    dst: UVec2,
+    buffer_offset: u64,
+    bytes_per_row: u32,
+}
+
+impl GpuTileStorage {
+    pub fn read_tile(
+        &self,
+        image_layer: Id<Layer>,
+        index: UVec2,
+    ) -> impl Future<Output = Result<Rgba32FImage, ReadbackError>> + Send + 'static {
+        let origin = index * Self::TILE_SIZE;
+        self.read_rect(
+            image_layer,
+            Rectangle {
+                x: origin.x,
+                y: origin.y,
+                width: Self::TILE_SIZE,
+                height: Self::TILE_SIZE,
+            },
+        )
+    }
+
+    /// Reads a rectangle of the layer, in pixels. Pixels without a tile are
+    /// transparent.
+    pub fn read_rect(
+        &self,
+        image_layer: Id<Layer>,
+        rect: Rectangle<u32>,
+    ) -> impl Future<Output = Result<Rgba32FImage, ReadbackError>> + Send + 'static {
+        let mut output = Rgba32FImage::new(rect.width, rect.height);
+        let rect_min = UVec2::new(rect.x, rect.y);
+        let rect_max = rect_min + UVec2::new(rect.width, rect.height);
+
+        let mut regions = Vec::new();
+        let mut buffer_size = 0;
+        let tile_min = rect_min / Self::TILE_SIZE;
+        let tile_max = UVec2::new(
+            rect_max.x.div_ceil(Self::TILE_SIZE),
+            rect_max.y.div_ceil(Self::TILE_SIZE),
+        );
+        for y in tile_min.y..tile_max.y {
+            for x in tile_min.x..tile_max.x {
+                let index = UVec2::new(x, y);
+                let tile_origin = index * Self::TILE_SIZE;
+                let min = rect_min.max(tile_origin);
+                let max = rect_max.min(tile_origin + Self::TILE_SIZE);
+                if min.cmpge(max).any() {
+                    continue;
+                }
+
+                let origin = min - tile_origin;
+                let size = max - min;
+                let dst = min - rect_min;
+
+                if let Some(resident) = self.tiles.get(&(image_layer, index)) {
+                    let bytes_per_row = padded_bytes_per_row(size.x);
+                    regions.push(Region {
+                        tile: resident.tile.clone(),
+                        origin,
+                        size,
+                        dst,
+                        buffer_offset: buffer_size,
+                        bytes_per_row,
+                    });
+                    buffer_size += bytes_per_row as u64 * size.y as u64;
+                } else if let Some(evicted) = self.backing.get(image_layer, index) {
+                    let data = evicted.decode();
+                    let row_bytes = Self::TILE_SIZE * Self::TILE_PIXEL_BYTES;
+                    let offset = origin.y * row_bytes + origin.x * Self::TILE_PIXEL_BYTES;
+                    write_texels(&mut output, &data[offset as usize..], row_bytes, size, dst);
+                }
+            }
+        }
+
+        let pending = (!regions.is_empty()).then(|| self.submit_readback(&regions, buffer_size));
+
+        async move {
+            let Some((buffer, receiver)) = pending else {
+                return Ok(output);
+            };
+            receiver.await.map_err(|_| ReadbackError::Cancelled)??;
+
+            let mapped = buffer.slice(..).get_mapped_range();
+            for region in &regions {
+                write_texels(
+                    &mut output,
+                    &mapped[region.buffer_offset as usize..],
+                    region.bytes_per_row,
+                    region.size,
+                    region.dst,
+                );
+            }
+            drop(mapped);
+            buffer.unmap();
+
+            Ok(output)
+        }
+    }
+
+    pub fn read_layer(
+        &self,
+        image_layer: Id<Layer>,
+        size: UVec2,
+    ) -> impl Future<Output = Result<Rgba32FImage, ReadbackError>> + Send + 'static {
+        self.read_rect(
+            image_layer,
+            Rectangle {
+                x: 0,
+                y: 0,
+                width: size.x,
+                height: size.y,
+            },
+        )
+    }
+
+    /// Reads every existing tile of the layer as raw `Rgba16Float` texels, rows
+    /// tightly packed.
+    pub fn read_layer_tiles(
+        &self,
+        image_layer: Id<Layer>,
+    ) -> impl Future<Output = Result<Vec<(UVec2, Vec<u8>)>, ReadbackError>> + Send + 'static {
+        let mut result = Vec::new();
+        let mut regions = Vec::new();
+        let tile_bytes = Self::TILE_BYTES;
+        for r in self.tiles.iter().filter(|r| r.key().0 == image_layer) {
+            regions.push(Region {
+                tile: r.tile.clone(),
+                origin: UVec2::ZERO,
+                size: UVec2::splat(Self::TILE_SIZE),
+                dst: UVec2::ZERO,
+                buffer_offset: regions.len() as u64 * tile_bytes,
+                bytes_per_row: Self::TILE_SIZE * Self::TILE_PIXEL_BYTES,
+            });
+        }
+        for index in self.backing.layer_tiles(image_layer) {
+            if let Some(evicted) = self.backing.get(image_layer, index) {
+                result.push((index, evicted.decode()));
+            }
+        }
+
+        let pending = (!regions.is_empty())
+            .then(|| self.submit_readback(&regions, regions.len() as u64 * tile_bytes));
+
+        async move {
+            let Some((buffer, receiver)) = pending else {
+                return Ok(result);
+            };
+            receiver.await.map_err(|_| ReadbackError::Cancelled)??;
+
+            let mapped = buffer.slice(..).get_mapped_range();
+            for region in &regions {
+                let start = region.buffer_offset as usize;
+                result.push((
+                    region.tile.id.index,
+                    mapped[start..start + tile_bytes as usize].to_vec(),
+                ));
+            }
+            drop(mapped);
+            buffer.unmap();
+
+            Ok(result)
+        }
+    }
+
+    /// Reads whole resident tiles as raw texels of the storage's format, rows
+    /// tightly packed, in the given order.
+    pub(crate) fn read_tiles(
+        &self,
+        tiles: &[Tile],
+    ) -> impl Future<Output = Result<Vec<Vec<u8>>, ReadbackError>> + Send + 'static {
+        let tile_bytes = self.tile_bytes();
+        let regions = tiles
+            .iter()
+            .enumerate()
+            .map(|(i, tile)| Region {
+                tile: tile.clone(),
+                origin: UVec2::ZERO,
+                size: UVec2::splat(Self::TILE_SIZE),
+                dst: UVec2::ZERO,
+                buffer_offset: i as u64 * tile_bytes,
+                // At least 256 bytes, already a multiple of COPY_BYTES_PER_ROW_ALIGNMENT.
+                bytes_per_row: Self::TILE_SIZE * self.pixel_bytes(),
+            })
+            .collect::<Vec<_>>();
+
+        let pending = (!regions.is_empty())
+            .then(|| self.submit_readback(&regions, regions.len() as u64 * tile_bytes));
+
+        async move {
+            let Some((buffer, receiver)) = pending else {
+                return Ok(Vec::new());
+            };
+            receiver.await.map_err(|_| ReadbackError::Cancelled)??;
+
+            let mapped = buffer.slice(..).get_mapped_range();
+            let data = mapped
+                .chunks_exact(tile_bytes as usize)
+                .map(|chunk| chunk.to_vec())
+                .collect();
+            drop(mapped);
+            buffer.unmap();
+
+            Ok(data)
+        }
+    }
+
+    /// Copies the regions into a staging buffer and maps it. The poller waits for
+    /// the copy, see [`ReadbackPoller`].
+    fn submit_readback(
+        &self,
+        regions: &[Region],
+        buffer_size: u64,
+    ) -> (
+        wgpu::Buffer,
+        oneshot::Receiver<Result<(), BufferAsyncError>>,
+    ) {
+        let buffer = self.device.create_buffer(&BufferDescriptor {
+            label: Some("tile readback buffer"),
+            size: buffer_size,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut ec = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("tile readback encoder"),
+            });
+        for region in regions {
+            ec.copy_texture_to_buffer(
+                TexelCopyTextureInfo {
+                    texture: region.tile.view.texture(),
+                    mip_level: 0,
+                    origin: Origin3d {
+                        x: region.origin.x,
+                        y: region.origin.y,
+                        z: region.tile.id.pile_layer,
+                    },
+                    aspect: TextureAspect::All,
+                },
+                TexelCopyBufferInfo {
+                    buffer: &buffer,
+                    layout: TexelCopyBufferLayout {
+                        offset: region.buffer_offset,
+                        bytes_per_row: Some(region.bytes_per_row),
+                        rows_per_image: Some(region.size.y),
+                    },
+                },
+                Extent3d {
+                    width: region.size.x,
+                    height: region.size.y,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+        let submission = self.queue.submit([ec.finish()]);
+
+        let (sender, receiver) = oneshot::channel();
+        buffer.slice(..).map_async(MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+
+        self.poller.wait(submission);
+
+        (buffer, receiver)
+    }
+}
+
+impl CImage {
+    /// Reads every visible layer back and composites them on the CPU, the same way
+    /// the canvas renderer does.
+    pub fn read_flattened(
+        &self,
+        tiles: &GpuTileStorage,
+    ) -> impl Future<Output = Result<Rgba32FImage, ReadbackError>> + Send + 'static {
+        self.read_flattened_rect(
+            tiles,
+            Rectangle {
+                x: 0,
+                y: 0,
+                width: self.size.x,
+                height: self.size.y,
+            },
+        )
+    }
+
+    /// Like [`Self::read_flattened`], but only the pixels inside `rect`.
+    pub fn read_flattened_rect(
+        &self,
+        tiles: &GpuTileStorage,
+        rect: Rectangle<u32>,
+    ) -> impl Future<Output = Result<Rgba32FImage, ReadbackError>> + Send + 'static {
+        let image = self.clone();
+        let readbacks = self
+            .visible_paint_layers()
+            .into_iter()
+            .map(|id| (id, tiles.read_rect(id, rect)))
+            .collect::<Vec<_>>();
+
+        async move {
+            let mut layers = HashMap::with_capacity(readbacks.len());
+            for (id, readback) in readbacks {
+                layers.insert(id, readback.await?);
+            }
+
+            let mut output = Rgba32FImage::new(rect.width, rect.height);
+            flatten_children(&image, image.root(), &mut output, 1.0, &layers);
+            Ok(output)
+        }
+    }
+
+    pub fn read_flattened_dynamic(
+        &self,
+        tiles: &GpuTileStorage,
+    ) -> impl Future<Output = Result<DynamicImage, ReadbackError>> + Send + 'static {
+        let readback = self.read_flattened(tiles);
+        async move { readback.await.map(DynamicImage::ImageRgba32F) }
+    }
+}
+
+/// Mirrors `CompositePlan` of the canvas renderer.
+fn flatten_children(
+    image: &CImage,
+    group: &Layer,
+    backdrop: &mut Rgba32FImage,
+    opacity: f32,
+    layers: &HashMap<Id<Layer>, Rgba32FImage>,
+) {
+    for child in group.children() {
+        let Ok(layer) = image.layer(*child) else {
+            continue;
+        };
+        if !layer.properties.visible {
+            continue;
+        }
+
+        let layer_opacity = layer.properties.opacity * opacity;
+        match layer.isolation() {
+            None => {
+                if let Some(source) = layers.get(child) {
+                    blend_into(backdrop, source, layer.properties.blend_mode, layer_opacity);
+                }
+            }
+            Some(GroupIsolation::PassThrough) => {
+                flatten_children(image, layer, backdrop, layer_opacity, layers);
+            }
+            Some(GroupIsolation::Isolated) => {
+                let mut isolated = Rgba32FImage::new(backdrop.width(), backdrop.height());
+                flatten_children(image, layer, &mut isolated, 1.0, layers);
+                blend_into(
+                    backdrop,
+                    &isolated,
+                    layer.properties.blend_mode,
+                    layer_opacity,
+                );
+            }
+        }
+    }
+}
+
+fn blend_into(
+    backdrop: &mut Rgba32FImage,
+    source: &Rgba32FImage,
+    mode: blend::BlendMode,
+    opacity: f32,
+) {
+    backdrop
+        .par_chunks_exact_mut(4)
+        .zip(source.par_chunks_exact(4))
+        .for_each(|(b, s)| {
+            let result = blend::composite(
+                mode,
+                glam::Vec4::from_slice(b),
+                glam::Vec4::from_slice(s),
+                opacity,
+            );
+            result.write_to_slice(b);
+        });
+}
+
+/// wgpu requires rows of a texture to buffer copy to be aligned.
+fn padded_bytes_per_row(width: u32) -> u32 {
+    (width * GpuTileStorage::TILE_PIXEL_BYTES).next_multiple_of(COPY_BYTES_PER_ROW_ALIGNMENT)
+}
+
+/// Converts `Rgba16Float` rows into the output image, skipping row padding.
+fn write_texels(
+    output: &mut Rgba32FImage,
+    data: &[u8],
+    bytes_per_row: u32,
+    size: UVec2,
+    dst: UVec2,
+) {
+    let texel_bytes = GpuTileStorage::TILE_PIXEL_BYTES as usize;
+    for y in 0..size.y {
+        let row = &data[(y * bytes_per_row) as usize..][..size.x as usize * texel_bytes];
+        for (x, texel) in row.chunks_exact(texel_bytes).enumerate() {
+            let px = output.get_pixel_mut(dst.x + x as u32, dst.y + y);
+            for (c, channel) in texel.chunks_exact(2).enumerate() {
+                px.0[c] =
+                    half::f16::from_bits(u16::from_le_bytes([channel[0], channel[1]])).to_f32();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A value for each channel that `f16` holds exactly.
+    fn value(x: u32, y: u32, c: usize) -> f32 {
+        x as f32 + y as f32 * 0.5 + c as f32 * 0.125 - 1.0
+    }
+
+    /// Rows of texels as the GPU lays them out, the padding filled with junk.
+    fn padded(size: UVec2, bytes_per_row: u32) -> Vec<u8> {
+        let mut data = vec![0xab; (bytes_per_row * size.y) as usize];
+        for y in 0..size.y {
+            for x in 0..size.x {
+                for c in 0..4 {
+                    let start =
+                        (y * bytes_per_row + x * GpuTileStorage::TILE_PIXEL_BYTES) as usize + c * 2;
+                    data[start..start + 2]
+                        .copy_from_slice(&half::f16::from_f32(value(x, y, c)).to_le_bytes());
+                }
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn rows_are_padded_to_the_copy_alignment() {
+        // 32 texels of 8 bytes fill the alignment exactly.
+        for (width, bytes) in [
+            (1, 256),
+            (32, 256),
+            (33, 512),
+            (64, 512),
+            (100, 1024),
+            (256, 2048),
+        ] {
+            assert_eq!(padded_bytes_per_row(width), bytes, "width {width}");
+        }
+    }
+
+    #[test]
+    fn texels_are_converted_without_the_padding() {
+        for width in [1, 33, 64, 100, 128] {
+            let size = UVec2::new(width, 3);
+            let bytes_per_row = padded_bytes_per_row(width);
+            let data = padded(size, bytes_per_row);
+            let dst = UVec2::new(1, 2);
+            let mut output = Rgba32FImage::new(width + 2, 6);
+            write_texels(&mut output, &data, bytes_per_row, size, dst);
+
+            for (x, y, pixel) in output.enumerate_pixels() {
+                let p = UVec2::new(x, y);
+                let expected = if p.cmpge(dst).all() && p.cmplt(dst + size).all() {
+                    let p = p - dst;
+                    [0, 1, 2, 3].map(|c| value(p.x, p.y, c))
+                } else {
+                    [0.0; 4]
+                };
+                assert_eq!(pixel.0, expected, "width {width} at {p}");
+            }
+        }
+    }
+
+    #[test]
+    fn tightly_packed_rows_are_read_as_is() {
+        let size = UVec2::new(GpuTileStorage::TILE_SIZE, 2);
+        let bytes_per_row = GpuTileStorage::TILE_SIZE * GpuTileStorage::TILE_PIXEL_BYTES;
+        assert_eq!(padded_bytes_per_row(size.x), bytes_per_row);
+
+        let data = padded(size, bytes_per_row);
+        let mut output = Rgba32FImage::new(size.x, size.y);
+        write_texels(&mut output, &data, bytes_per_row, size, UVec2::ZERO);
+        assert_eq!(
+            output.get_pixel(255, 1).0,
+            [0, 1, 2, 3].map(|c| value(255, 1, c))
+        );
+    }
+}
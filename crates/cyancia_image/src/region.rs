@@ -0,0 +1,599 @@
+//! Finding regions of similar color in read back pixels, for the magic wand,
+//! color range selections and flood fills. Everything but [`flood_tiles`] works
+//! on whole images in CPU memory, so tile boundaries of the GPU storage play no
+//! part.
+
+use std::future::Future;
+
+use glam::{UVec2, Vec4};
+use iced_core::Rectangle;
+use image::Rgba32FImage;
+use rayon::{
+    iter::{
+        IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator,
+        IntoParallelRefMutIterator, ParallelIterator,
+    },
+    slice::{ParallelSlice, ParallelSliceMut},
+};
+
+use cyancia_id::Id;
+
+use crate::{CImage, layer::Layer, readback::ReadbackError, tile::GpuTileStorage};
+
+/// Which pixels region searches look at.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SampleSource {
+    /// Only the layer being painted on.
+    #[default]
+    ActiveLayer,
+    /// All visible layers composited together.
+    Merged,
+    /// A chosen layer, like line art sitting above the colors.
+    Layer(Id<Layer>),
+}
+
+impl SampleSource {
+    /// Reads the pixels of the source back. Without the layer to read the result
+    /// is fully transparent.
+    pub fn read(
+        self,
+        image: &CImage,
+        tiles: &GpuTileStorage,
+    ) -> impl Future<Output = Result<Rgba32FImage, ReadbackError>> + Send + 'static {
+        let size = image.size();
+        self.read_rect(
+            image,
+            tiles,
+            Rectangle {
+                x: 0,
+                y: 0,
+                width: size.x,
+                height: size.y,
+            },
+        )
+    }
+
+    /// Like [`Self::read`], but only the pixels inside `rect`.
+    pub fn read_rect(
+        self,
+        image: &CImage,
+        tiles: &GpuTileStorage,
+        rect: Rectangle<u32>,
+    ) -> impl Future<Output = Result<Rgba32FImage, ReadbackError>> + Send + 'static {
+        let layer = match self {
+            SampleSource::ActiveLayer => image.active_layer(),
+            SampleSource::Merged => None,
+            SampleSource::Layer(id) => image.layer(id).is_ok().then_some(id),
+        };
+        let readback = layer.map(|layer| tiles.read_rect(layer, rect));
+        let flattened =
+            (self == SampleSource::Merged).then(|| image.read_flattened_rect(tiles, rect));
+
+        async move {
+            if let Some(readback) = readback {
+                readback.await
+            } else if let Some(flattened) = flattened {
+                flattened.await
+            } else {
+                Ok(Rgba32FImage::new(rect.width, rect.height))
+            }
+        }
+    }
+}
+
+/// How close a pixel has to be to a reference color. Colors are compared
+/// premultiplied, so all fully transparent pixels count as the same color.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorMatch {
+    /// Straight alpha.
+    pub reference: Vec4,
+    /// Largest difference of any channel still matching fully, from 0 to 1.
+    pub tolerance: f32,
+    /// How far beyond the tolerance partial matches fade out.
+    pub fuzziness: f32,
+}
+
+impl ColorMatch {
+    pub fn new(reference: Vec4, tolerance: f32) -> Self {
+        Self {
+            reference,
+            tolerance,
+            fuzziness: 0.0,
+        }
+    }
+
+    pub fn distance(&self, color: Vec4) -> f32 {
+        (premultiply(color) - premultiply(self.reference))
+            .abs()
+            .max_element()
+    }
+
+    pub fn matches(&self, color: Vec4) -> bool {
+        self.distance(color) <= self.tolerance
+    }
+
+    /// How much the color matches, from 0 to 1.
+    pub fn coverage(&self, color: Vec4) -> f32 {
+        let beyond = self.distance(color) - self.tolerance;
+        if beyond <= 0.0 {
+            1.0
+        } else if self.fuzziness > 0.0 {
+            (1.0 - beyond / self.fuzziness).max(0.0)
+        } else {
+            0.0
+        }
+    }
+}
+
+fn premultiply(color: Vec4) -> Vec4 {
+    (color.truncate() * color.w).extend(color.w)
+}
+
+pub fn pixel(pixels: &Rgba32FImage, p: UVec2) -> Vec4 {
+    Vec4::from_array(pixels.get_pixel(p.x, p.y).0)
+}
+
+/// Coverage of every pixel by the color match, one byte per pixel.
+pub fn color_range(pixels: &Rgba32FImage, color: &ColorMatch) -> Vec<u8> {
+    let mut coverage = vec![0; (pixels.width() * pixels.height()) as usize];
+    coverage
+        .par_iter_mut()
+        .zip(pixels.as_raw().par_chunks_exact(4))
+        .for_each(|(c, p)| {
+            let value = color.coverage(Vec4::from_slice(p));
+            *c = (value * 255.0).round() as u8;
+        });
+    coverage
+}
+
+/// Which pixels match the color, one flag per pixel.
+pub fn matching(pixels: &Rgba32FImage, color: &ColorMatch) -> Vec<bool> {
+    let mut matches = vec![false; (pixels.width() * pixels.height()) as usize];
+    matches
+        .par_iter_mut()
+        .zip(pixels.as_raw().par_chunks_exact(4))
+        .for_each(|(m, p)| *m = color.matches(Vec4::from_slice(p)));
+    matches
+}
+
+/// The pixels connected to `seed` through pixels that match, four way. Filled
+/// scanline by scanline, so huge regions need little more than the output.
+pub fn flood(size: UVec2, matches: &[bool], seed: UVec2) -> Vec<bool> {
+    let (w, h) = (size.x as usize, size.y as usize);
+    let mut filled = vec![false; w * h];
+    if seed.cmpge(size).any() || !matches[seed.y as usize * w + seed.x as usize] {
+        return filled;
+    }
+
+    let fillable = |filled: &[bool], i: usize| matches[i] && !filled[i];
+    let mut stack = vec![(seed.x as usize, seed.y as usize)];
+    while let Some((x, y)) = stack.pop() {
+        let row = y * w;
+        if !fillable(&filled, row + x) {
+            continue;
+        }
+        let mut left = x;
+        while left > 0 && fillable(&filled, row + left - 1) {
+            left -= 1;
+        }
+        let mut right = x;
+        while right + 1 < w && fillable(&filled, row + right + 1) {
+            right += 1;
+        }
+        filled[row + left..=row + right].fill(true);
+
+        // Only the start of each run in the neighbouring rows is pushed.
+        for ny in [y.wrapping_sub(1), y + 1] {
+            if ny >= h {
+                continue;
+            }
+            let mut in_run = false;
+            for nx in left..=right {
+                let fill = fillable(&filled, ny * w + nx);
+                if fill && !in_run {
+                    stack.push((nx, ny));
+                }
+                in_run = fill;
+            }
+        }
+    }
+    filled
+}
+
+/// The part of the image the tile at `index` covers.
+pub fn tile_rect(index: UVec2, size: UVec2) -> Rectangle<u32> {
+    let min = index * GpuTileStorage::TILE_SIZE;
+    let max = (min + GpuTileStorage::TILE_SIZE).min(size);
+    Rectangle {
+        x: min.x,
+        y: min.y,
+        width: max.x.saturating_sub(min.x),
+        height: max.y.saturating_sub(min.y),
+    }
+}
+
+/// Like [`flood`], but which pixels match is only found out tile by tile.
+/// `read` is handed the tiles the region reaches, a batch at a time, and
+/// returns the flags of the pixels of each of them, rows of its
+/// [`tile_rect`] tightly packed. Tiles the region never gets to are not read.
+pub fn flood_tiles<E>(
+    size: UVec2,
+    seed: UVec2,
+    mut read: impl FnMut(&[UVec2]) -> Result<Vec<Vec<bool>>, E>,
+) -> Result<Vec<bool>, E> {
+    let (w, h) = (size.x as usize, size.y as usize);
+    let mut filled = vec![false; w * h];
+    if seed.cmpge(size).any() {
+        return Ok(filled);
+    }
+
+    let tile = GpuTileStorage::TILE_SIZE as usize;
+    let count = GpuTileStorage::calc_tile_count(size);
+    let columns = count.x as usize;
+    let tile_of = |x: usize, y: usize| y / tile * columns + x / tile;
+    let mut loaded = vec![false; (count.x * count.y) as usize];
+    let mut matches = vec![false; w * h];
+
+    // Pixels the region reached inside of tiles not read yet.
+    let mut waiting = vec![(seed.x as usize, seed.y as usize)];
+    while !waiting.is_empty() {
+        let mut wanted = waiting
+            .iter()
+            .map(|(x, y)| tile_of(*x, *y))
+            .filter(|t| !loaded[*t])
+            .collect::<Vec<_>>();
+        wanted.sort_unstable();
+        wanted.dedup();
+        let indices = wanted
+            .iter()
+            .map(|t| UVec2::new((t % columns) as u32, (t / columns) as u32))
+            .collect::<Vec<_>>();
+        for (index, flags) in indices.iter().zip(read(&indices)?) {
+            let rect = tile_rect(*index, size);
+            for (row, src) in flags.chunks_exact(rect.width as usize).enumerate() {
+                let start = (rect.y as usize + row) * w + rect.x as usize;
+                matches[start..start + src.len()].copy_from_slice(src);
+            }
+        }
+        for t in wanted {
+            loaded[t] = true;
+        }
+
+        let fillable = |filled: &[bool], i: usize| matches[i] && !filled[i];
+        let mut stack = std::mem::take(&mut waiting);
+        while let Some((x, y)) = stack.pop() {
+            if !loaded[tile_of(x, y)] {
+                waiting.push((x, y));
+                continue;
+            }
+            let row = y * w;
+            if !fillable(&filled, row + x) {
+                continue;
+            }
+            let mut left = x;
+            while left > 0 && fillable(&filled, row + left - 1) {
+                left -= 1;
+            }
+            if left > 0 && !loaded[tile_of(left - 1, y)] {
+                waiting.push((left - 1, y));
+            }
+            let mut right = x;
+            while right + 1 < w && fillable(&filled, row + right + 1) {
+                right += 1;
+            }
+            if right + 1 < w && !loaded[tile_of(right + 1, y)] {
+                waiting.push((right + 1, y));
+            }
+            filled[row + left..=row + right].fill(true);
+
+            // The start of each run in the neighbouring rows is pushed, runs
+            // of pixels not read yet apart from those that can be filled.
+            for ny in [y.wrapping_sub(1), y + 1] {
+                if ny >= h {
+                    continue;
+                }
+                let mut run = None;
+                for nx in left..=right {
+                    let state = if !loaded[tile_of(nx, ny)] {
+                        Some(false)
+                    } else if fillable(&filled, ny * w + nx) {
+                        Some(true)
+                    } else {
+                        None
+                    };
+                    if state.is_some() && state != run {
+                        stack.push((nx, ny));
+                    }
+                    run = state;
+                }
+            }
+        }
+    }
+    Ok(filled)
+}
+
+/// Flags turned into coverage bytes.
+pub fn to_coverage(flags: &[bool]) -> Vec<u8> {
+    let mut coverage = vec![0; flags.len()];
+    coverage
+        .par_iter_mut()
+        .zip(flags.par_iter())
+        .for_each(|(c, f)| *c = if *f { u8::MAX } else { 0 });
+    coverage
+}
+
+/// Spreads the flags `radius` pixels in every direction, so a square around
+/// each set pixel ends up set.
+pub fn dilate(flags: &[bool], size: UVec2, radius: u32) -> Vec<bool> {
+    if radius == 0 {
+        return flags.to_vec();
+    }
+    let (w, h) = (size.x as usize, size.y as usize);
+    let r = radius as usize;
+
+    let mut rows = vec![false; w * h];
+    rows.par_chunks_mut(w)
+        .zip(flags.par_chunks(w))
+        .for_each(|(out, src)| {
+            let mut last = None;
+            for (x, (o, s)) in out.iter_mut().zip(src).enumerate() {
+                if *s {
+                    last = Some(x);
+                }
+                *o = last.is_some_and(|l| x - l <= r);
+            }
+            let mut next = None;
+            for (x, (o, s)) in out.iter_mut().zip(src).enumerate().rev() {
+                if *s {
+                    next = Some(x);
+                }
+                *o |= next.is_some_and(|n| n - x <= r);
+            }
+        });
+
+    // Columns are swept a whole row at a time to stay cache friendly.
+    let mut result = vec![false; w * h];
+    let mut last = vec![None; w];
+    let lines = rows.chunks_exact(w).zip(result.chunks_exact_mut(w));
+    for (y, (src, out)) in lines.enumerate() {
+        for ((l, s), o) in last.iter_mut().zip(src).zip(out.iter_mut()) {
+            if *s {
+                *l = Some(y);
+            }
+            *o = l.is_some_and(|l| y - l <= r);
+        }
+    }
+    let mut next = vec![None; w];
+    let lines = rows.chunks_exact(w).zip(result.chunks_exact_mut(w));
+    for (y, (src, out)) in lines.enumerate().rev() {
+        for ((n, s), o) in next.iter_mut().zip(src).zip(out.iter_mut()) {
+            if *s {
+                *n = Some(y);
+            }
+            *o |= n.is_some_and(|n| n - y <= r);
+        }
+    }
+    result
+}
+
+/// Shrinks the set flags by `radius` pixels, the opposite of [`dilate`].
+pub fn erode(flags: &[bool], size: UVec2, radius: u32) -> Vec<bool> {
+    let inverted = flags.par_iter().map(|f| !f).collect::<Vec<_>>();
+    dilate(&inverted, size, radius)
+        .into_par_iter()
+        .map(|f| !f)
+        .collect()
+}
+
+/// Grows the flags by `amount` pixels, or shrinks them if negative.
+pub fn grow(flags: &[bool], size: UVec2, amount: i32) -> Vec<bool> {
+    if amount >= 0 {
+        dilate(flags, size, amount as u32)
+    } else {
+        erode(flags, size, amount.unsigned_abs())
+    }
+}
+
+/// Like [`flood`], but gaps in the non-matching pixels narrower than twice
+/// `gap` don't let the region leak. The region is found with the barriers
+/// thickened by `gap`, then grown back by as much into matching pixels.
+/// Falls back to a plain flood if the seed is swallowed by the barriers.
+pub fn flood_closing_gaps(size: UVec2, matches: &[bool], seed: UVec2, gap: u32) -> Vec<bool> {
+    if gap == 0 {
+        return flood(size, matches, seed);
+    }
+
+    let barriers = matches.par_iter().map(|m| !m).collect::<Vec<_>>();
+    let thick = dilate(&barriers, size, gap);
+    let open = matches
+        .par_iter()
+        .zip(thick.par_iter())
+        .map(|(m, t)| *m && !t)
+        .collect::<Vec<_>>();
+    let core = flood(size, &open, seed);
+    if !core.contains(&true) {
+        return flood(size, matches, seed);
+    }
+
+    dilate(&core, size, gap)
+        .into_par_iter()
+        .zip(matches.par_iter())
+        .map(|(c, m)| c && *m)
+        .collect()
+}
+
+/// Coverage bytes of the flags with their edges antialiased. Set pixels stay
+/// fully covered and the ones around them get the share of set neighbours.
+pub fn soften(flags: &[bool], size: UVec2) -> Vec<u8> {
+    let (w, h) = (size.x as usize, size.y as usize);
+    let mut coverage = vec![0; w * h];
+    coverage.par_chunks_mut(w).enumerate().for_each(|(y, row)| {
+        for (x, c) in row.iter_mut().enumerate() {
+            if flags[y * w + x] {
+                *c = u8::MAX;
+                continue;
+            }
+            let mut set = 0;
+            for ny in y.saturating_sub(1)..(y + 2).min(h) {
+                for nx in x.saturating_sub(1)..(x + 2).min(w) {
+                    set += flags[ny * w + nx] as u32;
+                }
+            }
+            *c = (set * u8::MAX as u32 / 9) as u8;
+        }
+    });
+    coverage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flood_tiles_matches_flood() {
+        // Two walls, the first with a gap low down, the second without.
+        let size = UVec2::new(600, 300);
+        let matches = (0..size.y)
+            .flat_map(|y| (0..size.x).map(move |x| (x, y)))
+            .map(|(x, y)| match x {
+                300 => (280..290).contains(&y),
+                500..512 => false,
+                _ => true,
+            })
+            .collect::<Vec<_>>();
+        let seed = UVec2::new(10, 10);
+
+        let mut read = Vec::new();
+        let filled = flood_tiles(size, seed, |indices| {
+            read.extend_from_slice(indices);
+            let flags = indices.iter().map(|index| {
+                let rect = tile_rect(*index, size);
+                (rect.y..rect.y + rect.height)
+                    .flat_map(|y| (rect.x..rect.x + rect.width).map(move |x| (x, y)))
+                    .map(|(x, y)| matches[(y * size.x + x) as usize])
+                    .collect()
+            });
+            Ok::<_, ()>(flags.collect())
+        })
+        .unwrap();
+
+        assert_eq!(filled, flood(size, &matches, seed));
+        assert!(filled[(150 * size.x + 400) as usize]);
+        assert!(!filled[(150 * size.x + 550) as usize]);
+        // Every tile is read once, those beyond the second wall never.
+        read.sort_by_key(|i| (i.y, i.x));
+        assert_eq!(
+            read,
+            [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(x, y)| UVec2::new(x, y))
+        );
+    }
+
+    #[test]
+    fn flood_tiles_outside_of_the_image() {
+        let filled = flood_tiles(UVec2::splat(10), UVec2::new(10, 0), |_| {
+            Err::<Vec<Vec<bool>>, _>("nothing to read")
+        });
+        assert_eq!(filled, Ok(vec![false; 100]));
+    }
+
+    /// Flags of an image, set where `f` of the pixel is.
+    fn flags(size: UVec2, f: impl Fn(u32, u32) -> bool) -> Vec<bool> {
+        (0..size.y)
+            .flat_map(|y| (0..size.x).map(move |x| (x, y)))
+            .map(|(x, y)| f(x, y))
+            .collect()
+    }
+
+    fn at(flags: &[bool], size: UVec2, x: u32, y: u32) -> bool {
+        flags[(y * size.x + x) as usize]
+    }
+
+    /// A wall at x 20 with an opening `width` pixels tall from y 8.
+    fn wall(size: UVec2, width: u32) -> Vec<bool> {
+        flags(size, |x, y| x != 20 || (8..8 + width).contains(&y))
+    }
+
+    #[test]
+    fn gaps_narrower_than_twice_the_gap_are_closed() {
+        let size = UVec2::new(40, 20);
+        let seed = UVec2::new(5, 10);
+
+        let matches = wall(size, 3);
+        let filled = flood_closing_gaps(size, &matches, seed, 2);
+        assert!(!at(&filled, size, 30, 10));
+        // Grown back up to the wall.
+        assert!(at(&filled, size, 19, 2));
+        assert!(at(&filled, size, 0, 0));
+        // A plain flood leaks through the same opening.
+        assert!(at(&flood(size, &matches, seed), size, 30, 10));
+
+        let matches = wall(size, 6);
+        let filled = flood_closing_gaps(size, &matches, seed, 2);
+        assert!(at(&filled, size, 30, 10));
+        assert!(at(&filled, size, 39, 19));
+        assert!(!at(&filled, size, 20, 2));
+    }
+
+    #[test]
+    fn seed_inside_the_barriers_falls_back_to_a_plain_flood() {
+        let size = UVec2::new(40, 20);
+        let matches = wall(size, 3);
+        let seed = UVec2::new(20, 9);
+
+        let filled = flood_closing_gaps(size, &matches, seed, 2);
+        assert_eq!(filled, flood(size, &matches, seed));
+        assert!(at(&filled, size, 30, 10));
+        assert_eq!(flood_closing_gaps(size, &matches, seed, 0), filled);
+    }
+
+    #[test]
+    fn shrinking_keeps_the_image_border() {
+        let size = UVec2::new(10, 10);
+        let full = vec![true; 100];
+        assert_eq!(grow(&full, size, -3), full);
+
+        // The left half, touching the border on three sides.
+        let half = flags(size, |x, _| x < 5);
+        let shrunk = grow(&half, size, -2);
+        assert_eq!(shrunk, flags(size, |x, _| x < 3));
+        assert_eq!(grow(&half, size, -20), vec![false; 100]);
+
+        assert_eq!(grow(&half, size, 0), half);
+        assert_eq!(grow(&half, size, 2), flags(size, |x, _| x < 7));
+        assert_eq!(grow(&half, size, 20), full);
+    }
+
+    #[test]
+    fn growing_reaches_diagonals() {
+        let size = UVec2::new(10, 10);
+        let dot = flags(size, |x, y| x == 5 && y == 5);
+        let grown = grow(&dot, size, 1);
+        assert_eq!(
+            grown,
+            flags(size, |x, y| (4..=6).contains(&x) && (4..=6).contains(&y))
+        );
+        assert_eq!(grow(&grown, size, -1), dot);
+    }
+
+    #[test]
+    fn soften_covers_neighbours_by_their_set_share() {
+        let size = UVec2::new(10, 10);
+        let pair = flags(size, |x, y| (x == 4 || x == 5) && y == 5);
+        let coverage = soften(&pair, size);
+        let c = |x: u32, y: u32| coverage[(y * size.x + x) as usize];
+
+        assert_eq!(c(4, 5), 255);
+        assert_eq!(c(5, 5), 255);
+        // Next to one or both of the set pixels, a ninth of full coverage each.
+        assert_eq!(c(3, 5), 28);
+        assert_eq!(c(4, 4), 56);
+        assert_eq!(c(5, 6), 56);
+        assert_eq!(c(2, 5), 0);
+        assert_eq!(c(4, 7), 0);
+
+        // Neighbours outside the image count as unset.
+        let corner = soften(&flags(size, |x, y| x == 0 && y == 0), size);
+        assert_eq!(corner[0], 255);
+        assert_eq!(corner[11], 28);
+    }
+}
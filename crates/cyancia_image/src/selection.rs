@@ -0,0 +1,854 @@
+//! The selection of a document, a coverage mask over the image limiting where
+//! painting and filters apply. The mask lives on the CPU, where shapes are
+//! rasterized and combined tile by tile, and is mirrored into
+//! [`GPU_MASK_STORAGE`] for the brushes and the canvas.
+
+use std::{
+    collections::{HashMap, HashSet},
+    f32::consts::TAU,
+    sync::Arc,
+};
+
+use cyancia_id::Id;
+use cyancia_utils::global_instance::GlobalInstance;
+use glam::{IVec2, UVec2, Vec2};
+use image::Rgba32FImage;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use wgpu::TextureFormat;
+
+use crate::{layer::Layer, tile::GpuTileStorage};
+
+/// Tiles of selection masks, laid out like the color tiles.
+pub static GPU_MASK_STORAGE: GlobalInstance<GpuTileStorage> = GlobalInstance::new();
+
+/// Texel format of [`GPU_MASK_STORAGE`].
+pub const MASK_FORMAT: TextureFormat = TextureFormat::R8Unorm;
+
+const TILE_SIZE: u32 = GpuTileStorage::TILE_SIZE;
+const TILE_TEXELS: usize = (TILE_SIZE * TILE_SIZE) as usize;
+
+/// How a new shape changes the existing selection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SelectionMode {
+    #[default]
+    Replace,
+    Add,
+    Subtract,
+    Intersect,
+}
+
+impl SelectionMode {
+    pub fn combine(self, selected: f32, shape: f32) -> f32 {
+        match self {
+            SelectionMode::Replace => shape,
+            SelectionMode::Add => selected.max(shape),
+            SelectionMode::Subtract => selected * (1.0 - shape),
+            SelectionMode::Intersect => selected.min(shape),
+        }
+    }
+}
+
+/// An outline in image pixels.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectionShape {
+    Rect {
+        min: Vec2,
+        max: Vec2,
+    },
+    Ellipse {
+        center: Vec2,
+        radii: Vec2,
+    },
+    /// Closed implicitly from the last point back to the first.
+    Polygon(Vec<Vec2>),
+}
+
+impl SelectionShape {
+    /// The rectangle or ellipse spanning two opposite corners.
+    pub fn rect(a: Vec2, b: Vec2) -> Self {
+        SelectionShape::Rect {
+            min: a.min(b),
+            max: a.max(b),
+        }
+    }
+
+    pub fn ellipse(a: Vec2, b: Vec2) -> Self {
+        SelectionShape::Ellipse {
+            center: (a + b) * 0.5,
+            radii: (b - a).abs() * 0.5,
+        }
+    }
+
+    /// The shape as a polygon, ellipses flattened finely enough that the
+    /// segments don't show.
+    pub fn outline(&self) -> Vec<Vec2> {
+        match self {
+            SelectionShape::Rect { min, max } => {
+                vec![*min, Vec2::new(max.x, min.y), *max, Vec2::new(min.x, max.y)]
+            }
+            SelectionShape::Ellipse { center, radii } => {
+                let segments = (radii.max_element() * TAU / 4.0).clamp(16.0, 1024.0) as usize;
+                (0..segments)
+                    .map(|i| *center + Vec2::from_angle(i as f32 / segments as f32 * TAU) * *radii)
+                    .collect()
+            }
+            SelectionShape::Polygon(points) => points.clone(),
+        }
+    }
+}
+
+/// What a shape covers within one tile.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TileCoverage {
+    Empty,
+    Full,
+    /// Coverage of each texel, rows tightly packed.
+    Partial(Vec<f32>),
+}
+
+impl TileCoverage {
+    pub fn get(&self, i: usize) -> f32 {
+        match self {
+            TileCoverage::Empty => 0.0,
+            TileCoverage::Full => 1.0,
+            TileCoverage::Partial(values) => values[i],
+        }
+    }
+}
+
+/// Rasterizes a shape one tile at a time, so huge selections never need a
+/// buffer the size of the image. Polygons are filled with the even-odd rule.
+#[derive(Debug, Clone)]
+pub struct ShapeCoverage {
+    edges: Vec<(Vec2, Vec2)>,
+    min: Vec2,
+    max: Vec2,
+    antialias: bool,
+    /// Radius of each of the three box blurs approximating a gaussian.
+    blur: u32,
+}
+
+impl ShapeCoverage {
+    /// Subsampled rows per pixel when antialiasing.
+    const SUBSAMPLES: u32 = 4;
+
+    /// `feather` is how far the edge fades out, in pixels.
+    pub fn new(shape: &SelectionShape, antialias: bool, feather: f32) -> Self {
+        let points = shape.outline();
+        let edges = points
+            .iter()
+            .zip(points.iter().cycle().skip(1))
+            .map(|(a, b)| (*a, *b))
+            .filter(|(a, b)| a.y != b.y)
+            .collect::<Vec<_>>();
+        let (min, max) = points.iter().fold(
+            (Vec2::splat(f32::MAX), Vec2::splat(f32::MIN)),
+            |(min, max), p| (min.min(*p), max.max(*p)),
+        );
+        Self {
+            edges,
+            min,
+            max,
+            antialias,
+            blur: (feather.max(0.0) / 3.0).ceil() as u32,
+        }
+    }
+
+    /// How far the coverage reaches beyond the outline.
+    fn padding(&self) -> i32 {
+        self.blur as i32 * 3
+    }
+
+    /// Indices of the tiles of an image of `size` the shape can reach.
+    pub fn tiles(&self, size: UVec2) -> Vec<UVec2> {
+        if self.edges.is_empty() {
+            return Vec::new();
+        }
+        let pad = self.padding() as f32;
+        let min = (self.min - pad).floor().max(Vec2::ZERO).as_uvec2();
+        let max = (self.max + pad).ceil().min(size.as_vec2()).as_uvec2();
+        if min.cmpge(max).any() {
+            return Vec::new();
+        }
+        let first = min / TILE_SIZE;
+        let last = (max - 1) / TILE_SIZE;
+        (first.y..=last.y)
+            .flat_map(|y| (first.x..=last.x).map(move |x| UVec2::new(x, y)))
+            .collect()
+    }
+
+    pub fn tile(&self, index: UVec2) -> TileCoverage {
+        let pad = self.padding();
+        let origin = (index * TILE_SIZE).as_ivec2();
+        let region_min = origin - pad;
+        let region_size = UVec2::splat(TILE_SIZE + 2 * pad as u32);
+        let region_max = region_min + region_size.as_ivec2();
+
+        // Without an edge nearby the whole tile is either in or out.
+        let near = self
+            .edges
+            .iter()
+            .filter(|(a, b)| {
+                let (min, max) = (a.min(*b), a.max(*b));
+                max.cmpge(region_min.as_vec2()).all() && min.cmple(region_max.as_vec2()).all()
+            })
+            .count();
+        if near == 0 {
+            let center = origin.as_vec2() + TILE_SIZE as f32 * 0.5;
+            return if self.contains(center) {
+                TileCoverage::Full
+            } else {
+                TileCoverage::Empty
+            };
+        }
+
+        let mut values = self.rasterize(region_min, region_size);
+        if self.blur > 0 {
+            for _ in 0..3 {
+                box_blur(&mut values, region_size, self.blur);
+            }
+        }
+
+        let stride = region_size.x as usize;
+        let pad = pad as usize;
+        let tile = (0..TILE_SIZE as usize)
+            .flat_map(|y| {
+                let start = (y + pad) * stride + pad;
+                values[start..start + TILE_SIZE as usize].iter().copied()
+            })
+            .collect::<Vec<_>>();
+        if tile.iter().all(|v| *v <= 0.0) {
+            TileCoverage::Empty
+        } else if tile.iter().all(|v| *v >= 1.0) {
+            TileCoverage::Full
+        } else {
+            TileCoverage::Partial(tile)
+        }
+    }
+
+    fn contains(&self, p: Vec2) -> bool {
+        self.crossings(p.y).iter().filter(|x| **x < p.x).count() % 2 == 1
+    }
+
+    /// Where the outline crosses the horizontal line at `y`, sorted.
+    fn crossings(&self, y: f32) -> Vec<f32> {
+        let mut xs = self
+            .edges
+            .iter()
+            .filter(|(a, b)| (a.y <= y) != (b.y <= y))
+            .map(|(a, b)| a.x + (y - a.y) * (b.x - a.x) / (b.y - a.y))
+            .collect::<Vec<_>>();
+        xs.sort_by(f32::total_cmp);
+        xs
+    }
+
+    /// Coverage of the pixels from `min` over `size`.
+    fn rasterize(&self, min: IVec2, size: UVec2) -> Vec<f32> {
+        let mut values = vec![0.0; (size.x * size.y) as usize];
+        let (subsamples, weight) = if self.antialias {
+            (Self::SUBSAMPLES, 1.0 / Self::SUBSAMPLES as f32)
+        } else {
+            (1, 1.0)
+        };
+
+        for row in 0..size.y {
+            let line = &mut values[(row * size.x) as usize..((row + 1) * size.x) as usize];
+            for sub in 0..subsamples {
+                let y = min.y as f32 + row as f32 + (sub as f32 + 0.5) / subsamples as f32;
+                for span in self.crossings(y).chunks_exact(2) {
+                    let (x0, x1) = (span[0] - min.x as f32, span[1] - min.x as f32);
+                    if self.antialias {
+                        let first = x0.floor().max(0.0) as u32;
+                        let last = (x1.ceil().min(size.x as f32) as u32).max(first);
+                        for x in first..last {
+                            let overlap = x1.min(x as f32 + 1.0) - x0.max(x as f32);
+                            line[x as usize] += overlap.max(0.0) * weight;
+                        }
+                    } else {
+                        // Pixels whose centers lie inside the span.
+                        let first = (x0 - 0.5).ceil().max(0.0) as u32;
+                        let last = ((x1 - 0.5).ceil().min(size.x as f32) as u32).max(first);
+                        for x in first..last {
+                            line[x as usize] += weight;
+                        }
+                    }
+                }
+            }
+        }
+
+        for v in &mut values {
+            *v = v.min(1.0);
+        }
+        values
+    }
+}
+
+/// Blurs in place with a box of `radius` along both axes, outside reading as 0.
+fn box_blur(values: &mut [f32], size: UVec2, radius: u32) {
+    let (w, h) = (size.x as usize, size.y as usize);
+    let r = radius as isize;
+    let scale = 1.0 / (2 * r + 1) as f32;
+    let mut line = Vec::new();
+    let blur_line = |line: &mut Vec<f32>, get: &dyn Fn(usize) -> f32, len: usize| {
+        line.clear();
+        let mut sum = (0..=r.min(len as isize - 1))
+            .map(|i| get(i as usize))
+            .sum::<f32>();
+        for i in 0..len as isize {
+            line.push(sum * scale);
+            let enter = i + r + 1;
+            let leave = i - r;
+            if enter < len as isize {
+                sum += get(enter as usize);
+            }
+            if leave >= 0 {
+                sum -= get(leave as usize);
+            }
+        }
+    };
+
+    for y in 0..h {
+        let row = values[y * w..(y + 1) * w].to_vec();
+        blur_line(&mut line, &|x| row[x], w);
+        values[y * w..(y + 1) * w].copy_from_slice(&line);
+    }
+    for x in 0..w {
+        let column = (0..h).map(|y| values[y * w + x]).collect::<Vec<_>>();
+        blur_line(&mut line, &|y| column[y], h);
+        for (y, v) in line.iter().enumerate() {
+            values[y * w + x] = *v;
+        }
+    }
+}
+
+/// Coverage of a whole image, one byte per texel. Missing tiles are not
+/// selected at all.
+#[derive(Debug, Clone)]
+pub struct SelectionMask {
+    size: UVec2,
+    tiles: HashMap<UVec2, Arc<[u8]>>,
+}
+
+impl SelectionMask {
+    pub fn empty(size: UVec2) -> Self {
+        Self {
+            size,
+            tiles: HashMap::new(),
+        }
+    }
+
+    pub fn all(size: UVec2) -> Self {
+        let full = Self::full_tile();
+        let count = GpuTileStorage::calc_tile_count(size);
+        let tiles = (0..count.y)
+            .flat_map(|y| (0..count.x).map(move |x| UVec2::new(x, y)))
+            .map(|index| (index, full.clone()))
+            .collect();
+        Self { size, tiles }
+    }
+
+    /// Splits coverage of the whole image, one byte per pixel with rows tightly
+    /// packed, into tiles.
+    pub fn from_coverage(size: UVec2, coverage: &[u8]) -> Self {
+        let full = Self::full_tile();
+        let count = GpuTileStorage::calc_tile_count(size);
+        let tiles = (0..count.y)
+            .flat_map(|y| (0..count.x).map(move |x| UVec2::new(x, y)))
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .filter_map(|index| {
+                let origin = index * TILE_SIZE;
+                let mut texels = vec![0; TILE_TEXELS];
+                let width = TILE_SIZE.min(size.x - origin.x) as usize;
+                for y in 0..TILE_SIZE.min(size.y - origin.y) {
+                    let src = ((origin.y + y) * size.x + origin.x) as usize;
+                    let dst = (y * TILE_SIZE) as usize;
+                    texels[dst..dst + width].copy_from_slice(&coverage[src..src + width]);
+                }
+                if texels.iter().all(|t| *t == 0) {
+                    None
+                } else if texels.iter().all(|t| *t == u8::MAX) {
+                    Some((index, full.clone()))
+                } else {
+                    Some((index, Arc::from(texels)))
+                }
+            })
+            .collect();
+        Self { size, tiles }
+    }
+
+    fn full_tile() -> Arc<[u8]> {
+        Arc::from(vec![u8::MAX; TILE_TEXELS])
+    }
+
+    pub fn size(&self) -> UVec2 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    /// Raw texels of a tile, `None` if nothing in it is selected.
+    pub fn tile(&self, index: UVec2) -> Option<&[u8]> {
+        self.tiles.get(&index).map(|t| &t[..])
+    }
+
+    pub fn tile_indices(&self) -> impl Iterator<Item = UVec2> + '_ {
+        self.tiles.keys().copied()
+    }
+
+    /// How much of the pixel is selected, from 0 to 1.
+    pub fn value(&self, pixel: UVec2) -> f32 {
+        let Some(tile) = self.tiles.get(&(pixel / TILE_SIZE)) else {
+            return 0.0;
+        };
+        let local = pixel % TILE_SIZE;
+        tile[(local.y * TILE_SIZE + local.x) as usize] as f32 / 255.0
+    }
+
+    /// The mask with a shape combined into it. Tiles the shape leaves alone
+    /// are shared with this mask.
+    pub fn combined(&self, shape: &ShapeCoverage, mode: SelectionMode) -> Self {
+        if mode == SelectionMode::Replace {
+            return Self::empty(self.size).combined(shape, SelectionMode::Add);
+        }
+
+        let mut indices = shape.tiles(self.size);
+        if mode == SelectionMode::Intersect {
+            let touched = indices.iter().copied().collect::<HashSet<_>>();
+            indices.extend(self.tiles.keys().filter(|i| !touched.contains(i)));
+        }
+
+        let full = Self::full_tile();
+        let results = indices
+            .into_par_iter()
+            .map(|index| {
+                let old = self.tiles.get(&index);
+                let coverage = shape.tile(index);
+                let tile = match (mode, &coverage, old) {
+                    (SelectionMode::Replace | SelectionMode::Intersect, TileCoverage::Empty, _) => {
+                        None
+                    }
+                    (SelectionMode::Replace | SelectionMode::Add, TileCoverage::Full, _) => {
+                        Some(full.clone())
+                    }
+                    (SelectionMode::Subtract, TileCoverage::Full, _) => None,
+                    (SelectionMode::Add | SelectionMode::Subtract, TileCoverage::Empty, old) => {
+                        old.cloned()
+                    }
+                    (SelectionMode::Intersect, TileCoverage::Full, old) => old.cloned(),
+                    _ => {
+                        let texels = (0..TILE_TEXELS)
+                            .map(|i| {
+                                let selected = old.map_or(0.0, |t| t[i] as f32 / 255.0);
+                                let value = mode.combine(selected, coverage.get(i));
+                                (value.clamp(0.0, 1.0) * 255.0).round() as u8
+                            })
+                            .collect::<Vec<_>>();
+                        if texels.iter().all(|t| *t == 0) {
+                            None
+                        } else if texels.iter().all(|t| *t == u8::MAX) {
+                            Some(full.clone())
+                        } else {
+                            Some(Arc::from(texels))
+                        }
+                    }
+                };
+                (index, tile)
+            })
+            .collect::<Vec<_>>();
+
+        let mut mask = self.clone();
+        for (index, tile) in results {
+            match tile {
+                Some(tile) => mask.tiles.insert(index, tile),
+                None => mask.tiles.remove(&index),
+            };
+        }
+        mask
+    }
+
+    /// The mask with another one of the same size combined into it.
+    pub fn merged(&self, other: &SelectionMask, mode: SelectionMode) -> Self {
+        let mut indices = other.tiles.keys().copied().collect::<HashSet<_>>();
+        if matches!(mode, SelectionMode::Replace | SelectionMode::Intersect) {
+            indices.extend(self.tiles.keys());
+        }
+
+        let results = indices
+            .into_par_iter()
+            .map(|index| {
+                let old = self.tiles.get(&index);
+                let new = other.tiles.get(&index);
+                let tile = match (mode, old, new) {
+                    (SelectionMode::Replace, _, new) => new.cloned(),
+                    (SelectionMode::Intersect, None, _) | (SelectionMode::Intersect, _, None) => {
+                        None
+                    }
+                    (SelectionMode::Add | SelectionMode::Subtract, None, new) => {
+                        new.filter(|_| mode == SelectionMode::Add).cloned()
+                    }
+                    (_, Some(old), None) => Some(old.clone()),
+                    (_, Some(old), Some(new)) => {
+                        let texels = old
+                            .iter()
+                            .zip(new.iter())
+                            .map(|(o, n)| {
+                                let value = mode.combine(*o as f32 / 255.0, *n as f32 / 255.0);
+                                (value.clamp(0.0, 1.0) * 255.0).round() as u8
+                            })
+                            .collect::<Vec<_>>();
+                        (!texels.iter().all(|t| *t == 0)).then(|| Arc::from(texels))
+                    }
+                };
+                (index, tile)
+            })
+            .collect::<Vec<_>>();
+
+        let mut mask = self.clone();
+        for (index, tile) in results {
+            match tile {
+                Some(tile) => mask.tiles.insert(index, tile),
+                None => mask.tiles.remove(&index),
+            };
+        }
+        mask
+    }
+
+    pub fn inverted(&self) -> Self {
+        let all = Self::all(self.size);
+        let tiles = all
+            .tiles
+            .into_iter()
+            .filter_map(|(index, full)| match self.tiles.get(&index) {
+                None => Some((index, full)),
+                Some(tile) if tile.iter().all(|t| *t == u8::MAX) => None,
+                Some(tile) => Some((index, tile.iter().map(|t| u8::MAX - t).collect())),
+            })
+            .collect();
+        Self {
+            size: self.size,
+            tiles,
+        }
+    }
+
+    /// Tiles whose content differs between the masks.
+    pub fn changed_tiles(&self, other: &SelectionMask) -> Vec<UVec2> {
+        let mut changed = self
+            .tiles
+            .iter()
+            .filter(|(index, tile)| {
+                other
+                    .tiles
+                    .get(index)
+                    .is_none_or(|o| !Arc::ptr_eq(tile, o) && tile[..] != o[..])
+            })
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>();
+        changed.extend(other.tiles.keys().filter(|i| !self.tiles.contains_key(i)));
+        changed
+    }
+
+    /// Keeps `after` only where the pixels are selected, fading back to
+    /// `before` elsewhere. Both images start at `origin` in the image, which is
+    /// how filters working on a read back region respect the selection.
+    pub fn restrict(&self, origin: UVec2, before: &Rgba32FImage, after: &mut Rgba32FImage) {
+        for (x, y, pixel) in after.enumerate_pixels_mut() {
+            let selected = self.value(origin + UVec2::new(x, y));
+            let base = before.get_pixel(x, y);
+            for (c, b) in pixel.0.iter_mut().zip(base.0) {
+                *c = b + (*c - b) * selected;
+            }
+        }
+    }
+}
+
+/// The selection of a document. Without a mask everything counts as selected.
+#[derive(Debug, Clone)]
+pub struct Selection {
+    /// Key of the mask tiles in [`GPU_MASK_STORAGE`].
+    id: Id<Layer>,
+    mask: Option<SelectionMask>,
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Self {
+            id: Id::random(),
+            mask: None,
+        }
+    }
+}
+
+impl Selection {
+    pub fn id(&self) -> Id<Layer> {
+        self.id
+    }
+
+    pub fn mask(&self) -> Option<&SelectionMask> {
+        self.mask.as_ref()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.mask.is_some()
+    }
+
+    /// How much of the pixel can be edited.
+    pub fn value(&self, pixel: UVec2) -> f32 {
+        self.mask.as_ref().map_or(1.0, |m| m.value(pixel))
+    }
+
+    /// Combines a shape into the selection. Subtracting from or intersecting
+    /// with no selection works on the whole image.
+    pub fn apply(
+        &mut self,
+        size: UVec2,
+        shape: &ShapeCoverage,
+        mode: SelectionMode,
+        tiles: &GpuTileStorage,
+    ) {
+        let mask = Self::combine(self.mask.as_ref(), size, shape, mode);
+        self.set_mask(mask, tiles);
+    }
+
+    /// What [`Selection::apply`] makes of `mask`, `None` once nothing is left
+    /// selected.
+    pub fn combine(
+        mask: Option<&SelectionMask>,
+        size: UVec2,
+        shape: &ShapeCoverage,
+        mode: SelectionMode,
+    ) -> Option<SelectionMask> {
+        let mask = match (mask, mode) {
+            (Some(mask), _) => mask.combined(shape, mode),
+            (None, SelectionMode::Replace | SelectionMode::Add) => {
+                SelectionMask::empty(size).combined(shape, mode)
+            }
+            (None, SelectionMode::Subtract | SelectionMode::Intersect) => {
+                SelectionMask::all(size).combined(shape, mode)
+            }
+        };
+        (!mask.is_empty()).then_some(mask)
+    }
+
+    /// Combines a mask covering the whole image into the selection, like
+    /// [`Selection::apply`] does with shapes.
+    pub fn apply_mask(
+        &mut self,
+        mask: &SelectionMask,
+        mode: SelectionMode,
+        tiles: &GpuTileStorage,
+    ) {
+        let mask = match (&self.mask, mode) {
+            (Some(selected), _) => selected.merged(mask, mode),
+            (None, SelectionMode::Replace | SelectionMode::Add) => mask.clone(),
+            (None, SelectionMode::Subtract | SelectionMode::Intersect) => {
+                SelectionMask::all(mask.size()).merged(mask, mode)
+            }
+        };
+        self.set_mask((!mask.is_empty()).then_some(mask), tiles);
+    }
+
+    /// Replaces the mask, uploading the tiles that changed.
+    pub fn set_mask(&mut self, mask: Option<SelectionMask>, tiles: &GpuTileStorage) {
+        let empty = SelectionMask::empty(UVec2::ZERO);
+        let old = self.mask.as_ref().unwrap_or(&empty);
+        let new = mask.as_ref().unwrap_or(&empty);
+        for index in new.changed_tiles(old) {
+            match new.tile(index) {
+                Some(data) => tiles.write_tile_data(self.id, index, data),
+                None => tiles.free_tile(self.id, index),
+            }
+        }
+        self.mask = mask;
+    }
+
+    pub fn clear(&mut self, tiles: &GpuTileStorage) {
+        self.set_mask(None, tiles);
+    }
+
+    pub fn select_all(&mut self, size: UVec2, tiles: &GpuTileStorage) {
+        self.set_mask(Some(SelectionMask::all(size)), tiles);
+    }
+
+    /// Swaps what is selected. Without a selection there is nothing to invert.
+    pub fn invert(&mut self, tiles: &GpuTileStorage) {
+        let Some(mask) = &self.mask else {
+            return;
+        };
+        let mask = mask.inverted();
+        self.set_mask((!mask.is_empty()).then_some(mask), tiles);
+    }
+
+    /// Releases the mask tiles. Used when the document is closed.
+    pub fn release(&self, tiles: &GpuTileStorage) {
+        tiles.free_layer(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: UVec2 = UVec2::splat(512);
+
+    fn rect(min: (f32, f32), max: (f32, f32)) -> ShapeCoverage {
+        let shape = SelectionShape::rect(Vec2::new(min.0, min.1), Vec2::new(max.0, max.1));
+        ShapeCoverage::new(&shape, false, 0.0)
+    }
+
+    fn select(min: (f32, f32), max: (f32, f32)) -> SelectionMask {
+        SelectionMask::empty(SIZE).combined(&rect(min, max), SelectionMode::Replace)
+    }
+
+    fn value(mask: &SelectionMask, x: u32, y: u32) -> f32 {
+        mask.value(UVec2::new(x, y))
+    }
+
+    #[test]
+    fn combine_values() {
+        assert_eq!(SelectionMode::Replace.combine(0.8, 0.25), 0.25);
+        assert_eq!(SelectionMode::Add.combine(0.8, 0.25), 0.8);
+        assert_eq!(SelectionMode::Subtract.combine(0.8, 0.25), 0.6);
+        assert_eq!(SelectionMode::Intersect.combine(0.8, 0.25), 0.25);
+    }
+
+    #[test]
+    fn replace() {
+        let first = select((10.0, 10.0), (20.0, 20.0));
+        let mask = first.combined(&rect((300.0, 0.0), (400.0, 100.0)), SelectionMode::Replace);
+        assert_eq!(value(&mask, 15, 15), 0.0);
+        assert_eq!(value(&mask, 300, 0), 1.0);
+        assert_eq!(value(&mask, 399, 99), 1.0);
+        assert_eq!(value(&mask, 400, 50), 0.0);
+        assert_eq!(mask.tile_indices().collect::<Vec<_>>(), [UVec2::new(1, 0)]);
+    }
+
+    #[test]
+    fn add() {
+        let mask = select((0.0, 0.0), (100.0, 100.0))
+            .combined(&rect((300.0, 300.0), (400.0, 400.0)), SelectionMode::Add);
+        assert_eq!(value(&mask, 50, 50), 1.0);
+        assert_eq!(value(&mask, 350, 350), 1.0);
+        assert_eq!(value(&mask, 200, 200), 0.0);
+    }
+
+    #[test]
+    fn subtract() {
+        let mask = SelectionMask::all(SIZE)
+            .combined(&rect((0.0, 0.0), (100.0, 100.0)), SelectionMode::Subtract);
+        assert_eq!(value(&mask, 50, 50), 0.0);
+        assert_eq!(value(&mask, 150, 50), 1.0);
+        assert_eq!(value(&mask, 400, 400), 1.0);
+
+        // Everything taken away leaves no tiles behind.
+        let none = mask.combined(&rect((0.0, 0.0), (512.0, 512.0)), SelectionMode::Subtract);
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn intersect() {
+        let mask = select((0.0, 0.0), (300.0, 300.0)).combined(
+            &rect((200.0, 200.0), (512.0, 512.0)),
+            SelectionMode::Intersect,
+        );
+        assert_eq!(value(&mask, 250, 250), 1.0);
+        assert_eq!(value(&mask, 100, 100), 0.0);
+        assert_eq!(value(&mask, 400, 400), 0.0);
+
+        // Tiles the shape doesn't reach are dropped too.
+        let apart = select((0.0, 0.0), (100.0, 100.0)).combined(
+            &rect((400.0, 400.0), (500.0, 500.0)),
+            SelectionMode::Intersect,
+        );
+        assert!(apart.is_empty());
+    }
+
+    #[test]
+    fn antialiased_edges() {
+        let shape = SelectionShape::rect(Vec2::new(10.5, 0.0), Vec2::new(20.0, 20.0));
+        let mask = SelectionMask::empty(SIZE).combined(
+            &ShapeCoverage::new(&shape, true, 0.0),
+            SelectionMode::Replace,
+        );
+        assert_eq!(value(&mask, 9, 5), 0.0);
+        assert!((value(&mask, 10, 5) - 0.5).abs() <= 1.0 / 255.0);
+        assert_eq!(value(&mask, 11, 5), 1.0);
+    }
+
+    #[test]
+    fn feathering() {
+        let shape = SelectionShape::rect(Vec2::new(100.0, 100.0), Vec2::new(400.0, 400.0));
+        let coverage = ShapeCoverage::new(&shape, true, 12.0);
+        let mask = SelectionMask::empty(SIZE).combined(&coverage, SelectionMode::Replace);
+
+        assert_eq!(value(&mask, 250, 250), 1.0);
+        assert_eq!(value(&mask, 250, 80), 0.0);
+        // The edge fades out on both sides of the outline.
+        assert!(value(&mask, 250, 95) > 0.0);
+        assert!(value(&mask, 250, 105) < 1.0);
+        assert!(value(&mask, 250, 99) < 0.5 && value(&mask, 250, 100) > 0.5);
+        let column = (85..=115).map(|y| value(&mask, 250, y)).collect::<Vec<_>>();
+        assert!(column.windows(2).all(|w| w[0] <= w[1]), "{column:?}");
+
+        // Tiles are blurred on their own but match up across their borders.
+        for y in [95, 100, 105] {
+            assert!((value(&mask, 255, y) - value(&mask, 256, y)).abs() <= 1.0 / 255.0);
+        }
+
+        // The tiles reach as far as the feathering does.
+        let inside = SelectionShape::rect(Vec2::new(100.0, 100.0), Vec2::new(250.0, 250.0));
+        assert_eq!(ShapeCoverage::new(&inside, true, 0.0).tiles(SIZE).len(), 1);
+        assert_eq!(ShapeCoverage::new(&inside, true, 12.0).tiles(SIZE).len(), 4);
+    }
+
+    #[test]
+    fn merging_masks() {
+        let a = select((0.0, 0.0), (100.0, 100.0));
+        let b = select((50.0, 0.0), (150.0, 100.0));
+        let at = |mask: &SelectionMask| [25, 75, 125].map(|x| value(mask, x, 50));
+
+        assert_eq!(at(&a.merged(&b, SelectionMode::Replace)), [0.0, 1.0, 1.0]);
+        assert_eq!(at(&a.merged(&b, SelectionMode::Add)), [1.0, 1.0, 1.0]);
+        assert_eq!(at(&a.merged(&b, SelectionMode::Subtract)), [1.0, 0.0, 0.0]);
+        assert_eq!(at(&a.merged(&b, SelectionMode::Intersect)), [0.0, 1.0, 0.0]);
+
+        let empty = SelectionMask::empty(SIZE);
+        assert!(a.merged(&empty, SelectionMode::Intersect).is_empty());
+        assert!(a.merged(&a, SelectionMode::Subtract).is_empty());
+    }
+
+    #[test]
+    fn inverting() {
+        let mask = select((0.0, 0.0), (100.0, 100.0));
+        let inverted = mask.inverted();
+        assert_eq!(value(&inverted, 50, 50), 0.0);
+        assert_eq!(value(&inverted, 150, 50), 1.0);
+        assert_eq!(value(&inverted, 400, 400), 1.0);
+        assert!(inverted.inverted().changed_tiles(&mask).is_empty());
+        assert!(SelectionMask::all(SIZE).inverted().is_empty());
+    }
+
+    #[test]
+    fn changed_tiles() {
+        let mask = select((0.0, 0.0), (100.0, 100.0));
+        let more = mask.combined(&rect((300.0, 300.0), (400.0, 400.0)), SelectionMode::Add);
+        assert_eq!(mask.changed_tiles(&more), [UVec2::new(1, 1)]);
+        assert_eq!(more.changed_tiles(&mask), [UVec2::new(1, 1)]);
+        assert!(mask.changed_tiles(&mask.clone()).is_empty());
+    }
+
+    #[test]
+    fn from_coverage() {
+        let size = UVec2::new(300, 10);
+        let mut coverage = vec![u8::MAX; 3000];
+        coverage[0] = 0;
+        let mask = SelectionMask::from_coverage(size, &coverage);
+        assert_eq!(mask.tile_indices().count(), 2);
+        assert_eq!(mask.value(UVec2::new(0, 0)), 0.0);
+        assert_eq!(mask.value(UVec2::new(1, 0)), 1.0);
+        assert_eq!(mask.value(UVec2::new(299, 9)), 1.0);
+        assert!(SelectionMask::from_coverage(size, &[0; 3000]).is_empty());
+    }
+}
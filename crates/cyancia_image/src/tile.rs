@@ -1,26 +1,32 @@
-use std::{cell::OnceCell, collections::HashMap, ops::Deref, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
 
 use cyancia_id::Id;
 use cyancia_utils::global_instance::GlobalInstance;
-use dashmap::DashMap;
-use glam::{Mat3, UVec2};
+use dashmap::{DashMap, DashSet};
+use glam::{IVec2, Mat3, UVec2};
 use iced_core::Rectangle;
-use image::{DynamicImage, GenericImageView, RgbaImage};
+use image::{DynamicImage, GenericImageView, Rgba32FImage, RgbaImage};
 use palette::{LinSrgba, Srgb, Srgba};
 use parking_lot::RwLock;
-use rayon::iter::{
-    IndexedParallelIterator, IntoParallelRefIterator, ParallelBridge, ParallelIterator,
-};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use uuid::Uuid;
 use wgpu::{
-    BufferUsages, Device, Extent3d, Origin3d, Queue, TexelCopyBufferInfo, TexelCopyBufferLayout,
-    TexelCopyTextureInfo, Texture, TextureAspect, TextureDescriptor, TextureDimension,
-    TextureFormat, TextureUsages, TextureView, TextureViewDescriptor,
-    util::{BufferInitDescriptor, DeviceExt},
-    wgt::TextureDataOrder,
+    CommandEncoder, Device, Extent3d, Origin3d, Queue, TexelCopyBufferLayout, TexelCopyTextureInfo,
+    Texture, TextureAspect, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+    TextureView, TextureViewDescriptor, util::DeviceExt, wgt::TextureDataOrder,
 };
 
-use crate::layer::Layer;
+use crate::{
+    backing::{CpuTile, CpuTileStore, TileCompression},
+    layer::Layer,
+    readback::{ReadbackError, ReadbackPoller},
+};
 
 #[derive(Debug)]
 pub struct GpuTilePile {
@@ -50,13 +56,75 @@ pub struct Tile {
 
 pub static GPU_TILE_STORAGE: GlobalInstance<GpuTileStorage> = GlobalInstance::new();
 
+#[derive(Debug, Clone)]
+pub(crate) struct ResidentTile {
+    pub(crate) tile: Tile,
+    last_used: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileStorageConfig {
+    /// Maximum bytes of tiles kept on the GPU. Tiles used in the current frame are
+    /// never evicted, so this can be exceeded temporarily.
+    pub gpu_budget: u64,
+    pub compression: TileCompression,
+}
+
+impl Default for TileStorageConfig {
+    fn default() -> Self {
+        Self {
+            gpu_budget: 2 * 1024 * 1024 * 1024,
+            compression: TileCompression::Lz4,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TileStorageStats {
+    pub pile_count: usize,
+    pub resident_tiles: usize,
+    pub free_slices: usize,
+    /// Bytes of VRAM held by all piles, used or not.
+    pub gpu_bytes: u64,
+    pub gpu_budget: u64,
+    pub cpu_tiles: usize,
+    /// Bytes of RAM held by evicted tiles, after compression.
+    pub cpu_bytes: u64,
+    pub evictions: u64,
+    pub uploads: u64,
+    /// Tiles released because they turned out to be fully transparent.
+    pub released_empty: u64,
+}
+
 #[derive(Debug)]
 pub struct GpuTileStorage {
-    device: Arc<Device>,
-    queue: Arc<Queue>,
-    piles: RwLock<Vec<GpuTilePile>>,
-    tiles: DashMap<(Id<Layer>, UVec2), Tile>,
+    pub(crate) device: Arc<Device>,
+    pub(crate) queue: Arc<Queue>,
+    format: TextureFormat,
+    pixel_bytes: u32,
+    /// Dropped piles leave a `None` behind so indices of the others stay valid.
+    piles: RwLock<Vec<Option<GpuTilePile>>>,
+    pub(crate) tiles: DashMap<(Id<Layer>, UVec2), ResidentTile>,
     available_slices: RwLock<Vec<(usize, usize)>>,
+    /// Free slices that still hold pixels of the tile released from them.
+    stale_slices: DashSet<(usize, usize)>,
+    pub(crate) backing: CpuTileStore,
+    config: RwLock<TileStorageConfig>,
+    frame: AtomicU64,
+    evictions: AtomicU64,
+    uploads: AtomicU64,
+    /// Frame in which each tile was last fetched mutably, pending an emptiness check.
+    written: DashMap<(Id<Layer>, UVec2), u64>,
+    /// Bumped whenever pixels of a layer may change, see [`Self::generation`].
+    generations: DashMap<Id<Layer>, u64>,
+    sweeping: AtomicBool,
+    /// Whether tiles are being read back for eviction.
+    evicting: AtomicBool,
+    /// Set when slices are released, so piles are only compacted after that.
+    compact_pending: AtomicBool,
+    released_empty: AtomicU64,
+    /// Waits for readbacks to finish, see [`ReadbackPoller`].
+    pub(crate) poller: ReadbackPoller,
 }
 
 impl GpuTileStorage {
@@ -69,6 +137,9 @@ impl GpuTileStorage {
         pile_index: 0,
     };
     pub const TILE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+    pub const TILE_PIXEL_BYTES: u32 = 8;
+    pub const TILE_BYTES: u64 = (Self::TILE_SIZE * Self::TILE_SIZE * Self::TILE_PIXEL_BYTES) as u64;
+    pub const SWEEP_DELAY_FRAMES: u64 = 30;
 
     pub fn calc_tile_count(image_size: UVec2) -> UVec2 {
         UVec2::new(
@@ -78,6 +149,15 @@ impl GpuTileStorage {
     }
 
     pub fn new(device: Arc<Device>, queue: Arc<Queue>) -> Self {
+        Self::with_format(device, queue, Self::TILE_FORMAT)
+    }
+
+    /// A storage laid out like the color one, but whose tiles hold texels of
+    /// `format`. Used for masks, which need a single channel only.
+    pub fn with_format(device: Arc<Device>, queue: Arc<Queue>, format: TextureFormat) -> Self {
+        let pixel_bytes = format
+            .block_copy_size(None)
+            .expect("Tile formats are plain color formats.");
         let empty_tile = device.create_texture(&TextureDescriptor {
             label: Some("empty tile"),
             size: Extent3d {
@@ -88,7 +168,7 @@ impl GpuTileStorage {
             mip_level_count: 1,
             sample_count: 1,
             dimension: TextureDimension::D2,
-            format: Self::TILE_FORMAT,
+            format,
             usage: TextureUsages::TEXTURE_BINDING
                 | TextureUsages::COPY_DST
                 | TextureUsages::TEXTURE_BINDING,
@@ -109,13 +189,16 @@ impl GpuTileStorage {
 
         let views = DashMap::from_iter([(
             (Self::EMPTY_TILE_ID.image_layer, Self::EMPTY_TILE_ID.index),
-            Tile {
-                id: Self::EMPTY_TILE_ID,
-                view: empty_tile_view.into(),
+            ResidentTile {
+                tile: Tile {
+                    id: Self::EMPTY_TILE_ID,
+                    view: empty_tile_view.into(),
+                },
+                last_used: 0,
             },
         )]);
 
-        let piles = vec![GpuTilePile {
+        let piles = vec![Some(GpuTilePile {
             texture_view: empty_tile
                 .create_view(&TextureViewDescriptor {
                     label: Some("empty pile view"),
@@ -130,74 +213,656 @@ impl GpuTileStorage {
                 })
                 .into(),
             texture: empty_tile.into(),
-        }];
+        })];
 
         Self {
+            poller: ReadbackPoller::new(device.clone()),
             device,
             queue,
+            format,
+            pixel_bytes,
             piles: piles.into(),
             tiles: views,
             available_slices: Default::default(),
+            stale_slices: Default::default(),
+            backing: Default::default(),
+            config: Default::default(),
+            frame: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            uploads: AtomicU64::new(0),
+            written: Default::default(),
+            generations: Default::default(),
+            sweeping: AtomicBool::new(false),
+            evicting: AtomicBool::new(false),
+            compact_pending: AtomicBool::new(false),
+            released_empty: AtomicU64::new(0),
         }
     }
 
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &Arc<Queue> {
+        &self.queue
+    }
+
+    pub fn format(&self) -> TextureFormat {
+        self.format
+    }
+
+    pub fn pixel_bytes(&self) -> u32 {
+        self.pixel_bytes
+    }
+
+    /// Bytes of a whole tile, [`Self::TILE_BYTES`] for color tiles.
+    pub fn tile_bytes(&self) -> u64 {
+        (Self::TILE_SIZE * Self::TILE_SIZE * self.pixel_bytes) as u64
+    }
+
+    pub fn config(&self) -> TileStorageConfig {
+        *self.config.read()
+    }
+
+    pub fn set_config(&self, config: TileStorageConfig) {
+        *self.config.write() = config;
+    }
+
+    pub fn set_gpu_budget(&self, bytes: u64) {
+        self.config.write().gpu_budget = bytes;
+    }
+
+    pub fn stats(&self) -> TileStorageStats {
+        let pile_count = self.piles.read().iter().flatten().count() - 1;
+        TileStorageStats {
+            pile_count,
+            resident_tiles: self.tiles.len() - 1,
+            free_slices: self.available_slices.read().len(),
+            gpu_bytes: pile_count as u64 * Self::TILES_PER_PILE as u64 * self.tile_bytes(),
+            gpu_budget: self.config.read().gpu_budget,
+            cpu_tiles: self.backing.len(),
+            cpu_bytes: self.backing.stored_bytes() as u64,
+            evictions: self.evictions.load(Ordering::Relaxed),
+            uploads: self.uploads.load(Ordering::Relaxed),
+            released_empty: self.released_empty.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Marks the beginning of a new frame. Tiles touched after this call are
+    /// protected from eviction until the next one.
+    pub fn advance_frame(&self) {
+        self.frame.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn is_resident(&self, image_layer: Id<Layer>, index: UVec2) -> bool {
+        self.tiles.contains_key(&(image_layer, index))
+    }
+
     pub fn get_tile(&self, image_layer: Id<Layer>, index: UVec2) -> Tile {
-        self.tiles
-            .get(&(image_layer, index))
-            .map(|r| r.value().clone())
+        self.fetch_tile(image_layer, index, false)
             .unwrap_or_else(|| {
                 let mut empty = self
                     .tiles
                     .get(&(Self::EMPTY_TILE_ID.image_layer, Self::EMPTY_TILE_ID.index))
                     .unwrap()
-                    .value()
+                    .tile
                     .clone();
                 empty.id.index = index;
                 empty
             })
     }
 
+    /// Counts the changes to the pixels of the layer. Work done on pixels read
+    /// back compares it against the count from before the readback, to tell
+    /// whether the layer changed in the meantime.
+    pub fn generation(&self, image_layer: Id<Layer>) -> u64 {
+        self.generations.get(&image_layer).map_or(0, |g| *g)
+    }
+
+    fn bump_generation(&self, image_layer: Id<Layer>) {
+        *self.generations.entry(image_layer).or_default() += 1;
+    }
+
     pub fn get_tile_mut(&self, image_layer: Id<Layer>, index: UVec2) -> Tile {
-        dbg!(self.tiles.len(), self.available_slices.read().len());
+        self.bump_generation(image_layer);
+        self.written
+            .insert((image_layer, index), self.frame.load(Ordering::Relaxed));
+        self.fetch_tile(image_layer, index, true)
+            .expect("Tiles are always created when fetched mutably.")
+    }
+
+    /// Returns the resident tile, uploading it back from the CPU store if it was
+    /// evicted. If the tile doesn't exist at all, a blank one is allocated when
+    /// `create` is set.
+    fn fetch_tile(&self, image_layer: Id<Layer>, index: UVec2, create: bool) -> Option<Tile> {
+        let frame = self.frame.load(Ordering::Relaxed);
         match self.tiles.entry((image_layer, index)) {
-            dashmap::Entry::Occupied(e) => e.get().clone(),
+            dashmap::Entry::Occupied(mut e) => {
+                e.get_mut().last_used = frame;
+                Some(e.get().tile.clone())
+            }
             dashmap::Entry::Vacant(e) => {
-                self.try_allocate_new_tile_pile();
-                let (pile_index, slice_index) = self.available_slices.write().pop().unwrap();
-                let pile = &self.piles.read()[pile_index];
-                let view = pile.texture.create_view(&TextureViewDescriptor {
-                    label: Some("tile view"),
-                    format: None,
-                    dimension: Some(wgpu::TextureViewDimension::D2),
-                    aspect: wgpu::TextureAspect::All,
-                    base_mip_level: 0,
-                    mip_level_count: None,
-                    base_array_layer: slice_index as u32,
-                    array_layer_count: Some(1),
-                    usage: None,
+                let evicted = self.backing.remove(image_layer, index);
+                if evicted.is_none() && !create {
+                    return None;
+                }
+
+                let (tile, stale) = self.allocate_tile(image_layer, index);
+                if let Some(evicted) = evicted {
+                    self.write_tile(&tile, &evicted.decode());
+                    self.uploads.fetch_add(1, Ordering::Relaxed);
+                } else if stale {
+                    self.write_tile(&tile, &vec![0; self.tile_bytes() as usize]);
+                }
+                e.insert(ResidentTile {
+                    tile: tile.clone(),
+                    last_used: frame,
                 });
-                dbg!(slice_index);
-
-                let tile = Tile {
-                    id: TileId {
-                        image_layer,
-                        index,
-                        pile_index,
-                        pile_layer: slice_index as u32,
-                    },
-                    view: view.clone().into(),
-                };
-                e.insert(tile.clone());
-                tile
+                Some(tile)
             }
         }
     }
 
+    /// Takes a free slice for the tile. Also returns whether the slice still holds
+    /// pixels from a previously released tile.
+    fn allocate_tile(&self, image_layer: Id<Layer>, index: UVec2) -> (Tile, bool) {
+        self.try_allocate_new_tile_pile();
+        let (pile_index, slice_index) = self.available_slices.write().pop().unwrap();
+        let stale = self
+            .stale_slices
+            .remove(&(pile_index, slice_index))
+            .is_some();
+        let piles = self.piles.read();
+        let pile = piles[pile_index]
+            .as_ref()
+            .expect("Slices of dropped piles are never available.");
+        let view = pile.texture.create_view(&TextureViewDescriptor {
+            label: Some("tile view"),
+            format: None,
+            dimension: Some(wgpu::TextureViewDimension::D2),
+            aspect: wgpu::TextureAspect::All,
+            base_mip_level: 0,
+            mip_level_count: None,
+            base_array_layer: slice_index as u32,
+            array_layer_count: Some(1),
+            usage: None,
+        });
+
+        let tile = Tile {
+            id: TileId {
+                image_layer,
+                index,
+                pile_index,
+                pile_layer: slice_index as u32,
+            },
+            view: view.into(),
+        };
+        (tile, stale)
+    }
+
+    /// Replaces the whole tile with raw texels of the storage's format, rows
+    /// tightly packed.
+    pub fn write_tile_data(&self, image_layer: Id<Layer>, index: UVec2, data: &[u8]) {
+        let tile = self.get_tile_mut(image_layer, index);
+        self.write_tile(&tile, data);
+    }
+
+    /// Writes raw texels into the whole tile.
+    fn write_tile(&self, tile: &Tile, data: &[u8]) {
+        self.queue.write_texture(
+            TexelCopyTextureInfo {
+                texture: tile.view.texture(),
+                mip_level: 0,
+                origin: Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: tile.id.pile_layer,
+                },
+                aspect: TextureAspect::All,
+            },
+            data,
+            TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(Self::TILE_SIZE * self.pixel_bytes),
+                rows_per_image: Some(Self::TILE_SIZE),
+            },
+            Extent3d {
+                width: Self::TILE_SIZE,
+                height: Self::TILE_SIZE,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    /// Puts the slice of a tile that is no longer resident back into the free list.
+    /// The slice is only cleared once it is handed out as a blank tile again.
+    fn release_slice(&self, tile: &Tile) {
+        let slice = (tile.id.pile_index, tile.id.pile_layer as usize);
+        self.stale_slices.insert(slice);
+        self.available_slices.write().push(slice);
+        self.compact_pending.store(true, Ordering::Relaxed);
+    }
+
+    /// Releases the tile, both from the GPU and the CPU store. Reading it afterwards
+    /// gives the empty tile.
+    pub fn free_tile(&self, image_layer: Id<Layer>, index: UVec2) {
+        if image_layer == Self::EMPTY_TILE_ID.image_layer {
+            return;
+        }
+
+        self.bump_generation(image_layer);
+        if let Some((_, resident)) = self.tiles.remove(&(image_layer, index)) {
+            self.release_slice(&resident.tile);
+        }
+        self.backing.remove(image_layer, index);
+        self.written.remove(&(image_layer, index));
+    }
+
+    /// Releases all tiles of the layer.
+    pub fn free_layer(&self, image_layer: Id<Layer>) {
+        let resident = self
+            .tiles
+            .iter()
+            .filter(|r| r.key().0 == image_layer)
+            .map(|r| r.key().1)
+            .collect::<Vec<_>>();
+        for index in resident {
+            self.free_tile(image_layer, index);
+        }
+        for index in self.backing.layer_tiles(image_layer) {
+            self.backing.remove(image_layer, index);
+        }
+        self.written.retain(|(layer, _), _| *layer != image_layer);
+        self.bump_generation(image_layer);
+    }
+
+    /// Starts a background sweep releasing written tiles that are fully transparent,
+    /// unless one is already running. Only tiles left untouched for
+    /// [`Self::SWEEP_DELAY_FRAMES`] are checked, so strokes in progress are not read
+    /// back over and over.
+    pub fn request_empty_tile_sweep(self: &Arc<Self>) {
+        if self.written.is_empty() || self.sweeping.swap(true, Ordering::Acquire) {
+            return;
+        }
+
+        let storage = self.clone();
+        let spawned = std::thread::Builder::new()
+            .name("empty tile sweep".into())
+            .spawn(move || {
+                storage.sweep_empty_tiles();
+                storage.sweeping.store(false, Ordering::Release);
+            });
+        if let Err(e) = spawned {
+            log::error!("Failed to spawn empty tile sweep: {e}");
+            self.sweeping.store(false, Ordering::Release);
+        }
+    }
+
+    /// Reads back settled written tiles and releases the fully transparent ones.
+    /// Returns the number of released tiles.
+    pub fn sweep_empty_tiles(&self) -> usize {
+        const BATCH: usize = 64;
+
+        let frame = self.frame.load(Ordering::Relaxed);
+        let settled = self
+            .written
+            .iter()
+            .filter(|r| *r.value() + Self::SWEEP_DELAY_FRAMES <= frame)
+            .map(|r| (*r.key(), *r.value()))
+            .collect::<Vec<_>>();
+
+        let mut released = 0;
+        for batch in settled.chunks(BATCH) {
+            let mut resident = Vec::new();
+            let mut empty = Vec::new();
+            for (key, written_at) in batch {
+                if let Some(r) = self.tiles.get(key) {
+                    resident.push((r.tile.clone(), *written_at));
+                } else if let Some(cpu) = self.backing.get(key.0, key.1) {
+                    if self.is_blank(&cpu.decode()) {
+                        empty.push((*key, *written_at));
+                    }
+                } else {
+                    self.written.remove_if(key, |_, f| f == written_at);
+                }
+            }
+
+            let tiles = resident.iter().map(|(t, _)| t.clone()).collect::<Vec<_>>();
+            let data = match futures::executor::block_on(self.read_tiles(&tiles)) {
+                Ok(data) => data,
+                Err(e) => {
+                    // The batch stays written and is checked again by the next sweep.
+                    log::error!("Failed to read tiles back for the empty tile sweep: {e}");
+                    continue;
+                }
+            };
+            empty.extend(
+                resident
+                    .par_iter()
+                    .zip(data.par_iter())
+                    .filter(|(_, data)| self.is_blank(data))
+                    .map(|((tile, written_at), _)| {
+                        ((tile.id.image_layer, tile.id.index), *written_at)
+                    })
+                    .collect::<Vec<_>>(),
+            );
+
+            for (key, written_at) in batch {
+                // Tiles written again since the snapshot are checked in a later sweep.
+                if self
+                    .written
+                    .remove_if(key, |_, f| f == written_at)
+                    .is_none()
+                {
+                    continue;
+                }
+                if empty.iter().any(|(k, _)| k == key) && self.free_unwritten_tile(*key) {
+                    released += 1;
+                }
+            }
+        }
+
+        if released > 0 {
+            self.released_empty
+                .fetch_add(released as u64, Ordering::Relaxed);
+            log::info!("Released {released} fully transparent tiles.");
+        }
+        released
+    }
+
+    /// Frees the tile unless it was fetched mutably in the meantime. The check is
+    /// done while holding the tile entry, which `get_tile_mut` marks before taking.
+    fn free_unwritten_tile(&self, key: (Id<Layer>, UVec2)) -> bool {
+        match self.tiles.entry(key) {
+            dashmap::Entry::Occupied(e) => {
+                if self.written.contains_key(&key) {
+                    return false;
+                }
+                let (_, resident) = e.remove_entry();
+                self.release_slice(&resident.tile);
+            }
+            dashmap::Entry::Vacant(_) => {
+                if self.written.contains_key(&key) {
+                    return false;
+                }
+                self.backing.remove(key.0, key.1);
+            }
+        }
+        true
+    }
+
+    /// Whether raw tile data holds nothing, fully transparent for color tiles
+    /// and all zero otherwise.
+    pub fn is_blank(&self, data: &[u8]) -> bool {
+        if self.format == Self::TILE_FORMAT {
+            Self::is_transparent(data)
+        } else {
+            data.iter().all(|b| *b == 0)
+        }
+    }
+
+    /// Whether every texel of raw `Rgba16Float` tile data has zero alpha.
+    pub fn is_transparent(data: &[u8]) -> bool {
+        data.chunks_exact(Self::TILE_PIXEL_BYTES as usize)
+            // Mask out the sign bit so -0.0 counts as well.
+            .all(|px| u16::from_le_bytes([px[6], px[7]]) & 0x7fff == 0)
+    }
+
+    /// Drops piles that no longer hold any tile, then moves the tiles of at most one
+    /// sparsely used pile into the free slices of others so it can be dropped too.
+    /// Spreading the copies over several calls keeps each of them cheap. Does
+    /// nothing unless slices were released since the last call.
+    ///
+    /// An empty pile is kept if it holds the only free slices, so painting right
+    /// after a release doesn't reallocate it straight away.
+    pub fn compact(&self) {
+        if !self.compact_pending.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let existing = self
+            .piles
+            .read()
+            .iter()
+            .map(Option::is_some)
+            .collect::<Vec<_>>();
+        let mut usage = vec![0; existing.len()];
+        for r in self.tiles.iter() {
+            if r.tile.id == Self::EMPTY_TILE_ID {
+                continue;
+            }
+            // Piles allocated during the scan are left alone.
+            if let Some(used) = usage.get_mut(r.tile.id.pile_index) {
+                *used += 1;
+            }
+        }
+
+        let mut order = (1..existing.len())
+            .filter(|i| existing[*i])
+            .collect::<Vec<_>>();
+        order.sort_by_key(|i| usage[*i]);
+
+        let mut moved = false;
+        for pile_index in order {
+            let used = usage[pile_index];
+            let free_elsewhere = self
+                .available_slices
+                .read()
+                .iter()
+                .filter(|(p, _)| *p != pile_index)
+                .count();
+
+            if used == 0 {
+                if free_elsewhere > 0 {
+                    self.evacuate_pile(pile_index);
+                }
+                continue;
+            }
+
+            if moved || used > Self::TILES_PER_PILE as usize / 4 || free_elsewhere < used {
+                break;
+            }
+
+            self.evacuate_pile(pile_index);
+            moved = true;
+        }
+
+        // Other piles may be sparse enough to be moved in the next call.
+        if moved {
+            self.compact_pending.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Moves all tiles out of the pile, then drops it. The slices are withdrawn
+    /// first, so no tile allocated meanwhile is left behind.
+    fn evacuate_pile(&self, pile_index: usize) {
+        self.withdraw_pile(pile_index);
+        let keys = self
+            .tiles
+            .iter()
+            .filter(|r| r.tile.id != Self::EMPTY_TILE_ID && r.tile.id.pile_index == pile_index)
+            .map(|r| *r.key())
+            .collect::<Vec<_>>();
+        if !keys.is_empty() {
+            self.move_tiles(&keys);
+        }
+        self.drop_pile(pile_index);
+    }
+
+    /// Removes free slices of the pile so nothing new is allocated in it.
+    fn withdraw_pile(&self, pile_index: usize) {
+        self.available_slices
+            .write()
+            .retain(|(p, _)| *p != pile_index);
+        self.stale_slices.retain(|(p, _)| *p != pile_index);
+    }
+
+    fn drop_pile(&self, pile_index: usize) {
+        self.withdraw_pile(pile_index);
+        let mut piles = self.piles.write();
+        piles[pile_index] = None;
+        log::info!(
+            "Dropped tile pile {}. Current pile count: {}",
+            pile_index,
+            piles.iter().flatten().count() - 1
+        );
+    }
+
+    /// Moves resident tiles into newly allocated slices.
+    fn move_tiles(&self, keys: &[(Id<Layer>, UVec2)]) {
+        let mut ec = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("compact tiles encoder"),
+            });
+
+        for (image_layer, index) in keys {
+            let Some(mut resident) = self.tiles.get_mut(&(*image_layer, *index)) else {
+                continue;
+            };
+            let (tile, _) = self.allocate_tile(*image_layer, *index);
+            Self::copy_tile(&mut ec, &resident.tile, &tile);
+            resident.tile = tile;
+        }
+
+        self.queue.submit([ec.finish()]);
+    }
+
+    fn copy_tile(ec: &mut CommandEncoder, src: &Tile, dst: &Tile) {
+        ec.copy_texture_to_texture(
+            TexelCopyTextureInfo {
+                texture: src.view.texture(),
+                mip_level: 0,
+                origin: Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: src.id.pile_layer,
+                },
+                aspect: TextureAspect::All,
+            },
+            TexelCopyTextureInfo {
+                texture: dst.view.texture(),
+                mip_level: 0,
+                origin: Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: dst.id.pile_layer,
+                },
+                aspect: TextureAspect::All,
+            },
+            Extent3d {
+                width: Self::TILE_SIZE,
+                height: Self::TILE_SIZE,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    /// Evicts least recently used tiles to the CPU store until the GPU budget is
+    /// met, then compacts the piles. Tiles used in the current frame are kept.
+    pub fn maintain(self: &Arc<Self>) {
+        self.evict_over_budget();
+        self.compact();
+    }
+
+    /// Starts reading back the least recently used tiles over the budget, unless
+    /// an eviction is already running. Tiles are only moved to the CPU store once
+    /// their texels arrived, so nothing waits for the GPU here.
+    fn evict_over_budget(self: &Arc<Self>) {
+        let budget = (self.config.read().gpu_budget / self.tile_bytes()) as usize;
+        let resident = self.tiles.len() - 1;
+        if resident <= budget || self.evicting.swap(true, Ordering::Acquire) {
+            return;
+        }
+
+        let frame = self.frame.load(Ordering::Relaxed);
+        let mut candidates = self
+            .tiles
+            .iter()
+            .filter(|r| r.tile.id != Self::EMPTY_TILE_ID && r.last_used < frame)
+            .map(|r| (r.tile.clone(), r.last_used))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable_by_key(|(_, last_used)| *last_used);
+        candidates.truncate(resident - budget);
+        if candidates.is_empty() {
+            self.evicting.store(false, Ordering::Release);
+            return;
+        }
+
+        let tiles = candidates
+            .iter()
+            .map(|(tile, _)| tile.clone())
+            .collect::<Vec<_>>();
+        let readback = self.read_tiles(&tiles);
+        let storage = self.clone();
+        let spawned = std::thread::Builder::new()
+            .name("tile eviction".into())
+            .spawn(move || {
+                match futures::executor::block_on(readback) {
+                    Ok(data) => storage.evict(&candidates, data),
+                    Err(e) => log::error!("Failed to read tiles back for eviction: {e}"),
+                }
+                storage.evicting.store(false, Ordering::Release);
+            });
+        if let Err(e) = spawned {
+            log::error!("Failed to spawn tile eviction: {e}");
+            self.evicting.store(false, Ordering::Release);
+        }
+    }
+
+    /// Moves read back tiles into the CPU store and frees their slices. Tiles
+    /// used again while the readback was running stay on the GPU, their texels
+    /// may have changed since.
+    fn evict(&self, candidates: &[(Tile, u64)], data: Vec<Vec<u8>>) {
+        let compression = self.config.read().compression;
+        let mut evicted = 0;
+        for ((tile, last_used), data) in candidates.iter().zip(data) {
+            let key = (tile.id.image_layer, tile.id.index);
+            let dashmap::Entry::Occupied(e) = self.tiles.entry(key) else {
+                continue;
+            };
+            if e.get().last_used != *last_used || e.get().tile.id != tile.id {
+                continue;
+            }
+            self.backing
+                .insert(key.0, key.1, CpuTile::new(&data, compression));
+            e.remove();
+            self.release_slice(tile);
+            evicted += 1;
+        }
+
+        if evicted > 0 {
+            self.evictions.fetch_add(evicted, Ordering::Relaxed);
+            log::info!(
+                "Evicted {} tiles to CPU. {} tiles are now held in CPU memory.",
+                evicted,
+                self.backing.len()
+            );
+        }
+    }
+
     fn try_allocate_new_tile_pile(&self) {
         if !self.available_slices.read().is_empty() {
             return;
         }
 
+        let mut piles = self.piles.write();
+        let pile_index = piles
+            .iter()
+            .position(|p| p.is_none())
+            .unwrap_or(piles.len());
+
+        // Single channel formats like `R8Unorm` can't be written from shaders.
+        let mut usage =
+            TextureUsages::COPY_SRC | TextureUsages::COPY_DST | TextureUsages::TEXTURE_BINDING;
+        if self
+            .format
+            .guaranteed_format_features(self.device.features())
+            .allowed_usages
+            .contains(TextureUsages::STORAGE_BINDING)
+        {
+            usage |= TextureUsages::STORAGE_BINDING;
+        }
         let texture = self.device.create_texture(&TextureDescriptor {
             label: Some("pile"),
             size: Extent3d {
@@ -208,10 +873,8 @@ impl GpuTileStorage {
             mip_level_count: 1,
             sample_count: 1,
             dimension: TextureDimension::D2,
-            format: Self::TILE_FORMAT,
-            usage: TextureUsages::STORAGE_BINDING
-                | TextureUsages::COPY_DST
-                | TextureUsages::TEXTURE_BINDING,
+            format: self.format,
+            usage,
             view_formats: &[],
         });
         let texture_view = texture.create_view(&TextureViewDescriptor {
@@ -226,21 +889,124 @@ impl GpuTileStorage {
             array_layer_count: None,
         });
 
-        let mut piles = self.piles.write();
-        piles.push(GpuTilePile {
+        let pile = GpuTilePile {
             texture: Arc::new(texture),
             texture_view: Arc::new(texture_view),
-        });
+        };
+        if pile_index == piles.len() {
+            piles.push(Some(pile));
+        } else {
+            piles[pile_index] = Some(pile);
+        }
         log::info!(
             "Allocated new tile pile. Current pile count: {}",
-            piles.len()
+            piles.iter().flatten().count() - 1
         );
-        let pile_index = piles.len() - 1;
         self.available_slices
             .write()
             .extend((0..Self::TILES_PER_PILE as usize).map(|x| (pile_index, x)));
     }
 
+    /// Uploads the image with its top left corner at `offset`. Pixels outside of
+    /// `bounds` are dropped and tiles left fully transparent are not allocated.
+    pub fn upload_image_at(
+        &self,
+        layer_id: Id<Layer>,
+        img: &Rgba32FImage,
+        offset: IVec2,
+        bounds: UVec2,
+    ) {
+        let size = IVec2::new(img.width() as i32, img.height() as i32);
+        let min = offset.max(IVec2::ZERO).as_uvec2();
+        let max = (offset + size)
+            .min(bounds.as_ivec2())
+            .max(IVec2::ZERO)
+            .as_uvec2();
+        if min.cmpge(max).any() {
+            return;
+        }
+
+        let tile_min = min / Self::TILE_SIZE;
+        let tile_max = UVec2::new(
+            max.x.div_ceil(Self::TILE_SIZE),
+            max.y.div_ceil(Self::TILE_SIZE),
+        );
+        let indices = (tile_min.y..tile_max.y)
+            .flat_map(|y| (tile_min.x..tile_max.x).map(move |x| UVec2::new(x, y)))
+            .collect::<Vec<_>>();
+
+        let tiles = indices
+            .par_iter()
+            .filter_map(|index| {
+                let data = Self::tile_from_image(img, offset, *index, min, max);
+                (!Self::is_transparent(&data)).then_some((*index, data))
+            })
+            .collect::<Vec<_>>();
+
+        for (index, data) in tiles {
+            self.write_tile_data(layer_id, index, &data);
+        }
+    }
+
+    /// Rewrites the tiles at `indices` from the image placed at `offset`, which
+    /// may cover them only partly. Tiles left fully transparent are released,
+    /// so content can move across tiles without leaving stale ones behind.
+    pub fn replace_tiles(
+        &self,
+        layer_id: Id<Layer>,
+        img: &Rgba32FImage,
+        offset: IVec2,
+        indices: &[UVec2],
+    ) {
+        let size = IVec2::new(img.width() as i32, img.height() as i32);
+        let min = offset.max(IVec2::ZERO).as_uvec2();
+        let max = (offset + size).max(IVec2::ZERO).as_uvec2();
+
+        let tiles = indices
+            .par_iter()
+            .map(|index| {
+                let data = Self::tile_from_image(img, offset, *index, min, max);
+                (*index, (!Self::is_transparent(&data)).then_some(data))
+            })
+            .collect::<Vec<_>>();
+
+        for (index, data) in tiles {
+            match data {
+                Some(data) => self.write_tile_data(layer_id, index, &data),
+                None => self.free_tile(layer_id, index),
+            }
+        }
+    }
+
+    /// Raw texels of the tile at `index` taken from the image placed at
+    /// `offset`. Pixels outside of `min` to `max` are transparent.
+    fn tile_from_image(
+        img: &Rgba32FImage,
+        offset: IVec2,
+        index: UVec2,
+        min: UVec2,
+        max: UVec2,
+    ) -> Vec<u8> {
+        let origin = index * Self::TILE_SIZE;
+        let mut data = vec![0u8; Self::TILE_BYTES as usize];
+        for y in 0..Self::TILE_SIZE {
+            for x in 0..Self::TILE_SIZE {
+                let p = origin + UVec2::new(x, y);
+                if p.cmplt(min).any() || p.cmpge(max).any() {
+                    continue;
+                }
+                let src = (p.as_ivec2() - offset).as_uvec2();
+                let px = img.get_pixel(src.x, src.y);
+                let start = ((y * Self::TILE_SIZE + x) * Self::TILE_PIXEL_BYTES) as usize;
+                for (c, value) in px.0.iter().enumerate() {
+                    data[start + c * 2..start + c * 2 + 2]
+                        .copy_from_slice(&half::f16::from_f32(*value).to_le_bytes());
+                }
+            }
+        }
+        data
+    }
+
     pub fn upload_image(&self, layer_id: Id<Layer>, img: DynamicImage) {
         let width = img.width();
         let height = img.height();
@@ -319,59 +1085,207 @@ impl GpuTileStorage {
         self.queue.submit([ec.finish()]);
     }
 
-    // pub fn offload_tile(&self, tile_id: TileId, callback: impl FnOnce(Vec<u8>) + Send + 'static) {
-    //     let Some((id, tile_view)) = self.views.remove(&tile_id) else {
-    //         return;
-    //     };
-    //     let texture = tile_view.texture_view.texture();
-    //     let pixel_size = texture.format().block_copy_size(None).unwrap();
-    //     let buffer = self.device.create_buffer(BufferDescriptor {
-    //         label: Some("temp buffer"),
-    //         size: (texture.width() * texture.height() * pixel_size) as wgpu::BufferAddress,
-    //         usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-    //         mapped_at_creation: false,
-    //     });
-    //     let mut ce = self
-    //         .device
-    //         .create_command_encoder(CommandEncoderDescriptor { label: None });
-    //     ce.copy_texture_to_buffer(
-    //         wgpu::TexelCopyTextureInfo {
-    //             texture,
-    //             mip_level: 1,
-    //             aspect: wgpu::TextureAspect::All,
-    //             origin: wgpu::Origin3d {
-    //                 x: 0,
-    //                 y: 0,
-    //                 z: tile_view.texture_layer,
-    //             },
-    //         },
-    //         wgpu::TexelCopyBufferInfo {
-    //             buffer: &buffer,
-    //             layout: wgpu::TexelCopyBufferLayout {
-    //                 offset: 0,
-    //                 bytes_per_row: Some(texture.width() * pixel_size),
-    //                 rows_per_image: None,
-    //             },
-    //         },
-    //         wgpu::Extent3d {
-    //             width: texture.width(),
-    //             height: texture.height(),
-    //             depth_or_array_layers: 1,
-    //         },
-    //     );
-    //     self.queue.submit([ce.finish()]);
-    //     buffer
-    //         .clone()
-    //         .map_async(wgpu::MapMode::Read, .., move |result| {
-    //             if let Err(e) = result {
-    //                 return;
-    //             }
-
-    //             let data = buffer.slice(..).get_mapped_range().to_vec();
-    //             buffer.unmap();
-    //             callback(data);
-    //         });
-    // }
+    pub fn copy_layer(&self, src: Id<Layer>, dst: Id<Layer>) {
+        self.bump_generation(dst);
+        for index in self.backing.layer_tiles(src) {
+            if let Some(tile) = self.backing.get(src, index) {
+                self.backing.insert(dst, index, tile);
+            }
+        }
+
+        let src_tiles = self
+            .tiles
+            .iter()
+            .filter(|r| r.key().0 == src)
+            .map(|r| r.tile.clone())
+            .collect::<Vec<_>>();
+        if src_tiles.is_empty() {
+            return;
+        }
+
+        let mut ec = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("copy layer encoder"),
+            });
+
+        for src_tile in src_tiles {
+            let dst_tile = self.get_tile_mut(dst, src_tile.id.index);
+            Self::copy_tile(&mut ec, &src_tile, &dst_tile);
+        }
+
+        self.queue.submit([ec.finish()]);
+    }
+
+    /// Copies the tiles at `indices` that exist in `src` into `dst`. Returns the
+    /// number of copied tiles, missing ones are skipped.
+    pub fn copy_tiles(&self, src: Id<Layer>, dst: Id<Layer>, indices: &[UVec2]) -> usize {
+        let mut ec = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("copy tiles encoder"),
+            });
+
+        self.bump_generation(dst);
+        let mut copied = 0;
+        for index in indices {
+            if let Some(tile) = self.backing.get(src, *index) {
+                self.backing.insert(dst, *index, tile);
+                copied += 1;
+                continue;
+            }
+            let Some(src_tile) = self.tiles.get(&(src, *index)).map(|r| r.tile.clone()) else {
+                continue;
+            };
+            let dst_tile = self.get_tile_mut(dst, *index);
+            Self::copy_tile(&mut ec, &src_tile, &dst_tile);
+            copied += 1;
+        }
+
+        self.queue.submit([ec.finish()]);
+        copied
+    }
+
+    /// Records copies of the pixels of a layer from `min` to `max` (exclusive)
+    /// into `dst` at its origin, whichever tiles they lie in. Missing tiles read
+    /// as transparent. Work recorded afterwards in `ec` can overwrite the tiles
+    /// without affecting the copy, which is how brushes sample what they paint
+    /// over.
+    pub fn copy_region_to_texture(
+        &self,
+        ec: &mut CommandEncoder,
+        image_layer: Id<Layer>,
+        min: UVec2,
+        max: UVec2,
+        dst: &Texture,
+    ) {
+        if min.cmpge(max).any() {
+            return;
+        }
+
+        let first = min / Self::TILE_SIZE;
+        let last = (max - 1) / Self::TILE_SIZE;
+        for y in first.y..=last.y {
+            for x in first.x..=last.x {
+                let index = UVec2::new(x, y);
+                let tile_min = index * Self::TILE_SIZE;
+                let from = min.max(tile_min);
+                let to = max.min(tile_min + Self::TILE_SIZE);
+                let tile = self.get_tile(image_layer, index);
+                ec.copy_texture_to_texture(
+                    TexelCopyTextureInfo {
+                        texture: tile.view.texture(),
+                        mip_level: 0,
+                        origin: Origin3d {
+                            x: from.x - tile_min.x,
+                            y: from.y - tile_min.y,
+                            z: tile.id.pile_layer,
+                        },
+                        aspect: TextureAspect::All,
+                    },
+                    TexelCopyTextureInfo {
+                        texture: dst,
+                        mip_level: 0,
+                        origin: Origin3d {
+                            x: from.x - min.x,
+                            y: from.y - min.y,
+                            z: 0,
+                        },
+                        aspect: TextureAspect::All,
+                    },
+                    Extent3d {
+                        width: to.x - from.x,
+                        height: to.y - from.y,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Exchanges the tiles at `indices` between two layers without touching any
+    /// texels. Tiles missing on one side end up missing on the other.
+    pub fn swap_tiles(&self, a: Id<Layer>, b: Id<Layer>, indices: &[UVec2]) {
+        self.bump_generation(a);
+        self.bump_generation(b);
+        let frame = self.frame.load(Ordering::Relaxed);
+        for index in indices {
+            let (key_a, key_b) = ((a, *index), (b, *index));
+            // Keeps a running sweep from releasing what it read before the swap.
+            self.written.insert(key_a, frame);
+            self.written.insert(key_b, frame);
+
+            let resident_a = self.tiles.remove(&key_a).map(|(_, r)| r);
+            let resident_b = self.tiles.remove(&key_b).map(|(_, r)| r);
+            if let Some(mut resident) = resident_a {
+                resident.tile.id.image_layer = b;
+                self.tiles.insert(key_b, resident);
+            }
+            if let Some(mut resident) = resident_b {
+                resident.tile.id.image_layer = a;
+                self.tiles.insert(key_a, resident);
+            }
+
+            let cpu_a = self.backing.remove(a, *index);
+            let cpu_b = self.backing.remove(b, *index);
+            if let Some(tile) = cpu_a {
+                self.backing.insert(b, *index, tile);
+            }
+            if let Some(tile) = cpu_b {
+                self.backing.insert(a, *index, tile);
+            }
+        }
+    }
+
+    /// Indices of every tile the layer has, on the GPU or in the CPU store.
+    pub fn layer_tiles(&self, image_layer: Id<Layer>) -> Vec<UVec2> {
+        let mut indices = self
+            .tiles
+            .iter()
+            .filter(|r| r.key().0 == image_layer)
+            .map(|r| r.key().1)
+            .collect::<Vec<_>>();
+        indices.extend(self.backing.layer_tiles(image_layer));
+        indices
+    }
+
+    /// Whether the tile exists, either on the GPU or in the CPU store.
+    pub fn has_tile(&self, image_layer: Id<Layer>, index: UVec2) -> bool {
+        self.tiles.contains_key(&(image_layer, index)) || self.backing.contains(image_layer, index)
+    }
+
+    /// Moves every tile of the layer out of the storage, reading resident ones
+    /// back from the GPU first. Blocks until they arrived.
+    pub fn take_layer(
+        &self,
+        image_layer: Id<Layer>,
+    ) -> Result<Vec<(UVec2, CpuTile)>, ReadbackError> {
+        let resident = self
+            .tiles
+            .iter()
+            .filter(|r| r.key().0 == image_layer)
+            .map(|r| (r.tile.clone(), r.last_used))
+            .collect::<Vec<_>>();
+        let tiles = resident
+            .iter()
+            .map(|(tile, _)| tile.clone())
+            .collect::<Vec<_>>();
+        let data = futures::executor::block_on(self.read_tiles(&tiles))?;
+        self.evict(&resident, data);
+        self.written.retain(|(layer, _), _| *layer != image_layer);
+        self.bump_generation(image_layer);
+
+        Ok(self
+            .backing
+            .layer_tiles(image_layer)
+            .into_iter()
+            .filter_map(|index| {
+                self.backing
+                    .remove(image_layer, index)
+                    .map(|tile| (index, tile))
+            })
+            .collect())
+    }
 
     pub fn get_tile_views(
         &self,
@@ -405,10 +1319,70 @@ impl GpuTileStorage {
         let piles = self.piles.read();
         groups
             .into_iter()
-            .map(|(pile_index, tiles)| GroupedTileViews {
-                pile: piles[pile_index].texture_view.clone(),
-                tiles,
+            .filter_map(|(pile_index, tiles)| {
+                Some(GroupedTileViews {
+                    pile: piles[pile_index].as_ref()?.texture_view.clone(),
+                    tiles,
+                })
             })
             .collect()
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A storage on whatever adapter there is. Tests needing one are skipped
+    /// without it.
+    pub(crate) fn storage() -> Option<GpuTileStorage> {
+        let instance = wgpu::Instance::default();
+        let adapter =
+            futures::executor::block_on(instance.request_adapter(&Default::default())).ok()?;
+        let (device, queue) =
+            futures::executor::block_on(adapter.request_device(&Default::default())).ok()?;
+        Some(GpuTileStorage::new(Arc::new(device), Arc::new(queue)))
+    }
+
+    /// Raw texels of a tile filled with a single value in every channel.
+    pub(crate) fn filled(value: f32) -> Vec<u8> {
+        half::f16::from_f32(value)
+            .to_le_bytes()
+            .repeat(GpuTileStorage::TILE_BYTES as usize / 2)
+    }
+
+    #[test]
+    fn take_layer_moves_resident_and_evicted_tiles_out() {
+        let Some(storage) = storage() else {
+            return;
+        };
+        let layer = Id::random();
+        let other = Id::random();
+        storage.write_tile_data(layer, UVec2::new(0, 0), &filled(0.25));
+        storage.write_tile_data(other, UVec2::new(0, 0), &filled(1.0));
+        storage.backing.insert(
+            layer,
+            UVec2::new(1, 0),
+            CpuTile::new(&filled(0.5), TileCompression::Lz4),
+        );
+        let generation = storage.generation(layer);
+
+        let mut taken = storage.take_layer(layer).unwrap();
+        taken.sort_by_key(|(index, _)| index.x);
+        let taken = taken
+            .into_iter()
+            .map(|(index, tile)| (index, tile.decode()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            taken,
+            [
+                (UVec2::new(0, 0), filled(0.25)),
+                (UVec2::new(1, 0), filled(0.5)),
+            ]
+        );
+
+        assert!(storage.layer_tiles(layer).is_empty());
+        assert_ne!(storage.generation(layer), generation);
+        assert!(storage.is_resident(other, UVec2::new(0, 0)));
+    }
+}
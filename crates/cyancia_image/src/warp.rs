@@ -0,0 +1,460 @@
+//! Resampling pixels into a new shape, for the transform tool. The source is a
+//! rectangle of read back pixels and the result covers whatever part of the
+//! image it lands on. Every output pixel is mapped back into the source and
+//! sampled there, so no holes open up however far the pixels are stretched.
+
+use glam::{IVec2, Mat3, UVec2, Vec2, Vec3, Vec4};
+use image::Rgba32FImage;
+use rayon::{
+    iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator},
+    slice::{ParallelSlice, ParallelSliceMut},
+};
+
+/// How pixels are read between source pixel centers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResampleFilter {
+    /// The closest pixel, keeping hard pixel art edges.
+    Nearest,
+    #[default]
+    Bilinear,
+    /// Sharper than bilinear when scaling up, may ring a little along edges.
+    Bicubic,
+}
+
+/// Where the source ends up on the image.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warp {
+    /// The corners of the source, clockwise from the top left. Anything but a
+    /// parallelogram puts the source in perspective.
+    Quad([Vec2; 4]),
+    Mesh(MeshWarp),
+}
+
+impl Warp {
+    /// The smallest rectangle of whole pixels holding the result.
+    pub fn bounds(&self) -> (IVec2, IVec2) {
+        let points = match self {
+            Warp::Quad(corners) => corners.as_slice(),
+            Warp::Mesh(mesh) => mesh.points.as_slice(),
+        };
+        let (min, max) = points.iter().fold(
+            (Vec2::splat(f32::MAX), Vec2::splat(f32::MIN)),
+            |(min, max), p| (min.min(*p), max.max(*p)),
+        );
+        (min.floor().as_ivec2(), max.ceil().as_ivec2())
+    }
+
+    /// For each pixel of the `size` rectangle at `origin`, where its center
+    /// comes from in the `source` sized rectangle, or `None` if it's outside
+    /// of the result.
+    pub fn source_positions(&self, source: Vec2, origin: IVec2, size: UVec2) -> Vec<Option<Vec2>> {
+        let mut positions = vec![None; (size.x * size.y) as usize];
+        if size.x == 0 || size.y == 0 {
+            return positions;
+        }
+
+        match self {
+            Warp::Quad(corners) => {
+                let to_source =
+                    (square_to_quad(*corners) * Mat3::from_scale(Vec2::ONE / source)).inverse();
+                positions
+                    .par_chunks_mut(size.x as usize)
+                    .enumerate()
+                    .for_each(|(y, row)| {
+                        for (x, position) in row.iter_mut().enumerate() {
+                            let p = origin.as_vec2() + Vec2::new(x as f32, y as f32) + 0.5;
+                            let v = to_source * p.extend(1.0);
+                            if v.z <= f32::EPSILON {
+                                continue;
+                            }
+                            let s = v.truncate() / v.z;
+                            if s.cmpge(Vec2::ZERO).all() && s.cmple(source).all() {
+                                *position = Some(s);
+                            }
+                        }
+                    });
+            }
+            Warp::Mesh(mesh) => {
+                let triangles = mesh.triangles(source);
+                positions
+                    .par_chunks_mut(size.x as usize)
+                    .enumerate()
+                    .for_each(|(y, row)| {
+                        let py = origin.y as f32 + y as f32 + 0.5;
+                        for [a, b, c] in &triangles {
+                            let (dst, src) = ([a.0, b.0, c.0], [a.1, b.1, c.1]);
+                            let min_y = dst[0].y.min(dst[1].y).min(dst[2].y);
+                            let max_y = dst[0].y.max(dst[1].y).max(dst[2].y);
+                            if py < min_y || py > max_y {
+                                continue;
+                            }
+                            let min_x = dst[0].x.min(dst[1].x).min(dst[2].x);
+                            let max_x = dst[0].x.max(dst[1].x).max(dst[2].x);
+                            let first = ((min_x - origin.x as f32).floor().max(0.0)) as usize;
+                            let last =
+                                ((max_x - origin.x as f32).ceil().max(0.0) as usize).min(row.len());
+                            let span = row.iter_mut().enumerate().take(last).skip(first);
+                            for (x, position) in span {
+                                let p = Vec2::new(origin.x as f32 + x as f32 + 0.5, py);
+                                if let Some(w) = barycentric(dst, p) {
+                                    *position = Some(src[0] * w.x + src[1] * w.y + src[2] * w.z);
+                                }
+                            }
+                        }
+                    });
+            }
+        }
+        positions
+    }
+}
+
+/// A grid laid over the source whose points can be moved one by one, bending
+/// the pixels between them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeshWarp {
+    pub cols: u32,
+    pub rows: u32,
+    /// Where the grid points end up, row by row, `(cols + 1) * (rows + 1)`
+    /// of them.
+    pub points: Vec<Vec2>,
+}
+
+impl MeshWarp {
+    /// An evenly spaced grid over the quad.
+    pub fn new(cols: u32, rows: u32, corners: [Vec2; 4]) -> Self {
+        let cols = cols.max(1);
+        let rows = rows.max(1);
+        let points = (0..=rows)
+            .flat_map(|y| (0..=cols).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let u = x as f32 / cols as f32;
+                let v = y as f32 / rows as f32;
+                let top = corners[0].lerp(corners[1], u);
+                let bottom = corners[3].lerp(corners[2], u);
+                top.lerp(bottom, v)
+            })
+            .collect();
+        Self { cols, rows, points }
+    }
+
+    pub fn point(&self, x: u32, y: u32) -> Vec2 {
+        self.points[(y * (self.cols + 1) + x) as usize]
+    }
+
+    /// Corners of the grid, clockwise from the top left.
+    pub fn corners(&self) -> [Vec2; 4] {
+        [
+            self.point(0, 0),
+            self.point(self.cols, 0),
+            self.point(self.cols, self.rows),
+            self.point(0, self.rows),
+        ]
+    }
+
+    /// Lines between neighbouring grid points.
+    pub fn edges(&self) -> Vec<(Vec2, Vec2)> {
+        let mut edges = Vec::new();
+        for y in 0..=self.rows {
+            for x in 0..=self.cols {
+                if x < self.cols {
+                    edges.push((self.point(x, y), self.point(x + 1, y)));
+                }
+                if y < self.rows {
+                    edges.push((self.point(x, y), self.point(x, y + 1)));
+                }
+            }
+        }
+        edges
+    }
+
+    /// Two triangles per cell, each corner as the destination and the source
+    /// position it comes from.
+    fn triangles(&self, source: Vec2) -> Vec<[(Vec2, Vec2); 3]> {
+        let cell = source / UVec2::new(self.cols, self.rows).as_vec2();
+        let vertex = |x: u32, y: u32| (self.point(x, y), UVec2::new(x, y).as_vec2() * cell);
+        (0..self.rows)
+            .flat_map(|y| (0..self.cols).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let (a, b) = (vertex(x, y), vertex(x + 1, y));
+                let (c, d) = (vertex(x + 1, y + 1), vertex(x, y + 1));
+                [[a, b, c], [a, c, d]]
+            })
+            .collect()
+    }
+}
+
+/// Maps the unit square onto the quad, clockwise from the top left. Affine if
+/// the quad is a parallelogram, projective otherwise.
+pub fn square_to_quad(corners: [Vec2; 4]) -> Mat3 {
+    let [p0, p1, p2, p3] = corners;
+    let sum = p0 - p1 + p2 - p3;
+    let (g, h) = if sum.abs().max_element() <= f32::EPSILON {
+        (0.0, 0.0)
+    } else {
+        let d1 = p1 - p2;
+        let d2 = p3 - p2;
+        let det = d1.perp_dot(d2);
+        if det.abs() <= f32::EPSILON {
+            (0.0, 0.0)
+        } else {
+            (sum.perp_dot(d2) / det, d1.perp_dot(sum) / det)
+        }
+    };
+    let u = p1 - p0 + g * p1;
+    let v = p3 - p0 + h * p3;
+    Mat3::from_cols(u.extend(g), v.extend(h), p0.extend(1.0))
+}
+
+/// Weights of the triangle corners at `p`, or `None` if it's outside.
+fn barycentric(t: [Vec2; 3], p: Vec2) -> Option<Vec3> {
+    let area = (t[1] - t[0]).perp_dot(t[2] - t[0]);
+    if area.abs() <= f32::EPSILON {
+        return None;
+    }
+    let w1 = (p - t[0]).perp_dot(t[2] - t[0]) / area;
+    let w2 = (t[1] - t[0]).perp_dot(p - t[0]) / area;
+    let w0 = 1.0 - w1 - w2;
+    let e = -1e-4;
+    (w0 >= e && w1 >= e && w2 >= e).then_some(Vec3::new(w0, w1, w2))
+}
+
+/// Pixels of `source` resampled into the shape of the warp, clipped to
+/// `bounds`. Returns the top left corner of the result on the image, `None` if
+/// nothing lands inside.
+pub fn warp(
+    source: &Rgba32FImage,
+    warp: &Warp,
+    filter: ResampleFilter,
+    bounds: UVec2,
+) -> Option<(IVec2, Rgba32FImage)> {
+    let (min, max) = warp.bounds();
+    let min = min.max(IVec2::ZERO);
+    let max = max.min(bounds.as_ivec2());
+    if min.cmpge(max).any() {
+        return None;
+    }
+    let size = (max - min).as_uvec2();
+
+    let source_size = UVec2::new(source.width(), source.height());
+    let premultiplied = source
+        .as_raw()
+        .par_chunks_exact(4)
+        .map(|p| {
+            let p = Vec4::from_slice(p);
+            (p.truncate() * p.w).extend(p.w)
+        })
+        .collect::<Vec<_>>();
+    let positions = warp.source_positions(source_size.as_vec2(), min, size);
+
+    let mut result = Rgba32FImage::new(size.x, size.y);
+    result
+        .par_chunks_mut(4)
+        .zip(positions.par_iter())
+        .for_each(|(out, position)| {
+            let Some(position) = *position else {
+                return;
+            };
+            let color = sample(&premultiplied, source_size, position, filter);
+            if color.w > 0.0 {
+                let straight = (color.truncate() / color.w).extend(color.w);
+                out.copy_from_slice(&straight.to_array());
+            }
+        });
+    Some((min, result))
+}
+
+/// Premultiplied color at `position`, a point in pixel units where pixel
+/// centers sit at halves. Beyond the edges the source is transparent, which
+/// antialiases the border of the result.
+pub fn sample(pixels: &[Vec4], size: UVec2, position: Vec2, filter: ResampleFilter) -> Vec4 {
+    let texel = |x: i32, y: i32| {
+        if x < 0 || y < 0 || x >= size.x as i32 || y >= size.y as i32 {
+            Vec4::ZERO
+        } else {
+            pixels[(y as u32 * size.x + x as u32) as usize]
+        }
+    };
+
+    match filter {
+        ResampleFilter::Nearest => {
+            let p = position.floor().as_ivec2();
+            texel(p.x, p.y)
+        }
+        ResampleFilter::Bilinear => {
+            let p = position - 0.5;
+            let base = p.floor();
+            let t = p - base;
+            let (x, y) = (base.x as i32, base.y as i32);
+            let top = texel(x, y).lerp(texel(x + 1, y), t.x);
+            let bottom = texel(x, y + 1).lerp(texel(x + 1, y + 1), t.x);
+            top.lerp(bottom, t.y)
+        }
+        ResampleFilter::Bicubic => {
+            let p = position - 0.5;
+            let base = p.floor();
+            let t = p - base;
+            let (wx, wy) = (catmull_rom(t.x), catmull_rom(t.y));
+            let (x, y) = (base.x as i32, base.y as i32);
+            let mut color = Vec4::ZERO;
+            for (j, wy) in wy.iter().enumerate() {
+                for (i, wx) in wx.iter().enumerate() {
+                    color += texel(x + i as i32 - 1, y + j as i32 - 1) * (wx * wy);
+                }
+            }
+            // The negative lobes may overshoot, premultiplied colors have to
+            // stay within their alpha.
+            let alpha = color.w.clamp(0.0, 1.0);
+            color
+                .truncate()
+                .clamp(Vec3::ZERO, Vec3::splat(alpha))
+                .extend(alpha)
+        }
+    }
+}
+
+/// Weights of the four texels around a point `t` past the second one.
+fn catmull_rom(t: f32) -> [f32; 4] {
+    let (t2, t3) = (t * t, t * t * t);
+    [
+        0.5 * (-t3 + 2.0 * t2 - t),
+        0.5 * (3.0 * t3 - 5.0 * t2 + 2.0),
+        0.5 * (-3.0 * t3 + 4.0 * t2 + t),
+        0.5 * (t3 - t2),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(min: Vec2, max: Vec2) -> [Vec2; 4] {
+        [min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y)]
+    }
+
+    fn project(m: Mat3, p: Vec2) -> Vec2 {
+        let v = m * p.extend(1.0);
+        v.truncate() / v.z
+    }
+
+    /// Opaque pixels of different colors, premultiplied like `sample` expects.
+    fn source() -> (Vec<Vec4>, UVec2) {
+        let size = UVec2::new(5, 4);
+        let pixels = (0..size.x * size.y)
+            .map(|i| Vec4::new(i as f32 / 20.0, (i % 3) as f32 / 3.0, 0.5, 1.0))
+            .collect();
+        (pixels, size)
+    }
+
+    #[test]
+    fn identity_quad_maps_points_to_themselves() {
+        let m = square_to_quad(rect(Vec2::ZERO, Vec2::ONE));
+        assert!(m.abs_diff_eq(Mat3::IDENTITY, 1e-6));
+        for p in [Vec2::ZERO, Vec2::new(0.25, 0.75), Vec2::new(3.0, -2.0)] {
+            assert!(project(m, p).abs_diff_eq(p, 1e-6));
+        }
+    }
+
+    #[test]
+    fn corners_map_exactly() {
+        let quads = [
+            // Affine.
+            [
+                Vec2::new(10.0, 5.0),
+                Vec2::new(30.0, 15.0),
+                Vec2::new(25.0, 40.0),
+                Vec2::new(5.0, 30.0),
+            ],
+            // In perspective.
+            [
+                Vec2::new(0.0, 0.0),
+                Vec2::new(100.0, 20.0),
+                Vec2::new(90.0, 70.0),
+                Vec2::new(15.0, 100.0),
+            ],
+        ];
+        for corners in quads {
+            let m = square_to_quad(corners);
+            let square = rect(Vec2::ZERO, Vec2::ONE);
+            for (from, to) in square.iter().zip(corners) {
+                assert!(project(m, *from).abs_diff_eq(to, 1e-3), "{from} {to}");
+            }
+        }
+    }
+
+    #[test]
+    fn filters_give_the_source_at_pixel_centers() {
+        let (pixels, size) = source();
+        for filter in [
+            ResampleFilter::Nearest,
+            ResampleFilter::Bilinear,
+            ResampleFilter::Bicubic,
+        ] {
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    let center = UVec2::new(x, y).as_vec2() + 0.5;
+                    let expected = pixels[(y * size.x + x) as usize];
+                    let sampled = sample(&pixels, size, center, filter);
+                    assert!(sampled.abs_diff_eq(expected, 1e-5), "{filter:?} {x} {y}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bilinear_blends_between_pixel_centers() {
+        let (pixels, size) = source();
+        let between = sample(&pixels, size, Vec2::new(1.0, 0.5), ResampleFilter::Bilinear);
+        assert!(between.abs_diff_eq(pixels[0].lerp(pixels[1], 0.5), 1e-6));
+        // Half of the edge pixel against the transparent outside.
+        let edge = sample(&pixels, size, Vec2::new(0.0, 0.5), ResampleFilter::Bilinear);
+        assert!(edge.abs_diff_eq(pixels[0] * 0.5, 1e-6));
+    }
+
+    #[test]
+    fn positions_outside_of_the_mesh_are_none() {
+        let source = Vec2::new(10.0, 10.0);
+        let mesh = Warp::Mesh(MeshWarp::new(
+            2,
+            2,
+            rect(Vec2::new(10.0, 10.0), Vec2::new(20.0, 20.0)),
+        ));
+        let size = UVec2::new(30, 30);
+        let positions = mesh.source_positions(source, IVec2::ZERO, size);
+
+        for (i, position) in positions.iter().enumerate() {
+            let p = UVec2::new(i as u32 % size.x, i as u32 / size.x);
+            let inside = p.cmpge(UVec2::splat(10)).all() && p.cmplt(UVec2::splat(20)).all();
+            assert_eq!(position.is_some(), inside, "{p}");
+            if let Some(s) = position {
+                // An unmoved grid is a plain offset.
+                assert!(s.abs_diff_eq(p.as_vec2() + 0.5 - 10.0, 1e-4));
+            }
+        }
+    }
+
+    #[test]
+    fn identity_warp_keeps_the_pixels() {
+        let mut source = Rgba32FImage::new(4, 3);
+        for (x, y, pixel) in source.enumerate_pixels_mut() {
+            pixel.0 = [x as f32 / 4.0, y as f32 / 3.0, 1.0, 1.0];
+        }
+        let corners = rect(Vec2::ZERO, Vec2::new(4.0, 3.0));
+
+        let (origin, result) = warp(
+            &source,
+            &Warp::Quad(corners),
+            ResampleFilter::Bicubic,
+            UVec2::splat(8),
+        )
+        .unwrap();
+        assert_eq!(origin, IVec2::ZERO);
+        assert_eq!(result.dimensions(), source.dimensions());
+        for (a, b) in result.pixels().zip(source.pixels()) {
+            assert!(Vec4::from_array(a.0).abs_diff_eq(Vec4::from_array(b.0), 1e-5));
+        }
+
+        // Nothing lands on the image.
+        let away = Warp::Quad(rect(Vec2::splat(20.0), Vec2::splat(30.0)));
+        assert!(warp(&source, &away, ResampleFilter::Bilinear, UVec2::splat(8)).is_none());
+    }
+}
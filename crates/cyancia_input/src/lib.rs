@@ -5,6 +5,8 @@ use crate::action::ActionManifestLoader;
 pub mod action;
 pub mod key;
 pub mod mouse;
+pub mod predict;
+pub mod stabilizer;
 
 pub fn register_loaders(loaders: &mut AssetLoaderRegistry) {
     loaders.register::<ActionManifestLoader>();
@@ -1,10 +1,169 @@
-use iced_core::Point;
+use std::{collections::VecDeque, time::Instant};
 
-#[derive(Debug, Clone)]
-pub struct PressedMouseState {
-    pub position: Point,
+use iced_core::{Point, Vector};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PointerKind {
+    #[default]
+    Mouse,
+    Pen,
+    /// The eraser end of a pen.
+    Eraser,
+    Touch,
 }
 
-pub struct HoverMouseState {
+/// The button holding a stroke. Pen tips and touches count as primary.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PointerButton {
+    #[default]
+    Primary,
+    Secondary,
+    Middle,
+}
+
+/// Where the pointer is and, for styluses, how it is held.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PointerState {
     pub position: Point,
+    /// Normalized pressure from 0 to 1.
+    pub pressure: f32,
+    /// Tilt of the pen towards the x and y axes in degrees, from -90 to 90.
+    pub tilt: Vector,
+    /// Barrel rotation of the pen in degrees, from 0 to 360.
+    pub rotation: f32,
+    pub timestamp: Instant,
+    pub kind: PointerKind,
+    pub button: PointerButton,
+}
+
+impl PointerState {
+    /// A mouse has no stylus data, it is upright and presses fully while a
+    /// button is held.
+    pub fn mouse(position: Point, pressed: bool) -> Self {
+        Self {
+            position,
+            pressure: if pressed { 1.0 } else { 0.0 },
+            tilt: Vector::ZERO,
+            rotation: 0.0,
+            timestamp: Instant::now(),
+            kind: PointerKind::Mouse,
+            button: PointerButton::Primary,
+        }
+    }
+
+    pub fn with_button(self, button: PointerButton) -> Self {
+        Self { button, ..self }
+    }
+}
+
+pub type PressedMouseState = PointerState;
+
+pub type HoverMouseState = PointerState;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PointerEvent {
+    Pressed(PointerState),
+    MovedPressing(PointerState),
+    MovedHovering(PointerState),
+    Released(PointerState),
+}
+
+impl PointerEvent {
+    pub fn state(&self) -> &PointerState {
+        match self {
+            PointerEvent::Pressed(state)
+            | PointerEvent::MovedPressing(state)
+            | PointerEvent::MovedHovering(state)
+            | PointerEvent::Released(state) => state,
+        }
+    }
+}
+
+/// Pointer input coming from somewhere other than the window, like a tablet
+/// driver or synthetic data.
+pub trait PointerEventSource: Send {
+    /// Takes the next pending event.
+    fn poll(&mut self) -> Option<PointerEvent>;
+}
+
+/// Hands out a prepared list of events in order.
+#[derive(Debug, Default, Clone)]
+pub struct ScriptedPointerSource {
+    events: VecDeque<PointerEvent>,
+}
+
+impl ScriptedPointerSource {
+    pub fn new(events: impl IntoIterator<Item = PointerEvent>) -> Self {
+        Self {
+            events: events.into_iter().collect(),
+        }
+    }
+
+    pub fn push(&mut self, event: PointerEvent) {
+        self.events.push_back(event);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+impl PointerEventSource for ScriptedPointerSource {
+    fn poll(&mut self) -> Option<PointerEvent> {
+        self.events.pop_front()
+    }
+}
+
+/// Every source of pointer events besides the window.
+#[derive(Default)]
+pub struct PointerSources {
+    sources: Vec<Box<dyn PointerEventSource>>,
+}
+
+impl PointerSources {
+    pub fn add(&mut self, source: impl PointerEventSource + 'static) {
+        self.sources.push(Box::new(source));
+    }
+
+    /// Takes the pending events of all sources, one source after another.
+    pub fn drain(&mut self) -> Vec<PointerEvent> {
+        let mut events = Vec::new();
+        for source in &mut self.sources {
+            while let Some(event) = source.poll() {
+                events.push(event);
+            }
+        }
+        events
+    }
+}
+
+/// Every raw pointer event since the last frame, in the order they happened.
+/// Devices report far more often than frames are drawn, so tools get the
+/// events in batches instead of one update each.
+#[derive(Debug, Default, Clone)]
+pub struct InputQueue {
+    events: Vec<PointerEvent>,
+}
+
+impl InputQueue {
+    pub fn push(&mut self, event: PointerEvent) {
+        self.events.push(event);
+    }
+
+    pub fn extend(&mut self, events: impl IntoIterator<Item = PointerEvent>) {
+        self.events.extend(events);
+    }
+
+    /// Takes everything queued so far.
+    pub fn drain(&mut self) -> Vec<PointerEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
 }
@@ -0,0 +1,152 @@
+//! Guesses where the pointer is headed, so what follows it can be drawn
+//! ahead of the input that is still on its way.
+
+use std::{collections::VecDeque, time::Duration};
+
+use iced_core::Vector;
+
+use crate::mouse::PointerState;
+
+/// Extrapolates the pointer along its recent velocity, fit over the samples
+/// of the last [`MotionPredictor::WINDOW`] so single jittery events don't
+/// throw the guess off.
+#[derive(Debug, Clone)]
+pub struct MotionPredictor {
+    /// How far ahead to predict, about the latency to hide.
+    pub lead: Duration,
+    samples: VecDeque<PointerState>,
+}
+
+impl Default for MotionPredictor {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(16))
+    }
+}
+
+impl MotionPredictor {
+    pub const WINDOW: Duration = Duration::from_millis(50);
+
+    pub fn new(lead: Duration) -> Self {
+        Self {
+            lead,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn push(&mut self, sample: PointerState) {
+        self.samples.push_back(sample);
+        while self.samples.front().is_some_and(|first| {
+            sample.timestamp.saturating_duration_since(first.timestamp) > Self::WINDOW
+        }) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// The pointer `lead` after the last sample, `None` until there are
+    /// enough samples to tell a velocity.
+    pub fn predict(&self) -> Option<PointerState> {
+        let last = *self.samples.back()?;
+        let first = self.samples.front()?;
+
+        // Least squares fit of the position against time.
+        let n = self.samples.len() as f32;
+        let times = self.samples.iter().map(|s| {
+            s.timestamp
+                .saturating_duration_since(first.timestamp)
+                .as_secs_f32()
+        });
+        let mean_t = times.clone().sum::<f32>() / n;
+        let mean_p = self.samples.iter().fold(Vector::ZERO, |sum, s| {
+            sum + Vector::new(s.position.x, s.position.y) * (1.0 / n)
+        });
+
+        let mut variance = 0.0;
+        let mut covariance = Vector::ZERO;
+        for (t, s) in times.zip(&self.samples) {
+            let dt = t - mean_t;
+            variance += dt * dt;
+            covariance += (Vector::new(s.position.x, s.position.y) - mean_p) * dt;
+        }
+        if variance <= 0.0 {
+            return None;
+        }
+
+        let velocity = covariance * (1.0 / variance);
+        Some(PointerState {
+            position: last.position + velocity * self.lead.as_secs_f32(),
+            timestamp: last.timestamp + self.lead,
+            ..last
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use iced_core::Point;
+
+    use super::*;
+
+    fn at(start: Instant, ms: u64, position: Point) -> PointerState {
+        PointerState {
+            timestamp: start + Duration::from_millis(ms),
+            ..PointerState::mouse(position, true)
+        }
+    }
+
+    #[test]
+    fn nothing_to_predict_from() {
+        let mut predictor = MotionPredictor::default();
+        assert_eq!(predictor.predict(), None);
+
+        predictor.push(at(Instant::now(), 0, Point::new(10.0, 10.0)));
+        assert_eq!(predictor.predict(), None);
+    }
+
+    #[test]
+    fn samples_at_the_same_time() {
+        let start = Instant::now();
+        let mut predictor = MotionPredictor::default();
+        predictor.push(at(start, 5, Point::new(0.0, 0.0)));
+        predictor.push(at(start, 5, Point::new(10.0, 0.0)));
+        assert_eq!(predictor.predict(), None);
+    }
+
+    #[test]
+    fn constant_velocity_extrapolates_exactly() {
+        let start = Instant::now();
+        let mut predictor = MotionPredictor::new(Duration::from_millis(16));
+        // 1000 px/s along x, 500 px/s along y.
+        for i in 0..5 {
+            let t = i as f32 * 8.0;
+            predictor.push(at(start, i * 8, Point::new(t, 100.0 + t * 0.5)));
+        }
+
+        let predicted = predictor.predict().unwrap();
+        assert!((predicted.position.x - 48.0).abs() < 1e-3);
+        assert!((predicted.position.y - 124.0).abs() < 1e-3);
+        assert_eq!(predicted.timestamp, start + Duration::from_millis(48));
+    }
+
+    #[test]
+    fn old_samples_leave_the_window() {
+        let start = Instant::now();
+        let mut predictor = MotionPredictor::new(Duration::from_millis(10));
+        // Moving right, then turning around.
+        predictor.push(at(start, 0, Point::new(0.0, 0.0)));
+        predictor.push(at(start, 10, Point::new(10.0, 0.0)));
+        predictor.push(at(start, 100, Point::new(100.0, 0.0)));
+        predictor.push(at(start, 110, Point::new(90.0, 0.0)));
+
+        let predicted = predictor.predict().unwrap();
+        assert!((predicted.position.x - 80.0).abs() < 1e-3);
+
+        predictor.reset();
+        assert_eq!(predictor.predict(), None);
+    }
+}
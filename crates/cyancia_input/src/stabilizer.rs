@@ -0,0 +1,335 @@
+//! Smooths the pointer path of a stroke. Everything here works on timestamped
+//! [`PointerState`]s alone, so it runs the same on live and recorded input.
+
+use std::collections::VecDeque;
+
+use iced_core::{Point, Vector};
+use serde::{Deserialize, Serialize};
+
+use crate::mouse::PointerState;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StabilizerMode {
+    /// Samples go through untouched.
+    #[default]
+    None,
+    /// Averages the latest samples, newer ones weighing more.
+    WeightedAverage,
+    /// The brush hangs on a rope of `radius` behind the pointer and only moves
+    /// once the rope is taut, so small wobbles never reach the canvas.
+    Rope,
+    /// The brush is pulled towards the pointer like by a spring, lagging
+    /// further behind the faster the pointer moves.
+    PullString,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StabilizerSettings {
+    pub mode: StabilizerMode,
+    /// From 0 to 1, how much the path gets smoothed.
+    pub strength: f32,
+    /// Length of the rope in widget pixels.
+    pub radius: f32,
+    /// Draws the rest of the way to the pointer when the pen lifts, instead of
+    /// ending the stroke where the brush lags.
+    pub catch_up: bool,
+}
+
+impl Default for StabilizerSettings {
+    fn default() -> Self {
+        Self {
+            mode: StabilizerMode::None,
+            strength: 0.5,
+            radius: 20.0,
+            catch_up: true,
+        }
+    }
+}
+
+impl StabilizerSettings {
+    /// Samples averaged at full strength.
+    pub const MAX_WINDOW: usize = 32;
+    /// Seconds the brush takes to cover ~63% of the way to its target at full
+    /// strength.
+    pub const MAX_LAG: f32 = 0.25;
+
+    fn window(&self) -> usize {
+        1 + (self.strength.clamp(0.0, 1.0) * (Self::MAX_WINDOW - 1) as f32).round() as usize
+    }
+
+    fn lag(&self) -> f32 {
+        self.strength.clamp(0.0, 1.0) * Self::MAX_LAG
+    }
+}
+
+/// Smooths one stroke at a time: [`Stabilizer::press`] starts it,
+/// [`Stabilizer::advance`] smooths each move and [`Stabilizer::release`] ends it.
+#[derive(Debug, Default, Clone)]
+pub struct Stabilizer {
+    settings: StabilizerSettings,
+    window: VecDeque<PointerState>,
+    /// Where the brush is, for the rope and the pull string.
+    brush: Option<PointerState>,
+}
+
+impl Stabilizer {
+    pub fn new(settings: StabilizerSettings) -> Self {
+        Self {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    pub fn settings(&self) -> &StabilizerSettings {
+        &self.settings
+    }
+
+    /// Takes effect on the next stroke.
+    pub fn set_settings(&mut self, settings: StabilizerSettings) {
+        self.settings = settings;
+    }
+
+    /// Starts a stroke, the first sample is always exact.
+    pub fn press(&mut self, sample: PointerState) -> PointerState {
+        self.window.clear();
+        self.window.push_back(sample);
+        self.brush = Some(sample);
+        sample
+    }
+
+    pub fn advance(&mut self, sample: PointerState) -> PointerState {
+        match self.settings.mode {
+            StabilizerMode::None => sample,
+            StabilizerMode::WeightedAverage => self.average(sample),
+            StabilizerMode::Rope => self.follow(sample, self.settings.radius.max(0.0)),
+            StabilizerMode::PullString => self.follow(sample, 0.0),
+        }
+    }
+
+    /// Ends the stroke. Returns the samples left to draw, the last one being
+    /// where the stroke ends.
+    pub fn release(&mut self, sample: PointerState) -> Vec<PointerState> {
+        let samples = match self.settings.mode {
+            StabilizerMode::None => vec![sample],
+            _ if !self.settings.catch_up => vec![self.advance(sample)],
+            StabilizerMode::WeightedAverage => {
+                // Let the window fill up with the last sample, the path eases
+                // into it the way it would have had the pen stayed down.
+                (0..self.settings.window())
+                    .map(|_| self.average(sample))
+                    .collect()
+            }
+            StabilizerMode::Rope | StabilizerMode::PullString => {
+                vec![self.advance(sample), sample]
+            }
+        };
+        self.window.clear();
+        self.brush = None;
+        samples
+    }
+
+    fn average(&mut self, sample: PointerState) -> PointerState {
+        self.window.push_back(sample);
+        while self.window.len() > self.settings.window() {
+            self.window.pop_front();
+        }
+
+        let mut total = 0.0;
+        let mut position = Vector::ZERO;
+        let mut pressure = 0.0;
+        let mut tilt = Vector::ZERO;
+        for (i, s) in self.window.iter().enumerate() {
+            let weight = (i + 1) as f32;
+            total += weight;
+            position += Vector::new(s.position.x, s.position.y) * weight;
+            pressure += s.pressure * weight;
+            tilt += s.tilt * weight;
+        }
+
+        PointerState {
+            position: Point::new(position.x / total, position.y / total),
+            pressure: pressure / total,
+            tilt: tilt * (1.0 / total),
+            ..sample
+        }
+    }
+
+    /// Moves the brush towards the point `radius` short of the sample, easing
+    /// in over time so the result doesn't depend on the event rate.
+    fn follow(&mut self, sample: PointerState, radius: f32) -> PointerState {
+        let Some(brush) = self.brush else {
+            return self.press(sample);
+        };
+
+        let offset = sample.position - brush.position;
+        let distance = (offset.x * offset.x + offset.y * offset.y).sqrt();
+        let target = if distance > radius {
+            brush.position + offset * ((distance - radius) / distance)
+        } else {
+            brush.position
+        };
+
+        let lag = self.settings.lag();
+        let t = if lag > 0.0 {
+            let seconds = sample
+                .timestamp
+                .saturating_duration_since(brush.timestamp)
+                .as_secs_f32();
+            1.0 - (-seconds / lag).exp()
+        } else {
+            1.0
+        };
+
+        // Only the path lags, the pen's other sensors stay live.
+        let brush = PointerState {
+            position: brush.position + (target - brush.position) * t,
+            ..sample
+        };
+        self.brush = Some(brush);
+        brush
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{a} != {b}");
+    }
+
+    /// A sample `ms` into the stroke, `x` pixels along it.
+    fn at(start: Instant, ms: u64, x: f32) -> PointerState {
+        PointerState {
+            pressure: x / 100.0,
+            timestamp: start + Duration::from_millis(ms),
+            ..PointerState::mouse(Point::new(x, 0.0), true)
+        }
+    }
+
+    fn stabilizer(mode: StabilizerMode, strength: f32) -> Stabilizer {
+        Stabilizer::new(StabilizerSettings {
+            mode,
+            strength,
+            radius: 10.0,
+            catch_up: true,
+        })
+    }
+
+    #[test]
+    fn none_passes_samples_through() {
+        let start = Instant::now();
+        let mut s = stabilizer(StabilizerMode::None, 1.0);
+        s.press(at(start, 0, 0.0));
+        assert_eq!(s.advance(at(start, 10, 50.0)), at(start, 10, 50.0));
+        assert_eq!(s.release(at(start, 20, 80.0)), [at(start, 20, 80.0)]);
+    }
+
+    #[test]
+    fn moving_average() {
+        let start = Instant::now();
+        // Averages the last three samples, weighing them 1, 2 and 3.
+        let mut s = stabilizer(StabilizerMode::WeightedAverage, 2.0 / 31.0);
+        assert_eq!(s.press(at(start, 0, 0.0)), at(start, 0, 0.0));
+
+        let sample = s.advance(at(start, 10, 3.0));
+        assert_close(sample.position.x, 2.0);
+        assert_close(sample.pressure, 0.02);
+        assert_eq!(sample.timestamp, start + Duration::from_millis(10));
+        assert_close(s.advance(at(start, 20, 6.0)).position.x, 4.0);
+        // The first sample left the window.
+        assert_close(s.advance(at(start, 30, 9.0)).position.x, 7.0);
+    }
+
+    #[test]
+    fn rope_radius() {
+        let start = Instant::now();
+        // No lag, the brush moves as soon as the rope is taut.
+        let mut s = stabilizer(StabilizerMode::Rope, 0.0);
+        s.press(at(start, 0, 0.0));
+
+        let slack = s.advance(at(start, 10, 6.0));
+        assert_close(slack.position.x, 0.0);
+        // Only the path stays behind, not the other sensors.
+        assert_close(slack.pressure, 0.06);
+
+        assert_close(s.advance(at(start, 20, 25.0)).position.x, 15.0);
+        // Going back within the rope doesn't move the brush.
+        assert_close(s.advance(at(start, 30, 20.0)).position.x, 15.0);
+        assert_close(s.advance(at(start, 40, 40.0)).position.x, 30.0);
+    }
+
+    #[test]
+    fn pull_string() {
+        let start = Instant::now();
+        let mut s = stabilizer(StabilizerMode::PullString, 1.0);
+        s.press(at(start, 0, 0.0));
+
+        // One lag in, the brush covers 1 - 1/e of the way.
+        let lag = (StabilizerSettings::MAX_LAG * 1000.0) as u64;
+        let first = s.advance(at(start, lag, 100.0)).position.x;
+        assert_close(first, 100.0 * (1.0 - (-1.0f32).exp()));
+        let second = s.advance(at(start, 2 * lag, 100.0)).position.x;
+        assert_close(second, 100.0 * (1.0 - (-2.0f32).exp()));
+        // Samples arriving at the same time don't move it.
+        assert_close(s.advance(at(start, 2 * lag, 100.0)).position.x, second);
+    }
+
+    #[test]
+    fn pull_string_ignores_the_event_rate() {
+        let start = Instant::now();
+        let mut coarse = stabilizer(StabilizerMode::PullString, 1.0);
+        let mut fine = stabilizer(StabilizerMode::PullString, 1.0);
+        coarse.press(at(start, 0, 0.0));
+        fine.press(at(start, 0, 0.0));
+
+        let coarse = coarse.advance(at(start, 200, 100.0));
+        let fine = (1..=20)
+            .map(|i| fine.advance(at(start, i * 10, 100.0)))
+            .last()
+            .unwrap();
+        assert_close(coarse.position.x, fine.position.x);
+    }
+
+    #[test]
+    fn catch_up_on_lift() {
+        let start = Instant::now();
+        for mode in [
+            StabilizerMode::WeightedAverage,
+            StabilizerMode::Rope,
+            StabilizerMode::PullString,
+        ] {
+            let mut s = stabilizer(mode, 0.5);
+            s.press(at(start, 0, 0.0));
+            s.advance(at(start, 10, 50.0));
+            let lagging = s.advance(at(start, 20, 100.0));
+            assert!(lagging.position.x < 100.0, "{mode:?}");
+
+            let samples = s.release(at(start, 30, 100.0));
+            assert_eq!(samples.last(), Some(&at(start, 30, 100.0)), "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn lift_without_catch_up() {
+        let start = Instant::now();
+        let mut s = Stabilizer::new(StabilizerSettings {
+            mode: StabilizerMode::Rope,
+            strength: 0.0,
+            radius: 10.0,
+            catch_up: false,
+        });
+        s.press(at(start, 0, 0.0));
+        let samples = s.release(at(start, 10, 30.0));
+        assert_eq!(samples.len(), 1);
+        assert_close(samples[0].position.x, 20.0);
+
+        // The next stroke starts fresh.
+        assert_eq!(s.press(at(start, 20, 80.0)), at(start, 20, 80.0));
+        assert_close(s.advance(at(start, 30, 85.0)).position.x, 80.0);
+    }
+}
@@ -1,31 +1,269 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cyancia_brush::{
+    BrushMode, BrushSettings,
+    bitmap::{BRUSH_IMAGES, BrushImage},
+    dab::{Dab, StrokeParams},
+    preset::{BRUSH_PRESETS, BrushPreset},
+    smudge::{GpuSmudge, SmudgeParams, SmudgeStamper},
+    stamp::{DabStamper, GPU_BRUSH_TEXTURES, GpuShape, GpuStroke},
+    stroke::{Stroke, StrokePoint},
+};
 use cyancia_canvas::CCanvas;
 use cyancia_id::Id;
-use cyancia_input::{
-    action::Action,
-    key::{KeySequence, KeyboardState},
-    mouse::PressedMouseState,
+use cyancia_image::{
+    layer::{Layer, LayerLocks},
+    selection::GPU_MASK_STORAGE,
+    tile::GPU_TILE_STORAGE,
 };
-use iced_core::Point;
+use cyancia_input::{key::KeyboardState, mouse::PressedMouseState};
+use glam::{UVec2, Vec2, Vec3};
+
+use crate::{CanvasTool, CanvasToolFunction, eyedropper::PickedColor};
+
+struct Stampers {
+    dab: DabStamper,
+    smudge: SmudgeStamper,
+}
 
-use crate::{CanvasTool, CanvasToolFunction};
+enum GpuBrushStroke {
+    Paint(GpuStroke),
+    Smudge(GpuSmudge),
+}
+
+impl GpuBrushStroke {
+    fn tiles_of(&self, dabs: &[Dab]) -> Vec<UVec2> {
+        match self {
+            GpuBrushStroke::Paint(stroke) => stroke.tiles_of(dabs),
+            GpuBrushStroke::Smudge(stroke) => stroke.tiles_of(dabs),
+        }
+    }
+
+    fn target(&self) -> Id<Layer> {
+        match self {
+            GpuBrushStroke::Paint(stroke) => stroke.target(),
+            GpuBrushStroke::Smudge(stroke) => stroke.target(),
+        }
+    }
+}
 
 #[derive(Default)]
-pub struct BrushTool;
+pub struct BrushTool {
+    pub settings: BrushSettings,
+    pub color: Vec3,
+    /// Takes the stroke out of the layer's alpha instead of painting `color`.
+    pub erase: bool,
+    preset: Option<Id<BrushPreset>>,
+    /// Created on the first stroke, when the GPU is surely available.
+    stampers: Option<Stampers>,
+    stroke: Option<(Stroke, GpuBrushStroke)>,
+}
+
+impl BrushTool {
+    /// Replaces the settings with those of a loaded preset. Returns `false`
+    /// if no preset has this id.
+    pub fn switch_preset(&mut self, id: Id<BrushPreset>) -> bool {
+        let Some(preset) = BRUSH_PRESETS.get(id) else {
+            log::warn!("Brush preset {:?} doesn't exist.", id);
+            return false;
+        };
+        self.settings = preset.settings.clone();
+        self.preset = Some(id);
+        true
+    }
+
+    pub fn preset(&self) -> Option<Id<BrushPreset>> {
+        self.preset
+    }
+
+    fn begin_stroke(&mut self, canvas: &CCanvas) {
+        let image = canvas.image.read();
+        let Some(layer) = image.active_layer() else {
+            return;
+        };
+        let locks = image
+            .layer(layer)
+            .map(|l| l.properties.locks)
+            .unwrap_or_default();
+        if locks.contains(LayerLocks::PIXELS) {
+            log::warn!("Pixels of the active layer are locked.");
+            return;
+        }
+
+        let params = StrokeParams {
+            opacity: self.settings.opacity,
+            preserve_alpha: locks.contains(LayerLocks::ALPHA),
+            erase: self.erase,
+        };
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        let tip_image = self.settings.tip_image.as_ref().and_then(|path| {
+            let id = BrushImage::id(path);
+            let image = BRUSH_IMAGES.get(id);
+            if image.is_none() {
+                log::warn!("Tip image {} doesn't exist.", path.display());
+            }
+            image.map(|image| (id, image))
+        });
+        let texture = self.settings.texture.as_ref().and_then(|texture| {
+            let id = BrushImage::id(&texture.image);
+            let image = BRUSH_IMAGES.get(id);
+            if image.is_none() {
+                log::warn!("Paper texture {} doesn't exist.", texture.image.display());
+            }
+            image.map(|image| (id, image, texture.clone()))
+        });
+        let shape = GpuShape {
+            tip: self.settings.tip,
+            tip_image,
+            texture,
+        };
+
+        // Erasing removes coverage, whatever the mode would do with the pixels.
+        let mode = if self.erase {
+            BrushMode::Paint
+        } else {
+            self.settings.mode
+        };
+        let selection = image.selection();
+        let mask = selection.is_active().then(|| selection.id());
+        let gpu_stroke = match mode {
+            BrushMode::Paint => {
+                let mut stroke = GpuStroke::new(layer, image.size(), shape, params);
+                if let Some(mask) = mask {
+                    stroke = stroke.with_selection(GPU_MASK_STORAGE.clone_arc(), mask);
+                }
+                GpuBrushStroke::Paint(stroke)
+            }
+            BrushMode::Smudge | BrushMode::Mix | BrushMode::Blur => {
+                let params = SmudgeParams::new(&self.settings, params.preserve_alpha);
+                let mut stroke = GpuSmudge::new(layer, image.size(), shape, params);
+                if let Some(mask) = mask {
+                    stroke = stroke.with_selection(GPU_MASK_STORAGE.clone_arc(), mask);
+                }
+                GpuBrushStroke::Smudge(stroke)
+            }
+        };
+
+        self.stroke = Some((
+            Stroke::new(self.settings.clone(), self.color, seed),
+            gpu_stroke,
+        ));
+    }
+
+    fn paint(&mut self, samples: &[PressedMouseState], canvas: &CCanvas) {
+        let Some((stroke, gpu_stroke)) = &mut self.stroke else {
+            return;
+        };
+
+        let transform = canvas.transform.read();
+        let dabs = samples
+            .iter()
+            .flat_map(|mouse| {
+                let position = Vec2::new(mouse.position.x, mouse.position.y);
+                stroke.move_to(StrokePoint {
+                    position: transform.widget_to_pixel(position),
+                    pressure: mouse.pressure,
+                    tilt: Vec2::new(mouse.tilt.x, mouse.tilt.y),
+                    rotation: mouse.rotation,
+                    timestamp: mouse.timestamp,
+                })
+            })
+            .collect::<Vec<_>>();
+        if dabs.is_empty() {
+            return;
+        }
+
+        // The history has to see the tiles before the dabs land on them.
+        canvas.history.lock().record_tiles(
+            &GPU_TILE_STORAGE,
+            if self.erase { "Erase" } else { "Brush Stroke" },
+            gpu_stroke.target(),
+            gpu_stroke.tiles_of(&dabs),
+        );
+        let stampers = self.stampers.get_or_insert_with(|| {
+            let device = GPU_TILE_STORAGE.device();
+            Stampers {
+                dab: DabStamper::new(device),
+                smudge: SmudgeStamper::new(device),
+            }
+        });
+        match gpu_stroke {
+            GpuBrushStroke::Paint(stroke) => {
+                stampers
+                    .dab
+                    .stamp(&GPU_TILE_STORAGE, &GPU_BRUSH_TEXTURES, stroke, &dabs)
+            }
+            GpuBrushStroke::Smudge(stroke) => {
+                stampers
+                    .smudge
+                    .stamp(&GPU_TILE_STORAGE, &GPU_BRUSH_TEXTURES, stroke, &dabs)
+            }
+        }
+    }
+
+    fn end_stroke(&mut self, canvas: &CCanvas) {
+        if let Some((_, gpu_stroke)) = self.stroke.take() {
+            if let GpuBrushStroke::Paint(stroke) = gpu_stroke {
+                stroke.finish(&GPU_TILE_STORAGE);
+            }
+            canvas.history.lock().commit(&GPU_TILE_STORAGE);
+        }
+    }
+}
 
 impl CanvasToolFunction for BrushTool {
     fn id(&self) -> Id<CanvasTool> {
         Id::from_str("brush_tool")
     }
 
-    fn activate(&mut self, canvas: &CCanvas) {
-        println!("Switched to brush!");
+    fn begin(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.end_stroke(canvas);
+        self.begin_stroke(canvas);
+        self.paint(std::slice::from_ref(mouse), canvas);
     }
 
     fn update(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
-        println!("Painting at: {:?}", mouse.position);
+        self.paint(std::slice::from_ref(mouse), canvas);
+    }
+
+    fn update_batch(
+        &mut self,
+        keyboard: &KeyboardState,
+        samples: &[PressedMouseState],
+        canvas: &CCanvas,
+    ) {
+        self.paint(samples, canvas);
+    }
+
+    fn end(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.paint(std::slice::from_ref(mouse), canvas);
+        self.end_stroke(canvas);
     }
 
     fn deactivate(&mut self, canvas: &CCanvas) {
-        println!("Exited brush!");
+        self.end_stroke(canvas);
+    }
+
+    fn toggle_erase(&mut self) -> Option<bool> {
+        self.erase = !self.erase;
+        Some(self.erase)
+    }
+
+    fn stabilized(&self) -> bool {
+        true
+    }
+
+    fn paints(&self) -> bool {
+        true
+    }
+
+    fn set_color(&mut self, picked: PickedColor) {
+        self.color = picked.color;
+        if let Some(alpha) = picked.alpha {
+            self.settings.opacity = alpha;
+        }
     }
 }
@@ -0,0 +1,85 @@
+use cyancia_brush::{BrushSettings, BrushTip};
+use cyancia_canvas::CCanvas;
+use cyancia_id::Id;
+use cyancia_input::{key::KeyboardState, mouse::PressedMouseState};
+
+use crate::{CanvasTool, CanvasToolFunction, brush::BrushTool, eyedropper::PickedColor};
+
+/// A brush that always starts out erasing, with settings of its own so that
+/// switching between painting and erasing keeps both sizes.
+pub struct EraserTool {
+    brush: BrushTool,
+}
+
+impl Default for EraserTool {
+    fn default() -> Self {
+        let mut brush = BrushTool::default();
+        brush.settings.tip = BrushTip::Soft;
+        brush.erase = true;
+        Self { brush }
+    }
+}
+
+impl EraserTool {
+    pub fn settings(&self) -> &BrushSettings {
+        &self.brush.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut BrushSettings {
+        &mut self.brush.settings
+    }
+
+    /// Erases with a procedural round tip, dropping any tip image.
+    pub fn set_tip(&mut self, tip: BrushTip) {
+        self.brush.settings.tip = tip;
+        self.brush.settings.tip_image = None;
+    }
+}
+
+impl CanvasToolFunction for EraserTool {
+    fn id(&self) -> Id<CanvasTool> {
+        Id::from_str("eraser_tool")
+    }
+
+    fn begin(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.brush.begin(keyboard, mouse, canvas);
+    }
+
+    fn update(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.brush.update(keyboard, mouse, canvas);
+    }
+
+    fn update_batch(
+        &mut self,
+        keyboard: &KeyboardState,
+        samples: &[PressedMouseState],
+        canvas: &CCanvas,
+    ) {
+        self.brush.update_batch(keyboard, samples, canvas);
+    }
+
+    fn end(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.brush.end(keyboard, mouse, canvas);
+    }
+
+    fn deactivate(&mut self, canvas: &CCanvas) {
+        self.brush.deactivate(canvas);
+    }
+
+    fn toggle_erase(&mut self) -> Option<bool> {
+        self.brush.toggle_erase()
+    }
+
+    fn stabilized(&self) -> bool {
+        true
+    }
+
+    fn paints(&self) -> bool {
+        true
+    }
+
+    /// Only the color is taken, picked alpha would change how hard it erases.
+    fn set_color(&mut self, picked: PickedColor) {
+        self.brush.color = picked.color;
+    }
+}
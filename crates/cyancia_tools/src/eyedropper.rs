@@ -0,0 +1,202 @@
+//! Picking the color under the pointer. Only the few pixels around it are read
+//! back, and the picked color reaches the paint tools once they arrive. Paint
+//! tools hand their strokes over while Alt is held.
+
+use cyancia_canvas::CCanvas;
+use cyancia_id::Id;
+use cyancia_image::{CImage, region::SampleSource, tile::GPU_TILE_STORAGE};
+use cyancia_input::{key::KeyboardState, mouse::PressedMouseState};
+use futures::channel::oneshot;
+use glam::{IVec2, UVec2, Vec2, Vec3, Vec4};
+use iced_core::Rectangle;
+use image::Rgba32FImage;
+
+use crate::{CanvasTool, CanvasToolFunction};
+
+/// Which pixels around the pointer are averaged.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SampleSize {
+    /// Just the pixel under the pointer.
+    #[default]
+    Point,
+    Square3,
+    Square5,
+    /// Every pixel within the radius.
+    Average(u32),
+}
+
+impl SampleSize {
+    pub fn radius(self) -> u32 {
+        match self {
+            SampleSize::Point => 0,
+            SampleSize::Square3 => 1,
+            SampleSize::Square5 => 2,
+            SampleSize::Average(radius) => radius,
+        }
+    }
+
+    fn contains(self, offset: IVec2) -> bool {
+        match self {
+            SampleSize::Average(radius) => offset.length_squared() <= (radius * radius) as i32,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EyedropperOptions {
+    pub size: SampleSize,
+    pub source: SampleSource,
+    /// Takes the alpha of the pixels too, as the opacity to paint with.
+    pub pick_alpha: bool,
+}
+
+impl Default for EyedropperOptions {
+    fn default() -> Self {
+        Self {
+            size: SampleSize::Point,
+            source: SampleSource::Merged,
+            pick_alpha: false,
+        }
+    }
+}
+
+/// A color picked off the canvas.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PickedColor {
+    pub color: Vec3,
+    /// Only there if alpha was picked as well.
+    pub alpha: Option<f32>,
+}
+
+#[derive(Default)]
+pub struct EyedropperTool {
+    pub options: EyedropperOptions,
+    pending: Option<oneshot::Receiver<PickedColor>>,
+    /// Where the pointer went while the last readback was running, picked
+    /// once it's done.
+    queued: Option<UVec2>,
+    picked: Option<PickedColor>,
+}
+
+impl EyedropperTool {
+    fn pick(&mut self, mouse: &PressedMouseState, canvas: &CCanvas) {
+        let pixel = canvas
+            .transform
+            .read()
+            .widget_to_pixel(Vec2::new(mouse.position.x, mouse.position.y));
+        let image = canvas.image.read();
+        if pixel.cmplt(Vec2::ZERO).any() || pixel.cmpge(image.size().as_vec2()).any() {
+            return;
+        }
+
+        let pixel = pixel.as_uvec2();
+        if self.pending.is_some() {
+            self.queued = Some(pixel);
+        } else {
+            self.read(pixel, &image);
+        }
+    }
+
+    fn read(&mut self, pixel: UVec2, image: &CImage) {
+        let radius = UVec2::splat(self.options.size.radius());
+        let min = pixel.saturating_sub(radius);
+        let max = (pixel + radius + 1).min(image.size());
+        let readback = self.options.source.read_rect(
+            image,
+            &GPU_TILE_STORAGE,
+            Rectangle {
+                x: min.x,
+                y: min.y,
+                width: max.x - min.x,
+                height: max.y - min.y,
+            },
+        );
+        let options = self.options;
+        let center = (pixel - min).as_ivec2();
+        let (sender, receiver) = oneshot::channel();
+        self.pending = Some(receiver);
+
+        std::thread::spawn(move || {
+            let pixels = match futures::executor::block_on(readback) {
+                Ok(p) => p,
+                Err(e) => {
+                    log::error!("Unable to read pixels to pick the color of: {}", e);
+                    return;
+                }
+            };
+            if let Some(picked) = average(&pixels, center, options) {
+                let _ = sender.send(picked);
+            }
+        });
+    }
+}
+
+impl CanvasToolFunction for EyedropperTool {
+    fn id(&self) -> Id<CanvasTool> {
+        Id::from_str("eyedropper_tool")
+    }
+
+    fn begin(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.pick(mouse, canvas);
+    }
+
+    fn update(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.pick(mouse, canvas);
+    }
+
+    fn frame(&mut self, canvas: &CCanvas) {
+        let Some(receiver) = &mut self.pending else {
+            return;
+        };
+        match receiver.try_recv() {
+            Ok(Some(picked)) => self.picked = Some(picked),
+            Ok(None) => return,
+            // Nothing but transparent pixels, or the readback failed.
+            Err(_) => {}
+        }
+        self.pending = None;
+
+        if let Some(pixel) = self.queued.take() {
+            let image = canvas.image.read();
+            if pixel.cmplt(image.size()).all() {
+                self.read(pixel, &image);
+            }
+        }
+    }
+
+    fn take_picked_color(&mut self) -> Option<PickedColor> {
+        self.picked.take()
+    }
+}
+
+/// Average of the pixels the sample size covers around `center`, weighted by
+/// alpha so transparent pixels don't darken the color. `None` if all of them
+/// are fully transparent.
+fn average(
+    pixels: &Rgba32FImage,
+    center: IVec2,
+    options: EyedropperOptions,
+) -> Option<PickedColor> {
+    let mut sum = Vec4::ZERO;
+    let mut count = 0;
+    for (x, y, pixel) in pixels.enumerate_pixels() {
+        if !options
+            .size
+            .contains(IVec2::new(x as i32, y as i32) - center)
+        {
+            continue;
+        }
+        let p = Vec4::from_array(pixel.0);
+        sum += (p.truncate() * p.w).extend(p.w);
+        count += 1;
+    }
+    if sum.w <= 0.0 {
+        return None;
+    }
+
+    Some(PickedColor {
+        color: sum.truncate() / sum.w,
+        alpha: options.pick_alpha.then(|| sum.w / count as f32),
+    })
+}
@@ -0,0 +1,287 @@
+//! Filling the region around a click with a flat color. The region is searched
+//! on a background thread over read back pixels, and the filled part of the
+//! layer is uploaded once it's done, one undo step for the whole fill.
+
+use cyancia_canvas::CCanvas;
+use cyancia_id::Id;
+use cyancia_image::{
+    blend::{self, BlendMode},
+    layer::{Layer, LayerLocks},
+    region::{self, ColorMatch, SampleSource},
+    tile::{GPU_TILE_STORAGE, GpuTileStorage},
+};
+use cyancia_input::{key::KeyboardState, mouse::PressedMouseState};
+use futures::channel::oneshot;
+use glam::{UVec2, Vec2, Vec3, Vec4};
+use iced_core::Rectangle;
+use image::Rgba32FImage;
+
+use crate::{CanvasTool, CanvasToolFunction, eyedropper::PickedColor};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FillOptions {
+    /// Largest difference of any channel from the clicked color, from 0 to 1.
+    pub tolerance: f32,
+    /// Only fills pixels connected to the clicked one, instead of every
+    /// matching pixel of the image.
+    pub contiguous: bool,
+    pub source: SampleSource,
+    /// Pixels the filled region is grown by, shrunk if negative. Growing tucks
+    /// the fill under antialiased line art.
+    pub grow: i32,
+    pub antialias: bool,
+    /// Gaps in the line art up to twice this wide don't let the fill leak.
+    pub close_gaps: u32,
+    pub opacity: f32,
+}
+
+impl Default for FillOptions {
+    fn default() -> Self {
+        Self {
+            tolerance: 0.1,
+            contiguous: true,
+            source: SampleSource::ActiveLayer,
+            grow: 0,
+            antialias: true,
+            close_gaps: 0,
+            opacity: 1.0,
+        }
+    }
+}
+
+/// Pixels of the layer with the fill applied, covering whole tiles.
+struct FilledRegion {
+    layer: Id<Layer>,
+    /// Generation of the layer the pixels were read at.
+    generation: u64,
+    seed: UVec2,
+    image_size: UVec2,
+    origin: UVec2,
+    pixels: Rgba32FImage,
+}
+
+#[derive(Default)]
+pub struct FillTool {
+    pub options: FillOptions,
+    pub color: Vec3,
+    pending: Option<oneshot::Receiver<FilledRegion>>,
+}
+
+impl FillTool {
+    /// Starts filling the region around `seed` on the active layer.
+    fn fill(&mut self, seed: UVec2, canvas: &CCanvas) {
+        let image = canvas.image.read();
+        let size = image.size();
+        let Some(layer) = image.active_layer() else {
+            return;
+        };
+        let locks = image
+            .layer(layer)
+            .map(|l| l.properties.locks)
+            .unwrap_or_default();
+        if locks.contains(LayerLocks::PIXELS) {
+            log::warn!("Pixels of the active layer are locked.");
+            return;
+        }
+
+        let options = self.options;
+        let color = self.color;
+        let preserve_alpha = locks.contains(LayerLocks::ALPHA);
+        let selection = image.selection().mask().cloned();
+        // Taken before anything is read, so changes during the readback count.
+        let generation = GPU_TILE_STORAGE.generation(layer);
+        let readback = options.source.read(&image, &GPU_TILE_STORAGE);
+        let (sender, receiver) = oneshot::channel();
+        self.pending = Some(receiver);
+
+        std::thread::spawn(move || {
+            let source = match futures::executor::block_on(readback) {
+                Ok(p) => p,
+                Err(e) => {
+                    log::error!("Unable to read pixels for the fill: {}", e);
+                    return;
+                }
+            };
+
+            let reference = ColorMatch::new(region::pixel(&source, seed), options.tolerance);
+            let matches = region::matching(&source, &reference);
+            drop(source);
+            let filled = if options.contiguous {
+                region::flood_closing_gaps(size, &matches, seed, options.close_gaps)
+            } else {
+                matches
+            };
+            let filled = region::grow(&filled, size, options.grow);
+            let mut coverage = if options.antialias {
+                region::soften(&filled, size)
+            } else {
+                region::to_coverage(&filled)
+            };
+            if let Some(selection) = &selection {
+                for (i, c) in coverage.iter_mut().enumerate() {
+                    if *c > 0 {
+                        let p = UVec2::new(i as u32 % size.x, i as u32 / size.x);
+                        *c = (*c as f32 * selection.value(p)).round() as u8;
+                    }
+                }
+            }
+
+            let Some((origin, rect_size)) = tile_bounds(&coverage, size) else {
+                return;
+            };
+            let readback = GPU_TILE_STORAGE.read_rect(
+                layer,
+                Rectangle {
+                    x: origin.x,
+                    y: origin.y,
+                    width: rect_size.x,
+                    height: rect_size.y,
+                },
+            );
+            let mut pixels = match futures::executor::block_on(readback) {
+                Ok(p) => p,
+                Err(e) => {
+                    log::error!("Unable to read the layer to fill: {}", e);
+                    return;
+                }
+            };
+
+            for (x, y, pixel) in pixels.enumerate_pixels_mut() {
+                let p = origin + UVec2::new(x, y);
+                let k = coverage[(p.y * size.x + p.x) as usize] as f32 / 255.0;
+                if k <= 0.0 {
+                    continue;
+                }
+                let backdrop = Vec4::from_array(pixel.0);
+                let mut result = blend::composite(
+                    BlendMode::Normal,
+                    backdrop,
+                    color.extend(1.0),
+                    options.opacity * k,
+                );
+                if preserve_alpha {
+                    result.w = backdrop.w;
+                }
+                pixel.0 = result.to_array();
+            }
+
+            let _ = sender.send(FilledRegion {
+                layer,
+                generation,
+                seed,
+                image_size: size,
+                origin,
+                pixels,
+            });
+        });
+    }
+}
+
+impl CanvasToolFunction for FillTool {
+    fn id(&self) -> Id<CanvasTool> {
+        Id::from_str("fill_tool")
+    }
+
+    fn begin(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        if self.pending.is_some() {
+            log::warn!("The previous fill is still being computed.");
+            return;
+        }
+
+        let pixel = canvas
+            .transform
+            .read()
+            .widget_to_pixel(Vec2::new(mouse.position.x, mouse.position.y));
+        let size = canvas.image.read().size();
+        if pixel.cmplt(Vec2::ZERO).any() || pixel.cmpge(size.as_vec2()).any() {
+            return;
+        }
+        self.fill(pixel.as_uvec2(), canvas);
+    }
+
+    fn frame(&mut self, canvas: &CCanvas) {
+        let Some(receiver) = &mut self.pending else {
+            return;
+        };
+        let filled = match receiver.try_recv() {
+            Ok(Some(filled)) => filled,
+            Ok(None) => return,
+            Err(_) => {
+                self.pending = None;
+                return;
+            }
+        };
+        self.pending = None;
+
+        let image = canvas.image.read();
+        // The document was switched or the layer removed while filling.
+        if image.size() != filled.image_size || image.layer(filled.layer).is_err() {
+            return;
+        }
+        // The layer was painted on while filling. Uploading the pixels read
+        // before would undo that, so the fill starts over on the new ones.
+        if GPU_TILE_STORAGE.generation(filled.layer) != filled.generation {
+            let active = image.active_layer() == Some(filled.layer);
+            drop(image);
+            if active {
+                self.fill(filled.seed, canvas);
+            } else {
+                log::warn!("The layer changed while filling, the fill was dropped.");
+            }
+            return;
+        }
+
+        let first = filled.origin / GpuTileStorage::TILE_SIZE;
+        let end = filled.origin + UVec2::new(filled.pixels.width(), filled.pixels.height());
+        let last = UVec2::new(
+            end.x.div_ceil(GpuTileStorage::TILE_SIZE),
+            end.y.div_ceil(GpuTileStorage::TILE_SIZE),
+        );
+        let indices =
+            (first.y..last.y).flat_map(|y| (first.x..last.x).map(move |x| UVec2::new(x, y)));
+
+        let mut history = canvas.history.lock();
+        history.record_tiles(&GPU_TILE_STORAGE, "Fill", filled.layer, indices);
+        GPU_TILE_STORAGE.upload_image_at(
+            filled.layer,
+            &filled.pixels,
+            filled.origin.as_ivec2(),
+            filled.image_size,
+        );
+        history.commit(&GPU_TILE_STORAGE);
+    }
+
+    fn paints(&self) -> bool {
+        true
+    }
+
+    fn set_color(&mut self, picked: PickedColor) {
+        self.color = picked.color;
+        if let Some(alpha) = picked.alpha {
+            self.options.opacity = alpha;
+        }
+    }
+}
+
+/// The covered area grown to whole tiles, so uploading it doesn't clear the
+/// rest of any tile. Clamped to the image.
+fn tile_bounds(coverage: &[u8], size: UVec2) -> Option<(UVec2, UVec2)> {
+    let width = size.x as usize;
+    let (mut min, mut max) = (UVec2::MAX, UVec2::ZERO);
+    for (y, row) in coverage.chunks_exact(width).enumerate() {
+        let Some(first) = row.iter().position(|c| *c > 0) else {
+            continue;
+        };
+        let last = row.iter().rposition(|c| *c > 0).unwrap_or(first);
+        min = min.min(UVec2::new(first as u32, y as u32));
+        max = max.max(UVec2::new(last as u32 + 1, y as u32 + 1));
+    }
+    if min.cmpge(max).any() {
+        return None;
+    }
+
+    let tile = GpuTileStorage::TILE_SIZE;
+    let min = min / tile * tile;
+    let max = (UVec2::new(max.x.div_ceil(tile), max.y.div_ceil(tile)) * tile).min(size);
+    Some((min, max - min))
+}
@@ -1,18 +1,33 @@
-use std::{any::Any, collections::HashMap, sync::Arc, time::Instant};
+use std::{
+    any::Any,
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use cyancia_canvas::CCanvas;
 use cyancia_id::Id;
 use cyancia_input::{
     action::Action,
     key::KeyboardState,
-    mouse::{HoverMouseState, PressedMouseState},
+    mouse::{HoverMouseState, PointerButton, PointerEvent, PointerEventSource, PressedMouseState},
+    predict::MotionPredictor,
+    stabilizer::{Stabilizer, StabilizerSettings},
 };
 use iced_core::{Point, keyboard::key, mouse};
-use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::eyedropper::PickedColor;
 
 pub mod brush;
+pub mod eraser;
+pub mod eyedropper;
+pub mod fill;
 pub mod pan;
 pub mod rotate;
+pub mod select;
+pub mod transform;
+pub mod wand;
 pub mod zoom;
 
 pub struct CanvasTool {
@@ -27,6 +42,48 @@ pub trait CanvasToolFunction: Send + Sync + 'static {
     fn update(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {}
     fn end(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {}
     fn deactivate(&mut self, canvas: &CCanvas) {}
+    /// Switches a paint tool between laying down color and erasing. Returns
+    /// whether the tool now erases, or `None` if it doesn't paint at all.
+    fn toggle_erase(&mut self) -> Option<bool> {
+        None
+    }
+    /// Whether the pointer path goes through the stroke stabilizer before
+    /// reaching the tool.
+    fn stabilized(&self) -> bool {
+        false
+    }
+    /// Receives every pressed move since the last update, oldest first.
+    fn update_batch(
+        &mut self,
+        keyboard: &KeyboardState,
+        samples: &[PressedMouseState],
+        canvas: &CCanvas,
+    ) {
+        for mouse in samples {
+            self.update(keyboard, mouse, canvas);
+        }
+    }
+    /// Where the pointer is likely to be once the frame shows. Only tools that
+    /// can take it back on the next update should follow it.
+    fn predict(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {}
+    /// Called once per frame, lets tools pick up work finished in the
+    /// background.
+    fn frame(&mut self, canvas: &CCanvas) {}
+    /// Applies what the tool has been editing, like a transform in progress.
+    fn confirm(&mut self, canvas: &CCanvas) {}
+    /// Throws away what the tool has been editing.
+    fn cancel(&mut self, canvas: &CCanvas) {}
+    /// Whether the tool lays down color. Held modifiers can hand its strokes
+    /// to another tool, see [`ToolProxy::bind_modifier`].
+    fn paints(&self) -> bool {
+        false
+    }
+    /// Takes a color picked off the canvas as the one to paint with.
+    fn set_color(&mut self, picked: PickedColor) {}
+    /// The color picked since the last call, handed to every tool.
+    fn take_picked_color(&mut self) -> Option<PickedColor> {
+        None
+    }
 }
 
 pub struct CanvasToolFunctionCollection {
@@ -62,11 +119,21 @@ struct ToolProxyState {
     last: Id<CanvasTool>,
     current: Id<CanvasTool>,
     last_switch: Instant,
+    /// The tool the pressed button sent the stroke to, it keeps getting the
+    /// stroke until the button is released.
+    stroke: Option<Id<CanvasTool>>,
 }
 
 pub struct ToolProxy {
     state: RwLock<ToolProxyState>,
     tools: CanvasToolFunctionCollection,
+    stabilizer: Mutex<Stabilizer>,
+    predictor: Mutex<Option<MotionPredictor>>,
+    /// Tools taking strokes of buttons other than the primary one, which
+    /// always goes to the current tool.
+    button_tools: RwLock<HashMap<PointerButton, Id<CanvasTool>>>,
+    /// Tools taking primary strokes of paint tools while the key is held.
+    modifier_tools: RwLock<HashMap<key::Code, Id<CanvasTool>>>,
 }
 
 impl ToolProxy {
@@ -76,9 +143,69 @@ impl ToolProxy {
                 last: initial.clone(),
                 current: initial,
                 last_switch: Instant::now(),
+                stroke: None,
             }),
             tools: collection,
+            stabilizer: Mutex::new(Stabilizer::default()),
+            predictor: Mutex::new(Some(MotionPredictor::default())),
+            button_tools: RwLock::new(HashMap::from([(
+                PointerButton::Middle,
+                Id::from_str("pan_tool"),
+            )])),
+            modifier_tools: RwLock::new(HashMap::from([
+                (key::Code::AltLeft, Id::from_str("eyedropper_tool")),
+                (key::Code::AltRight, Id::from_str("eyedropper_tool")),
+            ])),
+        }
+    }
+
+    /// Sends strokes of `button` to `tool`, or nowhere if `None`.
+    pub fn bind_button(&self, button: PointerButton, tool: Option<Id<CanvasTool>>) {
+        let mut button_tools = self.button_tools.write();
+        match tool {
+            Some(tool) => button_tools.insert(button, tool),
+            None => button_tools.remove(&button),
+        };
+    }
+
+    /// Sends strokes of paint tools started with `key` held to `tool`, or
+    /// leaves them be if `None`.
+    pub fn bind_modifier(&self, key: key::Code, tool: Option<Id<CanvasTool>>) {
+        let mut modifier_tools = self.modifier_tools.write();
+        match tool {
+            Some(tool) => modifier_tools.insert(key, tool),
+            None => modifier_tools.remove(&key),
+        };
+    }
+
+    /// The tool a held modifier hands strokes of the current tool to.
+    fn modifier_tool(
+        &self,
+        keyboard: &KeyboardState,
+        current: Id<CanvasTool>,
+    ) -> Option<Id<CanvasTool>> {
+        if !self.tools.get(&current).is_some_and(|tool| tool.paints()) {
+            return None;
         }
+        self.modifier_tools
+            .read()
+            .iter()
+            .find(|(key, _)| keyboard.is_pressed(**key))
+            .map(|(_, tool)| *tool)
+    }
+
+    /// Predicts the pointer `lead` ahead of the input, or turns prediction off
+    /// if `None`.
+    pub fn set_prediction(&self, lead: Option<Duration>) {
+        *self.predictor.lock() = lead.map(MotionPredictor::new);
+    }
+
+    pub fn stabilizer_settings(&self) -> StabilizerSettings {
+        *self.stabilizer.lock().settings()
+    }
+
+    pub fn set_stabilizer_settings(&self, settings: StabilizerSettings) {
+        self.stabilizer.lock().set_settings(settings);
     }
 
     pub fn switch_tool(&self, tool: Id<CanvasTool>, canvas: &CCanvas) {
@@ -96,14 +223,53 @@ impl ToolProxy {
         }
     }
 
+    pub fn toggle_erase(&self) -> Option<bool> {
+        let state = self.state.read();
+        self.tools
+            .get_mut(&state.current)
+            .and_then(|mut tool| tool.toggle_erase())
+    }
+
+    pub fn confirm(&self, canvas: &CCanvas) {
+        let state = self.state.read();
+        if let Some(mut tool) = self.tools.get_mut(&state.current) {
+            tool.confirm(canvas);
+        }
+    }
+
+    pub fn cancel(&self, canvas: &CCanvas) {
+        let state = self.state.read();
+        if let Some(mut tool) = self.tools.get_mut(&state.current) {
+            tool.cancel(canvas);
+        }
+    }
+
     pub fn mouse_pressed(
         &self,
         keyboard: &KeyboardState,
         mouse: &PressedMouseState,
         canvas: &CCanvas,
     ) {
-        let state = self.state.read();
-        if let Some(mut tool) = self.tools.get_mut(&state.current) {
+        let mut state = self.state.write();
+        state.stroke = match mouse.button {
+            PointerButton::Primary => Some(
+                self.modifier_tool(keyboard, state.current)
+                    .unwrap_or(state.current),
+            ),
+            button => self.button_tools.read().get(&button).copied(),
+        };
+        let Some(mut tool) = state.stroke.and_then(|id| self.tools.get_mut(&id)) else {
+            return;
+        };
+
+        if let Some(predictor) = self.predictor.lock().as_mut() {
+            predictor.reset();
+            predictor.push(*mouse);
+        }
+        if tool.stabilized() {
+            let mouse = self.stabilizer.lock().press(*mouse);
+            tool.begin(keyboard, &mouse, canvas);
+        } else {
             tool.begin(keyboard, mouse, canvas);
         }
     }
@@ -113,10 +279,41 @@ impl ToolProxy {
         keyboard: &KeyboardState,
         mouse: &PressedMouseState,
         canvas: &CCanvas,
+    ) {
+        self.mouse_moved_pressing_batch(keyboard, std::slice::from_ref(mouse), canvas);
+    }
+
+    /// Hands every move of the stroke since the last update to its tool at
+    /// once, then lets it follow where the pointer is predicted to be.
+    pub fn mouse_moved_pressing_batch(
+        &self,
+        keyboard: &KeyboardState,
+        samples: &[PressedMouseState],
+        canvas: &CCanvas,
     ) {
         let state = self.state.read();
-        if let Some(mut tool) = self.tools.get_mut(&state.current) {
-            tool.update(keyboard, mouse, canvas);
+        let Some(mut tool) = state.stroke.and_then(|id| self.tools.get_mut(&id)) else {
+            return;
+        };
+
+        if tool.stabilized() {
+            let mut stabilizer = self.stabilizer.lock();
+            let samples = samples
+                .iter()
+                .map(|sample| stabilizer.advance(*sample))
+                .collect::<Vec<_>>();
+            tool.update_batch(keyboard, &samples, canvas);
+        } else {
+            tool.update_batch(keyboard, samples, canvas);
+        }
+
+        if let Some(predictor) = self.predictor.lock().as_mut() {
+            for sample in samples {
+                predictor.push(*sample);
+            }
+            if let Some(predicted) = predictor.predict() {
+                tool.predict(keyboard, &predicted, canvas);
+            }
         }
     }
 
@@ -138,9 +335,244 @@ impl ToolProxy {
         mouse: &PressedMouseState,
         canvas: &CCanvas,
     ) {
-        let state = self.state.read();
-        if let Some(mut tool) = self.tools.get_mut(&state.current) {
+        let mut state = self.state.write();
+        let Some(mut tool) = state.stroke.take().and_then(|id| self.tools.get_mut(&id)) else {
+            return;
+        };
+
+        if tool.stabilized() {
+            let samples = self.stabilizer.lock().release(*mouse);
+            if let Some((last, rest)) = samples.split_last() {
+                tool.update_batch(keyboard, rest, canvas);
+                tool.end(keyboard, last, canvas);
+            }
+        } else {
             tool.end(keyboard, mouse, canvas);
         }
     }
+
+    pub fn pointer_event(&self, keyboard: &KeyboardState, event: &PointerEvent, canvas: &CCanvas) {
+        self.pointer_events(keyboard, std::slice::from_ref(event), canvas);
+    }
+
+    /// Dispatches a batch of events in order. Consecutive pressed moves reach
+    /// the tool together and of consecutive hovers only the last one does.
+    pub fn pointer_events(
+        &self,
+        keyboard: &KeyboardState,
+        events: &[PointerEvent],
+        canvas: &CCanvas,
+    ) {
+        let mut moves = Vec::new();
+        for (i, event) in events.iter().enumerate() {
+            match event {
+                PointerEvent::Pressed(state) => self.mouse_pressed(keyboard, state, canvas),
+                PointerEvent::MovedPressing(state) => {
+                    moves.push(*state);
+                    if !matches!(events.get(i + 1), Some(PointerEvent::MovedPressing(_))) {
+                        self.mouse_moved_pressing_batch(keyboard, &moves, canvas);
+                        moves.clear();
+                    }
+                }
+                PointerEvent::MovedHovering(state) => {
+                    if !matches!(events.get(i + 1), Some(PointerEvent::MovedHovering(_))) {
+                        self.mouse_moved_hovering(keyboard, state, canvas);
+                    }
+                }
+                PointerEvent::Released(state) => self.mouse_released(keyboard, state, canvas),
+            }
+        }
+    }
+
+    /// Gives every tool the chance to finish background work, even those not
+    /// in use anymore.
+    pub fn frame(&self, canvas: &CCanvas) {
+        let mut picked = None;
+        for tool in self.tools.actions.values() {
+            let mut tool = tool.write();
+            tool.frame(canvas);
+            picked = tool.take_picked_color().or(picked);
+        }
+        if let Some(picked) = picked {
+            self.set_color(picked);
+        }
+    }
+
+    /// Gives every tool the color to paint with.
+    pub fn set_color(&self, picked: PickedColor) {
+        for tool in self.tools.actions.values() {
+            tool.write().set_color(picked);
+        }
+    }
+
+    /// Feeds every pending event of the source to the current tool.
+    pub fn replay(
+        &self,
+        keyboard: &KeyboardState,
+        source: &mut dyn PointerEventSource,
+        canvas: &CCanvas,
+    ) {
+        let mut events = Vec::new();
+        while let Some(event) = source.poll() {
+            events.push(event);
+        }
+        self.pointer_events(keyboard, &events, canvas);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cyancia_image::CImage;
+    use cyancia_input::mouse::{PointerKind, PointerState, ScriptedPointerSource};
+    use glam::UVec2;
+    use iced_core::Vector;
+
+    use super::*;
+
+    /// What a tool was handed, in order.
+    #[derive(Debug, Clone, PartialEq)]
+    enum Call {
+        Begin(Point, f32),
+        Update(Vec<Point>),
+        End(Point),
+        Hover(Point),
+    }
+
+    struct RecordingTool {
+        id: &'static str,
+        calls: Vec<Call>,
+    }
+
+    impl CanvasToolFunction for RecordingTool {
+        fn id(&self) -> Id<CanvasTool> {
+            Id::from_str(self.id)
+        }
+
+        fn hover(&mut self, keyboard: &KeyboardState, mouse: &HoverMouseState, canvas: &CCanvas) {
+            self.calls.push(Call::Hover(mouse.position));
+        }
+
+        fn begin(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+            self.calls.push(Call::Begin(mouse.position, mouse.pressure));
+        }
+
+        fn update_batch(
+            &mut self,
+            keyboard: &KeyboardState,
+            samples: &[PressedMouseState],
+            canvas: &CCanvas,
+        ) {
+            self.calls
+                .push(Call::Update(samples.iter().map(|s| s.position).collect()));
+        }
+
+        fn end(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+            self.calls.push(Call::End(mouse.position));
+        }
+    }
+
+    fn proxy(ids: &[&'static str]) -> (ToolProxy, Vec<Arc<RwLock<RecordingTool>>>) {
+        let mut collection = CanvasToolFunctionCollection::new();
+        let tools = ids
+            .iter()
+            .map(|id| {
+                let tool = Arc::new(RwLock::new(RecordingTool {
+                    id,
+                    calls: Vec::new(),
+                }));
+                collection.actions.insert(Id::from_str(id), tool.clone());
+                tool
+            })
+            .collect::<Vec<_>>();
+        let proxy = ToolProxy::new(Id::from_str(ids[0]), collection);
+        proxy.set_prediction(None);
+        (proxy, tools)
+    }
+
+    fn canvas() -> CCanvas {
+        CCanvas {
+            image: RwLock::new(CImage::new(UVec2::splat(64))),
+            transform: Default::default(),
+            path: Default::default(),
+            history: Default::default(),
+            overlay: Default::default(),
+            previews: Default::default(),
+        }
+    }
+
+    fn pen(x: f32, pressure: f32) -> PointerState {
+        PointerState {
+            position: Point::new(x, 10.0),
+            pressure,
+            tilt: Vector::new(30.0, 0.0),
+            rotation: 0.0,
+            timestamp: Instant::now(),
+            kind: PointerKind::Pen,
+            button: PointerButton::Primary,
+        }
+    }
+
+    #[test]
+    fn scripted_pen_stroke_reaches_current_tool() {
+        let (proxy, tools) = proxy(&["brush_tool"]);
+        let mut source = ScriptedPointerSource::new([
+            PointerEvent::MovedHovering(pen(0.0, 0.0)),
+            PointerEvent::MovedHovering(pen(1.0, 0.0)),
+            PointerEvent::Pressed(pen(1.0, 0.25)),
+            PointerEvent::MovedPressing(pen(2.0, 0.5)),
+            PointerEvent::MovedPressing(pen(3.0, 0.75)),
+            PointerEvent::MovedPressing(pen(4.0, 0.5)),
+            PointerEvent::Released(pen(4.0, 0.0)),
+        ]);
+
+        proxy.replay(&KeyboardState::default(), &mut source, &canvas());
+
+        assert!(source.is_empty());
+        let p = |x| Point::new(x, 10.0);
+        assert_eq!(
+            tools[0].read().calls,
+            [
+                // Only the last of consecutive hovers arrives.
+                Call::Hover(p(1.0)),
+                Call::Begin(p(1.0), 0.25),
+                // Consecutive moves arrive as one batch.
+                Call::Update(vec![p(2.0), p(3.0), p(4.0)]),
+                Call::End(p(4.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn middle_button_stroke_goes_to_pan_tool() {
+        let (proxy, tools) = proxy(&["brush_tool", "pan_tool"]);
+        let middle = |x| pen(x, 1.0).with_button(PointerButton::Middle);
+        let mut source = ScriptedPointerSource::new([
+            PointerEvent::Pressed(middle(0.0)),
+            PointerEvent::MovedPressing(middle(5.0)),
+            PointerEvent::Released(middle(5.0)),
+        ]);
+
+        proxy.replay(&KeyboardState::default(), &mut source, &canvas());
+
+        assert!(tools[0].read().calls.is_empty());
+        assert_eq!(tools[1].read().calls.len(), 3);
+    }
+
+    #[test]
+    fn sources_are_drained_in_order() {
+        let mut sources = cyancia_input::mouse::PointerSources::default();
+        sources.add(ScriptedPointerSource::new([
+            PointerEvent::Pressed(pen(0.0, 0.5)),
+            PointerEvent::Released(pen(0.0, 0.0)),
+        ]));
+        sources.add(ScriptedPointerSource::new([PointerEvent::MovedHovering(
+            pen(1.0, 0.0),
+        )]));
+
+        let events = sources.drain();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(events[0], PointerEvent::Pressed(_)));
+        assert!(matches!(events[2], PointerEvent::MovedHovering(_)));
+        assert!(sources.drain().is_empty());
+    }
 }
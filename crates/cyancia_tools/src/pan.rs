@@ -25,4 +25,20 @@ impl CanvasToolFunction for PanTool {
         let delta = Vec2::new(mouse.position.x, mouse.position.y) - self.start_pos;
         *canvas.transform.write() = self.original_transform.clone().translated(delta);
     }
+
+    /// Only where the pointer ended up matters for the view.
+    fn update_batch(
+        &mut self,
+        keyboard: &KeyboardState,
+        samples: &[PressedMouseState],
+        canvas: &CCanvas,
+    ) {
+        if let Some(mouse) = samples.last() {
+            self.update(keyboard, mouse, canvas);
+        }
+    }
+
+    fn predict(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.update(keyboard, mouse, canvas);
+    }
 }
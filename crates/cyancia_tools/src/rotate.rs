@@ -34,4 +34,20 @@ impl CanvasToolFunction for RotateTool {
             .clone()
             .rotated_around(cur_angle.angle_difference(self.initial_angle), self.center);
     }
+
+    /// Only where the pointer ended up matters for the view.
+    fn update_batch(
+        &mut self,
+        keyboard: &KeyboardState,
+        samples: &[PressedMouseState],
+        canvas: &CCanvas,
+    ) {
+        if let Some(mouse) = samples.last() {
+            self.update(keyboard, mouse, canvas);
+        }
+    }
+
+    fn predict(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.update(keyboard, mouse, canvas);
+    }
 }
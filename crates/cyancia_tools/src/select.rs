@@ -0,0 +1,394 @@
+//! Tools drawing shapes into the selection. Holding Shift adds to the
+//! selection, Alt subtracts from it and both intersect with it.
+
+use cyancia_canvas::CCanvas;
+use cyancia_id::Id;
+use cyancia_image::selection::{
+    GPU_MASK_STORAGE, Selection, SelectionMask, SelectionMode, SelectionShape, ShapeCoverage,
+};
+use cyancia_input::{
+    key::KeyboardState,
+    mouse::{HoverMouseState, PressedMouseState},
+};
+use glam::Vec2;
+use iced_core::keyboard::key;
+
+use crate::{CanvasTool, CanvasToolFunction};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelectionOptions {
+    /// Mode used when no modifier is held.
+    pub mode: SelectionMode,
+    /// How far the edge fades out, in pixels.
+    pub feather: f32,
+    pub antialias: bool,
+}
+
+impl Default for SelectionOptions {
+    fn default() -> Self {
+        Self {
+            mode: SelectionMode::Replace,
+            feather: 0.0,
+            antialias: true,
+        }
+    }
+}
+
+impl SelectionOptions {
+    pub fn mode(&self, keyboard: &KeyboardState) -> SelectionMode {
+        modifier_mode(keyboard, self.mode)
+    }
+}
+
+/// The mode picked by the held modifiers, `default` without any.
+pub fn modifier_mode(keyboard: &KeyboardState, default: SelectionMode) -> SelectionMode {
+    let shift =
+        keyboard.is_pressed(key::Code::ShiftLeft) || keyboard.is_pressed(key::Code::ShiftRight);
+    let alt = keyboard.is_pressed(key::Code::AltLeft) || keyboard.is_pressed(key::Code::AltRight);
+    match (shift, alt) {
+        (true, true) => SelectionMode::Intersect,
+        (true, false) => SelectionMode::Add,
+        (false, true) => SelectionMode::Subtract,
+        (false, false) => default,
+    }
+}
+
+/// A shape being drawn. The canvas shows it combined into the selection as it
+/// was before, without antialiasing or feathering to keep up with the pointer.
+#[derive(Default)]
+struct PendingSelection {
+    before: Option<Option<SelectionMask>>,
+    mode: SelectionMode,
+}
+
+impl PendingSelection {
+    fn is_active(&self) -> bool {
+        self.before.is_some()
+    }
+
+    fn begin(&mut self, canvas: &CCanvas, mode: SelectionMode) {
+        self.before = Some(canvas.image.read().selection().mask().cloned());
+        self.mode = mode;
+    }
+
+    fn preview(&self, canvas: &CCanvas, shape: &SelectionShape) {
+        self.show(canvas, &ShapeCoverage::new(shape, false, 0.0));
+    }
+
+    fn commit(&mut self, canvas: &CCanvas, shape: &SelectionShape, options: &SelectionOptions) {
+        self.show(
+            canvas,
+            &ShapeCoverage::new(shape, options.antialias, options.feather),
+        );
+        self.before = None;
+    }
+
+    /// A click without a drag drops a replaced selection and leaves others be.
+    fn click(&mut self, canvas: &CCanvas) {
+        let Some(before) = self.before.take() else {
+            return;
+        };
+        let mask = match self.mode {
+            SelectionMode::Replace => None,
+            _ => before,
+        };
+        canvas
+            .image
+            .write()
+            .selection_mut()
+            .set_mask(mask, &GPU_MASK_STORAGE);
+    }
+
+    fn cancel(&mut self, canvas: &CCanvas) {
+        if let Some(before) = self.before.take() {
+            canvas
+                .image
+                .write()
+                .selection_mut()
+                .set_mask(before, &GPU_MASK_STORAGE);
+        }
+    }
+
+    fn show(&self, canvas: &CCanvas, coverage: &ShapeCoverage) {
+        let Some(before) = &self.before else {
+            return;
+        };
+        let mut image = canvas.image.write();
+        let mask = Selection::combine(before.as_ref(), image.size(), coverage, self.mode);
+        image.selection_mut().set_mask(mask, &GPU_MASK_STORAGE);
+    }
+}
+
+pub(crate) fn pixel_position(mouse: &PressedMouseState, canvas: &CCanvas) -> Vec2 {
+    canvas
+        .transform
+        .read()
+        .widget_to_pixel(Vec2::new(mouse.position.x, mouse.position.y))
+}
+
+/// Whether the drag is too small to mean a shape.
+fn is_click(a: Vec2, b: Vec2) -> bool {
+    (b - a).abs().min_element() < 1.0
+}
+
+#[derive(Default)]
+pub struct RectSelectTool {
+    pub options: SelectionOptions,
+    start: Vec2,
+    pending: PendingSelection,
+}
+
+impl CanvasToolFunction for RectSelectTool {
+    fn id(&self) -> Id<CanvasTool> {
+        Id::from_str("rect_select_tool")
+    }
+
+    fn begin(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.start = pixel_position(mouse, canvas);
+        self.pending.begin(canvas, self.options.mode(keyboard));
+    }
+
+    fn update(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        let shape = SelectionShape::rect(self.start, pixel_position(mouse, canvas));
+        self.pending.preview(canvas, &shape);
+    }
+
+    /// Only the latest corner matters for the preview.
+    fn update_batch(
+        &mut self,
+        keyboard: &KeyboardState,
+        samples: &[PressedMouseState],
+        canvas: &CCanvas,
+    ) {
+        if let Some(mouse) = samples.last() {
+            self.update(keyboard, mouse, canvas);
+        }
+    }
+
+    fn end(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        let end = pixel_position(mouse, canvas);
+        if is_click(self.start, end) {
+            self.pending.click(canvas);
+        } else {
+            let shape = SelectionShape::rect(self.start, end);
+            self.pending.commit(canvas, &shape, &self.options);
+        }
+    }
+
+    fn deactivate(&mut self, canvas: &CCanvas) {
+        self.pending.cancel(canvas);
+    }
+}
+
+#[derive(Default)]
+pub struct EllipseSelectTool {
+    pub options: SelectionOptions,
+    start: Vec2,
+    pending: PendingSelection,
+}
+
+impl CanvasToolFunction for EllipseSelectTool {
+    fn id(&self) -> Id<CanvasTool> {
+        Id::from_str("ellipse_select_tool")
+    }
+
+    fn begin(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.start = pixel_position(mouse, canvas);
+        self.pending.begin(canvas, self.options.mode(keyboard));
+    }
+
+    fn update(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        let shape = SelectionShape::ellipse(self.start, pixel_position(mouse, canvas));
+        self.pending.preview(canvas, &shape);
+    }
+
+    /// Only the latest corner matters for the preview.
+    fn update_batch(
+        &mut self,
+        keyboard: &KeyboardState,
+        samples: &[PressedMouseState],
+        canvas: &CCanvas,
+    ) {
+        if let Some(mouse) = samples.last() {
+            self.update(keyboard, mouse, canvas);
+        }
+    }
+
+    fn end(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        let end = pixel_position(mouse, canvas);
+        if is_click(self.start, end) {
+            self.pending.click(canvas);
+        } else {
+            let shape = SelectionShape::ellipse(self.start, end);
+            self.pending.commit(canvas, &shape, &self.options);
+        }
+    }
+
+    fn deactivate(&mut self, canvas: &CCanvas) {
+        self.pending.cancel(canvas);
+    }
+}
+
+/// Selects the area enclosed by a freehand path, closed back to where it
+/// started once the pointer is released.
+#[derive(Default)]
+pub struct LassoTool {
+    pub options: SelectionOptions,
+    points: Vec<Vec2>,
+    pending: PendingSelection,
+}
+
+impl LassoTool {
+    /// Pixels the pointer has to move before the path gets another point.
+    const SPACING: f32 = 1.0;
+
+    fn push(&mut self, point: Vec2) {
+        if self
+            .points
+            .last()
+            .is_none_or(|last| last.distance(point) >= Self::SPACING)
+        {
+            self.points.push(point);
+        }
+    }
+}
+
+impl CanvasToolFunction for LassoTool {
+    fn id(&self) -> Id<CanvasTool> {
+        Id::from_str("lasso_tool")
+    }
+
+    fn begin(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.points.clear();
+        self.points.push(pixel_position(mouse, canvas));
+        self.pending.begin(canvas, self.options.mode(keyboard));
+    }
+
+    fn update(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.update_batch(keyboard, std::slice::from_ref(mouse), canvas);
+    }
+
+    /// Extends the path by every sample but previews once.
+    fn update_batch(
+        &mut self,
+        keyboard: &KeyboardState,
+        samples: &[PressedMouseState],
+        canvas: &CCanvas,
+    ) {
+        for mouse in samples {
+            self.push(pixel_position(mouse, canvas));
+        }
+        if self.points.len() >= 3 {
+            let shape = SelectionShape::Polygon(self.points.clone());
+            self.pending.preview(canvas, &shape);
+        }
+    }
+
+    fn end(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.push(pixel_position(mouse, canvas));
+        let points = std::mem::take(&mut self.points);
+        if points.len() >= 3 {
+            let shape = SelectionShape::Polygon(points);
+            self.pending.commit(canvas, &shape, &self.options);
+        } else {
+            self.pending.click(canvas);
+        }
+    }
+
+    fn deactivate(&mut self, canvas: &CCanvas) {
+        self.points.clear();
+        self.pending.cancel(canvas);
+    }
+}
+
+/// Selects a polygon placed one corner per click. Clicking the first corner
+/// again closes it, switching tools drops it.
+#[derive(Default)]
+pub struct PolygonLassoTool {
+    pub options: SelectionOptions,
+    points: Vec<Vec2>,
+    pending: PendingSelection,
+}
+
+impl PolygonLassoTool {
+    /// Widget pixels around the first corner that close the polygon.
+    const CLOSE_DISTANCE: f32 = 8.0;
+
+    fn closes(&self, mouse: &PressedMouseState, canvas: &CCanvas) -> bool {
+        let Some(first) = self.points.first() else {
+            return false;
+        };
+        let first = canvas
+            .transform
+            .read()
+            .pixel_to_widget
+            .transform_point2(*first);
+        first.distance(Vec2::new(mouse.position.x, mouse.position.y)) <= Self::CLOSE_DISTANCE
+    }
+
+    /// The polygon with its last corner following the pointer.
+    fn preview(&self, cursor: Vec2, canvas: &CCanvas) {
+        if !self.pending.is_active() {
+            return;
+        }
+        let mut points = self.points.clone();
+        points.push(cursor);
+        if points.len() >= 3 {
+            self.pending
+                .preview(canvas, &SelectionShape::Polygon(points));
+        }
+    }
+}
+
+impl CanvasToolFunction for PolygonLassoTool {
+    fn id(&self) -> Id<CanvasTool> {
+        Id::from_str("polygon_lasso_tool")
+    }
+
+    fn hover(&mut self, keyboard: &KeyboardState, mouse: &HoverMouseState, canvas: &CCanvas) {
+        self.preview(pixel_position(mouse, canvas), canvas);
+    }
+
+    fn begin(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        if !self.pending.is_active() {
+            self.points.clear();
+            self.pending.begin(canvas, self.options.mode(keyboard));
+        } else if self.closes(mouse, canvas) {
+            let points = std::mem::take(&mut self.points);
+            if points.len() >= 3 {
+                let shape = SelectionShape::Polygon(points);
+                self.pending.commit(canvas, &shape, &self.options);
+            } else {
+                self.pending.click(canvas);
+            }
+        }
+    }
+
+    /// Dragging moves the corner about to be placed.
+    fn update(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.preview(pixel_position(mouse, canvas), canvas);
+    }
+
+    fn update_batch(
+        &mut self,
+        keyboard: &KeyboardState,
+        samples: &[PressedMouseState],
+        canvas: &CCanvas,
+    ) {
+        if let Some(mouse) = samples.last() {
+            self.update(keyboard, mouse, canvas);
+        }
+    }
+
+    /// Corners are placed where the button is released.
+    fn end(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        if self.pending.is_active() {
+            self.points.push(pixel_position(mouse, canvas));
+        }
+    }
+
+    fn deactivate(&mut self, canvas: &CCanvas) {
+        self.points.clear();
+        self.pending.cancel(canvas);
+    }
+}
@@ -0,0 +1,863 @@
+//! Moving, scaling, rotating and distorting the pixels of the active layer, or
+//! only the selected ones. The pixels are read back once the tool is picked.
+//! Previews are resampled on a background thread into a scratch layer the
+//! canvas draws in place of the active one, which is only written once the
+//! transform is confirmed. If the layer changes in the meantime, its pixels
+//! are lifted again.
+//!
+//! Dragging inside the frame moves the pixels and outside of it rotates them
+//! about the pivot, with Shift snapping the angle. Corners scale from the
+//! opposite corner and edges from the opposite edge, Shift keeps the aspect
+//! and Alt scales about the pivot instead. Holding Ctrl, corners distort and
+//! edges skew.
+
+use std::{f32::consts::PI, sync::Arc};
+
+use cyancia_canvas::{CCanvas, overlay::CanvasOverlay};
+use cyancia_id::Id;
+use cyancia_image::{
+    blend::{self, BlendMode},
+    layer::{Layer, LayerLocks},
+    selection::{GPU_MASK_STORAGE, SelectionMask},
+    tile::{GPU_TILE_STORAGE, GpuTileStorage},
+    warp::{self, MeshWarp, ResampleFilter, Warp},
+};
+use cyancia_input::{key::KeyboardState, mouse::PressedMouseState};
+use futures::channel::oneshot;
+use glam::{IVec2, Mat2, UVec2, Vec2, Vec4};
+use iced_core::keyboard::key;
+use image::{GenericImageView, Rgba, Rgba32FImage};
+
+use crate::{CanvasTool, CanvasToolFunction, select};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransformMode {
+    /// Corners and edges scale, or distort and skew with Ctrl.
+    #[default]
+    Free,
+    /// Corners move on their own, putting the pixels in perspective.
+    Perspective,
+    /// Every point of a grid over the pixels moves on its own.
+    Mesh,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransformOptions {
+    pub mode: TransformMode,
+    /// Filter of the committed result. Previews always use bilinear to keep
+    /// up with the pointer.
+    pub filter: ResampleFilter,
+    /// Cells of the mesh warp grid, across and down.
+    pub mesh_cells: UVec2,
+}
+
+impl Default for TransformOptions {
+    fn default() -> Self {
+        Self {
+            mode: TransformMode::Free,
+            filter: ResampleFilter::Bicubic,
+            mesh_cells: UVec2::splat(4),
+        }
+    }
+}
+
+/// Pixels lifted off the layer when the transform began.
+struct Floating {
+    layer: Id<Layer>,
+    /// Generation of the layer the pixels were read at.
+    generation: u64,
+    image_size: UVec2,
+    /// Top left of the pixels on the image.
+    origin: UVec2,
+    pixels: Rgba32FImage,
+    /// Coverage of the selection over the pixels, in alpha.
+    mask: Option<Rgba32FImage>,
+    /// The whole layer with the lifted pixels cut out, `None` if nothing is
+    /// left of it.
+    backdrop: Option<Rgba32FImage>,
+}
+
+/// A part of the layer with the transform applied, covering whole tiles.
+struct Rendered {
+    origin: UVec2,
+    pixels: Rgba32FImage,
+    /// The selection moved along with the pixels, once committing.
+    selection: Option<SelectionMask>,
+    commit: bool,
+}
+
+/// Pixels from the first to past the last one.
+type PixelRect = (UVec2, UVec2);
+
+struct TransformSession {
+    floating: Arc<Floating>,
+    /// Scratch layer holding the previews, drawn in place of the layer.
+    preview: Id<Layer>,
+    /// Tiles the pixels were lifted from, which the committed result has to
+    /// clear.
+    lifted: PixelRect,
+    /// Where the corners of the lifted pixels are, clockwise from the top left.
+    corners: [Vec2; 4],
+    mesh: Option<MeshWarp>,
+    pivot: Vec2,
+    /// Tiles the last rendered result landed on, which the next one has to
+    /// clear.
+    shown: PixelRect,
+    /// Changed since the last render began.
+    dirty: bool,
+    /// Changed at all since the transform began.
+    changed: bool,
+    /// The final render has been asked for, input is ignored until it's done.
+    committing: bool,
+}
+
+impl TransformSession {
+    fn new(floating: Arc<Floating>) -> Self {
+        let min = floating.origin.as_vec2();
+        let size = UVec2::new(floating.pixels.width(), floating.pixels.height()).as_vec2();
+        let max = min + size;
+        let lifted = tile_rect(
+            floating.origin.as_ivec2(),
+            max.as_ivec2(),
+            floating.image_size,
+        );
+        Self {
+            preview: Id::random(),
+            lifted,
+            corners: [min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y)],
+            mesh: None,
+            pivot: min + size * 0.5,
+            shown: lifted,
+            dirty: false,
+            changed: false,
+            committing: false,
+            floating,
+        }
+    }
+
+    fn warp(&self) -> Warp {
+        match &self.mesh {
+            Some(mesh) => Warp::Mesh(mesh.clone()),
+            None => Warp::Quad(self.corners),
+        }
+    }
+
+    /// Moves every point of the transform, the pivot included.
+    fn map(&mut self, f: impl Fn(Vec2) -> Vec2) {
+        for corner in &mut self.corners {
+            *corner = f(*corner);
+        }
+        if let Some(mesh) = &mut self.mesh {
+            for point in &mut mesh.points {
+                *point = f(*point);
+            }
+        }
+        self.pivot = f(self.pivot);
+    }
+
+    fn edge_midpoint(&self, edge: usize) -> Vec2 {
+        self.corners[edge].lerp(self.corners[(edge + 1) % 4], 0.5)
+    }
+
+    fn hit(&self, p: Vec2, radius: f32, mode: TransformMode) -> DragKind {
+        if p.distance(self.pivot) <= radius {
+            return DragKind::Pivot;
+        }
+        let nearest = |points: &mut dyn Iterator<Item = Vec2>| {
+            points
+                .enumerate()
+                .map(|(i, point)| (i, point.distance(p)))
+                .filter(|(_, d)| *d <= radius)
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(i, _)| i)
+        };
+
+        match (&self.mesh, mode) {
+            (Some(mesh), TransformMode::Mesh) => {
+                if let Some(i) = nearest(&mut mesh.points.iter().copied()) {
+                    return DragKind::MeshPoint(i);
+                }
+            }
+            _ => {
+                if let Some(i) = nearest(&mut self.corners.iter().copied()) {
+                    return DragKind::Corner(i);
+                }
+                if let Some(i) = nearest(&mut (0..4).map(|e| self.edge_midpoint(e))) {
+                    return DragKind::Edge(i);
+                }
+            }
+        }
+
+        if contains(&self.corners, p) {
+            DragKind::Move
+        } else {
+            DragKind::Rotate
+        }
+    }
+
+    fn overlay(&self, mode: TransformMode) -> CanvasOverlay {
+        let lines = match &self.mesh {
+            Some(mesh) => mesh.edges(),
+            None => (0..4)
+                .map(|i| (self.corners[i], self.corners[(i + 1) % 4]))
+                .collect(),
+        };
+        let handles = match (&self.mesh, mode) {
+            (Some(mesh), TransformMode::Mesh) => mesh.points.clone(),
+            _ => self
+                .corners
+                .iter()
+                .copied()
+                .chain((0..4).map(|e| self.edge_midpoint(e)))
+                .collect(),
+        };
+        CanvasOverlay {
+            lines,
+            handles,
+            pivot: Some(self.pivot),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DragKind {
+    Move,
+    Rotate,
+    Pivot,
+    Corner(usize),
+    /// The edge from the corner of the same index to the next one.
+    Edge(usize),
+    MeshPoint(usize),
+}
+
+/// A drag in progress, with the transform as it was when it began. Every
+/// update starts over from there, so nothing drifts.
+struct Drag {
+    kind: DragKind,
+    start: Vec2,
+    corners: [Vec2; 4],
+    mesh: Option<MeshWarp>,
+    pivot: Vec2,
+}
+
+#[derive(Default)]
+pub struct TransformTool {
+    pub options: TransformOptions,
+    session: Option<TransformSession>,
+    drag: Option<Drag>,
+    loading: Option<oneshot::Receiver<Floating>>,
+    rendering: Option<oneshot::Receiver<Rendered>>,
+}
+
+impl TransformTool {
+    /// How close to a handle a press grabs it, in logical pixels.
+    const HANDLE_RADIUS: f32 = 8.0;
+    /// Steps rotation snaps to with Shift held.
+    const ANGLE_SNAP: f32 = PI / 12.0;
+
+    /// Starts lifting the pixels to transform off the active layer.
+    fn load(&mut self, canvas: &CCanvas) {
+        if self.session.is_some() || self.loading.is_some() {
+            return;
+        }
+
+        let image = canvas.image.read();
+        let Some(layer) = image.active_layer() else {
+            return;
+        };
+        let locks = image
+            .layer(layer)
+            .map(|l| l.properties.locks)
+            .unwrap_or_default();
+        if locks.contains(LayerLocks::PIXELS) {
+            log::warn!("Pixels of the active layer are locked.");
+            return;
+        }
+
+        let size = image.size();
+        let selection = image.selection().mask().cloned();
+        let generation = GPU_TILE_STORAGE.generation(layer);
+        let readback = GPU_TILE_STORAGE.read_layer(layer, size);
+        let (sender, receiver) = oneshot::channel();
+        self.loading = Some(receiver);
+
+        std::thread::spawn(move || {
+            let original = match futures::executor::block_on(readback) {
+                Ok(p) => p,
+                Err(e) => {
+                    log::error!("Unable to read the layer to transform: {}", e);
+                    return;
+                }
+            };
+            match lift(layer, generation, size, original, selection.as_ref()) {
+                Some(floating) => {
+                    let _ = sender.send(floating);
+                }
+                None => log::info!("Nothing to transform."),
+            }
+        });
+    }
+
+    /// Sends the transform off to be rendered, unless a render is still
+    /// running. It's picked up again once that one is done.
+    fn render(&mut self) {
+        if self.rendering.is_some() {
+            return;
+        }
+        let Some(session) = &mut self.session else {
+            return;
+        };
+        if !session.dirty {
+            return;
+        }
+        session.dirty = false;
+
+        let floating = session.floating.clone();
+        let warp = session.warp();
+        let commit = session.committing;
+        let filter = if commit {
+            self.options.filter
+        } else {
+            ResampleFilter::Bilinear
+        };
+        let (min, max) = warp.bounds();
+        let next = tile_rect(min, max, floating.image_size);
+        // Previews clear what the last one showed on the scratch layer, the
+        // committed result what was lifted off the layer itself.
+        let cleared = if commit {
+            session.lifted
+        } else {
+            session.shown
+        };
+        let (origin, end) = union(cleared, next);
+        session.shown = next;
+
+        let (sender, receiver) = oneshot::channel();
+        self.rendering = Some(receiver);
+
+        std::thread::spawn(move || {
+            let size = end.max(origin) - origin;
+            let pixels = compose(&floating, &warp, filter, origin, size);
+            let selection = commit
+                .then(|| transform_mask(&floating, &warp, filter))
+                .flatten();
+            let _ = sender.send(Rendered {
+                origin,
+                pixels,
+                selection,
+                commit,
+            });
+        });
+    }
+
+    fn poll_loading(&mut self, canvas: &CCanvas) {
+        let Some(receiver) = &mut self.loading else {
+            return;
+        };
+        let floating = match receiver.try_recv() {
+            Ok(Some(floating)) => floating,
+            Ok(None) => return,
+            Err(_) => {
+                self.loading = None;
+                return;
+            }
+        };
+        self.loading = None;
+
+        let image = canvas.image.read();
+        // The document was switched or the layer removed while reading.
+        if image.size() != floating.image_size || image.layer(floating.layer).is_err() {
+            return;
+        }
+        drop(image);
+        if GPU_TILE_STORAGE.generation(floating.layer) != floating.generation {
+            self.load(canvas);
+            return;
+        }
+
+        let session = TransformSession::new(Arc::new(floating));
+        GPU_TILE_STORAGE.copy_layer(session.floating.layer, session.preview);
+        canvas
+            .previews
+            .write()
+            .insert(session.floating.layer, session.preview);
+        self.session = Some(session);
+    }
+
+    /// Starts over from the layer as it is now if it changed since its pixels
+    /// were lifted, and gives up if it's gone.
+    fn check_layer(&mut self, canvas: &CCanvas) {
+        let Some(session) = &self.session else {
+            return;
+        };
+        let layer = session.floating.layer;
+        let image = canvas.image.read();
+        let gone = image.size() != session.floating.image_size || image.layer(layer).is_err();
+        drop(image);
+        if gone {
+            self.finish(canvas);
+            return;
+        }
+        if GPU_TILE_STORAGE.generation(layer) != session.floating.generation {
+            log::warn!("The layer changed while it was being transformed, starting over.");
+            self.finish(canvas);
+            self.load(canvas);
+        }
+    }
+
+    fn poll_rendering(&mut self, canvas: &CCanvas) {
+        let Some(receiver) = &mut self.rendering else {
+            return;
+        };
+        let rendered = match receiver.try_recv() {
+            Ok(Some(rendered)) => rendered,
+            Ok(None) => return,
+            Err(_) => {
+                self.rendering = None;
+                return;
+            }
+        };
+        self.rendering = None;
+
+        let Some(session) = &self.session else {
+            return;
+        };
+        let size = UVec2::new(rendered.pixels.width(), rendered.pixels.height());
+        let indices = tile_indices(rendered.origin, rendered.origin + size);
+        if !rendered.commit {
+            GPU_TILE_STORAGE.replace_tiles(
+                session.preview,
+                &rendered.pixels,
+                rendered.origin.as_ivec2(),
+                &indices,
+            );
+            return;
+        }
+
+        let layer = session.floating.layer;
+        let mut history = canvas.history.lock();
+        history.record_tiles(
+            &GPU_TILE_STORAGE,
+            "Transform",
+            layer,
+            indices.iter().copied(),
+        );
+        GPU_TILE_STORAGE.replace_tiles(
+            layer,
+            &rendered.pixels,
+            rendered.origin.as_ivec2(),
+            &indices,
+        );
+        history.commit(&GPU_TILE_STORAGE);
+        drop(history);
+        if let Some(mask) = rendered.selection {
+            canvas
+                .image
+                .write()
+                .selection_mut()
+                .set_mask(Some(mask), &GPU_MASK_STORAGE);
+        }
+        self.finish(canvas);
+    }
+
+    /// Ends the transform, dropping the previews. The layer keeps whatever was
+    /// committed to it.
+    fn finish(&mut self, canvas: &CCanvas) {
+        if let Some(session) = self.session.take() {
+            canvas.previews.write().remove(&session.floating.layer);
+            GPU_TILE_STORAGE.free_layer(session.preview);
+        }
+        self.drag = None;
+        self.rendering = None;
+        canvas.overlay.write().clear();
+    }
+
+    fn ensure_mesh(&mut self) {
+        let Some(session) = &mut self.session else {
+            return;
+        };
+        if self.options.mode == TransformMode::Mesh && session.mesh.is_none() {
+            let cells = self.options.mesh_cells;
+            session.mesh = Some(MeshWarp::new(cells.x, cells.y, session.corners));
+            session.dirty |= session.changed;
+        }
+    }
+}
+
+impl CanvasToolFunction for TransformTool {
+    fn id(&self) -> Id<CanvasTool> {
+        Id::from_str("transform_tool")
+    }
+
+    fn activate(&mut self, canvas: &CCanvas) {
+        self.load(canvas);
+    }
+
+    fn begin(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        if self.session.is_none() {
+            self.load(canvas);
+            return;
+        }
+        self.ensure_mesh();
+        let Some(session) = &self.session else {
+            return;
+        };
+        if session.committing {
+            return;
+        }
+
+        let p = select::pixel_position(mouse, canvas);
+        let zoom = canvas
+            .transform
+            .read()
+            .pixel_to_widget
+            .x_axis
+            .truncate()
+            .length();
+        let kind = session.hit(p, Self::HANDLE_RADIUS / zoom, self.options.mode);
+        self.drag = Some(Drag {
+            kind,
+            start: p,
+            corners: session.corners,
+            mesh: session.mesh.clone(),
+            pivot: session.pivot,
+        });
+    }
+
+    fn update(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        let (Some(session), Some(drag)) = (&mut self.session, &self.drag) else {
+            return;
+        };
+        if session.committing {
+            return;
+        }
+
+        let p = select::pixel_position(mouse, canvas);
+        let delta = p - drag.start;
+        let shift = is_held(keyboard, key::Code::ShiftLeft, key::Code::ShiftRight);
+        let ctrl = is_held(keyboard, key::Code::ControlLeft, key::Code::ControlRight);
+        let alt = is_held(keyboard, key::Code::AltLeft, key::Code::AltRight);
+
+        session.corners = drag.corners;
+        session.mesh = drag.mesh.clone();
+        session.pivot = drag.pivot;
+
+        let c = drag.corners;
+        let axes = Mat2::from_cols(c[1] - c[0], c[3] - c[0]);
+        let local = (axes.determinant().abs() > f32::EPSILON).then(|| axes.inverse());
+        // Scales along the axes of the frame, keeping `anchor` in place.
+        let scale_about = |session: &mut TransformSession, anchor: Vec2, scale: Vec2| {
+            if let Some(local) = local {
+                let m = axes * Mat2::from_diagonal(scale) * local;
+                session.map(|q| anchor + m * (q - anchor));
+            }
+        };
+
+        match drag.kind {
+            DragKind::Move => session.map(|q| q + delta),
+            DragKind::Pivot => session.pivot = drag.pivot + delta,
+            DragKind::Rotate => {
+                let pivot = drag.pivot;
+                let mut angle = (drag.start - pivot).angle_to(p - pivot);
+                if shift {
+                    angle = (angle / Self::ANGLE_SNAP).round() * Self::ANGLE_SNAP;
+                }
+                let rotation = Mat2::from_angle(angle);
+                session.map(|q| pivot + rotation * (q - pivot));
+            }
+            DragKind::Corner(i) if ctrl || self.options.mode == TransformMode::Perspective => {
+                session.corners[i] = c[i] + delta;
+                if let Some(mesh) = &mut session.mesh {
+                    let corner = mesh_corner(mesh, i);
+                    mesh.points[corner] += delta;
+                }
+            }
+            DragKind::Corner(i) => {
+                let anchor = if alt { drag.pivot } else { c[(i + 2) % 4] };
+                let from = c[i] - anchor;
+                let scale = if shift {
+                    Vec2::splat((p - anchor).dot(from) / from.length_squared().max(f32::EPSILON))
+                } else if let Some(local) = local {
+                    ratio(local * (p - anchor), local * from)
+                } else {
+                    Vec2::ONE
+                };
+                scale_about(session, anchor, scale);
+            }
+            DragKind::Edge(i) if ctrl => {
+                let Some(local) = local else {
+                    return;
+                };
+                let along = (c[(i + 1) % 4] - c[i]).normalize_or_zero();
+                let offset = along * delta.dot(along);
+                let origin = c[0];
+                // How far each point is from the opposite edge, from 0 to 1.
+                let weight = move |q: Vec2| {
+                    let l = local * (q - origin);
+                    match i {
+                        0 => 1.0 - l.y,
+                        1 => l.x,
+                        2 => l.y,
+                        _ => 1.0 - l.x,
+                    }
+                };
+                session.map(|q| q + offset * weight(q));
+            }
+            DragKind::Edge(i) => {
+                let Some(local) = local else {
+                    return;
+                };
+                let middle = c[i].lerp(c[(i + 1) % 4], 0.5);
+                let opposite = c[(i + 2) % 4].lerp(c[(i + 3) % 4], 0.5);
+                let anchor = if alt { drag.pivot } else { opposite };
+                let s = ratio(local * (p - anchor), local * (middle - anchor));
+                let s = if i % 2 == 0 { s.y } else { s.x };
+                let scale = if shift {
+                    Vec2::splat(s)
+                } else if i % 2 == 0 {
+                    Vec2::new(1.0, s)
+                } else {
+                    Vec2::new(s, 1.0)
+                };
+                scale_about(session, anchor, scale);
+            }
+            DragKind::MeshPoint(i) => {
+                if let Some(mesh) = &mut session.mesh {
+                    mesh.points[i] += delta;
+                    session.corners = mesh.corners();
+                }
+            }
+        }
+
+        session.dirty = true;
+        session.changed = true;
+        *canvas.overlay.write() = session.overlay(self.options.mode);
+        self.render();
+    }
+
+    fn end(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.update(keyboard, mouse, canvas);
+        self.drag = None;
+    }
+
+    fn frame(&mut self, canvas: &CCanvas) {
+        self.poll_loading(canvas);
+        self.check_layer(canvas);
+        self.poll_rendering(canvas);
+        self.ensure_mesh();
+        self.render();
+        if let Some(session) = &self.session {
+            if !session.committing {
+                *canvas.overlay.write() = session.overlay(self.options.mode);
+            }
+        }
+    }
+
+    fn confirm(&mut self, canvas: &CCanvas) {
+        self.loading = None;
+        self.drag = None;
+        let Some(session) = &mut self.session else {
+            return;
+        };
+        if !session.changed {
+            self.finish(canvas);
+            return;
+        }
+        if !session.committing {
+            session.committing = true;
+            session.dirty = true;
+            self.render();
+        }
+    }
+
+    fn cancel(&mut self, canvas: &CCanvas) {
+        self.loading = None;
+        self.finish(canvas);
+    }
+
+    fn deactivate(&mut self, canvas: &CCanvas) {
+        self.confirm(canvas);
+    }
+}
+
+/// Takes the pixels to transform out of the layer, only the selected ones if
+/// there is a selection. Returns `None` if there's nothing to take.
+fn lift(
+    layer: Id<Layer>,
+    generation: u64,
+    image_size: UVec2,
+    original: Rgba32FImage,
+    selection: Option<&SelectionMask>,
+) -> Option<Floating> {
+    let coverage = |x: u32, y: u32| selection.map_or(1.0, |m| m.value(UVec2::new(x, y)));
+
+    let (mut min, mut max) = (UVec2::MAX, UVec2::ZERO);
+    for (x, y, pixel) in original.enumerate_pixels() {
+        if pixel.0[3] > 0.0 && coverage(x, y) > 0.0 {
+            min = min.min(UVec2::new(x, y));
+            max = max.max(UVec2::new(x + 1, y + 1));
+        }
+    }
+    if min.cmpge(max).any() {
+        return None;
+    }
+
+    let size = max - min;
+    let mut pixels = original.view(min.x, min.y, size.x, size.y).to_image();
+    let mut mask = selection.map(|_| Rgba32FImage::new(size.x, size.y));
+    for (x, y, pixel) in pixels.enumerate_pixels_mut() {
+        let k = coverage(min.x + x, min.y + y);
+        pixel.0[3] *= k;
+        if let Some(mask) = &mut mask {
+            mask.put_pixel(x, y, Rgba([1.0, 1.0, 1.0, k]));
+        }
+    }
+
+    let backdrop = selection.map(|_| {
+        let mut backdrop = original;
+        for (x, y, pixel) in backdrop.enumerate_pixels_mut() {
+            pixel.0[3] *= 1.0 - coverage(x, y);
+        }
+        backdrop
+    });
+
+    Some(Floating {
+        layer,
+        generation,
+        image_size,
+        origin: min,
+        pixels,
+        mask,
+        backdrop,
+    })
+}
+
+/// The layer inside the rectangle at `origin`, the transformed pixels over
+/// what was left behind.
+fn compose(
+    floating: &Floating,
+    warp: &Warp,
+    filter: ResampleFilter,
+    origin: UVec2,
+    size: UVec2,
+) -> Rgba32FImage {
+    let mut pixels = match &floating.backdrop {
+        Some(backdrop) => backdrop.view(origin.x, origin.y, size.x, size.y).to_image(),
+        None => Rgba32FImage::new(size.x, size.y),
+    };
+    let Some((offset, warped)) = warp::warp(&floating.pixels, warp, filter, floating.image_size)
+    else {
+        return pixels;
+    };
+
+    let offset = offset - origin.as_ivec2();
+    for (x, y, pixel) in warped.enumerate_pixels() {
+        let p = offset + IVec2::new(x as i32, y as i32);
+        if pixel.0[3] <= 0.0 || p.cmplt(IVec2::ZERO).any() || p.cmpge(size.as_ivec2()).any() {
+            continue;
+        }
+        let target = pixels.get_pixel_mut(p.x as u32, p.y as u32);
+        target.0 = blend::composite(
+            BlendMode::Normal,
+            Vec4::from_array(target.0),
+            Vec4::from_array(pixel.0),
+            1.0,
+        )
+        .to_array();
+    }
+    pixels
+}
+
+/// The selection carried along with the pixels, `None` if there wasn't one.
+fn transform_mask(
+    floating: &Floating,
+    warp: &Warp,
+    filter: ResampleFilter,
+) -> Option<SelectionMask> {
+    let mask = floating.mask.as_ref()?;
+    let size = floating.image_size;
+    let mut coverage = vec![0; (size.x * size.y) as usize];
+    if let Some((offset, warped)) = warp::warp(mask, warp, filter, size) {
+        let offset = offset.as_uvec2();
+        for (x, y, pixel) in warped.enumerate_pixels() {
+            let p = offset + UVec2::new(x, y);
+            coverage[(p.y * size.x + p.x) as usize] =
+                (pixel.0[3].clamp(0.0, 1.0) * 255.0).round() as u8;
+        }
+    }
+    Some(SelectionMask::from_coverage(size, &coverage))
+}
+
+fn is_held(keyboard: &KeyboardState, left: key::Code, right: key::Code) -> bool {
+    keyboard.is_pressed(left) || keyboard.is_pressed(right)
+}
+
+/// `a / b` per component, 1 where `b` is about zero.
+fn ratio(a: Vec2, b: Vec2) -> Vec2 {
+    let component = |a: f32, b: f32| if b.abs() <= f32::EPSILON { 1.0 } else { a / b };
+    Vec2::new(component(a.x, b.x), component(a.y, b.y))
+}
+
+/// Index of the mesh point at the corner, clockwise from the top left.
+fn mesh_corner(mesh: &MeshWarp, corner: usize) -> usize {
+    let (x, y) = match corner {
+        0 => (0, 0),
+        1 => (mesh.cols, 0),
+        2 => (mesh.cols, mesh.rows),
+        _ => (0, mesh.rows),
+    };
+    (y * (mesh.cols + 1) + x) as usize
+}
+
+/// Whether the point is inside the polygon, by counting edge crossings so
+/// twisted quads work too.
+fn contains(polygon: &[Vec2], p: Vec2) -> bool {
+    let mut inside = false;
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        if (a.y > p.y) != (b.y > p.y) {
+            let x = a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x);
+            if p.x < x {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+/// The pixels from `min` to `max` grown to whole tiles and clamped to the
+/// image. Empty if nothing of it is on the image.
+fn tile_rect(min: IVec2, max: IVec2, size: UVec2) -> PixelRect {
+    let min = min.clamp(IVec2::ZERO, size.as_ivec2()).as_uvec2();
+    let max = max.clamp(IVec2::ZERO, size.as_ivec2()).as_uvec2();
+    if min.cmpge(max).any() {
+        return (UVec2::ZERO, UVec2::ZERO);
+    }
+
+    let tile = GpuTileStorage::TILE_SIZE;
+    let min = min / tile * tile;
+    let max = (UVec2::new(max.x.div_ceil(tile), max.y.div_ceil(tile)) * tile).min(size);
+    (min, max)
+}
+
+fn union(a: PixelRect, b: PixelRect) -> PixelRect {
+    if a.0.cmpge(a.1).any() {
+        b
+    } else if b.0.cmpge(b.1).any() {
+        a
+    } else {
+        (a.0.min(b.0), a.1.max(b.1))
+    }
+}
+
+fn tile_indices(min: UVec2, max: UVec2) -> Vec<UVec2> {
+    if min.cmpge(max).any() {
+        return Vec::new();
+    }
+    let tile = GpuTileStorage::TILE_SIZE;
+    let first = min / tile;
+    let last = UVec2::new(max.x.div_ceil(tile), max.y.div_ceil(tile));
+    (first.y..last.y)
+        .flat_map(|y| (first.x..last.x).map(move |x| UVec2::new(x, y)))
+        .collect()
+}
@@ -0,0 +1,182 @@
+//! Selecting the pixels of similar color around a click. The pixels are read
+//! back and searched on a background thread, the selection changes once the
+//! result arrives. Only the tiles the region reaches are read, a few at a time,
+//! so large documents never sit in memory whole.
+
+use cyancia_canvas::CCanvas;
+use cyancia_id::Id;
+use cyancia_image::{
+    CImage,
+    layer::Layer,
+    readback::ReadbackError,
+    region::{self, ColorMatch, SampleSource},
+    selection::{GPU_MASK_STORAGE, SelectionMask, SelectionMode},
+    tile::{GPU_TILE_STORAGE, GpuTileStorage},
+};
+use cyancia_input::{key::KeyboardState, mouse::PressedMouseState};
+use futures::channel::oneshot;
+use glam::{UVec2, Vec2};
+use iced_core::Rectangle;
+
+use crate::{CanvasTool, CanvasToolFunction, select};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MagicWandOptions {
+    /// Mode used when no modifier is held.
+    pub mode: SelectionMode,
+    /// Largest difference of any channel from the clicked color, from 0 to 1.
+    pub tolerance: f32,
+    /// Only selects pixels connected to the clicked one, instead of every
+    /// matching pixel of the image.
+    pub contiguous: bool,
+    pub source: SampleSource,
+}
+
+impl Default for MagicWandOptions {
+    fn default() -> Self {
+        Self {
+            mode: SelectionMode::Replace,
+            tolerance: 0.1,
+            contiguous: true,
+            source: SampleSource::ActiveLayer,
+        }
+    }
+}
+
+/// A search that finished, and the selection it was started on.
+struct WandResult {
+    selection: Id<Layer>,
+    mask: SelectionMask,
+    mode: SelectionMode,
+}
+
+#[derive(Default)]
+pub struct MagicWandTool {
+    pub options: MagicWandOptions,
+    pending: Option<oneshot::Receiver<WandResult>>,
+}
+
+impl MagicWandTool {
+    /// Tiles read back at once while searching.
+    const READ_BATCH: usize = 16;
+}
+
+impl CanvasToolFunction for MagicWandTool {
+    fn id(&self) -> Id<CanvasTool> {
+        Id::from_str("magic_wand_tool")
+    }
+
+    fn begin(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        let pixel = canvas
+            .transform
+            .read()
+            .widget_to_pixel(Vec2::new(mouse.position.x, mouse.position.y));
+        let image = canvas.image.read();
+        let size = image.size();
+        if pixel.cmplt(Vec2::ZERO).any() || pixel.cmpge(size.as_vec2()).any() {
+            return;
+        }
+
+        let seed = pixel.as_uvec2();
+        let options = self.options;
+        let mode = select::modifier_mode(keyboard, options.mode);
+        let selection = image.selection().id();
+        // Only the layer tree, the pixels stay in the tile storage.
+        let image = image.clone();
+        let (sender, receiver) = oneshot::channel();
+        self.pending = Some(receiver);
+
+        std::thread::spawn(move || {
+            let coverage = match search(&image, seed, options) {
+                Ok(c) => c,
+                Err(e) => {
+                    log::error!("Unable to read pixels for the magic wand: {}", e);
+                    return;
+                }
+            };
+            let _ = sender.send(WandResult {
+                selection,
+                mask: SelectionMask::from_coverage(size, &coverage),
+                mode,
+            });
+        });
+    }
+
+    fn frame(&mut self, canvas: &CCanvas) {
+        let Some(receiver) = &mut self.pending else {
+            return;
+        };
+        let result = match receiver.try_recv() {
+            Ok(Some(result)) => result,
+            Ok(None) => return,
+            Err(_) => {
+                self.pending = None;
+                return;
+            }
+        };
+        self.pending = None;
+
+        let mut image = canvas.image.write();
+        // The document was switched while searching.
+        if image.selection().id() != result.selection {
+            return;
+        }
+        image
+            .selection_mut()
+            .apply_mask(&result.mask, result.mode, &GPU_MASK_STORAGE);
+    }
+}
+
+/// Coverage of the whole image by the pixels matching the one at `seed`.
+fn search(
+    image: &CImage,
+    seed: UVec2,
+    options: MagicWandOptions,
+) -> Result<Vec<u8>, ReadbackError> {
+    let size = image.size();
+    let read = |rect| {
+        futures::executor::block_on(options.source.read_rect(image, &GPU_TILE_STORAGE, rect))
+    };
+    let reference = read(Rectangle {
+        x: seed.x,
+        y: seed.y,
+        width: 1,
+        height: 1,
+    })?;
+    let color = ColorMatch::new(region::pixel(&reference, UVec2::ZERO), options.tolerance);
+
+    if options.contiguous {
+        let filled = region::flood_tiles(size, seed, |indices| {
+            let mut flags = Vec::with_capacity(indices.len());
+            for batch in indices.chunks(MagicWandTool::READ_BATCH) {
+                // Submitted together, waited for one by one.
+                let readbacks = batch
+                    .iter()
+                    .map(|index| {
+                        let rect = region::tile_rect(*index, size);
+                        options.source.read_rect(image, &GPU_TILE_STORAGE, rect)
+                    })
+                    .collect::<Vec<_>>();
+                for readback in readbacks {
+                    let pixels = futures::executor::block_on(readback)?;
+                    flags.push(region::matching(&pixels, &color));
+                }
+            }
+            Ok(flags)
+        })?;
+        Ok(region::to_coverage(&filled))
+    } else {
+        // Every pixel is looked at, a row of tiles at a time.
+        let mut coverage = Vec::with_capacity((size.x * size.y) as usize);
+        for y in (0..size.y).step_by(GpuTileStorage::TILE_SIZE as usize) {
+            let pixels = read(Rectangle {
+                x: 0,
+                y,
+                width: size.x,
+                height: GpuTileStorage::TILE_SIZE.min(size.y - y),
+            })?;
+            coverage.extend(region::color_range(&pixels, &color));
+        }
+        Ok(coverage)
+    }
+}
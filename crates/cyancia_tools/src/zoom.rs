@@ -30,4 +30,20 @@ impl CanvasToolFunction for ZoomTool {
             .clone()
             .scaled_around(f, self.start_pos);
     }
+
+    /// Only where the pointer ended up matters for the view.
+    fn update_batch(
+        &mut self,
+        keyboard: &KeyboardState,
+        samples: &[PressedMouseState],
+        canvas: &CCanvas,
+    ) {
+        if let Some(mouse) = samples.last() {
+            self.update(keyboard, mouse, canvas);
+        }
+    }
+
+    fn predict(&mut self, keyboard: &KeyboardState, mouse: &PressedMouseState, canvas: &CCanvas) {
+        self.update(keyboard, mouse, canvas);
+    }
 }